@@ -0,0 +1,348 @@
+//! Advertising payloads of Apple iBeacon and Google Eddystone beacons, each
+//! a complete advertisement starting with the flags.
+
+use std::fmt;
+
+// LE General Discoverable, BR/EDR Not Supported
+const FLAGS: [u8; 3] = [0x02, 0x01, 0x06];
+const MAX_PAYLOAD_LEN: usize = 31;
+
+// AD types
+const COMPLETE_UUIDS16: u8 = 0x03;
+const SERVICE_DATA16: u8 = 0x16;
+const MANUFACTURER_DATA: u8 = 0xFF;
+
+const APPLE: u16 = 0x004C;
+// iBeacon type and length of the data following it
+const IBEACON_PREFIX: [u8; 2] = [0x02, 0x15];
+const EDDYSTONE: u16 = 0xFEAA;
+
+const EDDYSTONE_UID: u8 = 0x00;
+const EDDYSTONE_URL: u8 = 0x10;
+const EDDYSTONE_TLM: u8 = 0x20;
+const EDDYSTONE_URL_MAX_LEN: usize = 17;
+
+// Longer prefixes and expansions first, so the shortest encoding is picked
+const URL_SCHEMES: [(&str, u8); 4] = [
+    ("https://www.", 0x01),
+    ("http://www.", 0x00),
+    ("https://", 0x03),
+    ("http://", 0x02),
+];
+const URL_EXPANSIONS: [(&str, u8); 14] = [
+    (".info/", 0x04),
+    (".com/", 0x00),
+    (".org/", 0x01),
+    (".edu/", 0x02),
+    (".net/", 0x03),
+    (".biz/", 0x05),
+    (".gov/", 0x06),
+    (".info", 0x0B),
+    (".com", 0x07),
+    (".org", 0x08),
+    (".edu", 0x09),
+    (".net", 0x0A),
+    (".biz", 0x0C),
+    (".gov", 0x0D),
+];
+
+/// URL that cannot be sent in an Eddystone-URL frame
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UrlError {
+    /// Not starting with `http://` or `https://`
+    Scheme,
+    /// More than 17 bytes once compressed
+    TooLong(usize),
+    /// Only printable ASCII can be sent
+    Character(char),
+}
+
+impl fmt::Display for UrlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UrlError::Scheme => write!(f, "URL must start with http:// or https://"),
+            UrlError::TooLong(len) => write!(
+                f,
+                "URL is {} bytes compressed, at most {} fit",
+                len, EDDYSTONE_URL_MAX_LEN
+            ),
+            UrlError::Character(c) => write!(f, "URL contains {:?}", c),
+        }
+    }
+}
+
+/// Values sent in an Eddystone-TLM frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Telemetry {
+    /// `None` when the beacon does not measure it
+    pub battery_mv: Option<u16>,
+    /// `None` when the beacon does not measure it
+    pub temperature: Option<f32>,
+    /// Frames sent since power up
+    pub adv_count: u32,
+    /// Time since power up in tenths of a second
+    pub uptime_ds: u32,
+}
+
+/// iBeacon of `uuid`, with the RSSI in dBm measured at 1 m
+pub fn ibeacon(uuid: &[u8; 16], major: u16, minor: u16, measured_power: i8) -> Vec<u8> {
+    let mut data = IBEACON_PREFIX.to_vec();
+    data.extend_from_slice(uuid);
+    data.extend_from_slice(&major.to_be_bytes());
+    data.extend_from_slice(&minor.to_be_bytes());
+    data.push(measured_power as u8);
+
+    let mut payload = FLAGS.to_vec();
+    push_structure(
+        &mut payload,
+        MANUFACTURER_DATA,
+        &[&APPLE.to_le_bytes(), &data],
+    );
+    payload
+}
+
+/// Eddystone-UID, with the RSSI in dBm measured at 0 m
+pub fn eddystone_uid(tx_power: i8, namespace: &[u8; 10], instance: &[u8; 6]) -> Vec<u8> {
+    let mut frame = vec![EDDYSTONE_UID, tx_power as u8];
+    frame.extend_from_slice(namespace);
+    frame.extend_from_slice(instance);
+    // Reserved
+    frame.extend_from_slice(&[0, 0]);
+    eddystone(&frame)
+}
+
+/// Eddystone-URL of `url`, compressed with the scheme and expansion codes
+pub fn eddystone_url(tx_power: i8, url: &str) -> Result<Vec<u8>, UrlError> {
+    let (scheme, mut rest) = URL_SCHEMES
+        .iter()
+        .find_map(|&(prefix, code)| Some((code, url.strip_prefix(prefix)?)))
+        .ok_or(UrlError::Scheme)?;
+
+    let mut encoded = Vec::new();
+    while let Some(c) = rest.chars().next() {
+        let expansion = URL_EXPANSIONS
+            .iter()
+            .find(|(expansion, _)| rest.starts_with(expansion));
+        if let Some(&(expansion, code)) = expansion {
+            encoded.push(code);
+            rest = &rest[expansion.len()..];
+        } else if c.is_ascii_graphic() {
+            encoded.push(c as u8);
+            rest = &rest[1..];
+        } else {
+            return Err(UrlError::Character(c));
+        }
+    }
+    if encoded.len() > EDDYSTONE_URL_MAX_LEN {
+        return Err(UrlError::TooLong(encoded.len()));
+    }
+
+    let mut frame = vec![EDDYSTONE_URL, tx_power as u8, scheme];
+    frame.extend_from_slice(&encoded);
+    Ok(eddystone(&frame))
+}
+
+/// Unencrypted Eddystone-TLM
+pub fn eddystone_tlm(telemetry: &Telemetry) -> Vec<u8> {
+    // Signed 8.8 fixed point, 0x8000 when not measured
+    let temperature = match telemetry.temperature {
+        Some(celsius) => (celsius * 256.0)
+            .round()
+            .clamp(i16::MIN as f32 + 1.0, i16::MAX as f32) as i16,
+        None => i16::MIN,
+    };
+    // Version 0
+    let mut frame = vec![EDDYSTONE_TLM, 0x00];
+    frame.extend_from_slice(&telemetry.battery_mv.unwrap_or(0).to_be_bytes());
+    frame.extend_from_slice(&temperature.to_be_bytes());
+    frame.extend_from_slice(&telemetry.adv_count.to_be_bytes());
+    frame.extend_from_slice(&telemetry.uptime_ds.to_be_bytes());
+    eddystone(&frame)
+}
+
+// Eddystone frame in service data, next to the Eddystone UUID in the list
+// of services as the specification requires
+fn eddystone(frame: &[u8]) -> Vec<u8> {
+    let uuid = EDDYSTONE.to_le_bytes();
+    let mut payload = FLAGS.to_vec();
+    push_structure(&mut payload, COMPLETE_UUIDS16, &[&uuid]);
+    push_structure(&mut payload, SERVICE_DATA16, &[&uuid, frame]);
+    debug_assert!(payload.len() <= MAX_PAYLOAD_LEN);
+    payload
+}
+
+// Append an AD structure of `ad_type` holding `parts`
+fn push_structure(payload: &mut Vec<u8>, ad_type: u8, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    payload.push(len as u8 + 1);
+    payload.push(ad_type);
+    for part in parts {
+        payload.extend_from_slice(part);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; 16] = [
+        0xE2, 0xC5, 0x6D, 0xB5, 0xDF, 0xFB, 0x48, 0xD2, 0xB0, 0x60, 0xD0, 0xF5, 0xA7, 0x10, 0x96,
+        0xE0,
+    ];
+    // Flags, then the complete list of 16-bit UUIDs with Eddystone only
+    const EDDYSTONE_HEADER: [u8; 7] = [0x02, 0x01, 0x06, 0x03, 0x03, 0xAA, 0xFE];
+
+    // Eddystone payload of `frame` as it must come out
+    fn eddystone_payload(frame: &[u8]) -> Vec<u8> {
+        let mut payload = EDDYSTONE_HEADER.to_vec();
+        payload.extend([frame.len() as u8 + 3, 0x16, 0xAA, 0xFE]);
+        payload.extend(frame);
+        payload
+    }
+
+    #[test]
+    fn ibeacon_payload() {
+        let mut expected = vec![0x02, 0x01, 0x06, 0x1A, 0xFF, 0x4C, 0x00, 0x02, 0x15];
+        expected.extend(UUID);
+        expected.extend([0x12, 0x34, 0x00, 0x07, 0xC5]);
+        assert_eq!(ibeacon(&UUID, 0x1234, 7, -59), expected);
+        assert_eq!(expected.len(), 30);
+    }
+
+    #[test]
+    fn eddystone_uid_payload() {
+        let namespace = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A];
+        let instance = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+        let payload = eddystone_uid(-18, &namespace, &instance);
+        let mut frame = vec![0x00, 0xEE];
+        frame.extend(namespace);
+        frame.extend(instance);
+        frame.extend([0x00, 0x00]);
+        assert_eq!(payload, eddystone_payload(&frame));
+        // Exactly fills a legacy advertisement
+        assert_eq!(payload.len(), MAX_PAYLOAD_LEN);
+    }
+
+    #[test]
+    fn eddystone_url_schemes() {
+        let cases: [(&str, &[u8]); 4] = [
+            ("http://www.a.b", &[0x00, b'a', b'.', b'b']),
+            ("https://www.a.b", &[0x01, b'a', b'.', b'b']),
+            ("http://a.b", &[0x02, b'a', b'.', b'b']),
+            ("https://a.b", &[0x03, b'a', b'.', b'b']),
+        ];
+        for (url, encoded) in cases {
+            let mut frame = vec![0x10, 0xEB];
+            frame.extend(encoded);
+            assert_eq!(
+                eddystone_url(-21, url),
+                Ok(eddystone_payload(&frame)),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn eddystone_url_expansions() {
+        let cases: [(&str, &[u8]); 6] = [
+            ("https://www.apollolabs.com/", b"\x01apollolabs\x00"),
+            ("https://example.info", b"\x03example\x0B"),
+            ("http://www.x.info/y", b"\x00x\x04y"),
+            ("https://a.gov/b.org", b"\x03a\x06b\x08"),
+            ("http://goo.gl/abc", b"\x02goo.gl/abc"),
+            // Only whole expansions, ".co" is sent as it is
+            ("https://a.co/", b"\x03a.co/"),
+        ];
+        for (url, encoded) in cases {
+            let mut frame = vec![0x10, 0x00];
+            frame.extend(encoded);
+            assert_eq!(
+                eddystone_url(0, url),
+                Ok(eddystone_payload(&frame)),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn eddystone_url_length() {
+        // 17 bytes once compressed fill the advertisement
+        let payload = eddystone_url(0, "https://abcdefghijklmnopq").unwrap();
+        assert_eq!(payload.len(), MAX_PAYLOAD_LEN);
+        let payload = eddystone_url(0, "https://www.abcdefghijklmnop.com/").unwrap();
+        assert_eq!(payload.len(), MAX_PAYLOAD_LEN);
+
+        assert_eq!(
+            eddystone_url(0, "https://abcdefghijklmnopqr"),
+            Err(UrlError::TooLong(18))
+        );
+        assert_eq!(
+            UrlError::TooLong(18).to_string(),
+            "URL is 18 bytes compressed, at most 17 fit"
+        );
+    }
+
+    #[test]
+    fn eddystone_url_errors() {
+        assert_eq!(eddystone_url(0, "ftp://a.com"), Err(UrlError::Scheme));
+        assert_eq!(eddystone_url(0, "www.a.com"), Err(UrlError::Scheme));
+        assert_eq!(eddystone_url(0, "HTTPS://a.com"), Err(UrlError::Scheme));
+        assert_eq!(
+            eddystone_url(0, "https://café.fr"),
+            Err(UrlError::Character('é'))
+        );
+        assert_eq!(
+            eddystone_url(0, "http://a b"),
+            Err(UrlError::Character(' '))
+        );
+        assert_eq!(
+            eddystone_url(0, "http://a\u{1}"),
+            Err(UrlError::Character('\u{1}'))
+        );
+        assert_eq!(
+            UrlError::Scheme.to_string(),
+            "URL must start with http:// or https://"
+        );
+        assert_eq!(UrlError::Character('é').to_string(), "URL contains 'é'");
+    }
+
+    #[test]
+    fn eddystone_tlm_payload() {
+        let payload = eddystone_tlm(&Telemetry {
+            battery_mv: Some(3000),
+            temperature: Some(25.5),
+            adv_count: 256,
+            uptime_ds: 60_000,
+        });
+        let frame = [
+            0x20, 0x00, 0x0B, 0xB8, 0x19, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xEA, 0x60,
+        ];
+        assert_eq!(payload, eddystone_payload(&frame));
+    }
+
+    #[test]
+    fn eddystone_tlm_temperature() {
+        let temperature = |temperature| {
+            let payload = eddystone_tlm(&Telemetry {
+                battery_mv: None,
+                temperature,
+                adv_count: 0,
+                uptime_ds: 0,
+            });
+            // Battery and temperature follow the frame type and version
+            let frame = &payload[EDDYSTONE_HEADER.len() + 4..];
+            assert_eq!(frame[..4], [0x20, 0x00, 0x00, 0x00]);
+            [frame[4], frame[5]]
+        };
+        assert_eq!(temperature(None), [0x80, 0x00]);
+        assert_eq!(temperature(Some(0.0)), [0x00, 0x00]);
+        assert_eq!(temperature(Some(-1.25)), [0xFE, 0xC0]);
+        // 1/256 steps, rounded
+        assert_eq!(temperature(Some(21.0 + 1.0 / 512.0)), [0x15, 0x01]);
+        // Out of range saturates without reading as not measured
+        assert_eq!(temperature(Some(200.0)), [0x7F, 0xFF]);
+        assert_eq!(temperature(Some(-200.0)), [0x80, 0x01]);
+    }
+}
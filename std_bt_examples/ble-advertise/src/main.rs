@@ -1,40 +1,116 @@
+mod beacon;
+
+use beacon::Telemetry;
 use esp32_nimble::{
     enums::{ConnMode, DiscMode},
-    BLEAdvertisementData, BLEDevice,
+    BLEDevice,
 };
 use esp_idf_hal::delay::FreeRtos;
-use esp_idf_sys as _;
+use esp_idf_sys::{
+    ble_gap_adv_set_data, esp, esp_ble_power_type_t_ESP_BLE_PWR_TYPE_ADV, esp_ble_tx_power_set,
+    esp_power_level_t, esp_power_level_t_ESP_PWR_LVL_P9,
+};
+use std::time::{Duration, Instant};
+
+// Time each frame is advertised before moving to the next
+const FRAME_DURATION_MS: u32 = 2000;
+// Advertising interval, beacons commonly use 100 ms
+const ADV_INTERVAL_MS: u32 = 100;
+// Transmit power of the radio while advertising
+const TX_POWER: esp_power_level_t = esp_power_level_t_ESP_PWR_LVL_P9;
+// RSSI for the power above at 1 m (iBeacon) and 0 m (Eddystone), measure
+// these for the board to get usable distance estimates
+const MEASURED_POWER: i8 = -59;
+const TX_POWER_AT_0M: i8 = -18;
+
+const IBEACON_UUID: [u8; 16] = [
+    0xE2, 0xC5, 0x6D, 0xB5, 0xDF, 0xFB, 0x48, 0xD2, 0xB0, 0x60, 0xD0, 0xF5, 0xA7, 0x10, 0x96, 0xE0,
+];
+const IBEACON_MAJOR: u16 = 1;
+const IBEACON_MINOR: u16 = 1;
+const EDDYSTONE_NAMESPACE: [u8; 10] = [0x8B, 0x0C, 0xA7, 0x50, 0xE7, 0xA7, 0x4E, 0x14, 0xBD, 0x99];
+const EDDYSTONE_INSTANCE: [u8; 6] = [0x00, 0x00, 0x00, 0x00, 0x00, 0x01];
+const EDDYSTONE_URL: &str = "https://www.rust-lang.org/";
+
+#[derive(Debug)]
+enum Frame {
+    IBeacon,
+    EddystoneUid,
+    EddystoneUrl,
+    EddystoneTlm,
+}
+
+// Frames advertised in turn, leave out or repeat frames as needed
+const ROTATION: [Frame; 4] = [
+    Frame::IBeacon,
+    Frame::EddystoneUid,
+    Frame::EddystoneUrl,
+    Frame::EddystoneTlm,
+];
 
 fn main() {
     esp_idf_svc::sys::link_patches();
 
+    // Frames that never change, a URL that does not fit stops here
+    let ibeacon = beacon::ibeacon(&IBEACON_UUID, IBEACON_MAJOR, IBEACON_MINOR, MEASURED_POWER);
+    let uid = beacon::eddystone_uid(TX_POWER_AT_0M, &EDDYSTONE_NAMESPACE, &EDDYSTONE_INSTANCE);
+    let url = beacon::eddystone_url(TX_POWER_AT_0M, EDDYSTONE_URL).unwrap();
+
     // Take ownership of device
     let ble_device = BLEDevice::take();
     // Obtain handle for advertiser
     let ble_advertiser = ble_device.get_advertising();
 
-    // Specify Advertiser Name
-    let mut ad_data = BLEAdvertisementData::new();
-    ad_data.name("ESP32-C3 Peripheral");
-
-    // Configure Advertiser with Specified Data
-    ble_advertiser.lock().set_data(&mut ad_data).unwrap();
+    // Set the Advertising Power
+    esp!(unsafe { esp_ble_tx_power_set(esp_ble_power_type_t_ESP_BLE_PWR_TYPE_ADV, TX_POWER) })
+        .unwrap();
 
     // Make Advertiser Non-Connectable
     // Set Discovery Mode to General
     // Deactivate Scan Responses
+    // Set the Interval in Units of 0.625 ms
+    let interval = (ADV_INTERVAL_MS * 8 / 5) as u16;
     ble_advertiser
         .lock()
         .advertisement_type(ConnMode::Non)
         .disc_mode(DiscMode::Gen)
-        .scan_response(false);
+        .scan_response(false)
+        .min_interval(interval)
+        .max_interval(interval);
 
     // Start Advertising
     ble_advertiser.lock().start().unwrap();
     println!("Advertisement Started");
-    loop {
-        // Keep Advertising
-        // Add delay to prevent watchdog from triggering
-        FreeRtos::delay_ms(10);
+
+    // Swap the payload of the running advertisement on every frame
+    let start = Instant::now();
+    for frame in ROTATION.iter().cycle() {
+        let tlm;
+        let payload = match frame {
+            Frame::IBeacon => &ibeacon,
+            Frame::EddystoneUid => &uid,
+            Frame::EddystoneUrl => &url,
+            Frame::EddystoneTlm => {
+                tlm = beacon::eddystone_tlm(&telemetry(start.elapsed()));
+                &tlm
+            }
+        };
+        let rc = unsafe { ble_gap_adv_set_data(payload.as_ptr(), payload.len() as i32) };
+        if rc != 0 {
+            println!("Failed to advertise {:?}: {}", frame, rc);
+        }
+        FreeRtos::delay_ms(FRAME_DURATION_MS);
+    }
+}
+
+// The board measures neither battery nor temperature, and the controller
+// does not count frames so the count is estimated from the interval
+fn telemetry(uptime: Duration) -> Telemetry {
+    let uptime_ms = uptime.as_millis();
+    Telemetry {
+        battery_mv: None,
+        temperature: None,
+        adv_count: (uptime_ms / ADV_INTERVAL_MS as u128) as u32,
+        uptime_ds: (uptime_ms / 100) as u32,
     }
 }
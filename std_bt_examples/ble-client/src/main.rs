@@ -1,9 +1,32 @@
-use esp32_nimble::{uuid128, BLEClient, BLEDevice};
+mod explore;
+mod gatt;
+mod session;
+
+use esp32_nimble::{BLEClient, BLEDevice, BLEError, BleUuid};
 use esp_idf_hal::delay::FreeRtos;
 use esp_idf_hal::task::block_on;
 use esp_idf_sys as _;
+use session::{Action, Backoff, Event, Session};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+// Server to connect to, `None` takes the first device advertising a name
+const DEVICE_NAME: Option<&str> = Some("ESP32 Server");
+// Print every service, characteristic and descriptor of the server once
+// instead of streaming notifications
+const EXPLORE: bool = false;
 
-const DEVICE_NAME: &str = "iPhone";
+// Battery Level and Temperature of the ble-server example, as
+// (service, characteristic), subscribed again after every reconnect
+const SUBSCRIPTIONS: [(u16, u16); 2] = [(0x180F, 0x2A19), (0x181A, 0x2A6E)];
+const BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(500),
+    max: Duration::from_secs(30),
+};
+// How often the link is checked while waiting for notifications
+const LISTEN_POLL: Duration = Duration::from_millis(200);
+
+type Notification = (BleUuid, Vec<u8>);
 
 fn main() {
     esp_idf_sys::link_patches();
@@ -13,47 +36,144 @@ fn main() {
         let ble_device = BLEDevice::take();
         // Acquire Scan Handle
         let ble_scan = ble_device.get_scan();
-        // Scan and Find Device/Server
-        let device = ble_scan
-            .active_scan(true)
-            .interval(100)
-            .window(99)
-            .find_device(10000, |device| device.name() == DEVICE_NAME)
-            .await
-            .unwrap();
-
-        if let Some(device) = device {
-            // Create Client Handle
-            let mut client = BLEClient::new();
-
-            // Define Connect Behaviour
-            client.on_connect(|client| {
-                // Update Connect Parameters on Connect
-                client.update_conn_params(120, 250, 0, 60).unwrap();
-                println!("Connected to {}", DEVICE_NAME);
-            });
-
-            // Connect to advertising device using its address
-            client.connect(device.addr()).await.unwrap();
-
-            // Seek and Create Handle for the BLE Remote Server Service corresponding to the UUID
-            let service = client
-                .get_service(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1"))
-                .await
-                .unwrap();
-
-            // Seek and Create Handle for the BLE Remote Server Characteristic corresponding to the UUID
-            let uuid = uuid128!("681285a6-247f-48c6-80ad-68c3dce18585");
-            let characteristic = service.get_characteristic(uuid).await.unwrap();
-
-            loop {
-                // Read & Print Characteristic Value
-                let value = characteristic.read_value().await.unwrap();
-                println!("Read Value: {:?}", value);
-
-                // Wait 1 second before reading again
-                FreeRtos::delay_ms(1000);
-            }
+        ble_scan.active_scan(true).interval(100).window(99);
+
+        // Notifications arrive on the NimBLE task and are delivered here
+        let (sender, receiver) = mpsc::channel();
+
+        let mut session = Session::new(BACKOFF);
+        let mut address = None;
+        let mut client = BLEClient::new();
+        loop {
+            let event = match session.action() {
+                // Scan and Find Device/Server
+                Action::Scan => {
+                    let device = ble_scan
+                        .find_device(10000, |device| match DEVICE_NAME {
+                            Some(name) => device.name() == name,
+                            None => !device.name().is_empty(),
+                        })
+                        .await;
+                    match device {
+                        Ok(Some(device)) => {
+                            println!("Found {} at {}", device.name(), device.addr());
+                            address = Some(*device.addr());
+                            Event::Found
+                        }
+                        Ok(None) => {
+                            println!("No server found");
+                            Event::NotFound
+                        }
+                        Err(e) => {
+                            println!("Scan failed: {:?}", e);
+                            Event::NotFound
+                        }
+                    }
+                }
+                Action::Connect => {
+                    // Create Client Handle, a fresh one per connection so
+                    // nothing discovered on the last one is reused
+                    client = BLEClient::new();
+
+                    // Define Connect Behaviour
+                    client.on_connect(|client| {
+                        // Update Connect Parameters on Connect
+                        if let Err(e) = client.update_conn_params(120, 250, 0, 60) {
+                            println!("Failed to update connection parameters: {:?}", e);
+                        }
+                        println!("Connected");
+                    });
+
+                    // Connect to advertising device using its address
+                    let address = address.expect("connecting follows a scan that found the server");
+                    match client.connect(&address).await {
+                        Ok(()) => Event::Connected,
+                        Err(e) => {
+                            println!("Failed to connect: {:?}", e);
+                            Event::ConnectFailed
+                        }
+                    }
+                }
+                Action::Subscribe if EXPLORE => {
+                    if let Err(e) = explore::explore(&mut client).await {
+                        println!("Discovery failed: {:?}", e);
+                    }
+                    if let Err(e) = client.disconnect() {
+                        println!("Failed to disconnect: {:?}", e);
+                    }
+                    return;
+                }
+                Action::Subscribe => match subscribe(&mut client, &sender).await {
+                    Ok(()) => Event::Subscribed,
+                    Err(e) => {
+                        println!("Failed to subscribe: {:?}", e);
+                        Event::SubscribeFailed
+                    }
+                },
+                Action::Listen => {
+                    listen(&client, &receiver);
+                    println!("Disconnected");
+                    Event::Disconnected
+                }
+                Action::Wait(delay) => {
+                    if client.connected() {
+                        if let Err(e) = client.disconnect() {
+                            println!("Failed to disconnect: {:?}", e);
+                        }
+                    }
+                    println!("Retrying in {:?}", delay);
+                    FreeRtos::delay_ms(delay.as_millis() as u32);
+                    Event::Elapsed
+                }
+            };
+            session.handle(event);
         }
     });
 }
+
+// Enable notifications or else indications of every subscription through
+// their CCCD, forwarding the values to `sender`
+async fn subscribe(client: &mut BLEClient, sender: &Sender<Notification>) -> Result<(), BLEError> {
+    for (service, characteristic) in SUBSCRIPTIONS {
+        let uuid = BleUuid::from_uuid16(characteristic);
+        let characteristic = client
+            .get_service(BleUuid::from_uuid16(service))
+            .await?
+            .get_characteristic(uuid)
+            .await?;
+
+        let sender = sender.clone();
+        characteristic.on_notify(move |value| {
+            // Only fails once the receiver is gone
+            let _ = sender.send((uuid, value.to_vec()));
+        });
+        if characteristic.can_notify() {
+            characteristic.subscribe_notify(true).await?;
+        } else if characteristic.can_indicate() {
+            characteristic.subscribe_indicate(true).await?;
+        } else {
+            println!(
+                "{} neither notifies nor indicates",
+                explore::describe(uuid, gatt::characteristic_name)
+            );
+        }
+    }
+    Ok(())
+}
+
+// Deliver notifications while the link is up
+fn listen(client: &BLEClient, receiver: &Receiver<Notification>) {
+    while client.connected() {
+        if let Ok((uuid, value)) = receiver.recv_timeout(LISTEN_POLL) {
+            deliver(uuid, &value);
+        }
+    }
+}
+
+fn deliver(uuid: BleUuid, value: &[u8]) {
+    println!(
+        "{}: {}",
+        explore::describe(uuid, gatt::characteristic_name),
+        gatt::format_value(explore::short(uuid), value)
+    );
+}
@@ -0,0 +1,240 @@
+//! Report map and input reports of a media remote with a few keyboard keys.
+//! Keyboard keys are HID usage IDs from the Keyboard/Keypad usage page.
+
+/// Report ID of the keyboard input report
+pub const KEYBOARD_ID: u8 = 1;
+/// Report ID of the consumer control input report
+pub const CONSUMER_ID: u8 = 2;
+
+/// Keyboard keys
+pub const RIGHT_ARROW: u8 = 0x4F;
+pub const LEFT_ARROW: u8 = 0x50;
+
+// Keys a keyboard report holds besides the modifiers
+const KEYBOARD_ROLLOVER: usize = 6;
+// Reported in every key slot when more keys are down than fit
+const ERROR_ROLLOVER: u8 = 0x01;
+
+#[rustfmt::skip]
+pub const REPORT_MAP: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop)
+    0x09, 0x06,        // Usage (Keyboard)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, KEYBOARD_ID, //   Report ID
+    0x05, 0x07,        //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,        //   Usage Minimum (Left Control)
+    0x29, 0xE7,        //   Usage Maximum (Right GUI)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x08,        //   Report Count (8)
+    0x81, 0x02,        //   Input (Data, Variable, Absolute), modifiers
+    0x75, 0x08,        //   Report Size (8)
+    0x95, 0x01,        //   Report Count (1)
+    0x81, 0x01,        //   Input (Constant), reserved
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x65,        //   Logical Maximum (101)
+    0x19, 0x00,        //   Usage Minimum (0)
+    0x29, 0x65,        //   Usage Maximum (101)
+    0x75, 0x08,        //   Report Size (8)
+    0x95, 0x06,        //   Report Count (6)
+    0x81, 0x00,        //   Input (Data, Array), keys
+    0xC0,              // End Collection
+    0x05, 0x0C,        // Usage Page (Consumer)
+    0x09, 0x01,        // Usage (Consumer Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, CONSUMER_ID, //   Report ID
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x05,        //   Report Count (5), one bit per `Media` key
+    0x09, 0xB5,        //   Usage (Scan Next Track)
+    0x09, 0xB6,        //   Usage (Scan Previous Track)
+    0x09, 0xCD,        //   Usage (Play/Pause)
+    0x09, 0xE9,        //   Usage (Volume Increment)
+    0x09, 0xEA,        //   Usage (Volume Decrement)
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0x95, 0x03,        //   Report Count (3)
+    0x81, 0x01,        //   Input (Constant), padding
+    0xC0,              // End Collection
+];
+
+/// Consumer control keys, in the order of their bits in the report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Media {
+    NextTrack,
+    PreviousTrack,
+    PlayPause,
+    VolumeUp,
+    VolumeDown,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Media(Media),
+    /// Usage ID of a keyboard key
+    Keyboard(u8),
+}
+
+/// Input reports while `pressed` keys are held down, without their IDs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Reports {
+    /// Modifiers, reserved byte and up to six keys
+    pub keyboard: [u8; 8],
+    /// Bit per `Media` key
+    pub consumer: [u8; 1],
+}
+
+impl Reports {
+    pub fn new(pressed: &[Key]) -> Self {
+        let mut reports = Reports::default();
+        let mut keys = 0;
+        for key in pressed {
+            match *key {
+                Key::Media(media) => reports.consumer[0] |= 1 << media as u8,
+                Key::Keyboard(usage) if keys < KEYBOARD_ROLLOVER => {
+                    reports.keyboard[2 + keys] = usage;
+                    keys += 1;
+                }
+                Key::Keyboard(_) => {
+                    reports.keyboard[2..].fill(ERROR_ROLLOVER);
+                }
+            }
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    // Short items of a report descriptor as (tag, data), the tag including
+    // the item type
+    fn items(map: &[u8]) -> Vec<(u8, u32)> {
+        let mut items = Vec::new();
+        let mut rest = map;
+        while let [prefix, tail @ ..] = rest {
+            let len = match prefix & 0x03 {
+                3 => 4,
+                len => len as usize,
+            };
+            assert!(tail.len() >= len, "item {:#04X} is cut short", prefix);
+            let data = tail[..len]
+                .iter()
+                .rev()
+                .fold(0, |data, &byte| data << 8 | byte as u32);
+            items.push((prefix & 0xFC, data));
+            rest = &tail[len..];
+        }
+        items
+    }
+
+    // Bits of every input report by report ID, checking that collections
+    // are balanced
+    fn input_bits(map: &[u8]) -> BTreeMap<u8, u32> {
+        let mut bits = BTreeMap::new();
+        let (mut id, mut size, mut count, mut depth) = (0, 0, 0, 0);
+        for (tag, data) in items(map) {
+            match tag {
+                0x84 => id = data as u8,
+                0x74 => size = data,
+                0x94 => count = data,
+                0x80 => *bits.entry(id).or_default() += size * count,
+                0xA0 => depth += 1,
+                0xC0 => {
+                    assert!(depth > 0, "End Collection without a Collection");
+                    depth -= 1;
+                }
+                _ => (),
+            }
+        }
+        assert_eq!(depth, 0, "Collection left open");
+        bits
+    }
+
+    #[test]
+    fn report_map_matches_the_reports() {
+        let reports = Reports::default();
+        assert_eq!(
+            input_bits(REPORT_MAP),
+            BTreeMap::from([
+                (KEYBOARD_ID, reports.keyboard.len() as u32 * 8),
+                (CONSUMER_ID, reports.consumer.len() as u32 * 8),
+            ])
+        );
+    }
+
+    #[test]
+    fn report_map_has_a_usage_per_media_key() {
+        // Consumer usages follow the Consumer Control collection
+        let items = items(REPORT_MAP);
+        let start = items.iter().position(|&item| item == (0x04, 0x0C)).unwrap();
+        let usages: Vec<u32> = items[start..]
+            .iter()
+            .filter(|(tag, _)| *tag == 0x08)
+            .map(|&(_, usage)| usage)
+            .skip(1)
+            .collect();
+        assert_eq!(usages, [0xB5, 0xB6, 0xCD, 0xE9, 0xEA]);
+        assert_eq!(Media::VolumeDown as usize + 1, usages.len());
+    }
+
+    #[test]
+    fn nothing_pressed() {
+        assert_eq!(Reports::new(&[]), Reports::default());
+        assert_eq!(Reports::new(&[]).keyboard, [0; 8]);
+    }
+
+    #[test]
+    fn media_keys_set_their_bits() {
+        let bit = |media| Reports::new(&[Key::Media(media)]).consumer[0];
+        assert_eq!(bit(Media::NextTrack), 0b0_0001);
+        assert_eq!(bit(Media::PreviousTrack), 0b0_0010);
+        assert_eq!(bit(Media::PlayPause), 0b0_0100);
+        assert_eq!(bit(Media::VolumeUp), 0b0_1000);
+        assert_eq!(bit(Media::VolumeDown), 0b1_0000);
+
+        let both = Reports::new(&[Key::Media(Media::PlayPause), Key::Media(Media::VolumeUp)]);
+        assert_eq!(both.consumer, [0b0_1100]);
+        assert_eq!(both.keyboard, [0; 8]);
+    }
+
+    #[test]
+    fn keyboard_keys_fill_the_slots_in_order() {
+        let reports = Reports::new(&[
+            Key::Keyboard(LEFT_ARROW),
+            Key::Media(Media::NextTrack),
+            Key::Keyboard(RIGHT_ARROW),
+        ]);
+        assert_eq!(
+            reports.keyboard,
+            [0, 0, LEFT_ARROW, RIGHT_ARROW, 0, 0, 0, 0]
+        );
+        assert_eq!(reports.consumer, [0b0_0001]);
+
+        let six: Vec<Key> = (0x04..0x0A).map(Key::Keyboard).collect();
+        assert_eq!(
+            Reports::new(&six).keyboard,
+            [0, 0, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09]
+        );
+    }
+
+    #[test]
+    fn a_seventh_key_reports_error_rollover() {
+        let mut pressed: Vec<Key> = (0x04..0x0B).map(Key::Keyboard).collect();
+        pressed.push(Key::Media(Media::VolumeDown));
+        let reports = Reports::new(&pressed);
+        let rollover = [0, 0, 1, 1, 1, 1, 1, 1];
+        assert_eq!(ERROR_ROLLOVER, 1);
+        assert_eq!(reports.keyboard, rollover);
+        // Media keys are not affected
+        assert_eq!(reports.consumer, [0b1_0000]);
+
+        // Whichever order the keys came in
+        let mut pressed: Vec<Key> = (0x04..0x0C).map(Key::Keyboard).collect();
+        pressed.reverse();
+        assert_eq!(Reports::new(&pressed).keyboard, rollover);
+    }
+}
@@ -0,0 +1,132 @@
+mod hid;
+
+use esp32_nimble::enums::{AuthReq, SecurityIOCap};
+use esp32_nimble::hid::BLEHIDDevice;
+use esp32_nimble::{BLEAdvertisementData, BLEDevice, BleUuid};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::{IOPin, PinDriver, Pull};
+use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_sys as _;
+use hid::{Key, Media, Reports};
+
+const DEVICE_NAME: &str = "ESP32 Remote";
+const MANUFACTURER: &str = "Apollo Labs";
+
+// Assigned numbers of the HID service and the keyboard appearance
+const HID_SERVICE: u16 = 0x1812;
+const APPEARANCE_KEYBOARD: u16 = 0x03C1;
+// PnP ID with Espressif's USB vendor ID, products need IDs of their own
+const PNP_VENDOR_SOURCE_USB: u8 = 0x02;
+const PNP_VENDOR: u16 = 0x303A;
+const PNP_PRODUCT: u16 = 0x0001;
+const PNP_VERSION: u16 = 0x0100;
+// HID information: no country, remote wake-up and normally connectable
+const HID_COUNTRY: u8 = 0x00;
+const HID_FLAGS: u8 = 0x03;
+
+// Buttons are sampled at this period, longer than their contact bounce
+const POLL_MS: u32 = 20;
+
+fn main() {
+    esp_idf_sys::link_patches();
+
+    let peripherals = Peripherals::take().unwrap();
+    let pins = peripherals.pins;
+
+    // Buttons to ground with internal pull-ups, each one sending a key
+    let buttons = [
+        (pins.gpio9.downgrade(), Key::Media(Media::PlayPause)),
+        (pins.gpio3.downgrade(), Key::Media(Media::NextTrack)),
+        (pins.gpio4.downgrade(), Key::Media(Media::PreviousTrack)),
+        (pins.gpio5.downgrade(), Key::Media(Media::VolumeUp)),
+        (pins.gpio6.downgrade(), Key::Media(Media::VolumeDown)),
+        (pins.gpio7.downgrade(), Key::Keyboard(hid::LEFT_ARROW)),
+        (pins.gpio10.downgrade(), Key::Keyboard(hid::RIGHT_ARROW)),
+    ]
+    .map(|(pin, key)| {
+        let mut button = PinDriver::input(pin).unwrap();
+        button.set_pull(Pull::Up).unwrap();
+        (button, key)
+    });
+
+    // Take ownership of device
+    let ble_device = BLEDevice::take();
+
+    // Bond with Secure Connections. Without a display or keyboard pairing is
+    // Just Works, the HID characteristics only open up on an encrypted link.
+    ble_device
+        .security()
+        .set_auth(AuthReq::Bond | AuthReq::Sc)
+        .set_io_cap(SecurityIOCap::NoInputNoOutput);
+
+    // Obtain handle for server
+    let server = ble_device.get_server();
+
+    // Define server connect behaviour
+    server.on_connect(|_server, clntdesc| {
+        // Print connected client data
+        println!("{:?}", clntdesc);
+    });
+
+    // Define server disconnect behaviour
+    server.on_disconnect(|_desc, _reason| {
+        println!("Disconnected, back to advertising");
+    });
+
+    server.on_authentication_complete(|_desc, result| match result {
+        Ok(()) => println!("Paired, sending key presses"),
+        Err(e) => println!("Pairing failed: {:?}", e),
+    });
+
+    // HID service with its information, control point, report map and one
+    // input report per report ID, next to Device Information and Battery
+    let mut hid_device = BLEHIDDevice::new(server);
+    let keyboard = hid_device.input_report(hid::KEYBOARD_ID);
+    let consumer = hid_device.input_report(hid::CONSUMER_ID);
+    hid_device.manufacturer(MANUFACTURER);
+    hid_device.pnp(PNP_VENDOR_SOURCE_USB, PNP_VENDOR, PNP_PRODUCT, PNP_VERSION);
+    hid_device.hid_info(HID_COUNTRY, HID_FLAGS);
+    hid_device.report_map(hid::REPORT_MAP);
+    hid_device.set_battery_level(100);
+
+    // Configure Advertiser Data
+    let ble_advertiser = ble_device.get_advertising();
+    ble_advertiser
+        .lock()
+        .set_data(
+            BLEAdvertisementData::new()
+                .name(DEVICE_NAME)
+                .appearance(APPEARANCE_KEYBOARD)
+                .add_service_uuid(BleUuid::from_uuid16(HID_SERVICE)),
+        )
+        .unwrap();
+
+    // Start Advertising
+    ble_advertiser.lock().start().unwrap();
+
+    let mut reports = Reports::default();
+    loop {
+        FreeRtos::delay_ms(POLL_MS);
+
+        let pressed: Vec<Key> = buttons
+            .iter()
+            .filter(|(button, _)| button.is_low())
+            .map(|&(_, key)| key)
+            .collect();
+
+        // Send only the reports that changed, releases included. Presses
+        // while no host is connected are dropped.
+        let new = Reports::new(&pressed);
+        if server.connected_count() == 0 {
+            reports = new;
+            continue;
+        }
+        if new.keyboard != reports.keyboard {
+            keyboard.lock().set_value(&new.keyboard).notify();
+        }
+        if new.consumer != reports.consumer {
+            consumer.lock().set_value(&new.consumer).notify();
+        }
+        reports = new;
+    }
+}
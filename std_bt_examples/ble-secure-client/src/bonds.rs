@@ -0,0 +1,93 @@
+//! Bonds NimBLE keeps in NVS, read and deleted through its store API.
+//! Errors are NimBLE return codes.
+
+use crate::policy::Policy;
+use esp_idf_sys::{
+    ble_addr_t, ble_gap_conn_desc, ble_gap_conn_find, ble_gap_unpair, ble_store_clear,
+    ble_store_util_bonded_peers, CONFIG_BT_NIMBLE_MAX_BONDS,
+};
+use std::fmt;
+
+/// Identity address of a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Peer {
+    /// Public or random static
+    pub kind: u8,
+    /// Least significant byte first, as NimBLE stores it
+    pub address: [u8; 6],
+}
+
+impl From<ble_addr_t> for Peer {
+    fn from(addr: ble_addr_t) -> Self {
+        Peer {
+            kind: addr.type_,
+            address: addr.val,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.address.iter().rev().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        let kind = if self.kind == 0 { "public" } else { "random" };
+        write!(f, " ({})", kind)
+    }
+}
+
+/// Bonded peers, oldest first
+pub fn list() -> Result<Vec<Peer>, i32> {
+    let mut addrs = [ble_addr_t::default(); CONFIG_BT_NIMBLE_MAX_BONDS as usize];
+    let mut count = 0;
+    let rc =
+        unsafe { ble_store_util_bonded_peers(addrs.as_mut_ptr(), &mut count, addrs.len() as i32) };
+    if rc != 0 {
+        return Err(rc);
+    }
+    Ok(addrs[..count as usize]
+        .iter()
+        .map(|&addr| addr.into())
+        .collect())
+}
+
+/// Delete the bond with `peer`, disconnecting it if connected
+pub fn delete(peer: &Peer) -> Result<(), i32> {
+    let addr = ble_addr_t {
+        type_: peer.kind,
+        val: peer.address,
+    };
+    match unsafe { ble_gap_unpair(&addr) } {
+        0 => Ok(()),
+        rc => Err(rc),
+    }
+}
+
+pub fn clear() -> Result<(), i32> {
+    match unsafe { ble_store_clear() } {
+        0 => Ok(()),
+        rc => Err(rc),
+    }
+}
+
+/// Delete the oldest bonds beyond what `policy` keeps
+pub fn enforce(policy: &Policy) -> Result<(), i32> {
+    for peer in policy.excess(&list()?) {
+        println!("Deleting bond with {}, over the limit", peer);
+        delete(peer)?;
+    }
+    Ok(())
+}
+
+/// Identity of the peer on `conn_handle`, its resolved address when it
+/// uses a private one
+pub fn identity(conn_handle: u16) -> Option<Peer> {
+    let mut desc = ble_gap_conn_desc::default();
+    match unsafe { ble_gap_conn_find(conn_handle, &mut desc) } {
+        0 => Some(desc.peer_id_addr.into()),
+        _ => None,
+    }
+}
@@ -0,0 +1,80 @@
+//! Walk of the GATT database of a connected server, printed as a tree of
+//! services, characteristics and descriptors with their readable values.
+
+use crate::gatt;
+use esp32_nimble::{BLEClient, BLEError, BLERemoteCharacteristic, BleUuid};
+
+/// Print every service of the server `client` is connected to. Values that
+/// cannot be read, for instance without encryption, are reported and
+/// skipped, only failed discovery ends the walk.
+pub async fn explore(client: &mut BLEClient) -> Result<(), BLEError> {
+    for service in client.get_services().await? {
+        let uuid = service.uuid();
+        println!("Service {}", describe(uuid, gatt::service_name));
+
+        for characteristic in service.get_characteristics().await? {
+            let uuid = characteristic.uuid();
+            println!(
+                "  Characteristic {} [{}]",
+                describe(uuid, gatt::characteristic_name),
+                properties(characteristic)
+            );
+            if characteristic.can_read() {
+                match characteristic.read_value().await {
+                    Ok(value) => println!("    Value: {}", gatt::format_value(short(uuid), &value)),
+                    Err(e) => println!("    Value: not readable, {:?}", e),
+                }
+            }
+
+            for descriptor in characteristic.get_descriptors().await? {
+                let uuid = descriptor.uuid();
+                println!("    Descriptor {}", describe(uuid, gatt::descriptor_name));
+                match descriptor.read_value().await {
+                    Ok(value) => {
+                        println!("      Value: {}", gatt::format_value(short(uuid), &value))
+                    }
+                    Err(e) => println!("      Value: not readable, {:?}", e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// UUID followed by its SIG name, if it has one in `names`
+pub fn describe(uuid: BleUuid, names: fn(u16) -> Option<&'static str>) -> String {
+    match short(uuid).and_then(names) {
+        Some(name) => format!("{} {}", uuid, name),
+        None => uuid.to_string(),
+    }
+}
+
+/// 16-bit form of SIG UUIDs, whichever length the server used
+pub fn short(uuid: BleUuid) -> Option<u16> {
+    match uuid {
+        BleUuid::Uuid16(uuid) => Some(uuid),
+        BleUuid::Uuid32(uuid) => u16::try_from(uuid).ok(),
+        BleUuid::Uuid128(mut bytes) => {
+            // NimBLE stores the least significant byte first
+            bytes.reverse();
+            gatt::short_uuid(&bytes).and_then(|uuid| u16::try_from(uuid).ok())
+        }
+    }
+}
+
+fn properties(characteristic: &BLERemoteCharacteristic) -> String {
+    let properties = [
+        (characteristic.can_broadcast(), "BROADCAST"),
+        (characteristic.can_read(), "READ"),
+        (characteristic.can_write_no_response(), "WRITE_NO_RSP"),
+        (characteristic.can_write(), "WRITE"),
+        (characteristic.can_notify(), "NOTIFY"),
+        (characteristic.can_indicate(), "INDICATE"),
+    ];
+    let names: Vec<&str> = properties
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|&(_, name)| name)
+        .collect();
+    names.join(" ")
+}
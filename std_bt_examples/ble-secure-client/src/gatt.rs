@@ -0,0 +1,349 @@
+//! Names of Bluetooth SIG assigned UUIDs and a formatter for the values of
+//! well-known characteristics and descriptors.
+
+// Bluetooth Base UUID 00000000-0000-1000-8000-00805F9B34FB, the short forms
+// replace its first four bytes
+const BASE_UUID_TAIL: [u8; 12] = [
+    0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x80, 0x5F, 0x9B, 0x34, 0xFB,
+];
+
+/// Short form of a 128-bit UUID derived from the Bluetooth Base UUID, the
+/// bytes in the order they are written
+pub fn short_uuid(uuid: &[u8; 16]) -> Option<u32> {
+    (uuid[4..] == BASE_UUID_TAIL).then(|| u32::from_be_bytes([uuid[0], uuid[1], uuid[2], uuid[3]]))
+}
+
+pub fn service_name(uuid: u16) -> Option<&'static str> {
+    Some(match uuid {
+        0x1800 => "Generic Access",
+        0x1801 => "Generic Attribute",
+        0x1802 => "Immediate Alert",
+        0x1803 => "Link Loss",
+        0x1804 => "Tx Power",
+        0x1805 => "Current Time",
+        0x180A => "Device Information",
+        0x180D => "Heart Rate",
+        0x180F => "Battery",
+        0x1810 => "Blood Pressure",
+        0x1812 => "Human Interface Device",
+        0x1816 => "Cycling Speed and Cadence",
+        0x1818 => "Cycling Power",
+        0x1819 => "Location and Navigation",
+        0x181A => "Environmental Sensing",
+        0x181C => "User Data",
+        0x1826 => "Fitness Machine",
+        _ => return None,
+    })
+}
+
+pub fn characteristic_name(uuid: u16) -> Option<&'static str> {
+    Some(match uuid {
+        0x2A00 => "Device Name",
+        0x2A01 => "Appearance",
+        0x2A04 => "Peripheral Preferred Connection Parameters",
+        0x2A05 => "Service Changed",
+        0x2A06 => "Alert Level",
+        0x2A07 => "Tx Power Level",
+        0x2A19 => "Battery Level",
+        0x2A23 => "System ID",
+        0x2A24 => "Model Number String",
+        0x2A25 => "Serial Number String",
+        0x2A26 => "Firmware Revision String",
+        0x2A27 => "Hardware Revision String",
+        0x2A28 => "Software Revision String",
+        0x2A29 => "Manufacturer Name String",
+        0x2A2B => "Current Time",
+        0x2A37 => "Heart Rate Measurement",
+        0x2A38 => "Body Sensor Location",
+        0x2A4A => "HID Information",
+        0x2A4B => "Report Map",
+        0x2A4C => "HID Control Point",
+        0x2A4D => "Report",
+        0x2A4E => "Protocol Mode",
+        0x2A50 => "PnP ID",
+        0x2A6D => "Pressure",
+        0x2A6E => "Temperature",
+        0x2A6F => "Humidity",
+        0x2AA6 => "Central Address Resolution",
+        0x2B29 => "Client Supported Features",
+        0x2B2A => "Database Hash",
+        0x2B3A => "Server Supported Features",
+        _ => return None,
+    })
+}
+
+pub fn descriptor_name(uuid: u16) -> Option<&'static str> {
+    Some(match uuid {
+        0x2900 => "Characteristic Extended Properties",
+        0x2901 => "Characteristic User Description",
+        0x2902 => "Client Characteristic Configuration",
+        0x2903 => "Server Characteristic Configuration",
+        0x2904 => "Characteristic Presentation Format",
+        0x2905 => "Characteristic Aggregate Format",
+        0x2908 => "Report Reference",
+        0x290C => "Environmental Sensing Measurement",
+        _ => return None,
+    })
+}
+
+/// `value` of the characteristic or descriptor `uuid`, the short form of a
+/// SIG UUID. Values of other or unknown UUIDs and values of the wrong
+/// length are written as hex, followed by the text they hold if printable.
+pub fn format_value(uuid: Option<u16>, value: &[u8]) -> String {
+    uuid.and_then(|uuid| decode(uuid, value))
+        .unwrap_or_else(|| raw(value))
+}
+
+fn decode(uuid: u16, value: &[u8]) -> Option<String> {
+    let u16_at = |at: usize| Some(u16::from_le_bytes([*value.get(at)?, *value.get(at + 1)?]));
+    Some(match (uuid, value.len()) {
+        // Strings
+        (0x2A00 | 0x2A24..=0x2A29 | 0x2901, _) => {
+            format!("{:?}", std::str::from_utf8(value).ok()?)
+        }
+        (0x2A01, 2) => format!("0x{:04X}", u16_at(0)?),
+        (0x2A04, 8) => {
+            let (min, max) = (u16_at(0)?, u16_at(2)?);
+            format!(
+                "interval {}-{} ms, latency {}, timeout {} ms",
+                min as f32 * 1.25,
+                max as f32 * 1.25,
+                u16_at(4)?,
+                u16_at(6)? as u32 * 10
+            )
+        }
+        (0x2A06, 1) => match value[0] {
+            0 => "no alert".to_string(),
+            1 => "mild alert".to_string(),
+            2 => "high alert".to_string(),
+            _ => return None,
+        },
+        (0x2A07, 1) => format!("{} dBm", value[0] as i8),
+        (0x2A19, 1) => format!("{} %", value[0]),
+        (0x2A37, _) => {
+            // The lowest flag selects a 16-bit heart rate
+            let &flags = value.first()?;
+            let bpm = if flags & 0x01 != 0 {
+                u16_at(1)?
+            } else {
+                *value.get(1)? as u16
+            };
+            format!("{} bpm", bpm)
+        }
+        (0x2A50, 7) => format!(
+            "vendor 0x{:04X} ({}), product 0x{:04X}, version 0x{:04X}",
+            u16::from_le_bytes([value[1], value[2]]),
+            if value[0] == 1 {
+                "Bluetooth SIG"
+            } else {
+                "USB"
+            },
+            u16_at(3)?,
+            u16_at(5)?
+        ),
+        (0x2A6D, 4) => {
+            let decipascal = u32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+            format!("{:.1} hPa", decipascal as f64 / 1000.0)
+        }
+        (0x2A6E, 2) => match u16_at(0)? as i16 {
+            i16::MIN => "unknown".to_string(),
+            hundredths => format!("{:.2} °C", hundredths as f32 / 100.0),
+        },
+        (0x2A6F, 2) => match u16_at(0)? {
+            u16::MAX => "unknown".to_string(),
+            hundredths => format!("{:.2} %", hundredths as f32 / 100.0),
+        },
+        (0x2902, 2) => {
+            let bits = u16_at(0)?;
+            format!(
+                "notifications {}, indications {}",
+                on_off(bits & 0x01 != 0),
+                on_off(bits & 0x02 != 0)
+            )
+        }
+        (0x2904, 7) => format!(
+            "format 0x{:02X}, exponent {}, unit 0x{:04X}",
+            value[0],
+            value[1] as i8,
+            u16_at(2)?
+        ),
+        (0x2908, 2) => {
+            let kind = match value[1] {
+                1 => "input",
+                2 => "output",
+                3 => "feature",
+                _ => return None,
+            };
+            format!("report {}, {}", value[0], kind)
+        }
+        _ => return None,
+    })
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+// Hex, with the text when every byte is printable ASCII
+fn raw(value: &[u8]) -> String {
+    if value.is_empty() {
+        return "(empty)".to_string();
+    }
+    let hex: Vec<String> = value.iter().map(|byte| format!("{:02X}", byte)).collect();
+    match std::str::from_utf8(value) {
+        Ok(text)
+            if text
+                .bytes()
+                .all(|byte| byte.is_ascii_graphic() || byte == b' ') =>
+        {
+            format!("{} {:?}", hex.join(" "), text)
+        }
+        _ => hex.join(" "),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 128-bit form of a SIG UUID, as it is written
+    fn long(short: u32) -> [u8; 16] {
+        let mut uuid = [0_u8; 16];
+        uuid[..4].copy_from_slice(&short.to_be_bytes());
+        uuid[4..].copy_from_slice(&BASE_UUID_TAIL);
+        uuid
+    }
+
+    fn format(uuid: u16, value: &[u8]) -> String {
+        format_value(Some(uuid), value)
+    }
+
+    #[test]
+    fn short_form_of_base_uuids() {
+        assert_eq!(short_uuid(&long(0x180F)), Some(0x180F));
+        assert_eq!(short_uuid(&long(0x0001_2A19)), Some(0x0001_2A19));
+        // Nordic UART Service, not derived from the base UUID
+        let nus = [
+            0x6E, 0x40, 0x00, 0x01, 0xB5, 0xA3, 0xF3, 0x93, 0xE0, 0xA9, 0xE5, 0x0E, 0x24, 0xDC,
+            0xCA, 0x9E,
+        ];
+        assert_eq!(short_uuid(&nus), None);
+        let mut almost = long(0x180F);
+        almost[15] ^= 1;
+        assert_eq!(short_uuid(&almost), None);
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(service_name(0x180F), Some("Battery"));
+        assert_eq!(service_name(0x181A), Some("Environmental Sensing"));
+        assert_eq!(service_name(0x2A19), None);
+        assert_eq!(characteristic_name(0x2A19), Some("Battery Level"));
+        assert_eq!(
+            characteristic_name(0x2A29),
+            Some("Manufacturer Name String")
+        );
+        assert_eq!(characteristic_name(0x180F), None);
+        assert_eq!(
+            descriptor_name(0x2902),
+            Some("Client Characteristic Configuration")
+        );
+        assert_eq!(descriptor_name(0x2A19), None);
+    }
+
+    #[test]
+    fn name_tables_stay_in_their_ranges() {
+        // Services are 0x18xx, characteristics 0x2Axx and 0x2Bxx,
+        // descriptors 0x29xx
+        for uuid in 0..=u16::MAX {
+            if service_name(uuid).is_some() {
+                assert_eq!(uuid >> 8, 0x18, "0x{:04X}", uuid);
+            }
+            if characteristic_name(uuid).is_some() {
+                assert!(matches!(uuid >> 8, 0x2A | 0x2B), "0x{:04X}", uuid);
+            }
+            if descriptor_name(uuid).is_some() {
+                assert_eq!(uuid >> 8, 0x29, "0x{:04X}", uuid);
+            }
+        }
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(format(0x2A00, b"ESP32 Server"), "\"ESP32 Server\"");
+        assert_eq!(format(0x2A29, "Café".as_bytes()), "\"Café\"");
+        assert_eq!(format(0x2901, b"Blink\n"), "\"Blink\\n\"");
+        // Not UTF-8 falls back to hex
+        assert_eq!(format(0x2A24, &[0x41, 0xFF]), "41 FF");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(format(0x2A01, &[0xC1, 0x03]), "0x03C1");
+        assert_eq!(format(0x2A07, &[0xF4]), "-12 dBm");
+        assert_eq!(format(0x2A19, &[87]), "87 %");
+        assert_eq!(format(0x2A6D, &[0x0C, 0x76, 0x0F, 0x00]), "1013.3 hPa");
+        assert_eq!(format(0x2A6E, &[0x9C, 0x09]), "24.60 °C");
+        assert_eq!(format(0x2A6E, &[0x0C, 0xFE]), "-5.00 °C");
+        assert_eq!(format(0x2A6E, &[0x00, 0x80]), "unknown");
+        assert_eq!(format(0x2A6F, &[0x88, 0x13]), "50.00 %");
+        assert_eq!(format(0x2A6F, &[0xFF, 0xFF]), "unknown");
+    }
+
+    #[test]
+    fn structures() {
+        assert_eq!(
+            format(0x2A04, &[6, 0, 24, 0, 0, 0, 60, 0]),
+            "interval 7.5-30 ms, latency 0, timeout 600 ms"
+        );
+        assert_eq!(format(0x2A06, &[2]), "high alert");
+        assert_eq!(format(0x2A06, &[3]), "03");
+        assert_eq!(format(0x2A37, &[0x00, 72]), "72 bpm");
+        assert_eq!(format(0x2A37, &[0x01, 0x2C, 0x01]), "300 bpm");
+        assert_eq!(format(0x2A37, &[0x01, 0x2C]), "01 2C");
+        assert_eq!(
+            format(0x2A50, &[0x01, 0xE5, 0x02, 0x34, 0x12, 0x00, 0x01]),
+            "vendor 0x02E5 (Bluetooth SIG), product 0x1234, version 0x0100"
+        );
+        assert_eq!(
+            format(0x2A50, &[0x02, 0x6D, 0x04, 0x34, 0x12, 0x00, 0x01]),
+            "vendor 0x046D (USB), product 0x1234, version 0x0100"
+        );
+    }
+
+    #[test]
+    fn descriptors() {
+        assert_eq!(
+            format(0x2902, &[0x01, 0x00]),
+            "notifications on, indications off"
+        );
+        assert_eq!(
+            format(0x2902, &[0x02, 0x00]),
+            "notifications off, indications on"
+        );
+        assert_eq!(
+            format(0x2904, &[0x0E, 0xFE, 0x2F, 0x27, 0x01, 0x00, 0x00]),
+            "format 0x0E, exponent -2, unit 0x272F"
+        );
+        assert_eq!(format(0x2908, &[1, 1]), "report 1, input");
+        assert_eq!(format(0x2908, &[2, 3]), "report 2, feature");
+        assert_eq!(format(0x2908, &[2, 4]), "02 04");
+    }
+
+    #[test]
+    fn wrong_lengths_and_unknown_uuids_are_raw() {
+        assert_eq!(format(0x2A19, &[87, 0]), "57 00");
+        assert_eq!(format(0x2A6E, &[0x9C]), "9C");
+        assert_eq!(format(0x2902, &[]), "(empty)");
+        assert_eq!(
+            format_value(None, b"hi there"),
+            "68 69 20 74 68 65 72 65 \"hi there\""
+        );
+        assert_eq!(format_value(Some(0xFFF1), &[0x00, 0x41]), "00 41");
+        assert_eq!(format_value(None, b"tab\t"), "74 61 62 09");
+    }
+}
@@ -1,73 +1,324 @@
-use esp32_nimble::{enums::*, uuid128, BLEClient, BLEDevice};
-use esp_idf_hal::delay::FreeRtos;
+mod bonds;
+mod explore;
+mod gatt;
+mod policy;
+mod session;
+
+use esp32_nimble::{enums::*, uuid128, BLEClient, BLEDevice, BLEError, BleUuid};
+use esp_idf_hal::delay::{FreeRtos, TickType};
+use esp_idf_hal::gpio::{self, PinDriver, Pull};
+use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_hal::prelude::*;
 use esp_idf_hal::task::block_on;
+use esp_idf_hal::uart::{config, UartDriver};
 use esp_idf_sys as _;
+use policy::Policy;
+use session::{Action, Backoff, Event, Session};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Server to connect to, `None` takes the first device advertising a name
+const DEVICE_NAME: Option<&str> = Some("ESP32 Server");
+// Print every service, characteristic and descriptor of the server each
+// time the link is encrypted, instead of streaming notifications
+const EXPLORE: bool = false;
+
+// Pair with the passkey the server shows, or with numeric comparison where
+// both sides show a number and the user confirms they match. Has to match
+// the server.
+const NUMERIC_COMPARISON: bool = false;
+// Time to type the passkey or answer the comparison. NimBLE waits for the
+// answer on its own task, so the wait has to end well within the 30 s the
+// Security Manager allows for pairing.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(20);
+// At most CONFIG_BT_NIMBLE_MAX_BONDS bonds. Once a server is bonded, new
+// ones may only pair in the first two minutes after power up.
+const POLICY: Policy = Policy {
+    max_bonds: 4,
+    allowlist: true,
+    pairing_window: Duration::from_secs(120),
+};
 
-const DEVICE_NAME: &str = "iPhone";
+const BACKOFF: Backoff = Backoff {
+    initial: Duration::from_millis(500),
+    max: Duration::from_secs(30),
+};
+// How often the link is checked while waiting for notifications
+const LISTEN_POLL: Duration = Duration::from_millis(200);
+
+type Notification = (BleUuid, Vec<u8>);
 
 fn main() {
     esp_idf_sys::link_patches();
 
+    let boot = Instant::now();
+    let peripherals = Peripherals::take().unwrap();
+
+    // BOOT button, held at reset to delete all bonds
+    let mut button = PinDriver::input(peripherals.pins.gpio9).unwrap();
+    button.set_pull(Pull::Up).unwrap();
+
+    // Console UART to type the passkey or confirm the number on
+    let uart = UartDriver::new(
+        peripherals.uart0,
+        peripherals.pins.gpio21,
+        peripherals.pins.gpio20,
+        Option::<gpio::Gpio0>::None,
+        Option::<gpio::Gpio1>::None,
+        &config::Config::default().baudrate(Hertz(115_200)),
+    )
+    .unwrap();
+    let uart = Arc::new(Mutex::new(uart));
+
     block_on(async {
         // Acquire BLE Device Handle
         let ble_device = BLEDevice::take();
 
+        if button.is_low() {
+            match bonds::clear() {
+                Ok(()) => println!("Deleted all bonds"),
+                Err(rc) => println!("Failed to delete bonds: {}", rc),
+            }
+        }
+        match bonds::list() {
+            Ok(peers) => {
+                println!("{} bonded servers", peers.len());
+                for peer in peers {
+                    println!("  {}", peer);
+                }
+            }
+            Err(rc) => println!("Failed to list bonds: {}", rc),
+        }
+
         // Configure Device Security
         ble_device
             .security()
             .set_auth(AuthReq::all())
-            .set_io_cap(SecurityIOCap::KeyboardOnly);
+            .set_io_cap(if NUMERIC_COMPARISON {
+                SecurityIOCap::DisplayYesNo
+            } else {
+                SecurityIOCap::KeyboardOnly
+            });
 
         // Acquire Scan Handle
         let ble_scan = ble_device.get_scan();
+        ble_scan.active_scan(true).interval(100).window(99);
 
-        // Scan and Find Device/Server
-        let device = ble_scan
-            .active_scan(true)
-            .interval(100)
-            .window(99)
-            .find_device(10000, |device| device.name() == DEVICE_NAME)
-            .await
-            .unwrap();
-
-        if let Some(device) = device {
-            // Create Client Handle
-            let mut client = BLEClient::new();
-
-            // Define Connect Behaviour
-            client.on_connect(|client| {
-                // Update Connect Parameters on Connect
-                client.update_conn_params(120, 250, 0, 60).unwrap();
-                println!("Connected to {}", DEVICE_NAME);
-            });
+        // Notifications arrive on the NimBLE task and are delivered here
+        let (sender, receiver) = mpsc::channel();
+
+        let mut session = Session::new(BACKOFF);
+        let mut address = None;
+        let mut client = BLEClient::new();
+        loop {
+            let event = match session.action() {
+                // Scan and Find Device/Server
+                Action::Scan => {
+                    let device = ble_scan
+                        .find_device(10000, |device| match DEVICE_NAME {
+                            Some(name) => device.name() == name,
+                            None => !device.name().is_empty(),
+                        })
+                        .await;
+                    match device {
+                        Ok(Some(device)) => {
+                            println!("Found {} at {}", device.name(), device.addr());
+                            address = Some(*device.addr());
+                            Event::Found
+                        }
+                        Ok(None) => {
+                            println!("No server found");
+                            Event::NotFound
+                        }
+                        Err(e) => {
+                            println!("Scan failed: {:?}", e);
+                            Event::NotFound
+                        }
+                    }
+                }
+                Action::Connect => {
+                    // Create Client Handle, a fresh one per connection so
+                    // nothing discovered on the last one is reused
+                    client = BLEClient::new();
+
+                    // Define Connect Behaviour
+                    client.on_connect(|client| {
+                        // Update Connect Parameters on Connect
+                        if let Err(e) = client.update_conn_params(120, 250, 0, 60) {
+                            println!("Failed to update connection parameters: {:?}", e);
+                        }
+                        println!("Connected");
+                    });
+
+                    // Enter the passkey shown by the server on request
+                    let console = uart.clone();
+                    client.on_passkey_request(move || {
+                        println!("Type the passkey shown by the server and press Enter");
+                        // A passkey has to be returned, 0 fails the pairing
+                        // unless the server happens to show 000000
+                        read_passkey(&console.lock().unwrap()).unwrap_or_else(|| {
+                            println!("No passkey typed in time");
+                            0
+                        })
+                    });
+
+                    // Answer a numeric comparison with y or n
+                    let console = uart.clone();
+                    client.on_confirm_pin(move |pin| {
+                        println!("Does the server show {:06}? [y/n]", pin);
+                        read_yes(&console.lock().unwrap()).unwrap_or_else(|| {
+                            println!("Not answered in time");
+                            false
+                        })
+                    });
 
-            // Connect to advertising device using its address
-            client.connect(device.addr()).await.unwrap();
+                    // Connect to advertising device using its address
+                    let address = address.expect("connecting follows a scan that found the server");
+                    match client.connect(&address).await {
+                        Ok(()) => secure(&mut client, boot).await,
+                        Err(e) => {
+                            println!("Failed to connect: {:?}", e);
+                            Event::ConnectFailed
+                        }
+                    }
+                }
+                // Walk the server instead of subscribing, then hold the link
+                // until it drops and walk it again after the reconnect
+                Action::Subscribe if EXPLORE => match explore::explore(&mut client).await {
+                    Ok(()) => Event::Subscribed,
+                    Err(e) => {
+                        println!("Discovery failed: {:?}", e);
+                        Event::SubscribeFailed
+                    }
+                },
+                Action::Subscribe => match subscribe(&mut client, &sender).await {
+                    Ok(()) => Event::Subscribed,
+                    Err(e) => {
+                        println!("Failed to subscribe: {:?}", e);
+                        Event::SubscribeFailed
+                    }
+                },
+                Action::Listen => {
+                    listen(&client, &receiver);
+                    println!("Disconnected");
+                    Event::Disconnected
+                }
+                Action::Wait(delay) => {
+                    if client.connected() {
+                        if let Err(e) = client.disconnect() {
+                            println!("Failed to disconnect: {:?}", e);
+                        }
+                    }
+                    println!("Retrying in {:?}", delay);
+                    FreeRtos::delay_ms(delay.as_millis() as u32);
+                    Event::Elapsed
+                }
+            };
+            session.handle(event);
+        }
+    });
+}
 
-            // Enter 123456 passkey on request
-            client.on_passkey_request(|| 123456);
+// Apply the bond policy to the server just connected and encrypt the link,
+// pairing if there is no bond yet
+async fn secure(client: &mut BLEClient, boot: Instant) -> Event {
+    // Leave servers the bond policy does not admit
+    let bonded = bonds::list().unwrap_or_default();
+    let peer = bonds::identity(client.conn_handle());
+    if !peer.is_some_and(|peer| POLICY.admits(&peer, &bonded, boot.elapsed())) {
+        println!("Leaving server without a bond, pairing is closed");
+        return Event::ConnectFailed;
+    }
+
+    // Secure Connection
+    if let Err(e) = client.secure_connection().await {
+        println!("Pairing failed: {:?}", e);
+        return Event::ConnectFailed;
+    }
+    if let Err(rc) = bonds::enforce(&POLICY) {
+        println!("Failed to enforce the bond limit: {}", rc);
+    }
+    Event::Connected
+}
 
-            // Secure Connection
-            client.secure_connection().await.unwrap();
+// Enable notifications of the example characteristic through its CCCD,
+// forwarding the values to `sender`
+async fn subscribe(client: &mut BLEClient, sender: &Sender<Notification>) -> Result<(), BLEError> {
+    let uuid = uuid128!("681285a6-247f-48c6-80ad-68c3dce18585");
+    let characteristic = client
+        .get_service(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1"))
+        .await?
+        .get_characteristic(uuid)
+        .await?;
 
-            // Seek and Create Handle for the BLE Remote Server Service corresponding to the UUID
-            let service = client
-                .get_service(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1"))
-                .await
-                .unwrap();
+    let sender = sender.clone();
+    characteristic.on_notify(move |value| {
+        // Only fails once the receiver is gone
+        let _ = sender.send((uuid, value.to_vec()));
+    });
+    characteristic.subscribe_notify(true).await
+}
+
+// Deliver notifications while the link is up
+fn listen(client: &BLEClient, receiver: &Receiver<Notification>) {
+    while client.connected() {
+        if let Ok((uuid, value)) = receiver.recv_timeout(LISTEN_POLL) {
+            deliver(uuid, &value);
+        }
+    }
+}
 
-            // Seek and Create Handle for the BLE Remote Server Characteristic corresponding to the UUID
-            let uuid = uuid128!("681285a6-247f-48c6-80ad-68c3dce18585");
-            let characteristic = service.get_characteristic(uuid).await.unwrap();
+fn deliver(uuid: BleUuid, value: &[u8]) {
+    println!(
+        "{}: {}",
+        explore::describe(uuid, gatt::characteristic_name),
+        gatt::format_value(explore::short(uuid), value)
+    );
+}
 
-            loop {
-                // Read & Print Characteristic Value
-                let value = characteristic.read_value().await.unwrap();
-                println!("Read Value: {:?}", value);
+// Next byte typed before `deadline`, `None` once it passed
+fn read_byte(uart: &UartDriver, deadline: Instant) -> Option<u8> {
+    let mut byte = [0_u8; 1];
+    loop {
+        let remaining = deadline.checked_duration_since(Instant::now())?;
+        let timeout = TickType::new_millis(remaining.as_millis() as u64).ticks();
+        if uart.read(&mut byte, timeout).ok()? == 1 {
+            return Some(byte[0]);
+        }
+    }
+}
 
-                // Wait 1 second before reading again
-                FreeRtos::delay_ms(1000);
+// Digits typed up to Enter, echoed back, at most six of them, `None` if
+// Enter is not pressed within PROMPT_TIMEOUT
+fn read_passkey(uart: &UartDriver) -> Option<u32> {
+    let deadline = Instant::now() + PROMPT_TIMEOUT;
+    let mut passkey = 0;
+    let mut digits = 0;
+    loop {
+        match read_byte(uart, deadline)? {
+            digit @ b'0'..=b'9' if digits < 6 => {
+                passkey = passkey * 10 + (digit - b'0') as u32;
+                digits += 1;
+                uart.write(&[digit]).unwrap();
+            }
+            b'\r' | b'\n' if digits > 0 => {
+                uart.write(b"\r\n").unwrap();
+                return Some(passkey);
             }
+            _ => (),
         }
-    });
+    }
+}
+
+// Wait for y or n, `None` if neither is typed within PROMPT_TIMEOUT
+fn read_yes(uart: &UartDriver) -> Option<bool> {
+    let deadline = Instant::now() + PROMPT_TIMEOUT;
+    loop {
+        match read_byte(uart, deadline)? {
+            b'y' | b'Y' => return Some(true),
+            b'n' | b'N' => return Some(false),
+            _ => (),
+        }
+    }
 }
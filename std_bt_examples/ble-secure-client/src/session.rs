@@ -0,0 +1,228 @@
+//! Life cycle of a client streaming notifications: scan, connect, subscribe
+//! and listen, and after any failure or disconnect wait with exponential
+//! backoff before scanning again. Free of NimBLE, the caller performs each
+//! `Action` and reports how it went as an `Event`.
+
+use std::time::Duration;
+
+/// Step for the caller to perform
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Scan for the server
+    Scan,
+    /// Connect to the server found
+    Connect,
+    /// Subscribe to every characteristic, restoring them after a reconnect
+    Subscribe,
+    /// Deliver notifications until the link drops
+    Listen,
+    /// Drop the link if still up, then sleep
+    Wait(Duration),
+}
+
+/// Outcome of the last `Action`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Found,
+    NotFound,
+    Connected,
+    ConnectFailed,
+    Subscribed,
+    SubscribeFailed,
+    Disconnected,
+    Elapsed,
+}
+
+/// Delays between attempts, doubling from `initial` up to `max`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+pub struct Session {
+    backoff: Backoff,
+    delay: Duration,
+    action: Action,
+}
+
+impl Session {
+    /// Session starting with a scan
+    pub fn new(backoff: Backoff) -> Self {
+        Session {
+            backoff,
+            delay: backoff.initial,
+            action: Action::Scan,
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Next action after `event`. A session that got as far as listening
+    /// starts over with the initial delay, anything else going wrong
+    /// doubles it.
+    pub fn handle(&mut self, event: Event) -> Action {
+        self.action = match (self.action, event) {
+            (Action::Scan, Event::Found) => Action::Connect,
+            (Action::Connect, Event::Connected) => Action::Subscribe,
+            (Action::Subscribe, Event::Subscribed) => {
+                self.delay = self.backoff.initial;
+                Action::Listen
+            }
+            (Action::Wait(_), Event::Elapsed) => Action::Scan,
+            _ => {
+                let delay = self.delay;
+                self.delay = (delay * 2).min(self.backoff.max);
+                Action::Wait(delay)
+            }
+        };
+        self.action
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const BACKOFF: Backoff = Backoff {
+        initial: Duration::from_millis(500),
+        max: Duration::from_secs(4),
+    };
+
+    // Stand-in for the radio, answering each action with the next scripted
+    // outcome. Waiting always elapses and is not scripted.
+    struct FakeLink {
+        outcomes: VecDeque<Event>,
+        actions: Vec<Action>,
+    }
+
+    impl FakeLink {
+        fn new(outcomes: &[Event]) -> Self {
+            FakeLink {
+                outcomes: outcomes.iter().copied().collect(),
+                actions: Vec::new(),
+            }
+        }
+
+        // Drive `session` until the script runs out, returning every action
+        // performed
+        fn run(mut self, session: &mut Session) -> Vec<Action> {
+            loop {
+                let action = session.action();
+                self.actions.push(action);
+                let event = match action {
+                    Action::Wait(_) => Event::Elapsed,
+                    _ => match self.outcomes.pop_front() {
+                        Some(event) => event,
+                        None => return self.actions,
+                    },
+                };
+                session.handle(event);
+            }
+        }
+    }
+
+    fn ms(millis: u64) -> Action {
+        Action::Wait(Duration::from_millis(millis))
+    }
+
+    #[test]
+    fn connects_subscribes_and_listens() {
+        let mut session = Session::new(BACKOFF);
+        let link = FakeLink::new(&[Event::Found, Event::Connected, Event::Subscribed]);
+        assert_eq!(
+            link.run(&mut session),
+            [
+                Action::Scan,
+                Action::Connect,
+                Action::Subscribe,
+                Action::Listen
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_double_the_delay() {
+        let mut session = Session::new(BACKOFF);
+        let link = FakeLink::new(&[
+            Event::NotFound,
+            Event::Found,
+            Event::ConnectFailed,
+            Event::Found,
+            Event::Connected,
+            Event::SubscribeFailed,
+            Event::NotFound,
+        ]);
+        assert_eq!(
+            link.run(&mut session),
+            [
+                Action::Scan,
+                ms(500),
+                Action::Scan,
+                Action::Connect,
+                ms(1000),
+                Action::Scan,
+                Action::Connect,
+                Action::Subscribe,
+                ms(2000),
+                Action::Scan,
+                ms(4000),
+                Action::Scan,
+            ]
+        );
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let mut session = Session::new(BACKOFF);
+        let link = FakeLink::new(&[Event::NotFound; 6]);
+        let waits: Vec<_> = link
+            .run(&mut session)
+            .into_iter()
+            .filter(|action| matches!(action, Action::Wait(_)))
+            .collect();
+        assert_eq!(
+            waits,
+            [ms(500), ms(1000), ms(2000), ms(4000), ms(4000), ms(4000)]
+        );
+    }
+
+    #[test]
+    fn listening_resets_the_delay() {
+        let mut session = Session::new(BACKOFF);
+        let link = FakeLink::new(&[
+            Event::NotFound,
+            Event::NotFound,
+            Event::Found,
+            Event::Connected,
+            Event::Subscribed,
+            Event::Disconnected,
+            Event::NotFound,
+        ]);
+        let actions = link.run(&mut session);
+        assert_eq!(
+            actions[actions.len() - 6..],
+            [
+                Action::Subscribe,
+                Action::Listen,
+                ms(500),
+                Action::Scan,
+                ms(1000),
+                Action::Scan,
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_order_events_back_off() {
+        let mut session = Session::new(BACKOFF);
+        assert_eq!(session.handle(Event::Connected), ms(500));
+        // Anything but Elapsed while waiting backs off again
+        assert_eq!(session.handle(Event::Found), ms(1000));
+        assert_eq!(session.handle(Event::Elapsed), Action::Scan);
+        assert_eq!(session.action(), Action::Scan);
+    }
+}
@@ -1,31 +1,129 @@
+mod bonds;
+mod config;
+mod passkey;
+mod policy;
+mod settings;
+
 use esp32_nimble::{enums::*, uuid128, BLEAdvertisementData, BLEDevice, NimbleProperties};
 use esp_idf_hal::delay::FreeRtos;
-use esp_idf_sys as _;
+use esp_idf_hal::gpio::{PinDriver, Pull};
+use esp_idf_hal::ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver, Resolution};
+use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_hal::prelude::*;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_sys::esp_random;
+use policy::Policy;
+use std::sync::mpsc::{self, SyncSender};
+use std::time::{Duration, Instant};
+
+// Period of the main loop, the finest step of blinking and reporting
+const TICK_MS: u32 = 10;
+
+// Pair with a passkey typed on the client, or with numeric comparison where
+// both sides show a number and the user confirms they match
+const NUMERIC_COMPARISON: bool = false;
+// Time to confirm a numeric comparison with the BOOT button
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(15);
+// At most CONFIG_BT_NIMBLE_MAX_BONDS bonds. Once a client is bonded, new
+// ones may only pair in the first two minutes after power up.
+const POLICY: Policy = Policy {
+    max_bonds: 4,
+    allowlist: true,
+    pairing_window: Duration::from_secs(120),
+};
 
 fn main() {
     esp_idf_sys::link_patches();
 
+    let peripherals = Peripherals::take().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+
+    // LED blinking at the configured rate on GPIO5
+    let mut led = PinDriver::output(peripherals.pins.gpio5).unwrap();
+
+    // Servo on GPIO7, driven by a 50 Hz PWM with 0.5 to 2.5 ms pulses
+    let timer_driver = LedcTimerDriver::new(
+        peripherals.ledc.timer0,
+        &TimerConfig::default()
+            .frequency(50.Hz())
+            .resolution(Resolution::Bits14),
+    )
+    .unwrap();
+    let mut servo = LedcDriver::new(
+        peripherals.ledc.channel0,
+        timer_driver,
+        peripherals.pins.gpio7,
+    )
+    .unwrap();
+    let max_duty = servo.get_max_duty();
+    let min_limit = max_duty * 25 / 1000;
+    let max_limit = max_duty * 125 / 1000;
+
+    // BOOT button, held at reset to delete all bonds
+    let mut button = PinDriver::input(peripherals.pins.gpio9).unwrap();
+    button.set_pull(Pull::Up).unwrap();
+
     // Take ownership of device
     let ble_device = BLEDevice::take();
 
+    if button.is_low() {
+        match bonds::clear() {
+            Ok(()) => println!("Deleted all bonds"),
+            Err(rc) => println!("Failed to delete bonds: {}", rc),
+        }
+    }
+    match bonds::list() {
+        Ok(peers) => {
+            println!("{} bonded clients", peers.len());
+            for peer in peers {
+                println!("  {}", peer);
+            }
+        }
+        Err(rc) => println!("Failed to list bonds: {}", rc),
+    }
+
     // Obtain handle for peripheral advertiser
     let ble_advertiser = ble_device.get_advertising();
 
-    // Configure Device Security
+    // Configure Device Security, the passkey is chosen on every connection
     ble_device
         .security()
         .set_auth(AuthReq::all())
-        .set_passkey(123456)
-        .set_io_cap(SecurityIOCap::DisplayOnly)
+        .set_io_cap(if NUMERIC_COMPARISON {
+            SecurityIOCap::DisplayYesNo
+        } else {
+            SecurityIOCap::DisplayOnly
+        })
         .resolve_rpa();
 
     // Obtain handle for server
     let server = ble_device.get_server();
 
     // Define server connect behaviour
-    server.on_connect(|server, clntdesc| {
+    let boot = Instant::now();
+    server.on_connect(move |server, clntdesc| {
         // Print connected client data
         println!("{:?}", clntdesc);
+
+        // Turn away clients the bond policy does not admit
+        let bonded = bonds::list().unwrap_or_default();
+        let peer = bonds::identity(clntdesc.conn_handle());
+        if !peer.is_some_and(|peer| POLICY.admits(&peer, &bonded, boot.elapsed())) {
+            println!("Refusing client without a bond, pairing is closed");
+            server.disconnect(clntdesc.conn_handle()).unwrap();
+            return;
+        }
+
+        // New passkey from the hardware RNG for every pairing, a bonded
+        // client reconnects without one
+        if !peer.is_some_and(|peer| bonded.contains(&peer)) {
+            let passkey = passkey::passkey(|| unsafe { esp_random() });
+            BLEDevice::take().security().set_passkey(passkey);
+            if !NUMERIC_COMPARISON {
+                println!("Passkey for pairing: {:06}", passkey);
+            }
+        }
+
         // Update connection parameters
         server
             .update_conn_params(clntdesc.conn_handle(), 24, 48, 0, 60)
@@ -37,27 +135,66 @@ fn main() {
         println!("Disconnected, back to advertising");
     });
 
+    // Numeric comparison, confirmed by pressing BOOT. The main loop watches
+    // the button, this callback only waits for its answer since esp32-nimble
+    // replies to the pairing with what the callback returns.
+    let (confirm_requests, confirmations) = mpsc::sync_channel::<SyncSender<bool>>(1);
+    server.on_confirm_pin(move |pin| {
+        println!("Press BOOT if the client shows {:06}", pin);
+        let (answer, answered) = mpsc::sync_channel(1);
+        if confirm_requests.try_send(answer).is_err() {
+            return false;
+        }
+        answered.recv_timeout(CONFIRM_TIMEOUT).unwrap_or_else(|_| {
+            println!("Not confirmed in time");
+            false
+        })
+    });
+
+    // Keep the number of bonds within the policy after every pairing
+    server.on_authentication_complete(|_desc, result| match result {
+        Ok(()) => {
+            if let Err(rc) = bonds::enforce(&POLICY) {
+                println!("Failed to enforce the bond limit: {}", rc);
+            }
+        }
+        Err(e) => println!("Pairing failed: {:?}", e),
+    });
+
     // Create a service with custom UUID
     let my_service = server.create_service(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1"));
 
     // Create a characteristic to associate with created service
     let my_service_characteristic = my_service.lock().create_characteristic(
         uuid128!("681285a6-247f-48c6-80ad-68c3dce18585"),
-        NimbleProperties::READ | NimbleProperties::READ_ENC,
+        NimbleProperties::READ | NimbleProperties::READ_ENC | NimbleProperties::NOTIFY,
     );
 
     // Modify characteristic value
     my_service_characteristic.lock().set_value(b"Start Value");
 
-    // Configure Advertiser Data
-    ble_advertiser
-        .lock()
-        .set_data(
+    // Configuration service, only readable and writable over an encrypted link
+    let settings = config::create(
+        server,
+        nvs,
+        NimbleProperties::READ
+            | NimbleProperties::READ_ENC
+            | NimbleProperties::WRITE
+            | NimbleProperties::WRITE_ENC,
+    )
+    .unwrap();
+
+    // Configure Advertiser Data, again whenever the device name is changed
+    let advertise = |name: &str| {
+        BLEDevice::set_device_name(name)?;
+        ble_advertiser.lock().set_data(
             BLEAdvertisementData::new()
-                .name("ESP32 Server")
+                .name(name)
                 .add_service_uuid(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1")),
         )
-        .unwrap();
+    };
+    let mut name = settings.lock().unwrap().device_name.clone();
+    advertise(&name).unwrap();
 
     // Start Advertising
     ble_advertiser.lock().start().unwrap();
@@ -68,9 +205,71 @@ fn main() {
     // Init a value to pass to characteristic
     let mut val = 0;
 
+    let mut last_toggle = Instant::now();
+    let mut last_report = Instant::now();
+    let mut servo_angle = None;
+    let mut confirming = None;
+
     loop {
-        FreeRtos::delay_ms(1000);
+        FreeRtos::delay_ms(TICK_MS);
+
+        // Confirm a pending numeric comparison once BOOT is pressed, a
+        // request that timed out is answered to no one
+        if let Ok(answer) = confirmations.try_recv() {
+            confirming = Some(answer);
+        }
+        if button.is_low() {
+            if let Some(answer) = confirming.take() {
+                println!("Confirmed");
+                let _ = answer.send(true);
+            }
+        }
+
+        let (blink_period_ms, angle, report_interval_ms, renamed) = {
+            let settings = settings.lock().unwrap();
+            let renamed = (settings.device_name != name).then(|| settings.device_name.clone());
+            (
+                settings.blink_period_ms,
+                settings.servo_angle,
+                settings.report_interval_ms,
+                renamed,
+            )
+        };
+
+        // Blink the LED, or keep it off
+        if blink_period_ms == 0 {
+            led.set_low().unwrap();
+        } else if last_toggle.elapsed() >= Duration::from_millis(blink_period_ms as u64) {
+            led.toggle().unwrap();
+            last_toggle = Instant::now();
+        }
+
+        // Move the servo only when the angle changed
+        if servo_angle != Some(angle) {
+            servo
+                .set_duty(map(angle as u32, 0, 180, min_limit, max_limit))
+                .unwrap();
+            servo_angle = Some(angle);
+        }
+
+        if let Some(renamed) = renamed {
+            if let Err(e) = advertise(&renamed) {
+                println!("Failed to advertise the new name: {:?}", e);
+            }
+            name = renamed;
+        }
+
+        if last_report.elapsed() < Duration::from_millis(report_interval_ms as u64) {
+            continue;
+        }
+        last_report = Instant::now();
+
         my_service_characteristic.lock().set_value(&[val]).notify();
         val = val.wrapping_add(1);
     }
 }
+
+// Function that maps one range to another
+fn map(x: u32, in_min: u32, in_max: u32, out_min: u32, out_max: u32) -> u32 {
+    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+}
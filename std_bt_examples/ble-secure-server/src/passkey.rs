@@ -0,0 +1,51 @@
+//! Passkeys the secure server shows for pairing. Kept free of NimBLE so
+//! the draw can be checked on a PC.
+
+/// Passkeys have six decimal digits
+const PASSKEY_RANGE: u32 = 1_000_000;
+
+/// Random passkey from `random`, a source of uniform `u32`s such as the
+/// hardware RNG. Draws from the incomplete range at the top of `u32` are
+/// repeated so every passkey is equally likely.
+pub fn passkey(mut random: impl FnMut() -> u32) -> u32 {
+    let limit = u32::MAX - u32::MAX % PASSKEY_RANGE;
+    loop {
+        let value = random();
+        if value < limit {
+            return value % PASSKEY_RANGE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Passkey drawn from a fixed sequence of random values, with the number
+    // of values used
+    fn passkey_from(values: &[u32]) -> (u32, usize) {
+        let mut used = 0;
+        let passkey = passkey(|| {
+            used += 1;
+            values[used - 1]
+        });
+        (passkey, used)
+    }
+
+    #[test]
+    fn passkeys_have_six_digits() {
+        assert_eq!(passkey_from(&[0]), (0, 1));
+        assert_eq!(passkey_from(&[123_456]), (123_456, 1));
+        assert_eq!(passkey_from(&[1_234_567]), (234_567, 1));
+        assert_eq!(passkey_from(&[999_999_999]), (999_999, 1));
+    }
+
+    #[test]
+    fn passkeys_skip_the_incomplete_range() {
+        // 4_294_000_000 is the first value of the range missing passkeys
+        // above 967_295, drawing again keeps every passkey equally likely
+        assert_eq!(passkey_from(&[4_293_999_999]), (999_999, 1));
+        assert_eq!(passkey_from(&[4_294_000_000, 42]), (42, 2));
+        assert_eq!(passkey_from(&[u32::MAX, u32::MAX, 7]), (7, 3));
+    }
+}
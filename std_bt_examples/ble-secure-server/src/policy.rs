@@ -0,0 +1,86 @@
+//! Bond policy of the secure server and client. Kept free of NimBLE so
+//! the decisions can be checked on a PC.
+
+use std::time::Duration;
+
+/// Which peers may connect and how many bonds are kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+    /// Bonds kept, the oldest ones are deleted beyond it
+    pub max_bonds: usize,
+    /// Refuse peers without a bond once the pairing window closed
+    pub allowlist: bool,
+    /// Time after power up during which new peers may pair
+    pub pairing_window: Duration,
+}
+
+impl Policy {
+    /// Whether `peer` may connect, `bonds` being the identities bonded.
+    /// A device without any bond always lets a peer pair so it can be set up.
+    pub fn admits<T: PartialEq>(&self, peer: &T, bonds: &[T], uptime: Duration) -> bool {
+        !self.allowlist || bonds.is_empty() || bonds.contains(peer) || uptime < self.pairing_window
+    }
+
+    /// Bonds to delete to stay within `max_bonds`, from `bonds` listed
+    /// oldest first
+    pub fn excess<'a, T>(&self, bonds: &'a [T]) -> &'a [T] {
+        &bonds[..bonds.len().saturating_sub(self.max_bonds)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: Policy = Policy {
+        max_bonds: 2,
+        allowlist: true,
+        pairing_window: Duration::from_secs(120),
+    };
+    const EARLY: Duration = Duration::from_secs(5);
+    const LATE: Duration = Duration::from_secs(600);
+
+    #[test]
+    fn anyone_may_connect_without_an_allowlist() {
+        let open = Policy {
+            allowlist: false,
+            ..POLICY
+        };
+        assert!(open.admits(&1, &[2, 3], LATE));
+        assert!(open.admits(&1, &[], LATE));
+    }
+
+    #[test]
+    fn bonded_peers_are_always_admitted() {
+        assert!(POLICY.admits(&2, &[1, 2], EARLY));
+        assert!(POLICY.admits(&2, &[1, 2], LATE));
+    }
+
+    #[test]
+    fn new_peers_only_pair_within_the_window() {
+        assert!(POLICY.admits(&3, &[1, 2], EARLY));
+        assert!(POLICY.admits(&3, &[1, 2], Duration::from_millis(119_999)));
+        assert!(!POLICY.admits(&3, &[1, 2], Duration::from_secs(120)));
+        assert!(!POLICY.admits(&3, &[1, 2], LATE));
+    }
+
+    #[test]
+    fn a_device_without_bonds_can_always_be_set_up() {
+        assert!(POLICY.admits(&1, &[], LATE));
+    }
+
+    #[test]
+    fn excess_bonds_are_the_oldest() {
+        assert!(POLICY.excess::<u8>(&[]).is_empty());
+        assert!(POLICY.excess(&[1]).is_empty());
+        assert!(POLICY.excess(&[1, 2]).is_empty());
+        assert_eq!(POLICY.excess(&[1, 2, 3]), [1]);
+        assert_eq!(POLICY.excess(&[1, 2, 3, 4, 5]), [1, 2, 3]);
+
+        let none = Policy {
+            max_bonds: 0,
+            ..POLICY
+        };
+        assert_eq!(none.excess(&[1, 2]), [1, 2]);
+    }
+}
@@ -0,0 +1,81 @@
+//! Configuration service with one writable characteristic per setting.
+//! Accepted writes are saved to NVS and loaded again after a reset.
+
+use crate::settings::{self, Setting, Settings};
+use esp32_nimble::{uuid128, BLEServer, BleUuid, DescriptorProperties, NimbleProperties};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_sys::EspError;
+use std::sync::{Arc, Mutex};
+
+pub const SERVICE_UUID: BleUuid = uuid128!("c0f1a000-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+
+const NAMESPACE: &str = "config";
+const USER_DESCRIPTION: u16 = 0x2901;
+
+/// Settings as last written, shared with the GATT callbacks
+pub type SharedSettings = Arc<Mutex<Settings>>;
+
+fn uuid(setting: Setting) -> BleUuid {
+    match setting {
+        Setting::BlinkPeriod => uuid128!("c0f1a001-7b3e-4d2a-9f1e-5a8b6c2d4e10"),
+        Setting::ServoAngle => uuid128!("c0f1a002-7b3e-4d2a-9f1e-5a8b6c2d4e10"),
+        Setting::ReportInterval => uuid128!("c0f1a003-7b3e-4d2a-9f1e-5a8b6c2d4e10"),
+        Setting::DeviceName => uuid128!("c0f1a004-7b3e-4d2a-9f1e-5a8b6c2d4e10"),
+    }
+}
+
+/// Load the saved settings and add the service exposing them to `server`.
+/// `properties` must allow reading and writing, with the `_ENC` flags the
+/// characteristics need an encrypted link.
+pub fn create(
+    server: &mut BLEServer,
+    partition: EspDefaultNvsPartition,
+    properties: NimbleProperties,
+) -> Result<SharedSettings, EspError> {
+    let nvs = Arc::new(Mutex::new(EspNvs::new(partition, NAMESPACE, true)?));
+    let settings = Arc::new(Mutex::new(load(&nvs.lock().unwrap())));
+
+    let service = server.create_service(SERVICE_UUID);
+    for setting in Setting::ALL {
+        let characteristic = service
+            .lock()
+            .create_characteristic(uuid(setting), properties);
+        characteristic
+            .lock()
+            .set_value(&settings.lock().unwrap().encode(setting))
+            .create_descriptor(
+                BleUuid::from_uuid16(USER_DESCRIPTION),
+                DescriptorProperties::READ,
+            )
+            .lock()
+            .set_value(setting.description().as_bytes());
+
+        let settings = settings.clone();
+        let nvs = nvs.clone();
+        characteristic.lock().on_write(move |args| {
+            let mut settings = settings.lock().unwrap();
+            if let Err(rejection) = settings.apply(setting, args.recv_data()) {
+                println!("Rejected {:?} write: {:?}", setting, rejection);
+                args.reject_with_error_code(rejection.att_error());
+                return;
+            }
+            let value = settings.encode(setting);
+            if let Err(e) = nvs.lock().unwrap().set_raw(setting.key(), &value) {
+                println!("Failed to save {:?}: {}", setting, e);
+            }
+        });
+    }
+    Ok(settings)
+}
+
+// Saved settings, a missing or no longer valid value keeps its default
+fn load(nvs: &EspNvs<NvsDefault>) -> Settings {
+    let mut settings = Settings::default();
+    let mut buf = [0_u8; settings::MAX_LEN];
+    for setting in Setting::ALL {
+        if let Ok(Some(value)) = nvs.get_raw(setting.key(), &mut buf) {
+            let _ = settings.apply(setting, value);
+        }
+    }
+    settings
+}
@@ -0,0 +1,180 @@
+//! Firmware update service, carrying the transfer protocol of `ota` over
+//! GATT into the OTA partition that is not running. Data chunks are written
+//! without response and should fill the ATT MTU less 3 bytes.
+//!
+//! Unlike the rest of this server the service needs an encrypted link, so
+//! only a phone paired the way `main` sets up can replace the firmware.
+//! Products should also sign their images.
+
+use crate::ota::{Control, Phase, Sink, Status, Updater, WINDOW};
+use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
+use esp32_nimble::{uuid128, BLECharacteristic, BLEServer, BleUuid, NimbleProperties};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_sys::{
+    esp_err_t, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_handle_t, esp_ota_mark_app_valid_cancel_rollback, esp_ota_set_boot_partition,
+    esp_ota_write, esp_partition_t, ESP_ERR_INVALID_STATE, ESP_ERR_NOT_FOUND,
+};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, TrySendError};
+use std::sync::Arc;
+
+const SERVICE_UUID: BleUuid = uuid128!("c0f1c000-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+const CONTROL_UUID: BleUuid = uuid128!("c0f1c001-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+const DATA_UUID: BleUuid = uuid128!("c0f1c002-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+// Read and notified
+const STATUS_UUID: BleUuid = uuid128!("c0f1c003-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+
+// Time the last status gets to reach the phone before the reset
+const RESTART_DELAY_MS: u32 = 1000;
+
+// Messages waiting for flash: a full window of chunks and a control write
+const QUEUE_SIZE: usize = WINDOW as usize + 1;
+
+const ATT_INSUFFICIENT_RESOURCES: u8 = 0x11;
+
+enum Message {
+    Control(Control),
+    Data(Vec<u8>),
+}
+
+/// Applies the writes to flash, too slow for the NimBLE task that receives
+/// them
+pub struct Updates {
+    messages: Receiver<Message>,
+    status: Arc<NimbleMutex<BLECharacteristic>>,
+}
+
+/// Add the firmware update service to `server`
+pub fn create(server: &mut BLEServer) -> Updates {
+    let service = server.create_service(SERVICE_UUID);
+    let (sender, messages) = mpsc::sync_channel(QUEUE_SIZE);
+
+    let control = service.lock().create_characteristic(
+        CONTROL_UUID,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_ENC,
+    );
+    let control_sender = sender.clone();
+    control
+        .lock()
+        .on_write(move |args| match Control::parse(args.recv_data()) {
+            // The phone may retry a control write the queue has no room for,
+            // sending only fails otherwise once the worker is gone
+            Ok(control) => {
+                if let Err(TrySendError::Full(_)) =
+                    control_sender.try_send(Message::Control(control))
+                {
+                    args.reject_with_error_code(ATT_INSUFFICIENT_RESOURCES);
+                }
+            }
+            Err(error) => args.reject_with_error_code(error.att_error()),
+        });
+
+    let data = service.lock().create_characteristic(
+        DATA_UUID,
+        NimbleProperties::WRITE_NO_RSP | NimbleProperties::WRITE_ENC,
+    );
+    // A phone overrunning the window loses chunks, which the updater reports
+    // as a gap to resend from rather than the NimBLE task blocking on flash
+    data.lock().on_write(move |args| {
+        let _ = sender.try_send(Message::Data(args.recv_data().to_vec()));
+    });
+
+    let status = service.lock().create_characteristic(
+        STATUS_UUID,
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+
+    let idle = Status {
+        phase: Phase::Idle,
+        next_seq: 0,
+        offset: 0,
+    };
+    status.lock().set_value(&idle.encode());
+
+    Updates { messages, status }
+}
+
+/// Keep the running image once the device came up far enough to take the
+/// next update. Until then a rollback enabled bootloader returns to the
+/// previous image after a reset.
+pub fn confirm_running_image() {
+    unsafe { esp_ota_mark_app_valid_cancel_rollback() };
+}
+
+impl Updates {
+    /// Receive images until one is committed, then boot it
+    pub fn run(self) {
+        let mut updater = Updater::new(Partition::default());
+        while let Ok(message) = self.messages.recv() {
+            let status = match message {
+                Message::Control(control) => {
+                    let status = updater.control(control);
+                    println!("Firmware update {:?}: {:?}", control, status.phase);
+                    Some(status)
+                }
+                Message::Data(chunk) => updater.data(&chunk),
+            };
+            let Some(status) = status else {
+                continue;
+            };
+            self.publish(&status.encode());
+            if let Phase::Failed(failure) = status.phase {
+                println!("Firmware update failed: {:?}", failure);
+            }
+            if status.phase == Phase::Committed {
+                println!("Firmware update committed, restarting");
+                FreeRtos::delay_ms(RESTART_DELAY_MS);
+                esp_idf_hal::reset::restart();
+            }
+        }
+    }
+
+    fn publish(&self, status: &[u8]) {
+        self.status.lock().set_value(status).notify();
+    }
+}
+
+// The OTA partition written, while an image is open
+#[derive(Default)]
+struct Partition {
+    update: Option<(esp_ota_handle_t, *const esp_partition_t)>,
+}
+
+impl Sink for Partition {
+    fn begin(&mut self, size: u32) -> Result<(), i32> {
+        self.abort();
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()) };
+        if partition.is_null() {
+            return Err(ESP_ERR_NOT_FOUND as i32);
+        }
+        let mut handle = 0;
+        check(unsafe { esp_ota_begin(partition, size as usize, &mut handle) })?;
+        self.update = Some((handle, partition));
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), i32> {
+        let (handle, _) = self.update.ok_or(ESP_ERR_INVALID_STATE as i32)?;
+        check(unsafe { esp_ota_write(handle, data.as_ptr().cast(), data.len()) })
+    }
+
+    fn finish(&mut self) -> Result<(), i32> {
+        let (handle, partition) = self.update.take().ok_or(ESP_ERR_INVALID_STATE as i32)?;
+        check(unsafe { esp_ota_end(handle) })?;
+        check(unsafe { esp_ota_set_boot_partition(partition) })
+    }
+
+    fn abort(&mut self) {
+        if let Some((handle, _)) = self.update.take() {
+            unsafe { esp_ota_abort(handle) };
+        }
+    }
+}
+
+fn check(rc: esp_err_t) -> Result<(), i32> {
+    match rc {
+        0 => Ok(()),
+        rc => Err(rc),
+    }
+}
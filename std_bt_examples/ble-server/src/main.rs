@@ -1,16 +1,84 @@
-use esp32_nimble::{uuid128, BLEAdvertisementData, BLEDevice, NimbleProperties};
+mod config;
+mod firmware;
+mod ota;
+mod passkey;
+mod settings;
+mod sig;
+
+use esp32_nimble::enums::{AuthReq, SecurityIOCap};
+use esp32_nimble::{BLEAdvertisementData, BLEDevice, BleUuid, NimbleProperties};
+use esp_idf_hal::adc::attenuation::adc_atten_t_ADC_ATTEN_DB_11;
+use esp_idf_hal::adc::config::Config;
+use esp_idf_hal::adc::{AdcChannelDriver, AdcDriver};
 use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::{Gpio3, Gpio4, PinDriver};
+use esp_idf_hal::ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver, Resolution};
+use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_hal::prelude::*;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_sys as _;
+use std::time::{Duration, Instant};
+
+const MANUFACTURER: &str = "Apollo Labs";
+const MODEL: &str = "ESP32-C3 Sensor";
+
+const B: f64 = 3950.0; // B value of the thermistor
+const VMAX: f64 = 2500.0; // Full Range Voltage
+
+// The battery is measured through a divider halving its voltage
+const BATTERY_DIVIDER: u32 = 2;
+
+// Period of the main loop, the finest step of blinking and reporting
+const TICK_MS: u32 = 10;
 
 fn main() {
     esp_idf_sys::link_patches();
 
+    let peripherals = Peripherals::take().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+
+    // Configure ADC Driver with the thermistor on GPIO4 and the battery on GPIO3
+    let mut adc = AdcDriver::new(peripherals.adc1, &Config::new()).unwrap();
+    let mut thermistor_pin: AdcChannelDriver<'_, { adc_atten_t_ADC_ATTEN_DB_11 }, Gpio4> =
+        AdcChannelDriver::new(peripherals.pins.gpio4).unwrap();
+    let mut battery_pin: AdcChannelDriver<'_, { adc_atten_t_ADC_ATTEN_DB_11 }, Gpio3> =
+        AdcChannelDriver::new(peripherals.pins.gpio3).unwrap();
+
+    // LED blinking at the configured rate on GPIO5
+    let mut led = PinDriver::output(peripherals.pins.gpio5).unwrap();
+
+    // Servo on GPIO7, driven by a 50 Hz PWM with 0.5 to 2.5 ms pulses
+    let timer_driver = LedcTimerDriver::new(
+        peripherals.ledc.timer0,
+        &TimerConfig::default()
+            .frequency(50.Hz())
+            .resolution(Resolution::Bits14),
+    )
+    .unwrap();
+    let mut servo = LedcDriver::new(
+        peripherals.ledc.channel0,
+        timer_driver,
+        peripherals.pins.gpio7,
+    )
+    .unwrap();
+    let max_duty = servo.get_max_duty();
+    let min_limit = max_duty * 25 / 1000;
+    let max_limit = max_duty * 125 / 1000;
+
     // Take ownership of device
     let ble_device = BLEDevice::take();
 
     // Obtain handle for peripheral advertiser
     let ble_advertiser = ble_device.get_advertising();
 
+    // Configure Device Security, pairing with a passkey printed on the
+    // console. Only firmware updates need it.
+    ble_device
+        .security()
+        .set_auth(AuthReq::Bond | AuthReq::Mitm | AuthReq::Sc)
+        .set_io_cap(SecurityIOCap::DisplayOnly);
+    new_passkey();
+
     // Obtain handle for server
     let server = ble_device.get_server();
 
@@ -29,40 +97,160 @@ fn main() {
         println!("Disconnected, back to advertising");
     });
 
-    // Create a service with custom UUID
-    let my_service = server.create_service(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1"));
+    // Every pairing attempt, failed or not, uses up the passkey
+    server.on_authentication_complete(|_desc, result| {
+        if let Err(e) = result {
+            println!("Pairing failed: {:?}", e);
+        }
+        new_passkey();
+    });
 
-    // Create a characteristic to associate with created service
-    let my_service_characteristic = my_service.lock().create_characteristic(
-        uuid128!("681285a6-247f-48c6-80ad-68c3dce18585"),
-        NimbleProperties::READ | NimbleProperties::NOTIFY,
-    );
+    // Device Information service with fixed strings, the serial number is the MAC
+    let device_information = server.create_service(BleUuid::from_uuid16(sig::DEVICE_INFORMATION));
+    let mut mac = [0_u8; 6];
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) }).unwrap();
+    for (uuid, value) in
+        sig::device_information(MANUFACTURER, MODEL, &mac, env!("CARGO_PKG_VERSION"))
+    {
+        device_information
+            .lock()
+            .create_characteristic(BleUuid::from_uuid16(uuid), NimbleProperties::READ)
+            .lock()
+            .set_value(value.as_bytes());
+    }
 
-    // Modify characteristic value
-    my_service_characteristic.lock().set_value(b"Start Value");
+    // Battery service notifying the charge level
+    let battery_level = server
+        .create_service(BleUuid::from_uuid16(sig::BATTERY))
+        .lock()
+        .create_characteristic(
+            BleUuid::from_uuid16(sig::BATTERY_LEVEL),
+            NimbleProperties::READ | NimbleProperties::NOTIFY,
+        );
 
-    // Configure Advertiser Data
-    ble_advertiser
+    // Environmental Sensing service notifying the thermistor temperature
+    let temperature = server
+        .create_service(BleUuid::from_uuid16(sig::ENVIRONMENTAL_SENSING))
         .lock()
-        .set_data(
+        .create_characteristic(
+            BleUuid::from_uuid16(sig::TEMPERATURE),
+            NimbleProperties::READ | NimbleProperties::NOTIFY,
+        );
+
+    // Configuration service with writable settings saved to NVS
+    let settings = config::create(
+        server,
+        nvs,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    )
+    .unwrap();
+
+    // Firmware update service, writing flash on a thread of its own
+    let updates = firmware::create(server);
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || updates.run())
+        .unwrap();
+
+    // Configure Advertiser Data, again whenever the device name is changed
+    let advertise = |name: &str| {
+        BLEDevice::set_device_name(name)?;
+        ble_advertiser.lock().set_data(
             BLEAdvertisementData::new()
-                .name("ESP32 Server")
-                .add_service_uuid(uuid128!("9b574847-f706-436c-bed7-fc01eb0965c1")),
+                .name(name)
+                .add_service_uuid(BleUuid::from_uuid16(sig::ENVIRONMENTAL_SENSING))
+                .add_service_uuid(BleUuid::from_uuid16(sig::BATTERY)),
         )
-        .unwrap();
+    };
+    let mut name = settings.lock().unwrap().device_name.clone();
+    advertise(&name).unwrap();
 
     // Start Advertising
     ble_advertiser.lock().start().unwrap();
 
+    // Reachable for the next update, a rollback is no longer needed
+    firmware::confirm_running_image();
+
     // (Optional) Print dump of local GATT table
     // server.ble_gatts_show_local();
 
-    // Init a value to pass to characteristic
-    let mut val = 0;
+    let mut last_toggle = Instant::now();
+    let mut last_report = Instant::now();
+    let mut servo_angle = None;
 
     loop {
-        FreeRtos::delay_ms(1000);
-        my_service_characteristic.lock().set_value(&[val]).notify();
-        val = val.wrapping_add(1);
+        FreeRtos::delay_ms(TICK_MS);
+
+        let (blink_period_ms, angle, report_interval_ms, renamed) = {
+            let settings = settings.lock().unwrap();
+            let renamed = (settings.device_name != name).then(|| settings.device_name.clone());
+            (
+                settings.blink_period_ms,
+                settings.servo_angle,
+                settings.report_interval_ms,
+                renamed,
+            )
+        };
+
+        // Blink the LED, or keep it off
+        if blink_period_ms == 0 {
+            led.set_low().unwrap();
+        } else if last_toggle.elapsed() >= Duration::from_millis(blink_period_ms as u64) {
+            led.toggle().unwrap();
+            last_toggle = Instant::now();
+        }
+
+        // Move the servo only when the angle changed
+        if servo_angle != Some(angle) {
+            servo
+                .set_duty(map(angle as u32, 0, 180, min_limit, max_limit))
+                .unwrap();
+            servo_angle = Some(angle);
+        }
+
+        if let Some(renamed) = renamed {
+            if let Err(e) = advertise(&renamed) {
+                println!("Failed to advertise the new name: {:?}", e);
+            }
+            name = renamed;
+        }
+
+        if last_report.elapsed() < Duration::from_millis(report_interval_ms as u64) {
+            continue;
+        }
+        last_report = Instant::now();
+
+        // Get ADC Readings in millivolts
+        let sample = adc.read(&mut thermistor_pin).unwrap();
+        let battery_mv = adc.read(&mut battery_pin).unwrap() as u32 * BATTERY_DIVIDER;
+
+        // Convert to temperature, a shorted or open thermistor reads at a rail
+        // and is reported as unknown
+        let celsius = if sample == 0 || sample as f64 >= VMAX {
+            f64::NAN
+        } else {
+            1. / ((1. / (VMAX / sample as f64 - 1.)).ln() / B + 1.0 / 298.15) - 273.15
+        };
+
+        temperature
+            .lock()
+            .set_value(&sig::temperature(celsius))
+            .notify();
+        battery_level
+            .lock()
+            .set_value(&sig::battery_level(battery_mv))
+            .notify();
     }
 }
+
+// Set a passkey from the hardware RNG for the next pairing and show it
+fn new_passkey() {
+    let passkey = passkey::passkey(|| unsafe { esp_idf_sys::esp_random() });
+    BLEDevice::take().security().set_passkey(passkey);
+    println!("Passkey for pairing: {:06}", passkey);
+}
+
+// Function that maps one range to another
+fn map(x: u32, in_min: u32, in_max: u32, out_min: u32, out_max: u32) -> u32 {
+    (x - in_min) * (out_max - out_min) / (in_max - in_min) + out_min
+}
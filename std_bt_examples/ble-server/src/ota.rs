@@ -0,0 +1,667 @@
+//! Transfer protocol of the firmware update service, kept free of NimBLE and
+//! the OTA API so a lossy link can be simulated on a PC.
+//!
+//! The phone writes `Start` with the image size and CRC-32 to the control
+//! point, then streams the image as data chunks written without response:
+//!
+//! ```text
+//! sequence (u16 LE) | payload | CRC-16/CCITT of sequence and payload (u16 LE)
+//! ```
+//!
+//! Chunks are taken strictly in sequence. The device notifies its status
+//! every `ACK_INTERVAL` chunks, and once when a chunk is missing or damaged,
+//! after which the phone resends from the sequence number in the status.
+//! At most `WINDOW` chunks may be unacknowledged. After a disconnect the
+//! phone writes the same `Start` again and carries on from the status.
+
+/// Chunks the phone may send ahead of the last status
+pub const WINDOW: u16 = 16;
+/// Chunks accepted between two status notifications
+pub const ACK_INTERVAL: u16 = WINDOW / 2;
+/// Sequence number and CRC around every chunk's payload
+const CHUNK_OVERHEAD: usize = 4;
+
+// ATT errors refusing a control point write
+const ATT_INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0D;
+const ATT_VALUE_NOT_ALLOWED: u8 = 0x13;
+
+/// Written to the control point
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    /// Begin an image, or resume the one in progress if it is the same
+    Start {
+        size: u32,
+        crc: u32,
+    },
+    Abort,
+    /// Check the image and boot it next
+    Commit,
+}
+
+impl Control {
+    pub fn parse(value: &[u8]) -> Result<Control, ControlError> {
+        match value {
+            [0x01, rest @ ..] => {
+                let rest: &[u8; 8] = rest.try_into().map_err(|_| ControlError::Length)?;
+                let size = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+                let crc = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]);
+                if size == 0 {
+                    return Err(ControlError::EmptyImage);
+                }
+                Ok(Control::Start { size, crc })
+            }
+            [0x02] => Ok(Control::Abort),
+            [0x03] => Ok(Control::Commit),
+            [0x02 | 0x03, ..] => Err(ControlError::Length),
+            _ => Err(ControlError::Opcode),
+        }
+    }
+}
+
+/// Why a control point write was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlError {
+    /// Not a known command
+    Opcode,
+    /// Too few or too many bytes for the command
+    Length,
+    /// `Start` with a size of 0
+    EmptyImage,
+}
+
+impl ControlError {
+    /// ATT error code answering the write
+    pub fn att_error(self) -> u8 {
+        match self {
+            ControlError::Length => ATT_INVALID_ATTRIBUTE_VALUE_LENGTH,
+            ControlError::Opcode | ControlError::EmptyImage => ATT_VALUE_NOT_ALLOWED,
+        }
+    }
+}
+
+/// Why an update stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    /// The received image does not match the CRC given at the start
+    ImageCrc = 1,
+    /// A chunk went past the size given at the start
+    Overrun = 2,
+    /// The OTA partition refused the image or a write
+    Flash = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Idle,
+    Receiving,
+    /// Every byte is in, waiting for `Commit`
+    Received,
+    /// The image boots after the next reset
+    Committed,
+    Failed(Failure),
+}
+
+/// Progress notified to the phone
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub phase: Phase,
+    /// Sequence number of the chunk expected next
+    pub next_seq: u16,
+    /// Bytes of the image received
+    pub offset: u32,
+}
+
+impl Status {
+    /// Phase, failure, next sequence number and offset
+    pub fn encode(&self) -> [u8; 8] {
+        let (phase, failure) = match self.phase {
+            Phase::Idle => (0, 0),
+            Phase::Receiving => (1, 0),
+            Phase::Received => (2, 0),
+            Phase::Committed => (3, 0),
+            Phase::Failed(failure) => (4, failure as u8),
+        };
+        let seq = self.next_seq.to_le_bytes();
+        let offset = self.offset.to_le_bytes();
+        [
+            phase, failure, seq[0], seq[1], offset[0], offset[1], offset[2], offset[3],
+        ]
+    }
+}
+
+/// Where the image goes, the OTA partition on the chip. Errors are ESP-IDF
+/// error codes.
+pub trait Sink {
+    /// Prepare for an image of `size` bytes, dropping any partial one
+    fn begin(&mut self, size: u32) -> Result<(), i32>;
+    fn write(&mut self, data: &[u8]) -> Result<(), i32>;
+    /// Validate the image and make it boot next
+    fn finish(&mut self) -> Result<(), i32>;
+    /// Drop the partial image, if any
+    fn abort(&mut self);
+}
+
+/// Receiving side of the protocol, writing into `S`
+pub struct Updater<S: Sink> {
+    sink: S,
+    phase: Phase,
+    size: u32,
+    expected_crc: u32,
+    crc: u32,
+    offset: u32,
+    next_seq: u16,
+    since_ack: u16,
+    // A status asking for a resend went out since the last accepted chunk
+    nacked: bool,
+}
+
+impl<S: Sink> Updater<S> {
+    pub fn new(sink: S) -> Self {
+        Updater {
+            sink,
+            phase: Phase::Idle,
+            size: 0,
+            expected_crc: 0,
+            crc: 0,
+            offset: 0,
+            next_seq: 0,
+            since_ack: 0,
+            nacked: false,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            phase: self.phase,
+            next_seq: self.next_seq,
+            offset: self.offset,
+        }
+    }
+
+    /// Act on a control point write, the status to notify in return
+    pub fn control(&mut self, control: Control) -> Status {
+        match control {
+            Control::Start { size, crc } => {
+                let resuming = matches!(self.phase, Phase::Receiving | Phase::Received)
+                    && (size, crc) == (self.size, self.expected_crc);
+                if !resuming {
+                    self.start(size, crc);
+                }
+                self.since_ack = 0;
+                self.nacked = false;
+            }
+            Control::Abort => {
+                if matches!(self.phase, Phase::Receiving | Phase::Received) {
+                    self.sink.abort();
+                }
+                self.phase = Phase::Idle;
+            }
+            Control::Commit if self.phase == Phase::Received => {
+                self.phase = if !self.crc != self.expected_crc {
+                    self.sink.abort();
+                    Phase::Failed(Failure::ImageCrc)
+                } else if self.sink.finish().is_err() {
+                    Phase::Failed(Failure::Flash)
+                } else {
+                    Phase::Committed
+                };
+            }
+            // Nothing complete to commit, the status tells the phone why
+            Control::Commit => (),
+        }
+        self.status()
+    }
+
+    /// Take a data chunk, the status to notify if one is due
+    pub fn data(&mut self, chunk: &[u8]) -> Option<Status> {
+        if self.phase != Phase::Receiving {
+            return None;
+        }
+        if chunk.len() <= CHUNK_OVERHEAD {
+            return self.nack();
+        }
+        let (body, crc) = chunk.split_at(chunk.len() - 2);
+        if crc16(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+            return self.nack();
+        }
+        let seq = u16::from_le_bytes([body[0], body[1]]);
+        let payload = &body[2..];
+        if seq != self.next_seq {
+            // Resent chunks the phone had not heard back about yet
+            if self.next_seq.wrapping_sub(seq) <= WINDOW {
+                return None;
+            }
+            return self.nack();
+        }
+
+        if self.offset as u64 + payload.len() as u64 > self.size as u64 {
+            self.sink.abort();
+            self.phase = Phase::Failed(Failure::Overrun);
+            return Some(self.status());
+        }
+        if self.sink.write(payload).is_err() {
+            self.sink.abort();
+            self.phase = Phase::Failed(Failure::Flash);
+            return Some(self.status());
+        }
+        self.crc = crc32_update(self.crc, payload);
+        self.offset += payload.len() as u32;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.nacked = false;
+        self.since_ack += 1;
+
+        if self.offset == self.size {
+            self.phase = Phase::Received;
+        } else if self.since_ack < ACK_INTERVAL {
+            return None;
+        }
+        self.since_ack = 0;
+        Some(self.status())
+    }
+
+    fn start(&mut self, size: u32, crc: u32) {
+        if matches!(self.phase, Phase::Receiving | Phase::Received) {
+            self.sink.abort();
+        }
+        self.size = size;
+        self.expected_crc = crc;
+        self.crc = !0;
+        self.offset = 0;
+        self.next_seq = 0;
+        self.phase = match self.sink.begin(size) {
+            Ok(()) => Phase::Receiving,
+            Err(_) => Phase::Failed(Failure::Flash),
+        };
+    }
+
+    // Ask for a resend from the expected chunk, once until it arrives
+    fn nack(&mut self) -> Option<Status> {
+        if self.nacked {
+            return None;
+        }
+        self.nacked = true;
+        self.since_ack = 0;
+        Some(self.status())
+    }
+}
+
+/// CRC-16/CCITT-FALSE of a chunk
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF_u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// Running CRC-32 (IEEE 802.3) of the image, started at !0 and inverted at
+// the end
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // OTA partition in memory
+    #[derive(Default)]
+    struct Memory {
+        image: Vec<u8>,
+        open: bool,
+        finished: bool,
+        // Writes left before flash fails, if it should
+        fail_after: Option<usize>,
+    }
+
+    impl Sink for Memory {
+        fn begin(&mut self, _size: u32) -> Result<(), i32> {
+            self.image.clear();
+            self.open = true;
+            Ok(())
+        }
+
+        fn write(&mut self, data: &[u8]) -> Result<(), i32> {
+            assert!(self.open, "write outside an image");
+            if let Some(left) = self.fail_after.as_mut() {
+                if *left == 0 {
+                    return Err(-1);
+                }
+                *left -= 1;
+            }
+            self.image.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), i32> {
+            assert!(self.open, "finish outside an image");
+            self.open = false;
+            self.finished = true;
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.open = false;
+        }
+    }
+
+    fn chunk(seq: u16, payload: &[u8]) -> Vec<u8> {
+        let mut chunk = seq.to_le_bytes().to_vec();
+        chunk.extend_from_slice(payload);
+        let crc = crc16(&chunk);
+        chunk.extend_from_slice(&crc.to_le_bytes());
+        chunk
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        !crc32_update(!0, data)
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    fn start(image: &[u8]) -> Control {
+        Control::Start {
+            size: image.len() as u32,
+            crc: crc32(image),
+        }
+    }
+
+    // Deterministic stand-in for a noisy radio, with rates in percent
+    struct Link {
+        state: u32,
+        drop: u32,
+        duplicate: u32,
+        corrupt: u32,
+        // Writes in flight, delivered in order
+        queue: VecDeque<Vec<u8>>,
+    }
+
+    impl Link {
+        fn new(drop: u32, duplicate: u32, corrupt: u32) -> Self {
+            Link {
+                state: 0x2545_F491,
+                drop,
+                duplicate,
+                corrupt,
+                queue: VecDeque::new(),
+            }
+        }
+
+        fn percent(&mut self) -> u32 {
+            // xorshift32
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            self.state % 100
+        }
+
+        fn send(&mut self, mut chunk: Vec<u8>) {
+            if self.percent() < self.drop {
+                return;
+            }
+            if self.percent() < self.corrupt {
+                let at = self.state as usize % chunk.len();
+                chunk[at] ^= 0x5A;
+            }
+            if self.percent() < self.duplicate {
+                self.queue.push_back(chunk.clone());
+            }
+            self.queue.push_back(chunk);
+        }
+    }
+
+    // Phone streaming `image` as the protocol asks: at most WINDOW chunks
+    // ahead of the last status, resending from every status received, and
+    // writing Start again whenever it hears nothing back. Disconnects every
+    // `disconnect_every` deliveries, losing what is in flight. Returns the
+    // final status and the chunks written.
+    fn transfer(
+        updater: &mut Updater<Memory>,
+        image: &[u8],
+        payload_len: usize,
+        link: &mut Link,
+        disconnect_every: Option<usize>,
+    ) -> (Status, usize) {
+        let chunks: Vec<&[u8]> = image.chunks(payload_len).collect();
+        let mut status = updater.control(start(image));
+        let mut acked = 0;
+        let mut next = 0;
+        let mut written = 0;
+        let mut delivered = 0;
+        loop {
+            match status.phase {
+                Phase::Receiving => (),
+                Phase::Received => return (updater.control(Control::Commit), written),
+                _ => return (status, written),
+            }
+            assert!(written < chunks.len() * 10, "transfer does not progress");
+
+            while next < chunks.len() && next - acked < WINDOW as usize {
+                link.send(chunk(next as u16, chunks[next]));
+                next += 1;
+                written += 1;
+            }
+
+            let reconnect = disconnect_every.is_some_and(|every| {
+                delivered > 0 && delivered % every == 0 && !link.queue.is_empty()
+            });
+            let Some(write) = link.queue.pop_front().filter(|_| !reconnect) else {
+                // Nothing heard back, resume where the device is
+                link.queue.clear();
+                delivered += 1;
+                status = updater.control(start(image));
+                acked = status.offset as usize / payload_len;
+                next = acked;
+                continue;
+            };
+            delivered += 1;
+            if let Some(reply) = updater.data(&write) {
+                status = reply;
+                assert_eq!(
+                    status.next_seq,
+                    (status.offset as usize / payload_len) as u16
+                );
+                acked = status.offset as usize / payload_len;
+                next = acked;
+            }
+        }
+    }
+
+    #[test]
+    fn clean_transfer_acknowledges_every_interval() {
+        let image = image(100);
+        let mut updater = Updater::new(Memory::default());
+        assert_eq!(updater.control(start(&image)).phase, Phase::Receiving);
+
+        let mut statuses = Vec::new();
+        for (seq, payload) in image.chunks(4).enumerate() {
+            if let Some(status) = updater.data(&chunk(seq as u16, payload)) {
+                statuses.push((seq, status));
+            }
+        }
+        let expected = |seq: usize, phase| Status {
+            phase,
+            next_seq: seq as u16 + 1,
+            offset: (seq as u32 + 1) * 4,
+        };
+        assert_eq!(
+            statuses,
+            [
+                (7, expected(7, Phase::Receiving)),
+                (15, expected(15, Phase::Receiving)),
+                (23, expected(23, Phase::Receiving)),
+                (24, expected(24, Phase::Received)),
+            ]
+        );
+        assert_eq!(updater.control(Control::Commit).phase, Phase::Committed);
+        assert!(updater.sink.finished);
+        assert_eq!(updater.sink.image, image);
+    }
+
+    #[test]
+    fn damaged_and_missing_chunks_are_nacked_once() {
+        let image = image(40);
+        let mut updater = Updater::new(Memory::default());
+        updater.control(start(&image));
+        assert_eq!(updater.data(&chunk(0, &image[..4])), None);
+
+        let nack = Some(Status {
+            phase: Phase::Receiving,
+            next_seq: 1,
+            offset: 4,
+        });
+        let mut damaged = chunk(1, &image[4..8]);
+        damaged[3] ^= 1;
+        assert_eq!(updater.data(&damaged), nack);
+        // Chunks following the damaged one are skipped without a status
+        assert_eq!(updater.data(&chunk(2, &image[8..12])), None);
+        assert_eq!(updater.data(&[0, 0, 0, 0]), None);
+        // The resent chunk is taken, the next gap is reported again
+        assert_eq!(updater.data(&chunk(1, &image[4..8])), None);
+        assert_eq!(
+            updater.data(&chunk(3, &image[12..16])).map(|s| s.next_seq),
+            Some(2)
+        );
+        // A chunk already taken is ignored
+        assert_eq!(updater.data(&chunk(1, &image[4..8])), None);
+        assert_eq!(updater.status().offset, 8);
+    }
+
+    #[test]
+    fn lossy_link() {
+        let image = image(10_000);
+        let mut updater = Updater::new(Memory::default());
+        let mut link = Link::new(5, 5, 3);
+        let (status, written) = transfer(&mut updater, &image, 20, &mut link, None);
+        assert_eq!(status.phase, Phase::Committed);
+        assert_eq!(updater.sink.image, image);
+        // Losses cost resends
+        assert!(written > 500, "{} chunks written", written);
+    }
+
+    #[test]
+    fn resume_after_disconnects() {
+        let image = image(10_000);
+        let mut updater = Updater::new(Memory::default());
+        let mut link = Link::new(2, 2, 2);
+        let (status, _) = transfer(&mut updater, &image, 20, &mut link, Some(37));
+        assert_eq!(status.phase, Phase::Committed);
+        assert_eq!(updater.sink.image, image);
+    }
+
+    #[test]
+    fn resuming_keeps_the_image() {
+        let image = image(64);
+        let mut updater = Updater::new(Memory::default());
+        updater.control(start(&image));
+        for seq in 0..5 {
+            updater.data(&chunk(seq, &image[seq as usize * 4..][..4]));
+        }
+        // The same Start carries on, another one begins afresh
+        assert_eq!(updater.control(start(&image)).offset, 20);
+        let other = self::image(32);
+        let status = updater.control(start(&other));
+        assert_eq!((status.phase, status.offset), (Phase::Receiving, 0));
+        assert!(updater.sink.image.is_empty());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        // More chunks than sequence numbers, over a lossy link
+        let image = image(70_000);
+        let mut updater = Updater::new(Memory::default());
+        let mut link = Link::new(3, 3, 1);
+        let (status, _) = transfer(&mut updater, &image, 1, &mut link, Some(5_000));
+        assert_eq!(status.phase, Phase::Committed);
+        assert_eq!(updater.sink.image, image);
+    }
+
+    #[test]
+    fn failures() {
+        // Wrong image CRC
+        let image = image(8);
+        let mut updater = Updater::new(Memory::default());
+        updater.control(Control::Start {
+            size: 8,
+            crc: crc32(&image) ^ 1,
+        });
+        updater.data(&chunk(0, &image));
+        let status = updater.control(Control::Commit);
+        assert_eq!(status.phase, Phase::Failed(Failure::ImageCrc));
+        assert!(!updater.sink.finished);
+
+        // More than announced
+        let mut updater = Updater::new(Memory::default());
+        updater.control(Control::Start { size: 4, crc: 0 });
+        let status = updater.data(&chunk(0, &image)).unwrap();
+        assert_eq!(status.phase, Phase::Failed(Failure::Overrun));
+
+        // Flash refusing a write
+        let mut updater = Updater::new(Memory {
+            fail_after: Some(1),
+            ..Default::default()
+        });
+        updater.control(start(&image));
+        assert_eq!(updater.data(&chunk(0, &image[..4])), None);
+        let status = updater.data(&chunk(1, &image[4..])).unwrap();
+        assert_eq!(status.phase, Phase::Failed(Failure::Flash));
+        assert!(!updater.sink.open);
+        // Nothing is taken until the next Start
+        assert_eq!(updater.data(&chunk(1, &image[4..])), None);
+    }
+
+    #[test]
+    fn control_parsing() {
+        assert_eq!(
+            Control::parse(&[0x01, 0x10, 0x27, 0, 0, 0x78, 0x56, 0x34, 0x12]),
+            Ok(Control::Start {
+                size: 10_000,
+                crc: 0x1234_5678
+            })
+        );
+        assert_eq!(Control::parse(&[0x02]), Ok(Control::Abort));
+        assert_eq!(Control::parse(&[0x03]), Ok(Control::Commit));
+        assert_eq!(Control::parse(&[0x01, 1, 0, 0]), Err(ControlError::Length));
+        assert_eq!(Control::parse(&[0x03, 0]), Err(ControlError::Length));
+        assert_eq!(
+            Control::parse(&[0x01, 0, 0, 0, 0, 1, 2, 3, 4]),
+            Err(ControlError::EmptyImage)
+        );
+        assert_eq!(Control::parse(&[]), Err(ControlError::Opcode));
+        assert_eq!(Control::parse(&[0x04]), Err(ControlError::Opcode));
+        assert_eq!(ControlError::Length.att_error(), 0x0D);
+        assert_eq!(ControlError::Opcode.att_error(), 0x13);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        let status = Status {
+            phase: Phase::Failed(Failure::Flash),
+            next_seq: 0x0102,
+            offset: 0x0A0B_0C0D,
+        };
+        assert_eq!(status.encode(), [4, 3, 0x02, 0x01, 0x0D, 0x0C, 0x0B, 0x0A]);
+    }
+}
@@ -0,0 +1,275 @@
+//! Settings a phone can change through the configuration service. Values
+//! are read, written and saved to NVS in the same little-endian encoding,
+//! and a write is only applied once it validates.
+
+/// Longest encoded value of any setting
+pub const MAX_LEN: usize = 20;
+
+const BLINK_PERIOD_MAX_MS: u16 = 10_000;
+const BLINK_PERIOD_MIN_MS: u16 = 50;
+const SERVO_ANGLE_MAX: u8 = 180;
+const REPORT_INTERVAL_MIN_MS: u16 = 100;
+const REPORT_INTERVAL_MAX_MS: u16 = 60_000;
+// Short enough to fit an advertising packet next to the flags and a few
+// 16-bit UUIDs
+const DEVICE_NAME_MAX_LEN: usize = MAX_LEN;
+
+// ATT error codes from the Core Specification, Vol 3, Part F, 3.4.1.1
+const ATT_INVALID_ATTRIBUTE_VALUE_LENGTH: u8 = 0x0D;
+const ATT_VALUE_NOT_ALLOWED: u8 = 0x13;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Setting {
+    /// `uint16` time the LED stays on and off in ms, 0 turns it off
+    BlinkPeriod,
+    /// `uint8` servo position in degrees, 0 to 180
+    ServoAngle,
+    /// `uint16` time between two sensor notifications in ms
+    ReportInterval,
+    /// UTF-8 name advertised and shown as the GAP device name
+    DeviceName,
+}
+
+impl Setting {
+    pub const ALL: [Setting; 4] = [
+        Setting::BlinkPeriod,
+        Setting::ServoAngle,
+        Setting::ReportInterval,
+        Setting::DeviceName,
+    ];
+
+    /// NVS key the setting is saved under
+    pub fn key(self) -> &'static str {
+        match self {
+            Setting::BlinkPeriod => "blink",
+            Setting::ServoAngle => "servo",
+            Setting::ReportInterval => "interval",
+            Setting::DeviceName => "name",
+        }
+    }
+
+    /// Characteristic User Description shown by phone apps
+    pub fn description(self) -> &'static str {
+        match self {
+            Setting::BlinkPeriod => "Blink period (ms, 0 = off)",
+            Setting::ServoAngle => "Servo angle (degrees)",
+            Setting::ReportInterval => "Report interval (ms)",
+            Setting::DeviceName => "Device name",
+        }
+    }
+}
+
+/// Reason a written value was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Too few or too many bytes for the setting
+    Length,
+    /// Out of range, or not a usable name
+    Value,
+}
+
+impl Rejection {
+    /// ATT error code answering the write
+    pub fn att_error(self) -> u8 {
+        match self {
+            Rejection::Length => ATT_INVALID_ATTRIBUTE_VALUE_LENGTH,
+            Rejection::Value => ATT_VALUE_NOT_ALLOWED,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub blink_period_ms: u16,
+    pub servo_angle: u8,
+    pub report_interval_ms: u16,
+    pub device_name: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            blink_period_ms: 500,
+            servo_angle: 90,
+            report_interval_ms: 1000,
+            device_name: "ESP32 Server".to_string(),
+        }
+    }
+}
+
+impl Settings {
+    /// Value of `setting` as it is read and saved
+    pub fn encode(&self, setting: Setting) -> Vec<u8> {
+        match setting {
+            Setting::BlinkPeriod => self.blink_period_ms.to_le_bytes().to_vec(),
+            Setting::ServoAngle => vec![self.servo_angle],
+            Setting::ReportInterval => self.report_interval_ms.to_le_bytes().to_vec(),
+            Setting::DeviceName => self.device_name.as_bytes().to_vec(),
+        }
+    }
+
+    /// Validate `value` written to `setting` and apply it, leaving the
+    /// settings untouched when it is rejected
+    pub fn apply(&mut self, setting: Setting, value: &[u8]) -> Result<(), Rejection> {
+        match setting {
+            Setting::BlinkPeriod => {
+                let ms = u16::from_le_bytes(exact(value)?);
+                if ms != 0 && !(BLINK_PERIOD_MIN_MS..=BLINK_PERIOD_MAX_MS).contains(&ms) {
+                    return Err(Rejection::Value);
+                }
+                self.blink_period_ms = ms;
+            }
+            Setting::ServoAngle => {
+                let [degrees] = exact(value)?;
+                if degrees > SERVO_ANGLE_MAX {
+                    return Err(Rejection::Value);
+                }
+                self.servo_angle = degrees;
+            }
+            Setting::ReportInterval => {
+                let ms = u16::from_le_bytes(exact(value)?);
+                if !(REPORT_INTERVAL_MIN_MS..=REPORT_INTERVAL_MAX_MS).contains(&ms) {
+                    return Err(Rejection::Value);
+                }
+                self.report_interval_ms = ms;
+            }
+            Setting::DeviceName => {
+                if value.is_empty() || value.len() > DEVICE_NAME_MAX_LEN {
+                    return Err(Rejection::Length);
+                }
+                let name = std::str::from_utf8(value).map_err(|_| Rejection::Value)?;
+                if name.chars().any(char::is_control) || name.trim().is_empty() {
+                    return Err(Rejection::Value);
+                }
+                self.device_name = name.to_string();
+            }
+        }
+        Ok(())
+    }
+}
+
+// Value of a fixed size setting
+fn exact<const N: usize>(value: &[u8]) -> Result<[u8; N], Rejection> {
+    value.try_into().map_err(|_| Rejection::Length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_is_little_endian() {
+        let settings = Settings {
+            blink_period_ms: 0x1234,
+            servo_angle: 45,
+            report_interval_ms: 1000,
+            device_name: "Desk".to_string(),
+        };
+        assert_eq!(settings.encode(Setting::BlinkPeriod), [0x34, 0x12]);
+        assert_eq!(settings.encode(Setting::ServoAngle), [45]);
+        assert_eq!(settings.encode(Setting::ReportInterval), [0xE8, 0x03]);
+        assert_eq!(settings.encode(Setting::DeviceName), b"Desk");
+    }
+
+    #[test]
+    fn accepted_writes() {
+        let cases: &[(Setting, &[u8])] = &[
+            (Setting::BlinkPeriod, &[0, 0]),
+            (Setting::BlinkPeriod, &[50, 0]),
+            (Setting::BlinkPeriod, &[0x10, 0x27]),
+            (Setting::ServoAngle, &[0]),
+            (Setting::ServoAngle, &[180]),
+            (Setting::ReportInterval, &[100, 0]),
+            (Setting::ReportInterval, &[0x60, 0xEA]),
+            (Setting::DeviceName, b"x"),
+            (Setting::DeviceName, b"Kitchen sensor"),
+            (Setting::DeviceName, "Capteur café".as_bytes()),
+            (Setting::DeviceName, b"12345678901234567890"),
+        ];
+        for &(setting, value) in cases {
+            let mut settings = Settings::default();
+            assert_eq!(
+                settings.apply(setting, value),
+                Ok(()),
+                "{:?} {:?}",
+                setting,
+                value
+            );
+            // What was written reads back unchanged
+            assert_eq!(settings.encode(setting), value);
+        }
+    }
+
+    #[test]
+    fn rejected_writes() {
+        use Rejection::{Length, Value};
+        let cases: &[(Setting, &[u8], Rejection)] = &[
+            (Setting::BlinkPeriod, &[], Length),
+            (Setting::BlinkPeriod, &[100], Length),
+            (Setting::BlinkPeriod, &[100, 0, 0], Length),
+            (Setting::BlinkPeriod, &[49, 0], Value),
+            (Setting::BlinkPeriod, &[0x11, 0x27], Value),
+            (Setting::ServoAngle, &[], Length),
+            (Setting::ServoAngle, &[90, 0], Length),
+            (Setting::ServoAngle, &[181], Value),
+            (Setting::ReportInterval, &[0xE8], Length),
+            (Setting::ReportInterval, &[99, 0], Value),
+            (Setting::ReportInterval, &[0x61, 0xEA], Value),
+            (Setting::ReportInterval, &[0, 0], Value),
+            (Setting::DeviceName, b"", Length),
+            (Setting::DeviceName, b"123456789012345678901", Length),
+            (Setting::DeviceName, &[b'a', 0xC3], Value),
+            (Setting::DeviceName, &[0xFF], Value),
+            (Setting::DeviceName, b"two\nlines", Value),
+            (Setting::DeviceName, b"nul\0", Value),
+            (Setting::DeviceName, b"tab\t", Value),
+            (Setting::DeviceName, "nel\u{85}".as_bytes(), Value),
+            (Setting::DeviceName, b"   ", Value),
+        ];
+        for &(setting, value, rejection) in cases {
+            let mut settings = Settings::default();
+            assert_eq!(
+                settings.apply(setting, value),
+                Err(rejection),
+                "{:?} {:?}",
+                setting,
+                value
+            );
+            assert_eq!(settings, Settings::default());
+        }
+    }
+
+    #[test]
+    fn rejections_map_to_att_errors() {
+        assert_eq!(Rejection::Length.att_error(), 0x0D);
+        assert_eq!(Rejection::Value.att_error(), 0x13);
+    }
+
+    #[test]
+    fn defaults_survive_their_own_validation() {
+        let defaults = Settings::default();
+        let mut settings = Settings {
+            blink_period_ms: 0,
+            servo_angle: 0,
+            report_interval_ms: 0,
+            device_name: String::new(),
+        };
+        for setting in Setting::ALL {
+            let value = defaults.encode(setting);
+            assert!(value.len() <= MAX_LEN);
+            assert_eq!(settings.apply(setting, &value), Ok(()));
+        }
+        assert_eq!(settings, defaults);
+    }
+
+    #[test]
+    fn keys_fit_nvs_and_are_unique() {
+        for (i, setting) in Setting::ALL.iter().enumerate() {
+            // NVS keys are at most 15 characters
+            assert!(setting.key().len() <= 15);
+            assert!(Setting::ALL[i + 1..]
+                .iter()
+                .all(|other| other.key() != setting.key()));
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Bluetooth SIG services exposed by the server, with their characteristic
+//! values encoded as the GATT Specification Supplement defines them.
+
+// 16-bit UUIDs assigned by the Bluetooth SIG
+pub const DEVICE_INFORMATION: u16 = 0x180A;
+pub const BATTERY: u16 = 0x180F;
+pub const ENVIRONMENTAL_SENSING: u16 = 0x181A;
+pub const MANUFACTURER_NAME: u16 = 0x2A29;
+pub const MODEL_NUMBER: u16 = 0x2A24;
+pub const SERIAL_NUMBER: u16 = 0x2A25;
+pub const FIRMWARE_REVISION: u16 = 0x2A26;
+pub const BATTERY_LEVEL: u16 = 0x2A19;
+pub const TEMPERATURE: u16 = 0x2A6E;
+
+// Single Li-ion cell, read as empty and full at these voltages
+const BATTERY_EMPTY_MV: u32 = 3300;
+const BATTERY_FULL_MV: u32 = 4200;
+
+/// Battery Level in percent, linear between the empty and full cell voltage
+pub fn battery_level(millivolts: u32) -> [u8; 1] {
+    let level = millivolts.clamp(BATTERY_EMPTY_MV, BATTERY_FULL_MV) - BATTERY_EMPTY_MV;
+    [(level * 100 / (BATTERY_FULL_MV - BATTERY_EMPTY_MV)) as u8]
+}
+
+/// Temperature as a little-endian `sint16` in units of 0.01 °C. Values out
+/// of range saturate, an unknown value is sent as 0x8000.
+pub fn temperature(celsius: f64) -> [u8; 2] {
+    if celsius.is_nan() {
+        return i16::MIN.to_le_bytes();
+    }
+    // i16::MIN is reserved for the unknown value
+    let hundredths = (celsius * 100.0)
+        .round()
+        .clamp(i16::MIN as f64 + 1.0, i16::MAX as f64);
+    (hundredths as i16).to_le_bytes()
+}
+
+/// Serial Number String made from the factory MAC, e.g. `7CDFA1000102`
+pub fn serial_number(mac: &[u8; 6]) -> String {
+    mac.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Characteristics of the Device Information service with their UTF-8
+/// string values, the serial number made from `mac`
+pub fn device_information(
+    manufacturer: &str,
+    model: &str,
+    mac: &[u8; 6],
+    firmware: &str,
+) -> [(u16, String); 4] {
+    [
+        (MANUFACTURER_NAME, manufacturer.to_string()),
+        (MODEL_NUMBER, model.to_string()),
+        (SERIAL_NUMBER, serial_number(mac)),
+        (FIRMWARE_REVISION, firmware.to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn battery_level_is_linear_between_empty_and_full() {
+        assert_eq!(battery_level(0), [0]);
+        assert_eq!(battery_level(3300), [0]);
+        assert_eq!(battery_level(3309), [1]);
+        assert_eq!(battery_level(3750), [50]);
+        assert_eq!(battery_level(4191), [99]);
+        assert_eq!(battery_level(4200), [100]);
+        assert_eq!(battery_level(5000), [100]);
+    }
+
+    #[test]
+    fn temperature_is_sint16_hundredths_little_endian() {
+        assert_eq!(temperature(0.0), [0x00, 0x00]);
+        // 2500 = 0x09C4
+        assert_eq!(temperature(25.0), [0xC4, 0x09]);
+        // -1050 = 0xFBE6
+        assert_eq!(temperature(-10.5), [0xE6, 0xFB]);
+        assert_eq!(temperature(21.234), 2123_i16.to_le_bytes());
+        assert_eq!(temperature(21.235), 2124_i16.to_le_bytes());
+        assert_eq!(temperature(-0.004), [0x00, 0x00]);
+    }
+
+    #[test]
+    fn temperature_saturates_and_reserves_unknown() {
+        assert_eq!(temperature(327.67), [0xFF, 0x7F]);
+        assert_eq!(temperature(1000.0), [0xFF, 0x7F]);
+        assert_eq!(temperature(f64::INFINITY), [0xFF, 0x7F]);
+        // -32768 would read as unknown
+        assert_eq!(temperature(-327.68), [0x01, 0x80]);
+        assert_eq!(temperature(f64::NEG_INFINITY), [0x01, 0x80]);
+        assert_eq!(temperature(f64::NAN), [0x00, 0x80]);
+    }
+
+    #[test]
+    fn device_information_strings() {
+        let mac = [0x7C, 0xDF, 0xA1, 0x00, 0x01, 0x0A];
+        assert_eq!(serial_number(&mac), "7CDFA100010A");
+        assert_eq!(
+            device_information("Apollo Labs", "ESP32-C3 Sensor", &mac, "0.1.0"),
+            [
+                (0x2A29, "Apollo Labs".to_string()),
+                (0x2A24, "ESP32-C3 Sensor".to_string()),
+                (0x2A25, "7CDFA100010A".to_string()),
+                (0x2A26, "0.1.0".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,581 @@
+//! Decoder of the AD structures making up advertising and scan response
+//! payloads, as defined in the Core Specification Supplement, Part A.
+//!
+//! Only `core` is used and fields borrow from the payload, so the decoder
+//! works without an allocator.
+
+use core::fmt;
+use core::str;
+
+// AD types from the Assigned Numbers document
+const FLAGS: u8 = 0x01;
+const INCOMPLETE_UUIDS16: u8 = 0x02;
+const COMPLETE_UUIDS16: u8 = 0x03;
+const INCOMPLETE_UUIDS32: u8 = 0x04;
+const COMPLETE_UUIDS32: u8 = 0x05;
+const INCOMPLETE_UUIDS128: u8 = 0x06;
+const COMPLETE_UUIDS128: u8 = 0x07;
+const SHORTENED_NAME: u8 = 0x08;
+const COMPLETE_NAME: u8 = 0x09;
+const TX_POWER: u8 = 0x0A;
+const SERVICE_DATA16: u8 = 0x16;
+const SERVICE_DATA32: u8 = 0x20;
+const SERVICE_DATA128: u8 = 0x21;
+const MANUFACTURER_DATA: u8 = 0xFF;
+
+/// Payload that does not decode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// An AD structure is longer than what is left of the payload
+    Truncated,
+    /// The data of an AD structure of this type is too short or invalid
+    Malformed(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "Truncated AD structure"),
+            Error::Malformed(ad_type) => {
+                write!(f, "Malformed AD structure of type 0x{:02X}", ad_type)
+            }
+        }
+    }
+}
+
+/// Service UUID, 16 and 32-bit ones are short forms of Bluetooth SIG UUIDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Uuid {
+    Uuid16(u16),
+    Uuid32(u32),
+    /// Bytes in the order they are written, `12345678-...` as `[0x12, 0x34, ...]`
+    Uuid128([u8; 16]),
+}
+
+impl Uuid {
+    // UUID of `width` bytes sent least significant byte first
+    fn from_le(bytes: &[u8]) -> Uuid {
+        match bytes.len() {
+            2 => Uuid::Uuid16(u16::from_le_bytes([bytes[0], bytes[1]])),
+            4 => Uuid::Uuid32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            _ => {
+                let mut uuid = [0_u8; 16];
+                uuid.copy_from_slice(bytes);
+                uuid.reverse();
+                Uuid::Uuid128(uuid)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Uuid::Uuid16(uuid) => write!(f, "0x{:04X}", uuid),
+            Uuid::Uuid32(uuid) => write!(f, "0x{:08X}", uuid),
+            Uuid::Uuid128(bytes) => uuid128(f, bytes),
+        }
+    }
+}
+
+/// Write `bytes` as `XXXXXXXX-XXXX-XXXX-XXXX-XXXXXXXXXXXX`
+pub fn uuid128(f: &mut fmt::Formatter<'_>, bytes: &[u8; 16]) -> fmt::Result {
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            f.write_str("-")?;
+        }
+        write!(f, "{:02X}", byte)?;
+    }
+    Ok(())
+}
+
+/// UUIDs of one list, all of the same width
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UuidList<'a> {
+    width: usize,
+    bytes: &'a [u8],
+}
+
+impl<'a> Iterator for UuidList<'a> {
+    type Item = Uuid;
+
+    fn next(&mut self) -> Option<Uuid> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        let (uuid, rest) = self.bytes.split_at(self.width);
+        self.bytes = rest;
+        Some(Uuid::from_le(uuid))
+    }
+}
+
+/// Discoverability and BR/EDR support of the advertiser
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flags(pub u8);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const NAMES: [&str; 5] = [
+            "LE Limited Discoverable",
+            "LE General Discoverable",
+            "BR/EDR Not Supported",
+            "LE and BR/EDR Controller",
+            "LE and BR/EDR Host",
+        ];
+        let mut separator = "";
+        for (bit, name) in NAMES.iter().enumerate() {
+            if self.0 & 1 << bit != 0 {
+                write!(f, "{}{}", separator, name)?;
+                separator = ", ";
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bytes written as space separated hex
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// One decoded AD structure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field<'a> {
+    Flags(Flags),
+    /// Service UUIDs, `complete` when the device has no others
+    Uuids {
+        complete: bool,
+        uuids: UuidList<'a>,
+    },
+    /// Local name, `complete` unless shortened
+    Name {
+        complete: bool,
+        name: &'a str,
+    },
+    /// Transmit power in dBm
+    TxPower(i8),
+    ServiceData {
+        uuid: Uuid,
+        data: &'a [u8],
+    },
+    /// Data following a company identifier assigned by the Bluetooth SIG
+    ManufacturerData {
+        company: u16,
+        data: &'a [u8],
+    },
+    /// Any other AD type, left undecoded
+    Other {
+        ad_type: u8,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> Field<'a> {
+    fn decode(ad_type: u8, data: &'a [u8]) -> Result<Field<'a>, Error> {
+        let malformed = Error::Malformed(ad_type);
+        let field = match ad_type {
+            FLAGS => Field::Flags(Flags(*data.first().ok_or(malformed)?)),
+            INCOMPLETE_UUIDS16 | COMPLETE_UUIDS16 | INCOMPLETE_UUIDS32 | COMPLETE_UUIDS32
+            | INCOMPLETE_UUIDS128 | COMPLETE_UUIDS128 => {
+                let width = match ad_type {
+                    INCOMPLETE_UUIDS16 | COMPLETE_UUIDS16 => 2,
+                    INCOMPLETE_UUIDS32 | COMPLETE_UUIDS32 => 4,
+                    _ => 16,
+                };
+                if !data.chunks_exact(width).remainder().is_empty() {
+                    return Err(malformed);
+                }
+                Field::Uuids {
+                    complete: matches!(
+                        ad_type,
+                        COMPLETE_UUIDS16 | COMPLETE_UUIDS32 | COMPLETE_UUIDS128
+                    ),
+                    uuids: UuidList { width, bytes: data },
+                }
+            }
+            SHORTENED_NAME | COMPLETE_NAME => Field::Name {
+                complete: ad_type == COMPLETE_NAME,
+                name: str::from_utf8(data).map_err(|_| malformed)?,
+            },
+            TX_POWER => Field::TxPower(*data.first().ok_or(malformed)? as i8),
+            SERVICE_DATA16 | SERVICE_DATA32 | SERVICE_DATA128 => {
+                let width = match ad_type {
+                    SERVICE_DATA16 => 2,
+                    SERVICE_DATA32 => 4,
+                    _ => 16,
+                };
+                if data.len() < width {
+                    return Err(malformed);
+                }
+                let (uuid, data) = data.split_at(width);
+                Field::ServiceData {
+                    uuid: Uuid::from_le(uuid),
+                    data,
+                }
+            }
+            MANUFACTURER_DATA => {
+                if data.len() < 2 {
+                    return Err(malformed);
+                }
+                let (company, data) = data.split_at(2);
+                Field::ManufacturerData {
+                    company: u16::from_le_bytes([company[0], company[1]]),
+                    data,
+                }
+            }
+            _ => Field::Other { ad_type, data },
+        };
+        Ok(field)
+    }
+}
+
+impl fmt::Display for Field<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Flags(flags) => write!(f, "Flags: {}", flags),
+            Field::Uuids { complete, uuids } => {
+                write!(f, "Services ({}):", completeness(*complete))?;
+                for uuid in *uuids {
+                    write!(f, " {}", uuid)?;
+                }
+                Ok(())
+            }
+            Field::Name { complete, name } => {
+                write!(f, "Name ({}): {:?}", completeness(*complete), name)
+            }
+            Field::TxPower(dbm) => write!(f, "TX power: {} dBm", dbm),
+            Field::ServiceData { uuid, data } => {
+                write!(f, "Service data {}: {}", uuid, Hex(data))
+            }
+            Field::ManufacturerData { company, data } => {
+                write!(f, "Manufacturer data ")?;
+                if let Some(name) = company_name(*company) {
+                    write!(f, "{} ", name)?;
+                }
+                write!(f, "(0x{:04X}): {}", company, Hex(data))
+            }
+            Field::Other { ad_type, data } => write!(f, "AD type 0x{:02X}: {}", ad_type, Hex(data)),
+        }
+    }
+}
+
+fn completeness(complete: bool) -> &'static str {
+    if complete {
+        "complete"
+    } else {
+        "incomplete"
+    }
+}
+
+/// AD structures of a payload, in the order they were sent
+#[derive(Clone, Copy, Debug)]
+pub struct Fields<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = Result<Field<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&len, rest) = self.rest.split_first()?;
+        let len = len as usize;
+        // A zero length ends the significant part, the rest is padding
+        if len == 0 {
+            self.rest = &[];
+            return None;
+        }
+        if rest.len() < len {
+            self.rest = &[];
+            return Some(Err(Error::Truncated));
+        }
+        let (structure, rest) = rest.split_at(len);
+        self.rest = rest;
+        Some(Field::decode(structure[0], &structure[1..]))
+    }
+}
+
+/// Decode `payload`, an iteration stops after a truncated structure
+pub fn fields(payload: &[u8]) -> Fields<'_> {
+    Fields { rest: payload }
+}
+
+/// Name of a company identifier from the Assigned Numbers document, for the
+/// ones commonly seen around
+pub fn company_name(company: u16) -> Option<&'static str> {
+    Some(match company {
+        0x0006 => "Microsoft",
+        0x000D => "Texas Instruments",
+        0x004C => "Apple",
+        0x0059 => "Nordic Semiconductor",
+        0x0075 => "Samsung",
+        0x0087 => "Garmin",
+        0x00E0 => "Google",
+        0x0157 => "Anhui Huami",
+        0x0171 => "Amazon",
+        0x02E5 => "Espressif",
+        0x038F => "Xiaomi",
+        0x0499 => "Ruuvi Innovations",
+        0x0822 => "Adafruit",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fields of a payload that decodes
+    fn decode(payload: &[u8]) -> Vec<Field<'_>> {
+        fields(payload).collect::<Result<_, _>>().unwrap()
+    }
+
+    // Fields of a payload as the scanner prints them
+    fn show(payload: &[u8]) -> Vec<String> {
+        decode(payload)
+            .iter()
+            .map(|field| field.to_string())
+            .collect()
+    }
+
+    fn uuids(field: &Field) -> Vec<Uuid> {
+        match field {
+            Field::Uuids { uuids, .. } => uuids.collect(),
+            _ => panic!("{:?} is no UUID list", field),
+        }
+    }
+
+    #[test]
+    fn ble_server_advertisement() {
+        // ble-server example: flags, complete name, Battery and Environmental
+        // Sensing services
+        let payload = [
+            0x02, 0x01, 0x06, 0x0D, 0x09, b'E', b'S', b'P', b'3', b'2', b' ', b'S', b'e', b'r',
+            b'v', b'e', b'r', 0x05, 0x03, 0x0F, 0x18, 0x1A, 0x18,
+        ];
+        let fields = decode(&payload);
+        assert_eq!(fields[0], Field::Flags(Flags(0x06)));
+        assert_eq!(
+            fields[1],
+            Field::Name {
+                complete: true,
+                name: "ESP32 Server"
+            }
+        );
+        assert_eq!(
+            uuids(&fields[2]),
+            [Uuid::Uuid16(0x180F), Uuid::Uuid16(0x181A)]
+        );
+        assert_eq!(
+            show(&payload),
+            [
+                "Flags: LE General Discoverable, BR/EDR Not Supported",
+                "Name (complete): \"ESP32 Server\"",
+                "Services (complete): 0x180F 0x181A",
+            ]
+        );
+    }
+
+    #[test]
+    fn uuid128_bytes_are_reversed() {
+        // Nordic UART Service 6E400001-B5A3-F393-E0A9-E50E24DCCA9E, sent least
+        // significant byte first, with a shortened name and TX power
+        let payload = [
+            0x11, 0x07, 0x9E, 0xCA, 0xDC, 0x24, 0x0E, 0xE5, 0xA9, 0xE0, 0x93, 0xF3, 0xA3, 0xB5,
+            0x01, 0x00, 0x40, 0x6E, 0x05, 0x08, b'E', b'S', b'P', b'3', 0x02, 0x0A, 0xF4,
+        ];
+        let fields = decode(&payload);
+        assert_eq!(
+            uuids(&fields[0]),
+            [Uuid::Uuid128([
+                0x6E, 0x40, 0x00, 0x01, 0xB5, 0xA3, 0xF3, 0x93, 0xE0, 0xA9, 0xE5, 0x0E, 0x24, 0xDC,
+                0xCA, 0x9E
+            ])]
+        );
+        assert_eq!(fields[2], Field::TxPower(-12));
+        assert_eq!(
+            show(&payload),
+            [
+                "Services (complete): 6E400001-B5A3-F393-E0A9-E50E24DCCA9E",
+                "Name (incomplete): \"ESP3\"",
+                "TX power: -12 dBm",
+            ]
+        );
+    }
+
+    #[test]
+    fn incomplete_uuid_lists() {
+        let payload = [
+            0x03, 0x02, 0xAA, 0xFE, 0x09, 0x04, 0x78, 0x56, 0x34, 0x12, 0x0F, 0x18, 0x00, 0x00,
+        ];
+        let fields = decode(&payload);
+        assert_eq!(uuids(&fields[0]), [Uuid::Uuid16(0xFEAA)]);
+        assert_eq!(
+            uuids(&fields[1]),
+            [Uuid::Uuid32(0x1234_5678), Uuid::Uuid32(0x0000_180F)]
+        );
+        assert_eq!(
+            show(&payload),
+            [
+                "Services (incomplete): 0xFEAA",
+                "Services (incomplete): 0x12345678 0x0000180F",
+            ]
+        );
+
+        // An empty list is valid
+        assert!(uuids(&decode(&[0x01, 0x03])[0]).is_empty());
+    }
+
+    #[test]
+    fn service_and_manufacturer_data() {
+        // Eddystone TLM frame start, a 32-bit service, an iBeacon prefix and
+        // a company without a name
+        let payload = [
+            0x06, 0x16, 0xAA, 0xFE, 0x20, 0x00, 0x0B, 0x07, 0x20, 0x78, 0x56, 0x34, 0x12, 0x01,
+            0x02, 0x05, 0xFF, 0x4C, 0x00, 0x02, 0x15, 0x04, 0xFF, 0x34, 0x12, 0xAB,
+        ];
+        let fields = decode(&payload);
+        assert_eq!(
+            fields[0],
+            Field::ServiceData {
+                uuid: Uuid::Uuid16(0xFEAA),
+                data: &[0x20, 0x00, 0x0B]
+            }
+        );
+        assert_eq!(
+            fields[2],
+            Field::ManufacturerData {
+                company: 0x004C,
+                data: &[0x02, 0x15]
+            }
+        );
+        assert_eq!(
+            show(&payload),
+            [
+                "Service data 0xFEAA: 20 00 0B",
+                "Service data 0x12345678: 01 02",
+                "Manufacturer data Apple (0x004C): 02 15",
+                "Manufacturer data (0x1234): AB",
+            ]
+        );
+
+        // Service data of a 128-bit UUID, without data
+        let mut payload = [0_u8; 18];
+        payload[..2].copy_from_slice(&[0x11, 0x21]);
+        payload[2] = 0x01;
+        assert_eq!(
+            show(&payload),
+            ["Service data 00000000-0000-0000-0000-000000000001: "]
+        );
+    }
+
+    #[test]
+    fn other_ad_types_are_kept() {
+        // Appearance, generic thermometer
+        let payload = [0x03, 0x19, 0x00, 0x03];
+        assert_eq!(
+            decode(&payload),
+            [Field::Other {
+                ad_type: 0x19,
+                data: &[0x00, 0x03]
+            }]
+        );
+        assert_eq!(show(&payload), ["AD type 0x19: 00 03"]);
+    }
+
+    #[test]
+    fn padding_ends_the_payload() {
+        assert!(decode(&[]).is_empty());
+        assert!(decode(&[0x00, 0x02, 0x01, 0x06]).is_empty());
+        assert_eq!(
+            decode(&[0x02, 0x01, 0x06, 0x00, 0x00, 0x00]),
+            [Field::Flags(Flags(0x06))]
+        );
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(Flags(0x00).to_string(), "");
+        assert_eq!(
+            Flags(0x05).to_string(),
+            "LE Limited Discoverable, BR/EDR Not Supported"
+        );
+        assert_eq!(
+            Flags(0x1F).to_string(),
+            "LE Limited Discoverable, LE General Discoverable, BR/EDR Not Supported, \
+             LE and BR/EDR Controller, LE and BR/EDR Host"
+        );
+        // Reserved bits are not shown
+        assert_eq!(Flags(0xE2).to_string(), "LE General Discoverable");
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(Hex(&[]).to_string(), "");
+        assert_eq!(Hex(&[0x0A]).to_string(), "0A");
+        assert_eq!(Hex(&[0x00, 0xAB, 0xFF]).to_string(), "00 AB FF");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(Error::Truncated.to_string(), "Truncated AD structure");
+        assert_eq!(
+            Error::Malformed(0x16).to_string(),
+            "Malformed AD structure of type 0x16"
+        );
+    }
+
+    #[test]
+    fn malformed_payloads() {
+        // Manufacturer data longer than the payload ends the iteration
+        let truncated = [0x02, 0x01, 0x06, 0x1B, 0xFF, 0x99, 0x04, 0x05, 0x12];
+        let fields: Vec<_> = fields(&truncated).collect();
+        assert!(matches!(fields[..], [Ok(_), Err(Error::Truncated)]));
+
+        // Data too short or invalid for its type
+        let malformed: [(&[u8], u8); 8] = [
+            (&[0x01, 0x01], FLAGS),
+            (&[0x04, 0x03, 0x0F, 0x18, 0x1A], COMPLETE_UUIDS16),
+            (&[0x04, 0x05, 0x78, 0x56, 0x34], COMPLETE_UUIDS32),
+            (
+                &[0x10, 0x07, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                COMPLETE_UUIDS128,
+            ),
+            (&[0x03, 0x09, 0xFF, 0xFE], COMPLETE_NAME),
+            (&[0x01, 0x0A], TX_POWER),
+            (&[0x02, 0x16, 0x1A], SERVICE_DATA16),
+            (&[0x02, 0xFF, 0x4C], MANUFACTURER_DATA),
+        ];
+        for (payload, ad_type) in malformed {
+            assert_eq!(
+                super::fields(payload).next(),
+                Some(Err(Error::Malformed(ad_type))),
+                "{:02X?}",
+                payload
+            );
+        }
+
+        // The structures after a malformed one still decode
+        let fields: Vec<_> = super::fields(&[0x01, 0x01, 0x02, 0x0A, 0x00]).collect();
+        assert_eq!(
+            fields,
+            [Err(Error::Malformed(FLAGS)), Ok(Field::TxPower(0))]
+        );
+    }
+
+    #[test]
+    fn company_names() {
+        assert_eq!(company_name(0x004C), Some("Apple"));
+        assert_eq!(company_name(0x02E5), Some("Espressif"));
+        assert_eq!(company_name(0xFFFF), None);
+    }
+}
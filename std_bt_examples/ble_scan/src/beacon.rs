@@ -0,0 +1,386 @@
+//! Apple iBeacon and Google Eddystone frames, found in the manufacturer and
+//! service data of an advertisement.
+
+use crate::ad::{self, Field, Hex, Uuid};
+use core::fmt;
+
+const APPLE: u16 = 0x004C;
+// iBeacon type and length in front of the 21 bytes of the beacon
+const IBEACON_PREFIX: [u8; 2] = [0x02, 0x15];
+const EDDYSTONE: Uuid = Uuid::Uuid16(0xFEAA);
+
+const EDDYSTONE_UID: u8 = 0x00;
+const EDDYSTONE_URL: u8 = 0x10;
+const EDDYSTONE_TLM: u8 = 0x20;
+// Longest encoded URL, after the frame type, TX power and scheme
+const EDDYSTONE_URL_MAX_LEN: usize = 17;
+
+const URL_SCHEMES: [&str; 4] = ["http://www.", "https://www.", "http://", "https://"];
+const URL_EXPANSIONS: [&str; 14] = [
+    ".com/", ".org/", ".edu/", ".net/", ".info/", ".biz/", ".gov/", ".com", ".org", ".edu", ".net",
+    ".info", ".biz", ".gov",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Beacon<'a> {
+    IBeacon {
+        uuid: [u8; 16],
+        major: u16,
+        minor: u16,
+        /// RSSI in dBm expected at 1 m
+        measured_power: i8,
+    },
+    EddystoneUid {
+        /// RSSI in dBm expected at 0 m
+        tx_power: i8,
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+    EddystoneUrl {
+        tx_power: i8,
+        url: Url<'a>,
+    },
+    EddystoneTlm(Telemetry),
+}
+
+/// Eddystone-URL with its scheme and abbreviations still encoded, written
+/// out in full by `Display`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Url<'a> {
+    scheme: u8,
+    encoded: &'a [u8],
+}
+
+impl fmt::Display for Url<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(URL_SCHEMES[self.scheme as usize])?;
+        for &byte in self.encoded {
+            match URL_EXPANSIONS.get(byte as usize) {
+                Some(expansion) => f.write_str(expansion)?,
+                None => write!(f, "{}", byte as char)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Unencrypted Eddystone-TLM frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Telemetry {
+    /// `None` when the beacon does not measure it
+    pub battery_mv: Option<u16>,
+    /// `None` when the beacon does not measure it
+    pub temperature: Option<f32>,
+    /// Frames sent since power up
+    pub adv_count: u32,
+    /// Time since power up in tenths of a second
+    pub uptime_ds: u32,
+}
+
+impl<'a> Beacon<'a> {
+    /// Beacon carried by `field`, if any
+    pub fn decode(field: &Field<'a>) -> Option<Beacon<'a>> {
+        match *field {
+            Field::ManufacturerData {
+                company: APPLE,
+                data,
+            } => ibeacon(data),
+            Field::ServiceData {
+                uuid: EDDYSTONE,
+                data,
+            } => eddystone(data),
+            _ => None,
+        }
+    }
+}
+
+fn ibeacon(data: &[u8]) -> Option<Beacon<'_>> {
+    let beacon = data.strip_prefix(&IBEACON_PREFIX)?;
+    if beacon.len() != 21 {
+        return None;
+    }
+    Some(Beacon::IBeacon {
+        uuid: beacon[..16].try_into().unwrap(),
+        major: u16::from_be_bytes([beacon[16], beacon[17]]),
+        minor: u16::from_be_bytes([beacon[18], beacon[19]]),
+        measured_power: beacon[20] as i8,
+    })
+}
+
+fn eddystone(data: &[u8]) -> Option<Beacon<'_>> {
+    let (&frame_type, frame) = data.split_first()?;
+    match frame_type {
+        // The two reserved bytes at the end are optional
+        EDDYSTONE_UID if frame.len() == 17 || frame.len() == 19 => Some(Beacon::EddystoneUid {
+            tx_power: frame[0] as i8,
+            namespace: frame[1..11].try_into().unwrap(),
+            instance: frame[11..17].try_into().unwrap(),
+        }),
+        EDDYSTONE_URL if frame.len() >= 2 => {
+            let (scheme, encoded) = (frame[1], &frame[2..]);
+            let valid = |&byte: &u8| byte < URL_EXPANSIONS.len() as u8 || byte.is_ascii_graphic();
+            if scheme as usize >= URL_SCHEMES.len()
+                || encoded.len() > EDDYSTONE_URL_MAX_LEN
+                || !encoded.iter().all(valid)
+            {
+                return None;
+            }
+            Some(Beacon::EddystoneUrl {
+                tx_power: frame[0] as i8,
+                url: Url { scheme, encoded },
+            })
+        }
+        // Version 0, encrypted frames have version 1
+        EDDYSTONE_TLM if frame.len() == 13 && frame[0] == 0x00 => {
+            let battery_mv = u16::from_be_bytes([frame[1], frame[2]]);
+            // Signed 8.8 fixed point, 0x8000 when not measured
+            let temperature = i16::from_be_bytes([frame[3], frame[4]]);
+            Some(Beacon::EddystoneTlm(Telemetry {
+                battery_mv: (battery_mv != 0).then_some(battery_mv),
+                temperature: (temperature != i16::MIN).then(|| temperature as f32 / 256.0),
+                adv_count: u32::from_be_bytes(frame[5..9].try_into().unwrap()),
+                uptime_ds: u32::from_be_bytes(frame[9..13].try_into().unwrap()),
+            }))
+        }
+        _ => None,
+    }
+}
+
+impl fmt::Display for Beacon<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Beacon::IBeacon {
+                uuid,
+                major,
+                minor,
+                measured_power,
+            } => {
+                write!(f, "iBeacon ")?;
+                ad::uuid128(f, uuid)?;
+                write!(
+                    f,
+                    " major {} minor {}, {} dBm at 1 m",
+                    major, minor, measured_power
+                )
+            }
+            Beacon::EddystoneUid {
+                tx_power,
+                namespace,
+                instance,
+            } => write!(
+                f,
+                "Eddystone-UID namespace {} instance {}, {} dBm at 0 m",
+                Hex(namespace),
+                Hex(instance),
+                tx_power
+            ),
+            Beacon::EddystoneUrl { tx_power, url } => {
+                write!(f, "Eddystone-URL {}, {} dBm at 0 m", url, tx_power)
+            }
+            Beacon::EddystoneTlm(telemetry) => {
+                write!(f, "Eddystone-TLM")?;
+                if let Some(battery_mv) = telemetry.battery_mv {
+                    write!(f, " battery {} mV,", battery_mv)?;
+                }
+                if let Some(temperature) = telemetry.temperature {
+                    write!(f, " {:.2} °C,", temperature)?;
+                }
+                let seconds = telemetry.uptime_ds / 10;
+                write!(
+                    f,
+                    " {} frames, up {}d {:02}:{:02}:{:02}",
+                    telemetry.adv_count,
+                    seconds / 86400,
+                    seconds / 3600 % 24,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: [u8; 16] = [
+        0xE2, 0xC5, 0x6D, 0xB5, 0xDF, 0xFB, 0x48, 0xD2, 0xB0, 0x60, 0xD0, 0xF5, 0xA7, 0x10, 0x96,
+        0xE0,
+    ];
+
+    // Beacon of the first field that has one, as the scanner finds it
+    fn decode(payload: &[u8]) -> Option<Beacon<'_>> {
+        ad::fields(payload)
+            .flatten()
+            .find_map(|field| Beacon::decode(&field))
+    }
+
+    // Advertisement with Eddystone service data holding `frame`
+    fn eddystone(frame: &[u8]) -> Vec<u8> {
+        let mut payload = vec![0x02, 0x01, 0x06, 0x03, 0x03, 0xAA, 0xFE];
+        payload.extend([frame.len() as u8 + 3, 0x16, 0xAA, 0xFE]);
+        payload.extend(frame);
+        payload
+    }
+
+    #[test]
+    fn ibeacon() {
+        let mut payload = vec![0x02, 0x01, 0x06, 0x1A, 0xFF, 0x4C, 0x00, 0x02, 0x15];
+        payload.extend(UUID);
+        payload.extend([0x00, 0x01, 0x01, 0x02, 0xC5]);
+        let beacon = decode(&payload).unwrap();
+        assert_eq!(
+            beacon,
+            Beacon::IBeacon {
+                uuid: UUID,
+                major: 1,
+                minor: 258,
+                measured_power: -59,
+            }
+        );
+        assert_eq!(
+            beacon.to_string(),
+            "iBeacon E2C56DB5-DFFB-48D2-B060-D0F5A71096E0 major 1 minor 258, -59 dBm at 1 m"
+        );
+    }
+
+    #[test]
+    fn other_apple_data_is_not_ibeacon() {
+        // One byte short
+        let mut payload = vec![0x19, 0xFF, 0x4C, 0x00, 0x02, 0x15];
+        payload.extend(UUID);
+        payload.extend([0x00, 0x01, 0x00, 0x02]);
+        assert_eq!(decode(&payload), None);
+        // Nearby Info
+        let payload = [0x07, 0xFF, 0x4C, 0x00, 0x10, 0x05, 0x01, 0x18, 0x44];
+        assert_eq!(decode(&payload), None);
+        // iBeacon layout under another company
+        let mut payload = vec![0x1A, 0xFF, 0x59, 0x00, 0x02, 0x15];
+        payload.extend(UUID);
+        payload.extend([0x00, 0x01, 0x00, 0x02, 0xC5]);
+        assert_eq!(decode(&payload), None);
+    }
+
+    #[test]
+    fn eddystone_uid() {
+        let mut frame = vec![0x00, 0xEE];
+        frame.extend(1..=10);
+        frame.extend([0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]);
+        let payload = eddystone(&frame);
+        let expected = Beacon::EddystoneUid {
+            tx_power: -18,
+            namespace: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
+            instance: [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5],
+        };
+        assert_eq!(decode(&payload), Some(expected));
+        assert_eq!(
+            expected.to_string(),
+            "Eddystone-UID namespace 01 02 03 04 05 06 07 08 09 0A instance A0 A1 A2 A3 A4 A5, \
+             -18 dBm at 0 m"
+        );
+
+        // With the reserved bytes, but not with one of them
+        frame.extend([0, 0]);
+        assert_eq!(decode(&eddystone(&frame)), Some(expected));
+        frame.pop();
+        assert_eq!(decode(&eddystone(&frame)), None);
+    }
+
+    #[test]
+    fn eddystone_url() {
+        let mut frame = vec![0x10, 0xEB, 0x00];
+        frame.extend(b"apollolabs");
+        frame.push(0x00);
+        frame.extend(b"ping");
+        let payload = eddystone(&frame);
+        let beacon = decode(&payload).unwrap();
+        assert_eq!(
+            beacon.to_string(),
+            "Eddystone-URL http://www.apollolabs.com/ping, -21 dBm at 0 m"
+        );
+
+        let frame = [0x10, 0x00, 0x03, b'e', b'x', 0x0D];
+        let payload = eddystone(&frame);
+        let beacon = decode(&payload).unwrap();
+        assert_eq!(
+            beacon.to_string(),
+            "Eddystone-URL https://ex.gov, 0 dBm at 0 m"
+        );
+    }
+
+    #[test]
+    fn eddystone_url_invalid() {
+        // Unknown scheme
+        assert_eq!(decode(&eddystone(&[0x10, 0xEB, 0x04, b'a'])), None);
+        // Reserved code and a space
+        assert_eq!(decode(&eddystone(&[0x10, 0xEB, 0x02, b'a', 0x0E])), None);
+        assert_eq!(decode(&eddystone(&[0x10, 0xEB, 0x02, b'a', b' '])), None);
+        // Non-ASCII
+        assert_eq!(decode(&eddystone(&[0x10, 0xEB, 0x02, 0xC3, 0xA9])), None);
+        // No scheme
+        assert_eq!(decode(&eddystone(&[0x10, 0xEB])), None);
+        // 17 encoded bytes fit, 18 do not
+        let mut frame = vec![0x10, 0xEB, 0x02];
+        frame.extend([b'a'; 17]);
+        assert!(decode(&eddystone(&frame)).is_some());
+        frame.push(b'a');
+        assert_eq!(decode(&eddystone(&frame)), None);
+    }
+
+    #[test]
+    fn eddystone_tlm() {
+        let frame = [
+            0x20, 0x00, 0x0B, 0xB8, 0x19, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xEA, 0x60,
+        ];
+        let payload = eddystone(&frame);
+        let beacon = decode(&payload).unwrap();
+        assert_eq!(
+            beacon,
+            Beacon::EddystoneTlm(Telemetry {
+                battery_mv: Some(3000),
+                temperature: Some(25.5),
+                adv_count: 256,
+                uptime_ds: 60_000,
+            })
+        );
+        assert_eq!(
+            beacon.to_string(),
+            "Eddystone-TLM battery 3000 mV, 25.50 °C, 256 frames, up 0d 01:40:00"
+        );
+    }
+
+    #[test]
+    fn eddystone_tlm_without_measurements() {
+        let frame = [
+            0x20, 0x00, 0x00, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x0D, 0x2F, 0x00,
+        ];
+        let payload = eddystone(&frame);
+        let beacon = decode(&payload).unwrap();
+        assert_eq!(
+            beacon,
+            Beacon::EddystoneTlm(Telemetry {
+                battery_mv: None,
+                temperature: None,
+                adv_count: 7,
+                uptime_ds: 864_000,
+            })
+        );
+        assert_eq!(beacon.to_string(), "Eddystone-TLM 7 frames, up 1d 00:00:00");
+    }
+
+    #[test]
+    fn eddystone_tlm_invalid() {
+        // Encrypted version
+        let mut frame = vec![0x20, 0x01];
+        frame.extend([0; 12]);
+        assert_eq!(decode(&eddystone(&frame)), None);
+        // One byte short
+        frame[1] = 0x00;
+        frame.pop();
+        assert_eq!(decode(&eddystone(&frame)), None);
+        // Unknown frame type and empty service data
+        assert_eq!(decode(&eddystone(&[0x30, 0x00])), None);
+        assert_eq!(decode(&eddystone(&[])), None);
+    }
+}
@@ -1,26 +1,227 @@
+mod ad;
+mod beacon;
+mod presence;
+mod sensor;
+
+use ad::Field;
+use beacon::Beacon;
 use esp32_nimble::BLEDevice;
-use esp_idf_hal::task::block_on;
-use esp_idf_sys as _;
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_sys::{
+    ble_gap_disc, ble_gap_disc_params, ble_gap_event, BLE_GAP_EVENT_DISC,
+    BLE_GAP_EVENT_DISC_COMPLETE, BLE_OWN_ADDR_PUBLIC,
+};
+use presence::{Config, Sighting, Tracker};
+use sensor::Sensor;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+// Track the presence of the devices around, instead of printing what a
+// single scan finds
+const TRACK: bool = true;
+
+const SCAN_DURATION_MS: i32 = 5000;
+// BLE_HS_FOREVER, a scan that only ends when stopped
+const SCAN_FOREVER: i32 = i32::MAX;
+// Period of the timeout checks and of the list of present devices
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+const PRESENT_INTERVAL: Duration = Duration::from_secs(10);
+// Report type of a scan response, BLE_HCI_ADV_RPT_EVTYPE_SCAN_RSP
+const SCAN_RESPONSE: u8 = 4;
+
+/// Devices printed by the scan, every criterion set must match
+struct Filter {
+    /// Only devices sending an iBeacon or Eddystone frame
+    beacons: bool,
+    /// Only sensor tags with readings in a known format
+    sensors: bool,
+    /// Only devices with manufacturer data of this company
+    company: Option<u16>,
+    /// Only devices whose name starts with this
+    name: Option<&'static str>,
+}
+
+// Print everything, e.g. set `company: Some(0x004C)` for Apple devices only
+const FILTER: Filter = Filter {
+    beacons: false,
+    sensors: false,
+    company: None,
+    name: None,
+};
+
+// Devices entering within about 4 m and leaving past about 11 m at the
+// default -59 dBm at 1 m, or when nothing was heard from them for 15 s
+const PRESENCE: Config = Config {
+    enter_rssi: -75.0,
+    leave_rssi: -85.0,
+    timeout: Duration::from_secs(15),
+    path_loss_exponent: 2.5,
+};
+
+static SCAN_DONE: AtomicBool = AtomicBool::new(false);
+// Sightings passed from the NimBLE task to the tracker while tracking
+static SIGHTINGS: OnceLock<Sender<Sighting>> = OnceLock::new();
 
 fn main() {
     esp_idf_svc::sys::link_patches();
 
-    block_on(async {
-        let ble_device = BLEDevice::take();
-        let ble_scan = ble_device.get_scan();
-        ble_scan
-            .active_scan(true)
-            .interval(100)
-            .window(50)
-            .on_result(|_scan, param| {
-                println!(
-                    "Advertised Device Name: {:?}, Address: {:?} dB, RSSI: {:?}",
-                    param.name(),
-                    param.addr(),
-                    param.rssi()
-                );
-            });
-        ble_scan.start(5000).await.unwrap();
-        println!("Scan finished");
-    });
+    // Bring up the NimBLE host, the scan itself goes through the GAP API so
+    // the raw advertising payload can be decoded
+    BLEDevice::take();
+
+    if TRACK {
+        track();
+    }
+
+    start_scan(SCAN_DURATION_MS);
+    while !SCAN_DONE.load(Ordering::Acquire) {
+        FreeRtos::delay_ms(100);
+    }
+    println!("Scan finished");
+}
+
+fn start_scan(duration_ms: i32) {
+    // Active scan, so scan responses are reported as well. Duplicates are
+    // not filtered, every advertisement counts for the RSSI.
+    let mut params = ble_gap_disc_params {
+        itvl: 100,
+        window: 50,
+        ..Default::default()
+    };
+    params.set_passive(0);
+    let rc = unsafe {
+        ble_gap_disc(
+            BLE_OWN_ADDR_PUBLIC as u8,
+            duration_ms,
+            &params,
+            Some(on_gap_event),
+            std::ptr::null_mut(),
+        )
+    };
+    if rc != 0 {
+        panic!("Failed to start scan: {}", rc);
+    }
+}
+
+// Scan forever, printing devices entering and leaving and the ones present
+// as lines of JSON for whatever listens on the serial port
+fn track() -> ! {
+    let (sender, sightings) = mpsc::channel();
+    SIGHTINGS.set(sender).unwrap();
+    start_scan(SCAN_FOREVER);
+
+    let mut tracker = Tracker::new(PRESENCE);
+    let mut expired = Instant::now();
+    let mut listed = Instant::now();
+    loop {
+        if let Ok(sighting) = sightings.recv_timeout(EXPIRE_INTERVAL) {
+            if let Some(event) = tracker.sighting(sighting, Instant::now()) {
+                println!("{}", event);
+            }
+        }
+
+        let now = Instant::now();
+        if now - expired >= EXPIRE_INTERVAL {
+            for event in tracker.expire(now) {
+                println!("{}", event);
+            }
+            expired = now;
+            // The host ends scans when it resets
+            if SCAN_DONE.swap(false, Ordering::AcqRel) {
+                start_scan(SCAN_FOREVER);
+            }
+        }
+        if now - listed >= PRESENT_INTERVAL {
+            println!("{}", tracker.present());
+            listed = now;
+        }
+    }
+}
+
+unsafe extern "C" fn on_gap_event(event: *mut ble_gap_event, _arg: *mut c_void) -> i32 {
+    let event = &*event;
+    match event.type_ as u32 {
+        BLE_GAP_EVENT_DISC => {
+            let disc = &event.__bindgen_anon_1.disc;
+            let payload: &[u8] = if disc.length_data == 0 {
+                &[]
+            } else {
+                std::slice::from_raw_parts(disc.data, disc.length_data as usize)
+            };
+            match SIGHTINGS.get() {
+                Some(sightings) if FILTER.matches(payload) => {
+                    if let Some(sighting) =
+                        Sighting::from_report(&disc.addr.val, disc.rssi, payload)
+                    {
+                        // Only fails once the tracker is gone
+                        let _ = sightings.send(sighting);
+                    }
+                }
+                Some(_) => (),
+                None => report(
+                    &disc.addr.val,
+                    disc.rssi,
+                    disc.event_type == SCAN_RESPONSE,
+                    payload,
+                ),
+            }
+        }
+        BLE_GAP_EVENT_DISC_COMPLETE => SCAN_DONE.store(true, Ordering::Release),
+        _ => (),
+    }
+    0
+}
+
+fn report(addr: &[u8; 6], rssi: i8, scan_response: bool, payload: &[u8]) {
+    if !FILTER.matches(payload) {
+        return;
+    }
+
+    // The address is sent least significant byte first
+    let address: Vec<String> = addr.iter().rev().map(|b| format!("{:02X}", b)).collect();
+    println!(
+        "Address: {}, RSSI: {} dBm{}",
+        address.join(":"),
+        rssi,
+        if scan_response {
+            " (scan response)"
+        } else {
+            ""
+        }
+    );
+    for field in ad::fields(payload) {
+        match field {
+            Ok(field) => {
+                println!("  {}", field);
+                if let Some(beacon) = Beacon::decode(&field) {
+                    println!("    {}", beacon);
+                }
+                if let Some(sensor) = Sensor::decode(&field) {
+                    println!("    {}", sensor);
+                }
+            }
+            Err(e) => println!("  {}", e),
+        }
+    }
+}
+
+impl Filter {
+    fn matches(&self, payload: &[u8]) -> bool {
+        let any = |predicate: &dyn Fn(&Field) -> bool| {
+            ad::fields(payload).flatten().any(|field| predicate(&field))
+        };
+        (!self.beacons || any(&|field| Beacon::decode(field).is_some()))
+            && (!self.sensors || any(&|field| Sensor::decode(field).is_some()))
+            && self.company.iter().all(|&company| {
+                any(&|field| {
+                    matches!(field, Field::ManufacturerData { company: c, .. } if *c == company)
+                })
+            })
+            && self.name.iter().all(|prefix| {
+                any(&|field| matches!(field, Field::Name { name, .. } if name.starts_with(prefix)))
+            })
+    }
 }
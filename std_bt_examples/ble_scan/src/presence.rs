@@ -0,0 +1,626 @@
+//! Presence of devices from the RSSI of their advertisements, kept free of
+//! NimBLE so it can run on a PC.
+//!
+//! Beacons are tracked by their iBeacon or Eddystone-UID identity, anything
+//! else by its address. Devices using resolvable private addresses change
+//! address every few minutes and show up as new devices when they do.
+//!
+//! RSSI readings jump by several dB from one advertisement to the next, so
+//! each device's RSSI goes through a Kalman filter before it is compared to
+//! the enter and leave thresholds, and turned into a distance with the
+//! log-distance path loss model.
+
+use crate::ad::{self, Field};
+use crate::beacon::Beacon;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Devices in the table, absent ones are dropped first to make room
+const MAX_DEVICES: usize = 64;
+/// Advertisements heard before a device can enter
+const MIN_SIGHTINGS: u32 = 3;
+/// RSSI at 1 m of a device that does not advertise its TX power, typical of
+/// phones and beacons at 0 dBm
+const DEFAULT_POWER_AT_1M: i8 = -59;
+/// Path loss over the first metre, from the 0 m power of Eddystone frames and
+/// the TX power AD type to the 1 m power the model starts from
+const LOSS_AT_1M: i8 = 41;
+/// Variance of a single RSSI reading in dB², from multipath and body shadowing
+const MEASUREMENT_NOISE: f32 = 16.0;
+/// Variance the RSSI gains per second in dB², from the device moving
+const PROCESS_NOISE: f32 = 1.0;
+
+/// Thresholds of the tracker, `enter_rssi` above `leave_rssi`
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Smoothed RSSI in dBm a device enters at
+    pub enter_rssi: f32,
+    /// Smoothed RSSI in dBm a present device leaves below
+    pub leave_rssi: f32,
+    /// A present device not heard from for this long leaves
+    pub timeout: Duration,
+    /// 2 in free space, 2.5 to 4 indoors
+    pub path_loss_exponent: f32,
+}
+
+/// What a device is tracked by
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Id {
+    /// Bytes in the order they are written, most significant first
+    Address([u8; 6]),
+    IBeacon {
+        uuid: [u8; 16],
+        major: u16,
+        minor: u16,
+    },
+    Eddystone {
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Id::Address(address) => {
+                for (i, byte) in address.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(":")?;
+                    }
+                    write!(f, "{:02X}", byte)?;
+                }
+                Ok(())
+            }
+            Id::IBeacon { uuid, major, minor } => {
+                write!(f, "iBeacon ")?;
+                ad::uuid128(f, uuid)?;
+                write!(f, " {}/{}", major, minor)
+            }
+            Id::Eddystone {
+                namespace,
+                instance,
+            } => {
+                write!(f, "Eddystone ")?;
+                namespace.iter().try_for_each(|b| write!(f, "{:02X}", b))?;
+                f.write_str("/")?;
+                instance.iter().try_for_each(|b| write!(f, "{:02X}", b))
+            }
+        }
+    }
+}
+
+/// One advertisement, reduced to what the tracker needs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sighting {
+    pub id: Id,
+    pub rssi: i8,
+    /// RSSI expected at 1 m, when the advertisement tells
+    pub power_at_1m: Option<i8>,
+}
+
+impl Sighting {
+    /// Sighting of the advertiser at `addr`, sent least significant byte
+    /// first, from its `payload`. Eddystone-TLM frames are left out, they
+    /// carry neither the identity nor the TX power of the beacon sending them.
+    pub fn from_report(addr: &[u8; 6], rssi: i8, payload: &[u8]) -> Option<Sighting> {
+        let mut address = *addr;
+        address.reverse();
+        let mut id = Id::Address(address);
+        let mut beacon_power = None;
+        let mut tx_power = None;
+        let mut telemetry = false;
+        for field in ad::fields(payload).flatten() {
+            match Beacon::decode(&field) {
+                Some(Beacon::IBeacon {
+                    uuid,
+                    major,
+                    minor,
+                    measured_power,
+                }) => {
+                    id = Id::IBeacon { uuid, major, minor };
+                    beacon_power = Some(measured_power);
+                }
+                Some(Beacon::EddystoneUid {
+                    tx_power,
+                    namespace,
+                    instance,
+                }) => {
+                    id = Id::Eddystone {
+                        namespace,
+                        instance,
+                    };
+                    beacon_power = Some(tx_power.saturating_sub(LOSS_AT_1M));
+                }
+                Some(Beacon::EddystoneUrl { tx_power, .. }) => {
+                    beacon_power = Some(tx_power.saturating_sub(LOSS_AT_1M));
+                }
+                Some(Beacon::EddystoneTlm(_)) => telemetry = true,
+                None => {
+                    if let Field::TxPower(dbm) = field {
+                        tx_power = Some(dbm.saturating_sub(LOSS_AT_1M));
+                    }
+                }
+            }
+        }
+        let power_at_1m = beacon_power.or(tx_power);
+        if telemetry && power_at_1m.is_none() {
+            return None;
+        }
+        Some(Sighting {
+            id,
+            rssi,
+            power_at_1m,
+        })
+    }
+}
+
+/// One-dimensional Kalman filter of the RSSI, with time as the process
+#[derive(Clone, Copy, Debug)]
+struct Kalman {
+    estimate: f32,
+    variance: f32,
+    updated: Instant,
+}
+
+impl Kalman {
+    fn new(rssi: f32, now: Instant) -> Self {
+        Kalman {
+            estimate: rssi,
+            variance: MEASUREMENT_NOISE,
+            updated: now,
+        }
+    }
+
+    fn update(&mut self, rssi: f32, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f32();
+        let predicted = self.variance + PROCESS_NOISE * elapsed;
+        let gain = predicted / (predicted + MEASUREMENT_NOISE);
+        self.estimate += gain * (rssi - self.estimate);
+        self.variance = (1.0 - gain) * predicted;
+        self.updated = now;
+    }
+}
+
+/// A device in the table
+#[derive(Clone, Copy, Debug)]
+pub struct Device {
+    rssi: Kalman,
+    power_at_1m: i8,
+    sightings: u32,
+    present: bool,
+}
+
+impl Device {
+    /// Smoothed RSSI in dBm
+    pub fn rssi(&self) -> f32 {
+        self.rssi.estimate
+    }
+
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.rssi.updated
+    }
+}
+
+/// Why a device left
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reason {
+    /// Its RSSI fell below `leave_rssi`
+    Range,
+    /// Nothing was heard from it for `timeout`
+    Timeout,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Enter,
+    Leave(Reason),
+}
+
+/// A device entering or leaving, written as a line of JSON
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    pub change: Change,
+    pub id: Id,
+    /// Smoothed RSSI in dBm
+    pub rssi: f32,
+    /// Estimated distance in metres
+    pub distance: f32,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (event, reason) = match self.change {
+            Change::Enter => ("enter", ""),
+            Change::Leave(Reason::Range) => ("leave", ",\"reason\":\"range\""),
+            Change::Leave(Reason::Timeout) => ("leave", ",\"reason\":\"timeout\""),
+        };
+        write!(
+            f,
+            "{{\"event\":\"{}\",\"id\":\"{}\",\"rssi\":{:.1},\"distance\":{:.2}{}}}",
+            event, self.id, self.rssi, self.distance, reason
+        )
+    }
+}
+
+/// The devices present, written as a line of JSON
+pub struct Present<'a>(&'a Tracker);
+
+impl fmt::Display for Present<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("{\"event\":\"present\",\"devices\":[")?;
+        let present = self.0.devices().filter(|(_, device)| device.present());
+        for (i, (id, device)) in present.enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(
+                f,
+                "{{\"id\":\"{}\",\"rssi\":{:.1},\"distance\":{:.2}}}",
+                id,
+                device.rssi(),
+                self.0.distance(device)
+            )?;
+        }
+        f.write_str("]}")
+    }
+}
+
+/// Table of the devices heard, fed with sightings and regularly expired
+pub struct Tracker {
+    config: Config,
+    devices: BTreeMap<Id, Device>,
+}
+
+impl Tracker {
+    pub fn new(config: Config) -> Self {
+        Tracker {
+            config,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = (&Id, &Device)> {
+        self.devices.iter()
+    }
+
+    /// The devices present, to list them
+    pub fn present(&self) -> Present<'_> {
+        Present(self)
+    }
+
+    /// Estimated distance of `device` in metres
+    pub fn distance(&self, device: &Device) -> f32 {
+        let loss = (device.power_at_1m as f32 - device.rssi()) / 10.0;
+        10_f32.powf(loss / self.config.path_loss_exponent)
+    }
+
+    /// Take `sighting`, heard at `now`, the event it causes if any
+    pub fn sighting(&mut self, sighting: Sighting, now: Instant) -> Option<Event> {
+        if !self.devices.contains_key(&sighting.id) && !self.make_room() {
+            return None;
+        }
+        let rssi = sighting.rssi as f32;
+        let device = self
+            .devices
+            .entry(sighting.id)
+            .and_modify(|device| device.rssi.update(rssi, now))
+            .or_insert_with(|| Device {
+                rssi: Kalman::new(rssi, now),
+                power_at_1m: DEFAULT_POWER_AT_1M,
+                sightings: 0,
+                present: false,
+            });
+        device.sightings = device.sightings.saturating_add(1);
+        if let Some(power_at_1m) = sighting.power_at_1m {
+            device.power_at_1m = power_at_1m;
+        }
+
+        let change = if !device.present
+            && device.sightings >= MIN_SIGHTINGS
+            && device.rssi() >= self.config.enter_rssi
+        {
+            device.present = true;
+            Change::Enter
+        } else if device.present && device.rssi() < self.config.leave_rssi {
+            device.present = false;
+            Change::Leave(Reason::Range)
+        } else {
+            return None;
+        };
+        let device = *device;
+        Some(self.event(change, sighting.id, &device))
+    }
+
+    /// Drop the devices not heard from for `timeout` by `now`, the present
+    /// ones leaving
+    pub fn expire(&mut self, now: Instant) -> Vec<Event> {
+        let timeout = self.config.timeout;
+        let expired: Vec<(Id, Device)> = self
+            .devices
+            .iter()
+            .filter(|(_, device)| now.saturating_duration_since(device.last_seen()) >= timeout)
+            .map(|(&id, &device)| (id, device))
+            .collect();
+        let mut events = Vec::new();
+        for (id, device) in expired {
+            self.devices.remove(&id);
+            if device.present {
+                events.push(self.event(Change::Leave(Reason::Timeout), id, &device));
+            }
+        }
+        events
+    }
+
+    // Free a slot for a new device by dropping the absent one heard from
+    // longest ago, false when all of them are present
+    fn make_room(&mut self) -> bool {
+        if self.devices.len() < MAX_DEVICES {
+            return true;
+        }
+        let oldest = self
+            .devices
+            .iter()
+            .filter(|(_, device)| !device.present)
+            .min_by_key(|(_, device)| device.last_seen())
+            .map(|(&id, _)| id);
+        match oldest {
+            Some(id) => {
+                self.devices.remove(&id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn event(&self, change: Change, id: Id, device: &Device) -> Event {
+        Event {
+            change,
+            id,
+            rssi: device.rssi(),
+            distance: self.distance(device),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: Config = Config {
+        enter_rssi: -75.0,
+        leave_rssi: -85.0,
+        timeout: Duration::from_secs(15),
+        path_loss_exponent: 2.5,
+    };
+
+    fn address(n: u8) -> Id {
+        Id::Address([0xC0, 0, 0, 0, 0, n])
+    }
+
+    fn sighting(id: Id, rssi: i8) -> Sighting {
+        Sighting {
+            id,
+            rssi,
+            power_at_1m: None,
+        }
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    // Enter `id` with three sightings at -60 dBm, a second apart from `start`
+    fn enter(tracker: &mut Tracker, id: Id, start: Instant) {
+        let events: Vec<_> = (0..MIN_SIGHTINGS as u64)
+            .filter_map(|i| tracker.sighting(sighting(id, -60), secs(start, i)))
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].change, Change::Enter);
+    }
+
+    #[test]
+    fn kalman_weighs_readings_by_their_variance() {
+        let start = Instant::now();
+        let mut kalman = Kalman::new(-60.0, start);
+        assert_eq!(
+            (kalman.estimate, kalman.variance),
+            (-60.0, MEASUREMENT_NOISE)
+        );
+
+        // Same variance on both sides, the estimate goes halfway
+        kalman.update(-70.0, start);
+        assert_eq!((kalman.estimate, kalman.variance), (-65.0, 8.0));
+
+        // 8 s add 8 dB², the reading gets half the weight again
+        kalman.update(-75.0, secs(start, 8));
+        assert_eq!((kalman.estimate, kalman.variance), (-70.0, 8.0));
+
+        // A long silence trusts the new reading almost fully
+        kalman.update(-90.0, secs(start, 1000));
+        assert!((kalman.estimate + 89.7).abs() < 0.1, "{}", kalman.estimate);
+    }
+
+    #[test]
+    fn kalman_smooths_a_jump() {
+        let start = Instant::now();
+        let mut kalman = Kalman::new(-60.0, start);
+        for i in 1..=10 {
+            kalman.update(-60.0, secs(start, i));
+        }
+        kalman.update(-80.0, secs(start, 11));
+        assert!(kalman.estimate > -70.0, "{}", kalman.estimate);
+    }
+
+    #[test]
+    fn devices_enter_after_enough_sightings() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        let id = address(1);
+        assert_eq!(tracker.sighting(sighting(id, -60), start), None);
+        assert_eq!(tracker.sighting(sighting(id, -60), secs(start, 1)), None);
+        let event = tracker.sighting(sighting(id, -60), secs(start, 2));
+        assert_eq!(
+            event.map(|event| (event.change, event.id)),
+            Some((Change::Enter, id))
+        );
+        // Once
+        assert_eq!(tracker.sighting(sighting(id, -60), secs(start, 3)), None);
+    }
+
+    #[test]
+    fn weak_devices_do_not_enter() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        for i in 0..10 {
+            assert_eq!(
+                tracker.sighting(sighting(address(1), -80), secs(start, i)),
+                None
+            );
+        }
+        assert_eq!(
+            tracker.present().to_string(),
+            r#"{"event":"present","devices":[]}"#
+        );
+    }
+
+    #[test]
+    fn hysteresis_between_the_thresholds() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        let id = address(1);
+        enter(&mut tracker, id, start);
+
+        // Between the thresholds a present device stays
+        for i in 3..30 {
+            assert_eq!(tracker.sighting(sighting(id, -80), secs(start, i)), None);
+        }
+        // A single weak reading is smoothed away
+        assert_eq!(tracker.sighting(sighting(id, -95), secs(start, 30)), None);
+
+        let left = (31..60)
+            .find_map(|i| tracker.sighting(sighting(id, -95), secs(start, i)))
+            .unwrap();
+        assert_eq!(left.change, Change::Leave(Reason::Range));
+        assert!(left.rssi < CONFIG.leave_rssi);
+
+        // Coming back takes the enter threshold, not the leave one
+        for i in 60..90 {
+            assert_eq!(tracker.sighting(sighting(id, -80), secs(start, i)), None);
+        }
+        let entered = (90..120)
+            .find_map(|i| tracker.sighting(sighting(id, -60), secs(start, i)))
+            .unwrap();
+        assert_eq!(entered.change, Change::Enter);
+        assert!(entered.rssi >= CONFIG.enter_rssi);
+    }
+
+    #[test]
+    fn silent_devices_time_out() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        enter(&mut tracker, address(1), start);
+        tracker.sighting(sighting(address(2), -80), start);
+
+        // Last heard at 2 s and at 0 s
+        assert_eq!(tracker.expire(secs(start, 14)), []);
+        // The absent device goes without an event
+        assert_eq!(tracker.expire(secs(start, 15)), []);
+        assert_eq!(tracker.devices().count(), 1);
+
+        let events = tracker.expire(secs(start, 17));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].change, Change::Leave(Reason::Timeout));
+        assert_eq!(events[0].id, address(1));
+        assert_eq!(tracker.devices().count(), 0);
+    }
+
+    #[test]
+    fn a_full_table_drops_the_oldest_absent_device() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        enter(&mut tracker, address(0), start);
+        for n in 1..MAX_DEVICES as u8 {
+            tracker.sighting(sighting(address(n), -90), secs(start, n as u64));
+        }
+        assert_eq!(tracker.devices().count(), MAX_DEVICES);
+
+        // The present device was heard first but stays
+        tracker.sighting(sighting(address(100), -90), secs(start, 100));
+        assert_eq!(tracker.devices().count(), MAX_DEVICES);
+        let ids: Vec<Id> = tracker.devices().map(|(&id, _)| id).collect();
+        assert!(ids.contains(&address(0)));
+        assert!(!ids.contains(&address(1)));
+        assert!(ids.contains(&address(2)));
+        assert!(ids.contains(&address(100)));
+    }
+
+    #[test]
+    fn a_table_of_present_devices_refuses_new_ones() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        for n in 0..MAX_DEVICES as u8 {
+            enter(&mut tracker, address(n), start);
+        }
+        let newcomer = address(200);
+        for i in 0..5 {
+            assert_eq!(
+                tracker.sighting(sighting(newcomer, -50), secs(start, i)),
+                None
+            );
+        }
+        assert!(tracker.devices().all(|(&id, _)| id != newcomer));
+    }
+
+    #[test]
+    fn distance_from_the_path_loss() {
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        let near = Sighting {
+            power_at_1m: Some(-65),
+            ..sighting(address(1), -65)
+        };
+        tracker.sighting(near, start);
+        // At the default power at 1 m, 25 dB below it is 10 m away
+        tracker.sighting(sighting(address(2), -84), start);
+
+        let distances: Vec<f32> = tracker
+            .devices()
+            .map(|(_, device)| tracker.distance(device))
+            .collect();
+        assert!((distances[0] - 1.0).abs() < 1e-4, "{:?}", distances);
+        assert!((distances[1] - 10.0).abs() < 1e-3, "{:?}", distances);
+    }
+
+    #[test]
+    fn events_and_present_devices_as_json() {
+        let event = Event {
+            change: Change::Leave(Reason::Timeout),
+            id: address(0x2A),
+            rssi: -80.04,
+            distance: 6.3096,
+        };
+        assert_eq!(
+            event.to_string(),
+            r#"{"event":"leave","id":"C0:00:00:00:00:2A","rssi":-80.0,"distance":6.31,"reason":"timeout"}"#
+        );
+
+        let start = Instant::now();
+        let mut tracker = Tracker::new(CONFIG);
+        enter(&mut tracker, address(1), start);
+        enter(&mut tracker, address(2), start);
+        tracker.sighting(sighting(address(3), -90), start);
+        assert_eq!(
+            tracker.present().to_string(),
+            concat!(
+                r#"{"event":"present","devices":["#,
+                r#"{"id":"C0:00:00:00:00:01","rssi":-60.0,"distance":1.10},"#,
+                r#"{"id":"C0:00:00:00:00:02","rssi":-60.0,"distance":1.10}]}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! Readings of common sensor tags that broadcast them in advertisements:
+//! RuuviTag data format 5, the ATC1441 and pvvx firmwares of Xiaomi
+//! thermometers, and unencrypted BTHome v2.
+
+use crate::ad::{Field, Uuid};
+use core::fmt;
+
+const RUUVI: u16 = 0x0499;
+const RUUVI_RAW_V2: u8 = 5;
+// Environmental Sensing service data, used by the custom Xiaomi firmwares
+const ENVIRONMENTAL_SENSING: Uuid = Uuid::Uuid16(0x181A);
+const ATC1441_LEN: usize = 13;
+const PVVX_LEN: usize = 15;
+const BTHOME: Uuid = Uuid::Uuid16(0xFCD2);
+const BTHOME_ENCRYPTED: u8 = 0x01;
+const BTHOME_VERSION_2: u8 = 2 << 5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ruuvi,
+    Atc1441,
+    Pvvx,
+    BtHome,
+}
+
+impl Format {
+    fn name(self) -> &'static str {
+        match self {
+            Format::Ruuvi => "RuuviTag",
+            Format::Atc1441 => "ATC1441",
+            Format::Pvvx => "pvvx",
+            Format::BtHome => "BTHome",
+        }
+    }
+}
+
+/// Values broadcast by a sensor tag, `None` for the ones it leaves out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sensor {
+    pub format: Format,
+    pub temperature: Option<f32>,
+    /// Relative humidity in percent
+    pub humidity: Option<f32>,
+    pub pressure_hpa: Option<f32>,
+    pub battery_percent: Option<u8>,
+    pub battery_mv: Option<u16>,
+}
+
+impl Sensor {
+    fn new(format: Format) -> Self {
+        Sensor {
+            format,
+            temperature: None,
+            humidity: None,
+            pressure_hpa: None,
+            battery_percent: None,
+            battery_mv: None,
+        }
+    }
+
+    /// Readings carried by `field`, if it is in one of the known formats
+    pub fn decode(field: &Field<'_>) -> Option<Sensor> {
+        match *field {
+            Field::ManufacturerData {
+                company: RUUVI,
+                data,
+            } => ruuvi(data),
+            Field::ServiceData {
+                uuid: ENVIRONMENTAL_SENSING,
+                data,
+            } => match data.len() {
+                ATC1441_LEN => Some(atc1441(data)),
+                PVVX_LEN => Some(pvvx(data)),
+                _ => None,
+            },
+            Field::ServiceData { uuid: BTHOME, data } => bthome(data),
+            _ => None,
+        }
+    }
+}
+
+// Data format 5 (RAWv2), invalid values are all ones or i16::MIN
+fn ruuvi(data: &[u8]) -> Option<Sensor> {
+    if data.len() != 24 || data[0] != RUUVI_RAW_V2 {
+        return None;
+    }
+    let be16 = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+    let temperature = be16(1) as i16;
+    let humidity = be16(3);
+    let pressure = be16(5);
+    // 11 bits of battery voltage above 1.6 V, 5 bits of TX power
+    let battery = be16(13) >> 5;
+    Some(Sensor {
+        temperature: (temperature != i16::MIN).then_some(temperature as f32 * 0.005),
+        humidity: (humidity != u16::MAX).then_some(humidity as f32 * 0.0025),
+        pressure_hpa: (pressure != u16::MAX).then(|| (pressure as f32 + 50_000.0) / 100.0),
+        battery_mv: (battery != 0x7FF).then_some(battery + 1600),
+        ..Sensor::new(Format::Ruuvi)
+    })
+}
+
+// MAC, temperature in 0.1 °C, humidity and battery in percent, battery in
+// mV and a frame counter, big-endian
+fn atc1441(data: &[u8]) -> Sensor {
+    Sensor {
+        temperature: Some(i16::from_be_bytes([data[6], data[7]]) as f32 / 10.0),
+        humidity: Some(data[8] as f32),
+        battery_percent: Some(data[9]),
+        battery_mv: Some(u16::from_be_bytes([data[10], data[11]])),
+        ..Sensor::new(Format::Atc1441)
+    }
+}
+
+// MAC, temperature and humidity in hundredths, battery in mV and percent,
+// a frame counter and flags, little-endian
+fn pvvx(data: &[u8]) -> Sensor {
+    Sensor {
+        temperature: Some(i16::from_le_bytes([data[6], data[7]]) as f32 / 100.0),
+        humidity: Some(u16::from_le_bytes([data[8], data[9]]) as f32 / 100.0),
+        battery_mv: Some(u16::from_le_bytes([data[10], data[11]])),
+        battery_percent: Some(data[12]),
+        ..Sensor::new(Format::Pvvx)
+    }
+}
+
+// Device information byte followed by objects of an ID and a value whose
+// size the ID sets. Decoding stops at the first ID not known here.
+fn bthome(data: &[u8]) -> Option<Sensor> {
+    let (&info, mut objects) = data.split_first()?;
+    if info & BTHOME_ENCRYPTED != 0 || info & 0xE0 != BTHOME_VERSION_2 {
+        return None;
+    }
+    let mut sensor = Sensor::new(Format::BtHome);
+    while let Some((&id, rest)) = objects.split_first() {
+        let size = match bthome_size(id) {
+            Some(size) => size,
+            None => break,
+        };
+        if rest.len() < size {
+            break;
+        }
+        let (value, rest) = rest.split_at(size);
+        let le16 = || u16::from_le_bytes([value[0], value[1]]);
+        match id {
+            0x01 => sensor.battery_percent = Some(value[0]),
+            0x02 => sensor.temperature = Some(le16() as i16 as f32 / 100.0),
+            0x45 => sensor.temperature = Some(le16() as i16 as f32 / 10.0),
+            0x03 => sensor.humidity = Some(le16() as f32 / 100.0),
+            0x2E => sensor.humidity = Some(value[0] as f32),
+            0x04 => {
+                let pascal = u32::from_le_bytes([value[0], value[1], value[2], 0]);
+                sensor.pressure_hpa = Some(pascal as f32 / 100.0);
+            }
+            0x0C => sensor.battery_mv = Some(le16()),
+            _ => (),
+        }
+        objects = rest;
+    }
+    Some(sensor)
+}
+
+// Size of the value of a BTHome v2 object, from the object ID table of
+// the format specification. Text and raw objects have no fixed size.
+fn bthome_size(id: u8) -> Option<usize> {
+    Some(match id {
+        // Packet ID, battery, count, binary sensors, humidity, moisture,
+        // button, UV index
+        0x00 | 0x01 | 0x09 | 0x0F..=0x11 | 0x15..=0x2F | 0x3A | 0x46 => 1,
+        // Temperature, humidity, mass, dew point, voltage, PM2.5, PM10,
+        // CO2, VOC, moisture, dimmer, count
+        0x02 | 0x03 | 0x06..=0x08 | 0x0C..=0x0E | 0x12..=0x14 | 0x3C | 0x3D => 2,
+        // Rotation, distance, current, speed, temperature, volume, flow
+        // rate, voltage, acceleration, gyroscope
+        0x3F..=0x41 | 0x43..=0x45 | 0x47..=0x4A | 0x51 | 0x52 => 2,
+        // Pressure, illuminance, energy, power, duration, gas
+        0x04 | 0x05 | 0x0A | 0x0B | 0x42 | 0x4B => 3,
+        // Count, gas, energy, volume, water, timestamp
+        0x3E | 0x4C..=0x50 => 4,
+        _ => return None,
+    })
+}
+
+impl fmt::Display for Sensor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format.name())?;
+        let mut separator = " ";
+        let mut value = |f: &mut fmt::Formatter<'_>, args: fmt::Arguments<'_>| {
+            let result = write!(f, "{}{}", separator, args);
+            separator = ", ";
+            result
+        };
+        if let Some(temperature) = self.temperature {
+            value(f, format_args!("{:.2} °C", temperature))?;
+        }
+        if let Some(humidity) = self.humidity {
+            value(f, format_args!("{:.1} %RH", humidity))?;
+        }
+        if let Some(pressure) = self.pressure_hpa {
+            value(f, format_args!("{:.1} hPa", pressure))?;
+        }
+        if let Some(percent) = self.battery_percent {
+            value(f, format_args!("battery {} %", percent))?;
+        }
+        if let Some(millivolts) = self.battery_mv {
+            value(f, format_args!("battery {} mV", millivolts))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ad;
+
+    // Sensor of the first field that has one, as the scanner finds it
+    fn decode(payload: &[u8]) -> Option<Sensor> {
+        ad::fields(payload)
+            .flatten()
+            .find_map(|field| Sensor::decode(&field))
+    }
+
+    fn close(value: Option<f32>, expected: f32) -> bool {
+        value.is_some_and(|value| (value - expected).abs() < 0.001)
+    }
+
+    #[test]
+    fn ruuvi_data_format_5() {
+        // Valid data test vector of the Ruuvi specification
+        let payload = [
+            0x02, 0x01, 0x06, 0x1B, 0xFF, 0x99, 0x04, 0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C,
+            0x00, 0x04, 0xFF, 0xFC, 0x04, 0x0C, 0xAC, 0x36, 0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33,
+            0x4C, 0x88, 0x4F,
+        ];
+        let sensor = decode(&payload).unwrap();
+        assert_eq!(sensor.format, Format::Ruuvi);
+        assert!(close(sensor.temperature, 24.3));
+        assert!(close(sensor.humidity, 53.49));
+        assert!(close(sensor.pressure_hpa, 1000.44));
+        assert_eq!(sensor.battery_mv, Some(2977));
+        assert_eq!(sensor.battery_percent, None);
+        assert_eq!(
+            sensor.to_string(),
+            "RuuviTag 24.30 °C, 53.5 %RH, 1000.4 hPa, battery 2977 mV"
+        );
+    }
+
+    #[test]
+    fn ruuvi_invalid_values_are_none() {
+        // Invalid values test vector of the Ruuvi specification
+        let mut payload = vec![0x1B, 0xFF, 0x99, 0x04, 0x05, 0x80, 0x00];
+        payload.extend([0xFF; 4]);
+        payload.extend([0x80, 0x00, 0x80, 0x00, 0x80, 0x00]);
+        payload.extend([0xFF; 11]);
+        let sensor = decode(&payload).unwrap();
+        assert_eq!(
+            (sensor.temperature, sensor.humidity, sensor.pressure_hpa),
+            (None, None, None)
+        );
+        assert_eq!(sensor.battery_mv, None);
+        assert_eq!(sensor.to_string(), "RuuviTag");
+    }
+
+    #[test]
+    fn ruuvi_other_formats_and_lengths() {
+        let mut payload = vec![0x1B, 0xFF, 0x99, 0x04, 0x03];
+        payload.extend([0; 23]);
+        assert_eq!(decode(&payload), None);
+        // Format 5 one byte short
+        payload[0] = 0x1A;
+        payload[4] = 0x05;
+        payload.pop();
+        assert_eq!(decode(&payload), None);
+    }
+
+    #[test]
+    fn atc1441() {
+        let payload = [
+            0x10, 0x16, 0x1A, 0x18, 0xA4, 0xC1, 0x38, 0x12, 0x34, 0x56, 0xFF, 0xCE, 0x32, 0x55,
+            0x0B, 0xB8, 0x07,
+        ];
+        let sensor = decode(&payload).unwrap();
+        assert_eq!(sensor.format, Format::Atc1441);
+        assert!(close(sensor.temperature, -5.0));
+        assert!(close(sensor.humidity, 50.0));
+        assert_eq!(sensor.battery_percent, Some(85));
+        assert_eq!(sensor.battery_mv, Some(3000));
+        assert_eq!(
+            sensor.to_string(),
+            "ATC1441 -5.00 °C, 50.0 %RH, battery 85 %, battery 3000 mV"
+        );
+    }
+
+    #[test]
+    fn pvvx() {
+        let payload = [
+            0x12, 0x16, 0x1A, 0x18, 0x56, 0x34, 0x12, 0x38, 0xC1, 0xA4, 0x29, 0x09, 0x94, 0x13,
+            0x86, 0x0B, 0x50, 0x01, 0x04,
+        ];
+        let sensor = decode(&payload).unwrap();
+        assert_eq!(sensor.format, Format::Pvvx);
+        assert!(close(sensor.temperature, 23.45));
+        assert!(close(sensor.humidity, 50.12));
+        assert_eq!(sensor.battery_mv, Some(2950));
+        assert_eq!(sensor.battery_percent, Some(80));
+    }
+
+    #[test]
+    fn environmental_sensing_of_other_lengths() {
+        let payload = [0x07, 0x16, 0x1A, 0x18, 0x01, 0x02, 0x03, 0x04];
+        assert_eq!(decode(&payload), None);
+    }
+
+    #[test]
+    fn bthome_v2() {
+        // Example objects of the BTHome format specification
+        let payload = [
+            0x02, 0x01, 0x06, 0x15, 0x16, 0xD2, 0xFC, 0x40, 0x00, 0x07, 0x01, 0x61, 0x02, 0xCA,
+            0x09, 0x03, 0xBF, 0x13, 0x04, 0x13, 0x8A, 0x01, 0x0C, 0x02, 0x0C,
+        ];
+        let sensor = decode(&payload).unwrap();
+        assert_eq!(sensor.format, Format::BtHome);
+        assert_eq!(sensor.battery_percent, Some(97));
+        assert!(close(sensor.temperature, 25.06));
+        assert!(close(sensor.humidity, 50.55));
+        assert!(close(sensor.pressure_hpa, 1008.83));
+        assert_eq!(sensor.battery_mv, Some(3074));
+    }
+
+    #[test]
+    fn bthome_objects_are_skipped_by_their_size() {
+        // CO2, VOC and moisture are two bytes, binary sensors one, energy
+        // three and a timestamp four, all before the temperature
+        let payload = [
+            0x1B, 0x16, 0xD2, 0xFC, 0x40, 0x12, 0xE2, 0x04, 0x13, 0x33, 0x01, 0x14, 0x02, 0x0C,
+            0x1A, 0x01, 0x0A, 0x13, 0x8A, 0x14, 0x50, 0x5D, 0x39, 0x61, 0x64, 0x45, 0x11, 0x01,
+        ];
+        let sensor = decode(&payload).unwrap();
+        assert!(close(sensor.temperature, 27.3));
+        assert_eq!(sensor.battery_mv, None);
+        assert_eq!(sensor.pressure_hpa, None);
+    }
+
+    #[test]
+    fn bthome_sizes() {
+        for id in [0x12, 0x13, 0x14, 0x3D, 0x45, 0x4A] {
+            assert_eq!(bthome_size(id), Some(2), "0x{:02X}", id);
+        }
+        for id in [0x0F, 0x10, 0x11, 0x15, 0x2D, 0x2E, 0x2F, 0x3A] {
+            assert_eq!(bthome_size(id), Some(1), "0x{:02X}", id);
+        }
+        assert_eq!(bthome_size(0x42), Some(3));
+        assert_eq!(bthome_size(0x3E), Some(4));
+        // Variable length text and raw, and unassigned IDs
+        for id in [0x30, 0x3B, 0x53, 0x54, 0xFF] {
+            assert_eq!(bthome_size(id), None, "0x{:02X}", id);
+        }
+    }
+
+    #[test]
+    fn bthome_stops_at_unknown_and_truncated_objects() {
+        let unknown = [
+            0x0A, 0x16, 0xD2, 0xFC, 0x40, 0x01, 0x50, 0x53, 0x02, 0xCA, 0x09,
+        ];
+        let sensor = decode(&unknown).unwrap();
+        assert_eq!(sensor.battery_percent, Some(80));
+        assert_eq!(sensor.temperature, None);
+
+        let truncated = [0x08, 0x16, 0xD2, 0xFC, 0x40, 0x01, 0x50, 0x02, 0xCA];
+        let sensor = decode(&truncated).unwrap();
+        assert_eq!(sensor.battery_percent, Some(80));
+        assert_eq!(sensor.temperature, None);
+    }
+
+    #[test]
+    fn bthome_encrypted_or_other_versions() {
+        let encrypted = [0x06, 0x16, 0xD2, 0xFC, 0x41, 0x01, 0x50];
+        assert_eq!(decode(&encrypted), None);
+        let version_1 = [0x06, 0x16, 0xD2, 0xFC, 0x20, 0x01, 0x50];
+        assert_eq!(decode(&version_1), None);
+        let empty = [0x03, 0x16, 0xD2, 0xFC];
+        assert_eq!(decode(&empty), None);
+    }
+}
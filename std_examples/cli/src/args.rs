@@ -0,0 +1,488 @@
+//! Typed arguments for menu callbacks.
+//!
+//! Each command declares its parameters as a table of [`Arg`]s naming the
+//! `menu::Parameter`s of its item. [`parse`] checks every argument against
+//! the table, so callbacks only see valid values and bad input is reported
+//! instead of panicking the device.
+
+use menu::{argument_finder, Item, ItemType, Parameter};
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+/// What an argument accepts
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    /// Integer in an inclusive range, decimal or `0x` hexadecimal
+    Int(i64, i64),
+    /// Duration in an inclusive range, with a unit of `us`, `ms`, `s`, `m`
+    /// or `h`. Bare numbers are seconds.
+    Duration(Duration, Duration),
+    /// One of a fixed set of words
+    Choice(&'static [&'static str]),
+    /// `--name` without a value
+    Flag,
+    /// Any text, checked by the command itself
+    Text,
+}
+
+/// Declaration of one argument
+#[derive(Clone, Copy, Debug)]
+pub struct Arg {
+    /// `parameter_name` of the matching `menu::Parameter`
+    pub name: &'static str,
+    pub kind: Kind,
+    /// Used when the argument is not given
+    pub default: Option<&'static str>,
+}
+
+impl Arg {
+    pub const fn new(name: &'static str, kind: Kind) -> Self {
+        Arg {
+            name,
+            kind,
+            default: None,
+        }
+    }
+
+    pub const fn default(self, value: &'static str) -> Self {
+        Arg {
+            default: Some(value),
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Int(i64),
+    Duration(Duration),
+    Flag(bool),
+    Text(&'a str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArgError {
+    /// A mandatory argument is absent
+    Missing(&'static str),
+    /// The value does not match the declared kind
+    Invalid {
+        name: &'static str,
+        value: String,
+        expected: String,
+    },
+    /// The table names a parameter the menu item does not have
+    Undeclared(&'static str),
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgError::Missing(name) => write!(f, "Missing argument <{}>", name),
+            ArgError::Invalid {
+                name,
+                value,
+                expected,
+            } => write!(f, "Invalid {} '{}': expected {}", name, value, expected),
+            ArgError::Undeclared(name) => write!(f, "Unknown argument {}", name),
+        }
+    }
+}
+
+/// Arguments of one invocation, validated against their declarations
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parsed<'a> {
+    values: Vec<(&'static str, Value<'a>)>,
+}
+
+impl<'a> Parsed<'a> {
+    /// Value of `name`, `None` when it was neither given nor has a default
+    pub fn get<T: FromValue<'a>>(&self, name: &str) -> Option<T> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .and_then(|(_, value)| T::from_value(value))
+    }
+
+    /// Value of an argument that is mandatory or has a default
+    pub fn value<T: FromValue<'a>>(&self, name: &str) -> T {
+        self.get(name)
+            .unwrap_or_else(|| panic!("argument {} has no value of this type", name))
+    }
+}
+
+/// Conversion out of a parsed [`Value`]
+pub trait FromValue<'a>: Sized {
+    fn from_value(value: &Value<'a>) -> Option<Self>;
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(impl<'a> FromValue<'a> for $t {
+            fn from_value(value: &Value<'a>) -> Option<Self> {
+                match value {
+                    Value::Int(n) => <$t>::try_from(*n).ok(),
+                    _ => None,
+                }
+            }
+        })*
+    };
+}
+
+from_int!(i64, u64, u32, u16, u8, usize);
+
+impl<'a> FromValue<'a> for Duration {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Duration(d) => Some(*d),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for bool {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Flag(set) => Some(*set),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for &'a str {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Check `args` against `specs`
+pub fn parse<'a, T>(
+    item: &'a Item<'a, T>,
+    args: &'a [&'a str],
+    specs: &[Arg],
+) -> Result<Parsed<'a>, ArgError> {
+    let mut values = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let given =
+            argument_finder(item, args, spec.name).map_err(|_| ArgError::Undeclared(spec.name))?;
+        let value = match (given, spec.kind) {
+            (given, Kind::Flag) => Value::Flag(given.is_some()),
+            (Some(text), kind) => parse_value(spec.name, text, kind)?,
+            (None, kind) => match spec.default {
+                Some(text) => parse_value(spec.name, text, kind)?,
+                None if is_mandatory(item, spec.name) => return Err(ArgError::Missing(spec.name)),
+                None => continue,
+            },
+        };
+        values.push((spec.name, value));
+    }
+    Ok(Parsed { values })
+}
+
+/// [`parse`], writing the error and the usage of `item` to `context` on failure
+pub fn parse_or_report<'a, T: Write>(
+    item: &'a Item<'a, T>,
+    args: &'a [&'a str],
+    specs: &[Arg],
+    context: &mut T,
+) -> Option<Parsed<'a>> {
+    match parse(item, args, specs) {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            writeln!(context, "{}", e).unwrap();
+            writeln!(context, "{}", usage(item)).unwrap();
+            None
+        }
+    }
+}
+
+/// One line summary such as `Usage: ping <hostname/IP> [--count=<cnt>]`
+pub fn usage<T>(item: &Item<T>) -> String {
+    let mut out = format!("Usage: {}", item.command);
+    if let ItemType::Callback { parameters, .. } = &item.item_type {
+        for parameter in parameters.iter() {
+            match parameter {
+                Parameter::Mandatory { parameter_name, .. } => {
+                    write!(out, " <{}>", parameter_name).unwrap()
+                }
+                Parameter::Optional { parameter_name, .. } => {
+                    write!(out, " [{}]", parameter_name).unwrap()
+                }
+                Parameter::Named { parameter_name, .. } => {
+                    write!(out, " [--{}]", parameter_name).unwrap()
+                }
+                Parameter::NamedValue {
+                    parameter_name,
+                    argument_name,
+                    ..
+                } => write!(out, " [--{}=<{}>]", parameter_name, argument_name).unwrap(),
+            }
+        }
+    }
+    out
+}
+
+fn is_mandatory<T>(item: &Item<T>, name: &str) -> bool {
+    match &item.item_type {
+        ItemType::Callback { parameters, .. } => parameters.iter().any(|parameter| {
+            matches!(parameter, Parameter::Mandatory { parameter_name, .. } if *parameter_name == name)
+        }),
+        _ => false,
+    }
+}
+
+/// Parse `text` as `kind`, `name` is only used in the error
+pub fn parse_value<'a>(
+    name: &'static str,
+    text: &'a str,
+    kind: Kind,
+) -> Result<Value<'a>, ArgError> {
+    let invalid = |expected: String| ArgError::Invalid {
+        name,
+        value: text.into(),
+        expected,
+    };
+    match kind {
+        Kind::Int(min, max) => match parse_int(text) {
+            Some(n) if (min..=max).contains(&n) => Ok(Value::Int(n)),
+            _ => Err(invalid(format!("an integer from {} to {}", min, max))),
+        },
+        Kind::Duration(min, max) => match parse_duration(text) {
+            Some(d) if (min..=max).contains(&d) => Ok(Value::Duration(d)),
+            _ => Err(invalid(format!(
+                "a duration from {:?} to {:?} (e.g. 500ms, 2s)",
+                min, max
+            ))),
+        },
+        Kind::Choice(choices) => match choices.iter().find(|choice| **choice == text) {
+            Some(choice) => Ok(Value::Text(choice)),
+            None => Err(invalid(format!("one of {}", choices.join(", ")))),
+        },
+        Kind::Flag => Ok(Value::Flag(true)),
+        Kind::Text => Ok(Value::Text(text)),
+    }
+}
+
+fn parse_int(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let n = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    Some(if negative { -n } else { n })
+}
+
+/// Parse `500ms`, `1.5s`, `2m` and the like, bare numbers are seconds
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let scale = match unit {
+        "us" => 1e-6,
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(number * scale).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop(_menu: &menu::Menu<String>, _item: &Item<String>, _args: &[&str], _out: &mut String) {}
+
+    const ITEM: Item<String> = Item {
+        item_type: ItemType::Callback {
+            function: noop,
+            parameters: &[
+                Parameter::Mandatory {
+                    parameter_name: "chip-address",
+                    help: None,
+                },
+                Parameter::Optional {
+                    parameter_name: "data-address",
+                    help: None,
+                },
+                Parameter::NamedValue {
+                    parameter_name: "mode",
+                    argument_name: "b|w",
+                    help: None,
+                },
+                Parameter::NamedValue {
+                    parameter_name: "time",
+                    argument_name: "duration",
+                    help: None,
+                },
+                Parameter::Named {
+                    parameter_name: "stop",
+                    help: None,
+                },
+            ],
+        },
+        command: "i2cget",
+        help: None,
+    };
+
+    const SPECS: &[Arg] = &[
+        Arg::new("chip-address", Kind::Int(0x08, 0x77)),
+        Arg::new("data-address", Kind::Int(0, 0xff)),
+        Arg::new("mode", Kind::Choice(&["b", "w"])).default("b"),
+        Arg::new(
+            "time",
+            Kind::Duration(Duration::from_millis(100), Duration::from_secs(3600)),
+        ),
+        Arg::new("stop", Kind::Flag),
+    ];
+
+    fn invalid(name: &'static str, value: &str, expected: &str) -> ArgError {
+        ArgError::Invalid {
+            name,
+            value: value.into(),
+            expected: expected.into(),
+        }
+    }
+
+    #[test]
+    fn integers_in_decimal_or_hex() {
+        let kind = Kind::Int(-10, 0x77);
+        assert_eq!(parse_value("n", "0x3c", kind), Ok(Value::Int(0x3c)));
+        assert_eq!(parse_value("n", "0X3C", kind), Ok(Value::Int(0x3c)));
+        assert_eq!(parse_value("n", "60", kind), Ok(Value::Int(60)));
+        assert_eq!(parse_value("n", "-0xa", kind), Ok(Value::Int(-10)));
+        let expected = "an integer from -10 to 119";
+        assert_eq!(
+            parse_value("n", "0x78", kind),
+            Err(invalid("n", "0x78", expected))
+        );
+        assert_eq!(
+            parse_value("n", "-11", kind),
+            Err(invalid("n", "-11", expected))
+        );
+        assert_eq!(
+            parse_value("n", "3c", kind),
+            Err(invalid("n", "3c", expected))
+        );
+        assert_eq!(parse_value("n", "", kind), Err(invalid("n", "", expected)));
+    }
+
+    #[test]
+    fn durations_with_units() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("250us"), Some(Duration::from_micros(250)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1d"), None);
+        assert_eq!(parse_duration("ms"), None);
+        let kind = Kind::Duration(Duration::from_millis(100), Duration::from_secs(60));
+        assert_eq!(
+            parse_value("time", "50ms", kind),
+            Err(invalid(
+                "time",
+                "50ms",
+                "a duration from 100ms to 60s (e.g. 500ms, 2s)"
+            ))
+        );
+    }
+
+    #[test]
+    fn choices_flags_and_text() {
+        let kind = Kind::Choice(&["b", "w"]);
+        assert_eq!(parse_value("mode", "w", kind), Ok(Value::Text("w")));
+        assert_eq!(
+            parse_value("mode", "W", kind),
+            Err(invalid("mode", "W", "one of b, w"))
+        );
+        assert_eq!(parse_value("stop", "", Kind::Flag), Ok(Value::Flag(true)));
+        assert_eq!(
+            parse_value("name", "any thing", Kind::Text),
+            Ok(Value::Text("any thing"))
+        );
+    }
+
+    #[test]
+    fn parse_against_a_table() {
+        let args = ["0x50", "0x12", "--mode=w", "--time=2s", "--stop"];
+        let parsed = parse(&ITEM, &args, SPECS).unwrap();
+        assert_eq!(parsed.value::<u8>("chip-address"), 0x50);
+        assert_eq!(parsed.get::<u8>("data-address"), Some(0x12));
+        assert_eq!(parsed.value::<&str>("mode"), "w");
+        assert_eq!(parsed.value::<Duration>("time"), Duration::from_secs(2));
+        assert!(parsed.value::<bool>("stop"));
+        // Values convert only to types they fit
+        assert_eq!(parsed.get::<&str>("chip-address"), None);
+    }
+
+    #[test]
+    fn defaults_and_absent_optionals() {
+        let args = ["0x50"];
+        let parsed = parse(&ITEM, &args, SPECS).unwrap();
+        assert_eq!(parsed.get::<u8>("data-address"), None);
+        assert_eq!(parsed.get::<Duration>("time"), None);
+        assert_eq!(parsed.value::<&str>("mode"), "b");
+        assert!(!parsed.value::<bool>("stop"));
+    }
+
+    #[test]
+    fn table_errors() {
+        let args: [&str; 0] = [];
+        assert_eq!(
+            parse(&ITEM, &args, SPECS),
+            Err(ArgError::Missing("chip-address"))
+        );
+        let args = ["0x50", "--mode=x"];
+        assert_eq!(
+            parse(&ITEM, &args, SPECS),
+            Err(invalid("mode", "x", "one of b, w"))
+        );
+        let args = ["0x50"];
+        assert_eq!(
+            parse(&ITEM, &args, &[Arg::new("count", Kind::Int(1, 10))]),
+            Err(ArgError::Undeclared("count"))
+        );
+        assert_eq!(
+            ArgError::Missing("pin").to_string(),
+            "Missing argument <pin>"
+        );
+        assert_eq!(
+            invalid("pin", "22", "an integer from 0 to 21").to_string(),
+            "Invalid pin '22': expected an integer from 0 to 21"
+        );
+    }
+
+    #[test]
+    fn usage_lists_every_parameter() {
+        assert_eq!(
+            usage(&ITEM),
+            "Usage: i2cget <chip-address> [data-address] [--mode=<b|w>] \
+             [--time=<duration>] [--stop]"
+        );
+    }
+
+    #[test]
+    fn errors_are_reported_with_the_usage() {
+        let args = ["0x7f"];
+        let mut out = String::new();
+        assert_eq!(parse_or_report(&ITEM, &args, SPECS, &mut out), None);
+        assert_eq!(
+            out,
+            "Invalid chip-address '0x7f': expected an integer from 8 to 119\n\
+             Usage: i2cget <chip-address> [data-address] [--mode=<b|w>] \
+             [--time=<duration>] [--stop]\n"
+        );
+    }
+}
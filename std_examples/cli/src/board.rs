@@ -0,0 +1,328 @@
+//! Runtime-selected GPIO, PWM and ADC drivers behind the bring-up commands.
+//!
+//! Pins are picked by number at runtime, so drivers are built on
+//! `AnyIOPin`. The pin registry makes sure each pin has a single owner.
+
+use crate::pins::{self, Attenuation, Mode, Owner, PinRegistry, Pull, Summary};
+use esp_idf_hal::adc::attenuation::{DB_11, DB_2_5, DB_6, NONE};
+use esp_idf_hal::adc::{self, ADCPin, AdcChannelDriver, AdcDriver, ADC1};
+use esp_idf_hal::delay::FreeRtos;
+use esp_idf_hal::gpio::{self, AnyIOPin, Input, InputOutput, PinDriver};
+use esp_idf_hal::ledc::config::TimerConfig;
+use esp_idf_hal::ledc::*;
+use esp_idf_hal::prelude::*;
+use esp_idf_hal::sys::{adc_atten_t, EspError};
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// One LEDC timer per PWM output so each can run at its own frequency
+const PWM_SLOTS: usize = 4;
+/// Sampling period of `gpio watch`
+const WATCH_PERIOD_MS: u32 = 10;
+
+enum GpioDriver {
+    Input(PinDriver<'static, AnyIOPin, Input>),
+    // Outputs are also inputs so their level can be read back
+    Output(PinDriver<'static, AnyIOPin, InputOutput>),
+}
+
+impl GpioDriver {
+    fn is_high(&self) -> bool {
+        match self {
+            GpioDriver::Input(driver) => driver.is_high(),
+            GpioDriver::Output(driver) => driver.is_high(),
+        }
+    }
+}
+
+struct Pwm {
+    pin: u8,
+    slot: usize,
+    frequency: u32,
+    driver: LedcDriver<'static>,
+}
+
+/// Settings applied by `pwm set`
+pub struct PwmInfo {
+    pub frequency: u32,
+    pub resolution: u32,
+    pub duty: u32,
+    pub max_duty: u32,
+}
+
+pub struct Board {
+    registry: PinRegistry,
+    gpio: Vec<(u8, GpioDriver)>,
+    pwm: Vec<Pwm>,
+    adc: AdcDriver<'static, ADC1>,
+}
+
+impl Board {
+    pub fn new(registry: PinRegistry, adc: ADC1) -> Result<Self, EspError> {
+        Ok(Board {
+            registry,
+            gpio: Vec::new(),
+            pwm: Vec::new(),
+            adc: AdcDriver::new(adc, &adc::config::Config::new().calibration(true))?,
+        })
+    }
+
+    // Take `pin` for `owner`, dropping the driver of its previous function
+    fn claim(&mut self, pin: u8, owner: Owner) -> Result<(), String> {
+        match self.registry.claim(pin, owner).map_err(|e| e.to_string())? {
+            Some(Owner::Gpio) => self.gpio.retain(|(p, _)| *p != pin),
+            Some(Owner::Pwm) => self.pwm.retain(|pwm| pwm.pin != pin),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub fn gpio_mode(&mut self, pin: u8, mode: Mode, pull: Pull) -> Result<(), String> {
+        self.claim(pin, Owner::Gpio)?;
+        // Release the current driver so the pin can be handed out again
+        self.gpio.retain(|(p, _)| *p != pin);
+
+        // The registry guarantees no other driver owns the pin
+        let any = unsafe { AnyIOPin::new(pin as i32) };
+        let pull = match pull {
+            Pull::None => gpio::Pull::Floating,
+            Pull::Up => gpio::Pull::Up,
+            Pull::Down => gpio::Pull::Down,
+        };
+        let driver = match mode {
+            Mode::Input => {
+                let mut driver = PinDriver::input(any).map_err(|e| e.to_string())?;
+                driver.set_pull(pull).map_err(|e| e.to_string())?;
+                GpioDriver::Input(driver)
+            }
+            Mode::Output | Mode::OpenDrain => {
+                let mut driver = if mode == Mode::Output {
+                    PinDriver::input_output(any)
+                } else {
+                    PinDriver::input_output_od(any)
+                }
+                .map_err(|e| e.to_string())?;
+                driver.set_pull(pull).map_err(|e| e.to_string())?;
+                GpioDriver::Output(driver)
+            }
+        };
+        self.gpio.push((pin, driver));
+        Ok(())
+    }
+
+    // Driver of `pin`, configured as a floating input if the pin has none
+    fn gpio_driver(&mut self, pin: u8) -> Result<&mut GpioDriver, String> {
+        if !self.gpio.iter().any(|(p, _)| *p == pin) {
+            self.gpio_mode(pin, Mode::Input, Pull::None)?;
+        }
+        Ok(self
+            .gpio
+            .iter_mut()
+            .find(|(p, _)| *p == pin)
+            .map(|(_, driver)| driver)
+            .unwrap())
+    }
+
+    pub fn gpio_read(&mut self, pin: u8) -> Result<bool, String> {
+        Ok(self.gpio_driver(pin)?.is_high())
+    }
+
+    /// Drive `pin`, switching it to a push-pull output unless it is one
+    pub fn gpio_write(&mut self, pin: u8, high: bool) -> Result<(), String> {
+        if !matches!(self.gpio_driver(pin)?, GpioDriver::Output(_)) {
+            self.gpio_mode(pin, Mode::Output, Pull::None)?;
+        }
+        match self.gpio_driver(pin)? {
+            GpioDriver::Output(driver) => driver.set_level(high.into()).map_err(|e| e.to_string()),
+            GpioDriver::Input(_) => unreachable!(),
+        }
+    }
+
+    /// Invert the output level, returning the new one
+    pub fn gpio_toggle(&mut self, pin: u8) -> Result<bool, String> {
+        let high = match self.gpio_driver(pin)? {
+            GpioDriver::Output(driver) => driver.is_set_high(),
+            GpioDriver::Input(_) => return Err(format!("GPIO{} is not an output", pin)),
+        };
+        self.gpio_write(pin, !high)?;
+        Ok(!high)
+    }
+
+    /// Print every level change of `pin` for `duration`
+    pub fn gpio_watch(
+        &mut self,
+        pin: u8,
+        duration: Duration,
+        out: &mut impl Write,
+    ) -> Result<(), String> {
+        let driver = self.gpio_driver(pin)?;
+        let start = Instant::now();
+        let mut level = driver.is_high();
+        writeln!(out, "{:8.3} s  GPIO{} {}", 0.0, pin, level_name(level)).unwrap();
+        while start.elapsed() < duration {
+            FreeRtos::delay_ms(WATCH_PERIOD_MS);
+            if driver.is_high() != level {
+                level = !level;
+                writeln!(
+                    out,
+                    "{:8.3} s  GPIO{} {}",
+                    start.elapsed().as_secs_f32(),
+                    pin,
+                    level_name(level)
+                )
+                .unwrap();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn pwm_set(&mut self, pin: u8, frequency: u32, percent: f32) -> Result<PwmInfo, String> {
+        let existing = self.pwm.iter().position(|pwm| pwm.pin == pin);
+        if existing.is_none() && self.pwm.len() == PWM_SLOTS {
+            return Err(format!("All {} PWM channels are in use", PWM_SLOTS));
+        }
+        self.claim(pin, Owner::Pwm)?;
+
+        // A new frequency needs its timer reconfigured, the channel is rebuilt
+        let slot = match existing {
+            Some(index) if self.pwm[index].frequency == frequency => None,
+            Some(index) => Some(self.pwm.remove(index).slot),
+            None => (0..PWM_SLOTS).find(|slot| self.pwm.iter().all(|pwm| pwm.slot != *slot)),
+        };
+        if let Some(slot) = slot {
+            let driver = match ledc_driver(slot, pin, frequency) {
+                Ok(driver) => driver,
+                Err(e) => {
+                    // Nothing drives the pin now, hand it back to gpio
+                    let _ = self.registry.claim(pin, Owner::Gpio);
+                    return Err(e.to_string());
+                }
+            };
+            self.pwm.push(Pwm {
+                pin,
+                slot,
+                frequency,
+                driver,
+            });
+        }
+
+        let pwm = self.pwm.iter_mut().find(|pwm| pwm.pin == pin).unwrap();
+        let max_duty = pwm.driver.get_max_duty();
+        let duty = pins::duty_value(max_duty, percent);
+        pwm.driver.set_duty(duty).map_err(|e| e.to_string())?;
+        Ok(PwmInfo {
+            frequency,
+            resolution: pins::resolution_bits(frequency),
+            duty,
+            max_duty,
+        })
+    }
+
+    pub fn adc_read(
+        &mut self,
+        pin: u8,
+        attenuation: Attenuation,
+        samples: u32,
+    ) -> Result<Summary, String> {
+        pins::check_adc_pin(pin)?;
+        self.claim(pin, Owner::Adc)?;
+
+        // Channel drivers are typed by pin and attenuation, so dispatch on both.
+        // The registry guarantees no other driver owns the pin.
+        let adc = &mut self.adc;
+        let readings = unsafe {
+            match pin {
+                0 => read_adc(adc, gpio::Gpio0::new(), attenuation, samples),
+                1 => read_adc(adc, gpio::Gpio1::new(), attenuation, samples),
+                2 => read_adc(adc, gpio::Gpio2::new(), attenuation, samples),
+                3 => read_adc(adc, gpio::Gpio3::new(), attenuation, samples),
+                _ => read_adc(adc, gpio::Gpio4::new(), attenuation, samples),
+            }
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(Summary::new(&readings).unwrap())
+    }
+}
+
+fn level_name(high: bool) -> &'static str {
+    if high {
+        "high"
+    } else {
+        "low"
+    }
+}
+
+// Build the LEDC channel and timer of `slot` driving `pin`
+fn ledc_driver(slot: usize, pin: u8, frequency: u32) -> Result<LedcDriver<'static>, EspError> {
+    let config = TimerConfig::default()
+        .frequency(frequency.Hz())
+        .resolution(resolution(pins::resolution_bits(frequency)));
+    // Each slot is handed out once, so channels, timers and the pin are never aliased
+    unsafe {
+        let pin = AnyIOPin::new(pin as i32);
+        match slot {
+            0 => LedcDriver::new(
+                CHANNEL0::new(),
+                LedcTimerDriver::new(TIMER0::new(), &config)?,
+                pin,
+            ),
+            1 => LedcDriver::new(
+                CHANNEL1::new(),
+                LedcTimerDriver::new(TIMER1::new(), &config)?,
+                pin,
+            ),
+            2 => LedcDriver::new(
+                CHANNEL2::new(),
+                LedcTimerDriver::new(TIMER2::new(), &config)?,
+                pin,
+            ),
+            _ => LedcDriver::new(
+                CHANNEL3::new(),
+                LedcTimerDriver::new(TIMER3::new(), &config)?,
+                pin,
+            ),
+        }
+    }
+}
+
+fn resolution(bits: u32) -> Resolution {
+    match bits {
+        1 => Resolution::Bits1,
+        2 => Resolution::Bits2,
+        3 => Resolution::Bits3,
+        4 => Resolution::Bits4,
+        5 => Resolution::Bits5,
+        6 => Resolution::Bits6,
+        7 => Resolution::Bits7,
+        8 => Resolution::Bits8,
+        9 => Resolution::Bits9,
+        10 => Resolution::Bits10,
+        11 => Resolution::Bits11,
+        12 => Resolution::Bits12,
+        13 => Resolution::Bits13,
+        _ => Resolution::Bits14,
+    }
+}
+
+fn read_adc<T: ADCPin<Adc = ADC1>>(
+    adc: &mut AdcDriver<'static, ADC1>,
+    pin: T,
+    attenuation: Attenuation,
+    samples: u32,
+) -> Result<Vec<u16>, EspError> {
+    match attenuation {
+        Attenuation::Db0 => sample::<NONE, T>(adc, pin, samples),
+        Attenuation::Db2_5 => sample::<DB_2_5, T>(adc, pin, samples),
+        Attenuation::Db6 => sample::<DB_6, T>(adc, pin, samples),
+        Attenuation::Db11 => sample::<DB_11, T>(adc, pin, samples),
+    }
+}
+
+fn sample<const A: adc_atten_t, T: ADCPin<Adc = ADC1>>(
+    adc: &mut AdcDriver<'static, ADC1>,
+    pin: T,
+    samples: u32,
+) -> Result<Vec<u16>, EspError> {
+    let mut channel: AdcChannelDriver<'_, A, T> = AdcChannelDriver::new(pin)?;
+    (0..samples).map(|_| adc.read(&mut channel)).collect()
+}
@@ -0,0 +1,671 @@
+//! Line editor sitting between the UART and the menu runner.
+//!
+//! Keystrokes are collected and echoed here, with cursor movement, history
+//! recall and tab completion. Only a finished line is handed to the runner.
+
+use menu::{ItemType, Menu, Parameter};
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+const ESC: u8 = 0x1b;
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const DEL: u8 = 0x7f;
+
+/// What the terminal on the other end of the UART understands
+#[derive(Clone, Copy)]
+pub struct Terminal {
+    /// Sent once at startup, e.g. `\x1b[20h` (line feed mode) for Wokwi
+    pub init: Option<&'static str>,
+    /// VT100 cursor movement and erase sequences. Without them the cursor
+    /// is moved with backspaces and the line is erased with spaces.
+    pub vt100: bool,
+    /// Prompt the runner prints, redrawn after completions are listed
+    pub prompt: &'static str,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Normal,
+    // Previous byte was a CR, a following LF is dropped
+    Cr,
+    Escape,
+    // `ESC [` followed by an optional numeric parameter
+    Csi(u8),
+    // `ESC O`
+    Ss3,
+}
+
+#[derive(Clone, Copy)]
+enum Key {
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Delete,
+}
+
+pub struct Editor {
+    terminal: Terminal,
+    state: State,
+    line: Vec<u8>,
+    cursor: usize,
+    // Longest line the runner buffer can hold
+    capacity: usize,
+    history: VecDeque<String>,
+    history_len: usize,
+    // History changed since it was last saved
+    history_changed: bool,
+    // History entry shown, counted from the newest, `None` while editing
+    recalled: Option<usize>,
+    // Line being typed before history browsing started
+    draft: Vec<u8>,
+}
+
+impl Editor {
+    pub fn new(terminal: Terminal, capacity: usize, history_len: usize) -> Self {
+        Editor {
+            terminal,
+            state: State::Normal,
+            line: Vec::with_capacity(capacity),
+            cursor: 0,
+            capacity,
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            history_changed: false,
+            recalled: None,
+            draft: Vec::new(),
+        }
+    }
+
+    /// Send the terminal's init sequence
+    pub fn init(&self, out: &mut impl Write) {
+        if let Some(init) = self.terminal.init {
+            out.write_str(init).unwrap();
+        }
+    }
+
+    /// History entries, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &str> {
+        self.history.iter().map(String::as_str)
+    }
+
+    /// Append a line to the history, dropping the oldest entry when full.
+    /// Empty lines and repeats of the newest entry are not recorded.
+    pub fn add_history(&mut self, line: &str) {
+        let line = line.trim();
+        if self.history_len == 0
+            || line.is_empty()
+            || self.history.back().map(String::as_str) == Some(line)
+        {
+            return;
+        }
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(line.into());
+        self.history_changed = true;
+    }
+
+    /// True when lines were added since [`Editor::history_saved`] was called
+    pub fn history_changed(&self) -> bool {
+        self.history_changed
+    }
+
+    /// Record that the history as it is now has been saved
+    pub fn history_saved(&mut self) {
+        self.history_changed = false;
+    }
+
+    /// Process one received byte, echoing to `out`. Returns the line once
+    /// Enter is pressed, with the cursor left at its end.
+    pub fn feed<T>(&mut self, byte: u8, menu: &Menu<T>, out: &mut impl Write) -> Option<String> {
+        match self.state {
+            State::Normal | State::Cr => {
+                let after_cr = self.state == State::Cr;
+                self.state = State::Normal;
+                match byte {
+                    b'\n' if after_cr => (),
+                    b'\r' | b'\n' => {
+                        if byte == b'\r' {
+                            self.state = State::Cr;
+                        }
+                        return Some(self.submit(out));
+                    }
+                    ESC => self.state = State::Escape,
+                    BACKSPACE | DEL => self.backspace(out),
+                    TAB => self.complete(menu, out),
+                    CTRL_A => self.key(Key::Home, out),
+                    CTRL_E => self.key(Key::End, out),
+                    CTRL_C => {
+                        // Abandon the line and start over on a fresh prompt
+                        self.key(Key::End, out);
+                        write!(out, "^C\r\n{}", self.terminal.prompt).unwrap();
+                        self.line.clear();
+                        self.cursor = 0;
+                        self.recalled = None;
+                    }
+                    0x20..=0x7e => self.insert(&[byte], out),
+                    // Other control characters and non-ASCII are ignored
+                    _ => (),
+                }
+            }
+            State::Escape => {
+                self.state = match byte {
+                    b'[' => State::Csi(0),
+                    b'O' => State::Ss3,
+                    _ => State::Normal,
+                };
+            }
+            State::Csi(param) => {
+                self.state = State::Normal;
+                let key = match byte {
+                    b'0'..=b'9' => {
+                        self.state =
+                            State::Csi(param.saturating_mul(10).saturating_add(byte - b'0'));
+                        None
+                    }
+                    b'A' => Some(Key::Up),
+                    b'B' => Some(Key::Down),
+                    b'C' => Some(Key::Right),
+                    b'D' => Some(Key::Left),
+                    b'H' => Some(Key::Home),
+                    b'F' => Some(Key::End),
+                    b'~' => match param {
+                        1 | 7 => Some(Key::Home),
+                        3 => Some(Key::Delete),
+                        4 | 8 => Some(Key::End),
+                        _ => None,
+                    },
+                    // Unknown sequences end with a byte in 0x40..=0x7e
+                    0x40..=0x7e => None,
+                    _ => {
+                        self.state = State::Csi(param);
+                        None
+                    }
+                };
+                if let Some(key) = key {
+                    self.key(key, out);
+                }
+            }
+            State::Ss3 => {
+                self.state = State::Normal;
+                match byte {
+                    b'A' => self.key(Key::Up, out),
+                    b'B' => self.key(Key::Down, out),
+                    b'C' => self.key(Key::Right, out),
+                    b'D' => self.key(Key::Left, out),
+                    b'H' => self.key(Key::Home, out),
+                    b'F' => self.key(Key::End, out),
+                    _ => (),
+                }
+            }
+        }
+        None
+    }
+
+    fn key(&mut self, key: Key, out: &mut impl Write) {
+        match key {
+            Key::Left if self.cursor > 0 => {
+                self.move_left(1, out);
+                self.cursor -= 1;
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                self.move_right(1, out);
+                self.cursor += 1;
+            }
+            Key::Home => {
+                self.move_left(self.cursor, out);
+                self.cursor = 0;
+            }
+            Key::End => {
+                self.move_right(self.line.len() - self.cursor, out);
+                self.cursor = self.line.len();
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw_tail(1, out);
+            }
+            Key::Up => {
+                let next = self.recalled.map_or(0, |n| n + 1);
+                if next < self.history.len() {
+                    if self.recalled.is_none() {
+                        self.draft = self.line.clone();
+                    }
+                    self.recalled = Some(next);
+                    let entry = self.history[self.history.len() - 1 - next].clone();
+                    self.replace(entry.as_bytes(), out);
+                }
+            }
+            Key::Down => match self.recalled {
+                Some(0) => {
+                    self.recalled = None;
+                    let draft = core::mem::take(&mut self.draft);
+                    self.replace(&draft, out);
+                }
+                Some(n) => {
+                    self.recalled = Some(n - 1);
+                    let entry = self.history[self.history.len() - n].clone();
+                    self.replace(entry.as_bytes(), out);
+                }
+                None => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn submit(&mut self, out: &mut impl Write) -> String {
+        self.key(Key::End, out);
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.add_history(&line);
+        self.line.clear();
+        self.cursor = 0;
+        self.recalled = None;
+        self.draft.clear();
+        line
+    }
+
+    // Insert at the cursor as much of `bytes` as fits
+    fn insert(&mut self, bytes: &[u8], out: &mut impl Write) {
+        let bytes = &bytes[..bytes.len().min(self.capacity - self.line.len())];
+        if bytes.is_empty() {
+            return;
+        }
+        self.line
+            .splice(self.cursor..self.cursor, bytes.iter().copied());
+        write_bytes(out, bytes);
+        self.cursor += bytes.len();
+        self.redraw_tail(0, out);
+    }
+
+    fn backspace(&mut self, out: &mut impl Write) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.move_left(1, out);
+        self.cursor -= 1;
+        self.line.remove(self.cursor);
+        self.redraw_tail(1, out);
+    }
+
+    // Replace the whole line, leaving the cursor at its end
+    fn replace(&mut self, line: &[u8], out: &mut impl Write) {
+        let old_len = self.line.len();
+        self.move_left(self.cursor, out);
+        self.line = line[..line.len().min(self.capacity)].to_vec();
+        write_bytes(out, &self.line);
+        self.cursor = self.line.len();
+        self.erase(old_len.saturating_sub(self.line.len()), out);
+    }
+
+    // Rewrite the line from the cursor, erasing the `removed` characters
+    // it got shorter by, and put the cursor back
+    fn redraw_tail(&self, removed: usize, out: &mut impl Write) {
+        let tail = &self.line[self.cursor..];
+        write_bytes(out, tail);
+        let len = tail.len();
+        self.erase(removed, out);
+        self.move_left(len, out);
+    }
+
+    // Blank `count` characters after the cursor without moving it
+    fn erase(&self, count: usize, out: &mut impl Write) {
+        if count == 0 {
+            return;
+        }
+        if self.terminal.vt100 {
+            out.write_str("\x1b[K").unwrap();
+        } else {
+            for _ in 0..count {
+                out.write_char(' ').unwrap();
+            }
+            self.move_left(count, out);
+        }
+    }
+
+    fn move_left(&self, count: usize, out: &mut impl Write) {
+        if count == 0 {
+            return;
+        }
+        if self.terminal.vt100 {
+            write!(out, "\x1b[{}D", count).unwrap();
+        } else {
+            for _ in 0..count {
+                out.write_char(BACKSPACE as char).unwrap();
+            }
+        }
+    }
+
+    // Move right from the cursor position
+    fn move_right(&self, count: usize, out: &mut impl Write) {
+        if count == 0 {
+            return;
+        }
+        if self.terminal.vt100 {
+            write!(out, "\x1b[{}C", count).unwrap();
+        } else {
+            // Retyping the characters moves the cursor over them
+            write_bytes(out, &self.line[self.cursor..self.cursor + count]);
+        }
+    }
+
+    fn complete<T>(&mut self, menu: &Menu<T>, out: &mut impl Write) {
+        let before = String::from_utf8_lossy(&self.line[..self.cursor]).into_owned();
+        let word_start = before.rfind(' ').map_or(0, |i| i + 1);
+        let word = &before[word_start..];
+        let candidates = candidates(menu, &before[..word_start], word);
+
+        let Some(first) = candidates.first() else {
+            return;
+        };
+        let common = candidates.iter().fold(first.as_str(), |common, candidate| {
+            let len = common
+                .bytes()
+                .zip(candidate.bytes())
+                .take_while(|(a, b)| a == b)
+                .count();
+            &common[..len]
+        });
+
+        if candidates.len() == 1 {
+            // A finished word is followed by a space, `--name=` by its value
+            let mut completion = first[word.len()..].to_string();
+            if !first.ends_with('=') {
+                completion.push(' ');
+            }
+            self.insert(completion.as_bytes(), out);
+        } else if common.len() > word.len() {
+            let completion = common[word.len()..].to_string();
+            self.insert(completion.as_bytes(), out);
+        } else {
+            // Ambiguous, list the choices and redraw the line below them
+            self.key(Key::End, out);
+            out.write_str("\r\n").unwrap();
+            out.write_str(&candidates.join("  ")).unwrap();
+            write!(out, "\r\n{}", self.terminal.prompt).unwrap();
+            write_bytes(out, &self.line);
+            let back = self.line.len() - word_start - word.len();
+            self.cursor = word_start + word.len();
+            self.move_left(back, out);
+        }
+    }
+}
+
+fn write_bytes(out: &mut impl Write, bytes: &[u8]) {
+    // The line only ever holds printable ASCII
+    out.write_str(core::str::from_utf8(bytes).unwrap_or_default())
+        .unwrap();
+}
+
+/// Completions for `word` given the words typed before it: command names
+/// for the first word, `--name`/`--name=` parameters of the command after it
+pub fn candidates<T>(menu: &Menu<T>, before: &str, word: &str) -> Vec<String> {
+    let mut words = before.split_whitespace();
+    let mut candidates: Vec<String> = match words.next() {
+        None => menu
+            .items
+            .iter()
+            .map(|item| item.command.to_string())
+            .chain(["help".to_string()])
+            .collect(),
+        Some("help") => menu
+            .items
+            .iter()
+            .map(|item| item.command.to_string())
+            .collect(),
+        Some(command) => match menu.items.iter().find(|item| item.command == command) {
+            Some(item) => match &item.item_type {
+                ItemType::Callback { parameters, .. } => parameters
+                    .iter()
+                    .filter_map(|parameter| match parameter {
+                        Parameter::Named { parameter_name, .. } => {
+                            Some(format!("--{}", parameter_name))
+                        }
+                        Parameter::NamedValue { parameter_name, .. } => {
+                            Some(format!("--{}=", parameter_name))
+                        }
+                        _ => None,
+                    })
+                    // Named parameters already given are not offered again
+                    .filter(|name| {
+                        !before
+                            .split_whitespace()
+                            .any(|given| given.starts_with(name.as_str()))
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            },
+            None => Vec::new(),
+        },
+    };
+    candidates.retain(|candidate| candidate.starts_with(word));
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::Item;
+
+    const VT100: Terminal = Terminal {
+        init: None,
+        vt100: true,
+        prompt: "> ",
+    };
+    const DUMB: Terminal = Terminal {
+        init: None,
+        vt100: false,
+        prompt: "> ",
+    };
+
+    fn noop(_menu: &Menu<()>, _item: &Item<()>, _args: &[&str], _context: &mut ()) {}
+
+    const MENU: Menu<()> = Menu {
+        label: "root",
+        items: &[
+            &Item {
+                item_type: ItemType::Callback {
+                    function: noop,
+                    parameters: &[
+                        Parameter::Mandatory {
+                            parameter_name: "pin",
+                            help: None,
+                        },
+                        Parameter::Named {
+                            parameter_name: "pullup",
+                            help: None,
+                        },
+                        Parameter::NamedValue {
+                            parameter_name: "freq",
+                            argument_name: "hz",
+                            help: None,
+                        },
+                    ],
+                },
+                command: "pwm",
+                help: None,
+            },
+            &Item {
+                item_type: ItemType::Callback {
+                    function: noop,
+                    parameters: &[],
+                },
+                command: "ping",
+                help: None,
+            },
+            &Item {
+                item_type: ItemType::Callback {
+                    function: noop,
+                    parameters: &[],
+                },
+                command: "gpio",
+                help: None,
+            },
+        ],
+        entry: None,
+        exit: None,
+    };
+
+    // Feed `input`, returning the lines submitted and the echo
+    fn type_in(editor: &mut Editor, input: &[u8]) -> (Vec<String>, String) {
+        let mut echo = String::new();
+        let lines = input
+            .iter()
+            .filter_map(|&byte| editor.feed(byte, &MENU, &mut echo))
+            .collect();
+        (lines, echo)
+    }
+
+    fn line(terminal: Terminal, input: &[u8]) -> String {
+        let mut editor = Editor::new(terminal, 64, 8);
+        let (lines, _) = type_in(&mut editor, input);
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        lines[0].clone()
+    }
+
+    #[test]
+    fn lines_end_at_cr_lf_or_both() {
+        let mut editor = Editor::new(VT100, 64, 8);
+        let (lines, echo) = type_in(&mut editor, b"one\r\ntwo\nthree\r\r");
+        assert_eq!(lines, ["one", "two", "three", ""]);
+        assert_eq!(echo, "onetwothree");
+    }
+
+    #[test]
+    fn arrows_move_the_cursor_for_inserts() {
+        assert_eq!(line(VT100, b"ac\x1b[Db\r"), "abc");
+        assert_eq!(line(VT100, b"bc\x1bOHa\x1b[Fd\r"), "abcd");
+        assert_eq!(line(VT100, b"bc\x01a\x05d\r"), "abcd");
+        assert_eq!(line(VT100, b"bc\x1b[1~a\x1b[4~d\r"), "abcd");
+        assert_eq!(
+            line(VT100, b"ab\x1b[D\x1b[D\x1b[D\x1b[C\x1b[C\x1b[Cc\r"),
+            "abc"
+        );
+    }
+
+    #[test]
+    fn delete_and_backspace() {
+        assert_eq!(line(VT100, b"abxc\x1b[D\x1b[D\x1b[3~\r"), "abc");
+        assert_eq!(line(VT100, b"abxc\x1b[D\x08\r"), "abc");
+        assert_eq!(line(VT100, b"abc\x7f\x7f\x7f\x7fx\r"), "x");
+        // Delete at the end of the line does nothing
+        assert_eq!(line(VT100, b"abc\x1b[3~\r"), "abc");
+    }
+
+    #[test]
+    fn unknown_sequences_and_control_characters_are_dropped() {
+        // F5, a parameterless final byte, SS3 F1, Ctrl-B and a non-ASCII byte
+        assert_eq!(line(VT100, b"a\x1b[15~b\x1b[Zc\x1bOPd\x02e\xc3\r"), "abcde");
+    }
+
+    #[test]
+    fn vt100_echo() {
+        let mut editor = Editor::new(VT100, 64, 8);
+        let (_, echo) = type_in(&mut editor, b"ac\x1b[Db");
+        assert_eq!(echo, "ac\x1b[1Dbc\x1b[1D");
+        let (_, echo) = type_in(&mut editor, b"\x7f");
+        assert_eq!(echo, "\x1b[1Dc\x1b[K\x1b[1D");
+        let (_, echo) = type_in(&mut editor, b"\x01\x05");
+        assert_eq!(echo, "\x1b[1D\x1b[2C");
+    }
+
+    #[test]
+    fn dumb_terminal_echo() {
+        let mut editor = Editor::new(DUMB, 64, 8);
+        let (_, echo) = type_in(&mut editor, b"ac\x1b[Db");
+        assert_eq!(echo, "ac\x08bc\x08");
+        let (_, echo) = type_in(&mut editor, b"\x7f");
+        assert_eq!(echo, "\x08c \x08\x08");
+        let (_, echo) = type_in(&mut editor, b"\x01\x05");
+        assert_eq!(echo, "\x08ac");
+    }
+
+    #[test]
+    fn lines_stop_at_the_capacity() {
+        let mut editor = Editor::new(VT100, 4, 8);
+        let (lines, _) = type_in(&mut editor, b"abcdef\r");
+        assert_eq!(lines, ["abcd"]);
+    }
+
+    #[test]
+    fn ctrl_c_abandons_the_line() {
+        let mut editor = Editor::new(VT100, 64, 8);
+        let (lines, echo) = type_in(&mut editor, b"ab\x1b[D\x03x\r");
+        assert_eq!(lines, ["x"]);
+        assert_eq!(echo, "ab\x1b[1D\x1b[1C^C\r\n> x");
+    }
+
+    #[test]
+    fn history_recalls_lines_and_the_draft() {
+        let mut editor = Editor::new(VT100, 64, 8);
+        type_in(&mut editor, b"one\rtwo\r");
+        assert_eq!(editor.history().collect::<Vec<_>>(), ["one", "two"]);
+        let (lines, _) = type_in(&mut editor, b"dra\x1b[A\x1b[A\x1b[A\x1b[B\x1b[B\r");
+        assert_eq!(lines, ["dra"]);
+        let (lines, _) = type_in(&mut editor, b"\x1b[A\x1b[A\r");
+        assert_eq!(lines, ["two"]);
+        let (lines, _) = type_in(&mut editor, b"\x1bOA\x1b[Ds\r");
+        assert_eq!(lines, ["twso"]);
+    }
+
+    #[test]
+    fn recalled_lines_overwrite_longer_ones() {
+        let mut editor = Editor::new(VT100, 64, 8);
+        type_in(&mut editor, b"ab\r");
+        let (_, echo) = type_in(&mut editor, b"abcdef\x1b[D\x1b[A");
+        assert_eq!(echo, "abcdef\x1b[1D\x1b[5Dab\x1b[K");
+    }
+
+    #[test]
+    fn history_skips_blanks_and_repeats_and_drops_the_oldest() {
+        let mut editor = Editor::new(VT100, 64, 3);
+        for line in ["a", "  ", "b", "b ", "c", "d", "c"] {
+            editor.add_history(line);
+        }
+        assert_eq!(editor.history().collect::<Vec<_>>(), ["c", "d", "c"]);
+        let mut editor = Editor::new(VT100, 64, 0);
+        editor.add_history("a");
+        assert_eq!(editor.history().count(), 0);
+        assert!(!editor.history_changed());
+    }
+
+    #[test]
+    fn history_changes_until_saved() {
+        let mut editor = Editor::new(VT100, 64, 8);
+        assert!(!editor.history_changed());
+        type_in(&mut editor, b"one\r");
+        assert!(editor.history_changed());
+        editor.history_saved();
+        // Repeats and empty lines leave the saved history as it is
+        type_in(&mut editor, b"one\r\r");
+        assert!(!editor.history_changed());
+        type_in(&mut editor, b"two\r");
+        assert!(editor.history_changed());
+    }
+
+    #[test]
+    fn completion_candidates() {
+        assert_eq!(candidates(&MENU, "", "p"), ["ping", "pwm"]);
+        assert_eq!(candidates(&MENU, "", ""), ["gpio", "help", "ping", "pwm"]);
+        assert_eq!(candidates(&MENU, "help ", "g"), ["gpio"]);
+        assert_eq!(candidates(&MENU, "pwm ", "--"), ["--freq=", "--pullup"]);
+        assert_eq!(candidates(&MENU, "pwm --pullup ", "--"), ["--freq="]);
+        assert!(candidates(&MENU, "nope ", "").is_empty());
+    }
+
+    #[test]
+    fn tab_completes_words() {
+        assert_eq!(line(VT100, b"pw\t\r"), "pwm ");
+        assert_eq!(line(VT100, b"pwm --f\t100\r"), "pwm --freq=100");
+        let mut editor = Editor::new(VT100, 64, 8);
+        let (_, echo) = type_in(&mut editor, b"p\t");
+        assert_eq!(echo, "p\r\nping  pwm\r\n> p");
+    }
+}
@@ -0,0 +1,361 @@
+//! i2c-tools style commands (`i2cdetect`, `i2cget`, `i2cset`, `i2cdump`)
+//! running against any [`Bus`], so they can be exercised with a mock bus.
+
+use std::fmt::{self, Write};
+
+/// Lowest and highest address that is not reserved by the I2C specification
+pub const FIRST_ADDRESS: u8 = 0x08;
+pub const LAST_ADDRESS: u8 = 0x77;
+
+/// The device did not acknowledge or the transfer failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusError;
+
+pub trait Bus {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), BusError>;
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), BusError>;
+    /// Write then read with a repeated start in between
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buf: &mut [u8]) -> Result<(), BusError>;
+
+    /// Check whether a device acknowledges `addr`
+    fn probe(&mut self, addr: u8) -> bool {
+        self.read(addr, &mut [0]).is_ok()
+    }
+}
+
+/// Transfer size of `i2cget`/`i2cset`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Size {
+    Byte,
+    /// SMBus word, least significant byte first on the wire
+    Word,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Get {
+    pub addr: u8,
+    /// Register written before reading, `None` reads straight away
+    pub register: Option<u8>,
+    pub size: Size,
+    /// Repeated start between register write and read, else a stop
+    pub repeated_start: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Set {
+    pub addr: u8,
+    pub register: u8,
+    pub value: u16,
+    pub size: Size,
+}
+
+/// Parse the `b`/`w` mode letter, bytes are the default
+pub fn parse_size(text: Option<&str>) -> Result<Size, &'static str> {
+    match text {
+        None | Some("b") => Ok(Size::Byte),
+        Some("w") => Ok(Size::Word),
+        Some(_) => Err("Error: Invalid mode!"),
+    }
+}
+
+/// Check that `value` fits a transfer of `size`
+pub fn check_value(value: u16, size: Size) -> Result<u16, &'static str> {
+    match size {
+        Size::Byte if value > 0xff => Err("Error: Data value out of range!"),
+        _ => Ok(value),
+    }
+}
+
+/// Scan the non-reserved addresses and print the i2cdetect grid
+pub fn detect(bus: &mut impl Bus, out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f")?;
+    for row in (0..128_u8).step_by(16) {
+        write!(out, "{:02x}: ", row)?;
+        for addr in row..row + 16 {
+            if !(FIRST_ADDRESS..=LAST_ADDRESS).contains(&addr) {
+                write!(out, "   ")?;
+            } else if bus.probe(addr) {
+                write!(out, "{:02x} ", addr)?;
+            } else {
+                write!(out, "-- ")?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+pub fn get(bus: &mut impl Bus, command: &Get, out: &mut impl Write) -> fmt::Result {
+    let mut buf = [0_u8; 2];
+    let buf = match command.size {
+        Size::Byte => &mut buf[..1],
+        Size::Word => &mut buf[..],
+    };
+    let result = match command.register {
+        Some(register) if command.repeated_start => bus.write_read(command.addr, &[register], buf),
+        Some(register) => bus
+            .write(command.addr, &[register])
+            .and_then(|_| bus.read(command.addr, buf)),
+        None => bus.read(command.addr, buf),
+    };
+    match (result, command.size) {
+        (Err(BusError), _) => writeln!(out, "Error: Read failed"),
+        (Ok(()), Size::Byte) => writeln!(out, "0x{:02x}", buf[0]),
+        (Ok(()), Size::Word) => writeln!(out, "0x{:04x}", u16::from_le_bytes([buf[0], buf[1]])),
+    }
+}
+
+/// Write the value, printing nothing on success like `i2cset -y`
+pub fn set(bus: &mut impl Bus, command: &Set, out: &mut impl Write) -> fmt::Result {
+    let [low, high] = command.value.to_le_bytes();
+    let result = match command.size {
+        Size::Byte => bus.write(command.addr, &[command.register, low]),
+        Size::Word => bus.write(command.addr, &[command.register, low, high]),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(BusError) => writeln!(out, "Error: Write failed"),
+    }
+}
+
+/// Read all 256 registers one byte at a time and print them like i2cdump,
+/// registers that fail to read are shown as `XX`
+pub fn dump(bus: &mut impl Bus, addr: u8, out: &mut impl Write) -> fmt::Result {
+    writeln!(
+        out,
+        "     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f    0123456789abcdef"
+    )?;
+    for row in (0..=255_u8).step_by(16) {
+        let mut values = [None; 16];
+        for (offset, value) in values.iter_mut().enumerate() {
+            let mut buf = [0];
+            if bus
+                .write_read(addr, &[row + offset as u8], &mut buf)
+                .is_ok()
+            {
+                *value = Some(buf[0]);
+            }
+        }
+
+        write!(out, "{:02x}: ", row)?;
+        for value in values {
+            match value {
+                Some(value) => write!(out, "{:02x} ", value)?,
+                None => write!(out, "XX ")?,
+            }
+        }
+        write!(out, "   ")?;
+        for value in values {
+            let c = match value {
+                None => 'X',
+                Some(0x00) | Some(0xff) => '.',
+                Some(value) if !(32..127).contains(&value) => '?',
+                Some(value) => value as char,
+            };
+            out.write_char(c)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Transfer {
+        Write(u8, Vec<u8>),
+        Read(u8, usize),
+        WriteRead(u8, Vec<u8>, usize),
+    }
+
+    // Bus with one device answering at `addr`, whose registers read back
+    // their own number unless `unreadable`. Plain reads start at the
+    // register last written, like EEPROMs and most sensors.
+    struct MockBus {
+        addr: u8,
+        registers: [u8; 256],
+        pointer: u8,
+        unreadable: Option<u8>,
+        transfers: Vec<Transfer>,
+    }
+
+    impl MockBus {
+        fn new(addr: u8) -> Self {
+            let mut registers = [0; 256];
+            for (i, register) in registers.iter_mut().enumerate() {
+                *register = i as u8;
+            }
+            MockBus {
+                addr,
+                registers,
+                pointer: 0,
+                unreadable: None,
+                transfers: Vec::new(),
+            }
+        }
+    }
+
+    impl Bus for MockBus {
+        fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), BusError> {
+            self.transfers.push(Transfer::Write(addr, bytes.to_vec()));
+            if addr != self.addr {
+                return Err(BusError);
+            }
+            if let [register, data @ ..] = bytes {
+                self.pointer = *register;
+                for (i, byte) in data.iter().enumerate() {
+                    self.registers[*register as usize + i] = *byte;
+                }
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), BusError> {
+            self.transfers.push(Transfer::Read(addr, buf.len()));
+            if addr != self.addr {
+                return Err(BusError);
+            }
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = self.registers[self.pointer as usize + i];
+            }
+            Ok(())
+        }
+
+        fn write_read(&mut self, addr: u8, bytes: &[u8], buf: &mut [u8]) -> Result<(), BusError> {
+            self.transfers
+                .push(Transfer::WriteRead(addr, bytes.to_vec(), buf.len()));
+            let register = bytes[0];
+            if addr != self.addr || self.unreadable == Some(register) {
+                return Err(BusError);
+            }
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = self.registers[register as usize + i];
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sizes_and_values() {
+        assert_eq!(parse_size(None), Ok(Size::Byte));
+        assert_eq!(parse_size(Some("b")), Ok(Size::Byte));
+        assert_eq!(parse_size(Some("w")), Ok(Size::Word));
+        assert_eq!(parse_size(Some("c")), Err("Error: Invalid mode!"));
+        assert_eq!(check_value(0xff, Size::Byte), Ok(0xff));
+        assert_eq!(
+            check_value(0x100, Size::Byte),
+            Err("Error: Data value out of range!")
+        );
+        assert_eq!(check_value(0xffff, Size::Word), Ok(0xffff));
+    }
+
+    #[test]
+    fn detect_grid() {
+        let mut bus = MockBus::new(0x3c);
+        let mut out = String::new();
+        detect(&mut bus, &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(
+            lines[0],
+            "     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f"
+        );
+        assert_eq!(
+            lines[1],
+            "00:                         -- -- -- -- -- -- -- -- "
+        );
+        assert_eq!(
+            lines[4],
+            "30: -- -- -- -- -- -- -- -- -- -- -- -- 3c -- -- -- "
+        );
+        assert_eq!(
+            lines[8],
+            "70: -- -- -- -- -- -- -- --                         "
+        );
+        // Reserved addresses are never probed
+        assert_eq!(bus.transfers.len(), 0x78 - 0x08);
+    }
+
+    #[test]
+    fn get_bytes_and_words() {
+        let mut bus = MockBus::new(0x50);
+        let mut out = String::new();
+        let mut command = Get {
+            addr: 0x50,
+            register: Some(0x12),
+            size: Size::Byte,
+            repeated_start: true,
+        };
+        get(&mut bus, &command, &mut out).unwrap();
+        command.size = Size::Word;
+        get(&mut bus, &command, &mut out).unwrap();
+        command.repeated_start = false;
+        get(&mut bus, &command, &mut out).unwrap();
+        command.register = None;
+        get(&mut bus, &command, &mut out).unwrap();
+        command.addr = 0x51;
+        get(&mut bus, &command, &mut out).unwrap();
+        assert_eq!(out, "0x12\n0x1312\n0x1312\n0x1312\nError: Read failed\n");
+        assert_eq!(
+            bus.transfers,
+            [
+                Transfer::WriteRead(0x50, vec![0x12], 1),
+                Transfer::WriteRead(0x50, vec![0x12], 2),
+                Transfer::Write(0x50, vec![0x12]),
+                Transfer::Read(0x50, 2),
+                Transfer::Read(0x50, 2),
+                Transfer::Read(0x51, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn set_writes_words_least_significant_byte_first() {
+        let mut bus = MockBus::new(0x50);
+        let mut out = String::new();
+        let mut command = Set {
+            addr: 0x50,
+            register: 0x20,
+            value: 0xbeef,
+            size: Size::Word,
+        };
+        set(&mut bus, &command, &mut out).unwrap();
+        assert_eq!(bus.registers[0x20..0x22], [0xef, 0xbe]);
+        command.size = Size::Byte;
+        command.value = 0x7f;
+        set(&mut bus, &command, &mut out).unwrap();
+        assert_eq!(bus.registers[0x20], 0x7f);
+        assert_eq!(out, "");
+        command.addr = 0x51;
+        set(&mut bus, &command, &mut out).unwrap();
+        assert_eq!(out, "Error: Write failed\n");
+    }
+
+    #[test]
+    fn dump_table() {
+        let mut bus = MockBus::new(0x50);
+        bus.unreadable = Some(0x42);
+        let mut out = String::new();
+        dump(&mut bus, 0x50, &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 17);
+        assert_eq!(
+            lines[0],
+            "     0  1  2  3  4  5  6  7  8  9  a  b  c  d  e  f    0123456789abcdef"
+        );
+        assert_eq!(
+            lines[1],
+            "00: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f    .???????????????"
+        );
+        assert_eq!(
+            lines[5],
+            "40: 40 41 XX 43 44 45 46 47 48 49 4a 4b 4c 4d 4e 4f    @AXCDEFGHIJKLMNO"
+        );
+        assert_eq!(
+            lines[16],
+            "f0: f0 f1 f2 f3 f4 f5 f6 f7 f8 f9 fa fb fc fd fe ff    ???????????????."
+        );
+    }
+}
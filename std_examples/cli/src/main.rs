@@ -9,28 +9,344 @@ For notifications on similar examples and more, subscribe to newsletter here:
 https://www.theembeddedrustacean.com/subscribe
 */
 
-use esp_idf_hal::delay::BLOCK;
+mod args;
+mod board;
+mod editor;
+mod i2c_tools;
+mod pins;
+
+use args::{Arg, Kind, Parsed};
+use board::{Board, PwmInfo};
+use editor::{Editor, Terminal};
+use esp_idf_hal::delay::{TickType, BLOCK};
 use esp_idf_hal::gpio;
+use esp_idf_hal::i2c::{I2cConfig, I2cDriver};
 use esp_idf_hal::peripherals::Peripherals;
 use esp_idf_hal::prelude::*;
 use esp_idf_hal::uart::*;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use i2c_tools::{Bus, BusError};
 use menu::*;
-use std::fmt::Write;
+use pins::PinRegistry;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+// Terminal on the other end of the UART, Wokwi needs line feed mode
+// (`\x1b[20h`) for the console output to be formatted correctly
+const TERMINAL: Terminal = Terminal {
+    init: Some("\x1b[20h"),
+    vt100: true,
+    prompt: "> ",
+};
+
+// Number of command lines kept in the history, which is stored in NVS
+const HISTORY_LEN: usize = 16;
+const HISTORY_NAMESPACE: &str = "cli";
+const HISTORY_KEY: &str = "history";
+// Idle time of the console after which a changed history is written out, so
+// flash is not written for every command
+const HISTORY_SAVE_DELAY_MS: u64 = 5000;
+
+// Pins the bring-up commands must leave alone
+const RESERVED_PINS: &[(u8, &str)] = &[
+    (2, "I2C SCL"),
+    (3, "I2C SDA"),
+    (12, "SPI flash"),
+    (13, "SPI flash"),
+    (14, "SPI flash"),
+    (15, "SPI flash"),
+    (16, "SPI flash"),
+    (17, "SPI flash"),
+    (18, "USB-Serial-JTAG D-"),
+    (19, "USB-Serial-JTAG D+"),
+    (20, "UART0 RX"),
+    (21, "UART0 TX"),
+];
+
+// Arguments of the commands, checked before their callbacks run
+const HW_ARGS: &[Arg] = &[Arg::new("name", Kind::Text)];
+const CHIP_ADDRESS: Kind = Kind::Int(
+    i2c_tools::FIRST_ADDRESS as i64,
+    i2c_tools::LAST_ADDRESS as i64,
+);
+const DATA_ADDRESS: Kind = Kind::Int(0, 0xff);
+const PIN: Kind = Kind::Int(0, pins::NUM_PINS as i64 - 1);
+const I2CGET_ARGS: &[Arg] = &[
+    Arg::new("chip-address", CHIP_ADDRESS),
+    Arg::new("data-address", DATA_ADDRESS),
+    Arg::new("mode", Kind::Choice(&["b", "w"])),
+    Arg::new("stop", Kind::Flag),
+];
+const I2CSET_ARGS: &[Arg] = &[
+    Arg::new("chip-address", CHIP_ADDRESS),
+    Arg::new("data-address", DATA_ADDRESS),
+    // Bytes are limited to 0xff once the mode is known
+    Arg::new("value", Kind::Int(0, 0xffff)),
+    Arg::new("mode", Kind::Choice(&["b", "w"])),
+];
+const I2CDUMP_ARGS: &[Arg] = &[Arg::new("chip-address", CHIP_ADDRESS)];
+const GPIO_ARGS: &[Arg] = &[
+    Arg::new(
+        "action",
+        Kind::Choice(&["mode", "read", "write", "toggle", "watch"]),
+    ),
+    Arg::new("pin", PIN),
+    // A mode or a level, depending on the action
+    Arg::new(
+        "value",
+        Kind::Choice(&[
+            "in",
+            "input",
+            "out",
+            "output",
+            "od",
+            "opendrain",
+            "1",
+            "high",
+            "on",
+            "0",
+            "low",
+            "off",
+        ]),
+    ),
+    Arg::new("pull", Kind::Choice(&["up", "down", "none", "floating"])).default("none"),
+    Arg::new(
+        "time",
+        Kind::Duration(Duration::from_millis(100), Duration::from_secs(3600)),
+    )
+    .default("10s"),
+];
+const PWM_ARGS: &[Arg] = &[
+    Arg::new("action", Kind::Choice(&["set"])),
+    Arg::new("pin", PIN),
+    Arg::new(
+        "freq",
+        Kind::Int(pins::MIN_FREQUENCY as i64, pins::MAX_FREQUENCY as i64),
+    ),
+    Arg::new("duty", Kind::Int(0, 100)),
+];
+const ADC_ARGS: &[Arg] = &[
+    Arg::new("action", Kind::Choice(&["read"])),
+    Arg::new(
+        "pin",
+        Kind::Int(*pins::ADC_PINS.start() as i64, *pins::ADC_PINS.end() as i64),
+    ),
+    Arg::new("atten", Kind::Choice(&["0", "2.5", "6", "11"])).default("11"),
+    Arg::new("samples", Kind::Int(1, pins::MAX_SAMPLES as i64)).default("1"),
+];
+
+// UART console whose output can be muted while the runner is fed a line the
+// editor already shows, together with the peripherals the commands use
+struct Console {
+    uart: UartDriver<'static>,
+    muted: bool,
+    i2c: I2cDriver<'static>,
+    board: Board,
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.muted {
+            return Ok(());
+        }
+        self.uart.write_str(s)
+    }
+}
+
+// I2C bus the i2c* commands run on
+impl Bus for I2cDriver<'static> {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), BusError> {
+        I2cDriver::write(self, addr, bytes, BLOCK).map_err(|_| BusError)
+    }
+
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<(), BusError> {
+        I2cDriver::read(self, addr, buf, BLOCK).map_err(|_| BusError)
+    }
+
+    fn write_read(&mut self, addr: u8, bytes: &[u8], buf: &mut [u8]) -> Result<(), BusError> {
+        I2cDriver::write_read(self, addr, bytes, buf, BLOCK).map_err(|_| BusError)
+    }
+}
 
 // CLI Root Menu Struct Initialization
-const ROOT_MENU: Menu<UartDriver> = Menu {
+const ROOT_MENU: Menu<Console> = Menu {
     label: "root",
-    items: &[&Item {
-        item_type: ItemType::Callback {
-            function: hello_name,
-            parameters: &[Parameter::Mandatory {
-                parameter_name: "name",
-                help: Some("Enter your name"),
-            }],
+    items: &[
+        &Item {
+            item_type: ItemType::Callback {
+                function: hello_name,
+                parameters: &[Parameter::Mandatory {
+                    parameter_name: "name",
+                    help: Some("Enter your name"),
+                }],
+            },
+            command: "hw",
+            help: Some("This is an embedded CLI terminal. Check the summary for the list of supported commands"),
         },
-        command: "hw",
-        help: Some("This is an embedded CLI terminal. Check the summary for the list of supported commands"),
-    }],
+        &Item {
+            item_type: ItemType::Callback {
+                function: i2cdetect,
+                parameters: &[],
+            },
+            command: "i2cdetect",
+            help: Some("Scan the I2C bus for devices"),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: i2cget,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "chip-address",
+                        help: Some("Device address (0x08-0x77)"),
+                    },
+                    Parameter::Optional {
+                        parameter_name: "data-address",
+                        help: Some("Register to read (0x00-0xff)"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "mode",
+                        argument_name: "b|w",
+                        help: Some("Read a byte (default) or a word"),
+                    },
+                    Parameter::Named {
+                        parameter_name: "stop",
+                        help: Some("Send a stop instead of a repeated start before reading"),
+                    },
+                ],
+            },
+            command: "i2cget",
+            help: Some("Read a register of an I2C device"),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: i2cset,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "chip-address",
+                        help: Some("Device address (0x08-0x77)"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "data-address",
+                        help: Some("Register to write (0x00-0xff)"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "value",
+                        help: Some("Value to write"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "mode",
+                        argument_name: "b|w",
+                        help: Some("Write a byte (default) or a word"),
+                    },
+                ],
+            },
+            command: "i2cset",
+            help: Some("Write a register of an I2C device"),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: i2cdump,
+                parameters: &[Parameter::Mandatory {
+                    parameter_name: "chip-address",
+                    help: Some("Device address (0x08-0x77)"),
+                }],
+            },
+            command: "i2cdump",
+            help: Some("Dump all 256 registers of an I2C device"),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: gpio_cmd,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "action",
+                        help: Some("mode, read, write, toggle or watch"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "pin",
+                        help: Some("GPIO number"),
+                    },
+                    Parameter::Optional {
+                        parameter_name: "value",
+                        help: Some("in/out/od for mode, 1/0 for write"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "pull",
+                        argument_name: "up|down|none",
+                        help: Some("Pull resistor set by mode"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "time",
+                        argument_name: "duration",
+                        help: Some("How long watch prints level changes (default 10s)"),
+                    },
+                ],
+            },
+            command: "gpio",
+            help: Some("
+            Configure, read and drive GPIO pins.
+
+            Usage:
+              gpio mode <pin> <in|out|od> [--pull=up|down|none]
+              gpio read <pin>
+              gpio write <pin> <1|0>
+              gpio toggle <pin>
+              gpio watch <pin> [--time=<duration>]
+            "),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: pwm_cmd,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "action",
+                        help: Some("set"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "pin",
+                        help: Some("GPIO number"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "freq",
+                        help: Some("Frequency in Hz (5-1000000)"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "duty",
+                        help: Some("Duty cycle in percent (0-100)"),
+                    },
+                ],
+            },
+            command: "pwm",
+            help: Some("Output PWM on a pin: pwm set <pin> <freq> <duty>"),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: adc_cmd,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "action",
+                        help: Some("read"),
+                    },
+                    Parameter::Mandatory {
+                        parameter_name: "pin",
+                        help: Some("GPIO number (0-4)"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "atten",
+                        argument_name: "dB",
+                        help: Some("Attenuation: 0, 2.5, 6 or 11 (default)"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "samples",
+                        argument_name: "n",
+                        help: Some("Number of samples to average (default 1)"),
+                    },
+                ],
+            },
+            command: "adc",
+            help: Some("Read an analog input: adc read <pin> [--atten=<dB>] [--samples=<n>]"),
+        },
+    ],
     entry: None,
     exit: None,
 };
@@ -54,36 +370,267 @@ fn main() {
     )
     .unwrap();
 
-    // This line is for Wokwi only so that the console output is formatted correctly
-    uart.write_str("\x1b[20h").unwrap();
+    // Configure I2C on the same pins as the i2c example
+    let config = I2cConfig::new().baudrate(100.kHz().into());
+    let i2c = I2cDriver::new(
+        peripherals.i2c0,
+        peripherals.pins.gpio3,
+        peripherals.pins.gpio2,
+        &config,
+    )
+    .unwrap();
+
+    // Bring-up commands may use any pin not taken by the drivers above
+    let mut registry = PinRegistry::new();
+    for (pin, what) in RESERVED_PINS {
+        registry.reserve(*pin, what);
+    }
+    let board = Board::new(registry, peripherals.adc1).unwrap();
+
+    // Restore the history saved by previous sessions
+    let mut nvs = EspNvs::new(
+        EspDefaultNvsPartition::take().unwrap(),
+        HISTORY_NAMESPACE,
+        true,
+    )
+    .unwrap();
 
     // Create a buffer to store CLI input
     let mut clibuf = [0u8; 64];
+    // Instantiate line editor able to fill the CLI buffer
+    let mut editor = Editor::new(TERMINAL, clibuf.len(), HISTORY_LEN);
+    load_history(&nvs, &mut editor);
+    editor.init(&mut uart);
+
     // Instantiate CLI runner with root menu, buffer, and uart
-    let mut r = Runner::new(ROOT_MENU, &mut clibuf, uart);
+    let console = Console {
+        uart,
+        muted: false,
+        i2c,
+        board,
+    };
+    let mut r = Runner::new(ROOT_MENU, &mut clibuf, console);
 
     loop {
         // Create single element buffer for UART characters
         let mut buf = [0_u8; 1];
         // Read single byte from UART
-        r.context.read(&mut buf, BLOCK).unwrap();
-        // Pass read byte to CLI runner for processing
-        r.input_byte(buf[0]);
+        let read = r
+            .context
+            .uart
+            .read(
+                &mut buf,
+                TickType::new_millis(HISTORY_SAVE_DELAY_MS).ticks(),
+            )
+            .unwrap();
+        if read == 0 {
+            if editor.history_changed() {
+                save_history(&mut nvs, &mut editor);
+            }
+            continue;
+        }
+        // Pass read byte to the line editor, which echoes it
+        if let Some(line) = editor.feed(buf[0], &ROOT_MENU, &mut r.context) {
+            // Pass the finished line to CLI runner for processing without
+            // echoing it a second time
+            r.context.muted = true;
+            for byte in line.bytes() {
+                r.input_byte(byte);
+            }
+            r.context.muted = false;
+            r.input_byte(b'\r');
+        }
+    }
+}
+
+fn load_history(nvs: &EspNvs<NvsDefault>, editor: &mut Editor) {
+    let mut buf = vec![0_u8; HISTORY_LEN * 65];
+    if let Ok(Some(history)) = nvs.get_str(HISTORY_KEY, &mut buf) {
+        for line in history.lines() {
+            editor.add_history(line);
+        }
+    }
+    editor.history_saved();
+}
+
+fn save_history(nvs: &mut EspNvs<NvsDefault>, editor: &mut Editor) {
+    let history = editor.history().collect::<Vec<_>>().join("\n");
+    // Losing the history is not worth interrupting the CLI for
+    match nvs.set_str(HISTORY_KEY, &history) {
+        Ok(()) => editor.history_saved(),
+        Err(e) => println!("Failed to save history: {}", e),
     }
 }
 
 // Callback function for hw commans
 fn hello_name<'a>(
-    _menu: &Menu<UartDriver>,
-    item: &Item<UartDriver>,
+    _menu: &Menu<Console>,
+    item: &Item<Console>,
     args: &[&str],
-    context: &mut UartDriver,
+    context: &mut Console,
 ) {
+    let args = match args::parse_or_report(item, args, HW_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
     // Print to console passed "name" argument
-    writeln!(
-        context,
-        "Hello, {}!",
-        argument_finder(item, args, "name").unwrap().unwrap()
-    )
-    .unwrap();
+    writeln!(context, "Hello, {}!", args.value::<&str>("name")).unwrap();
+}
+
+// Callback function for i2cdetect command
+fn i2cdetect<'a>(
+    _menu: &Menu<Console>,
+    _item: &Item<Console>,
+    _args: &[&str],
+    context: &mut Console,
+) {
+    let mut out = String::new();
+    i2c_tools::detect(&mut context.i2c, &mut out).unwrap();
+    context.write_str(&out).unwrap();
+}
+
+// Callback function for i2cget command
+fn i2cget<'a>(_menu: &Menu<Console>, item: &Item<Console>, args: &[&str], context: &mut Console) {
+    let args = match args::parse_or_report(item, args, I2CGET_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
+    match parse_get(&args) {
+        Ok(command) => {
+            let mut out = String::new();
+            i2c_tools::get(&mut context.i2c, &command, &mut out).unwrap();
+            context.write_str(&out).unwrap();
+        }
+        Err(e) => writeln!(context, "{}", e).unwrap(),
+    }
+}
+
+fn parse_get(args: &Parsed) -> Result<i2c_tools::Get, &'static str> {
+    Ok(i2c_tools::Get {
+        addr: args.value("chip-address"),
+        register: args.get("data-address"),
+        size: i2c_tools::parse_size(args.get("mode"))?,
+        repeated_start: !args.value::<bool>("stop"),
+    })
+}
+
+// Callback function for i2cset command
+fn i2cset<'a>(_menu: &Menu<Console>, item: &Item<Console>, args: &[&str], context: &mut Console) {
+    let args = match args::parse_or_report(item, args, I2CSET_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
+    match parse_set(&args) {
+        Ok(command) => {
+            let mut out = String::new();
+            i2c_tools::set(&mut context.i2c, &command, &mut out).unwrap();
+            context.write_str(&out).unwrap();
+        }
+        Err(e) => writeln!(context, "{}", e).unwrap(),
+    }
+}
+
+fn parse_set(args: &Parsed) -> Result<i2c_tools::Set, &'static str> {
+    let size = i2c_tools::parse_size(args.get("mode"))?;
+    Ok(i2c_tools::Set {
+        addr: args.value("chip-address"),
+        register: args.value("data-address"),
+        value: i2c_tools::check_value(args.value("value"), size)?,
+        size,
+    })
+}
+
+// Callback function for i2cdump command
+fn i2cdump<'a>(_menu: &Menu<Console>, item: &Item<Console>, args: &[&str], context: &mut Console) {
+    let args = match args::parse_or_report(item, args, I2CDUMP_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
+    let mut out = String::new();
+    i2c_tools::dump(&mut context.i2c, args.value("chip-address"), &mut out).unwrap();
+    context.write_str(&out).unwrap();
+}
+
+// Callback function for gpio command
+fn gpio_cmd<'a>(_menu: &Menu<Console>, item: &Item<Console>, args: &[&str], context: &mut Console) {
+    let args = match args::parse_or_report(item, args, GPIO_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
+    if let Err(e) = gpio(&args, context) {
+        writeln!(context, "{}", e).unwrap();
+    }
+}
+
+fn gpio(args: &Parsed, context: &mut Console) -> Result<(), String> {
+    let pin = args.value("pin");
+    match (args.value::<&str>("action"), args.get::<&str>("value")) {
+        ("mode", Some(mode)) => {
+            let mode = pins::parse_mode(mode)?;
+            let pull = pins::parse_pull(args.value("pull"))?;
+            context.board.gpio_mode(pin, mode, pull)?;
+            writeln!(context, "GPIO{} configured as {:?}", pin, mode).unwrap();
+            Ok(())
+        }
+        ("mode", None) => Err("Usage: gpio mode <pin> <in|out|od>".to_string()),
+        ("read", _) => {
+            let high = context.board.gpio_read(pin)?;
+            writeln!(context, "{}", high as u8).unwrap();
+            Ok(())
+        }
+        ("write", Some(level)) => context.board.gpio_write(pin, pins::parse_level(level)?),
+        ("write", None) => Err("Usage: gpio write <pin> <1|0>".to_string()),
+        ("toggle", _) => {
+            let high = context.board.gpio_toggle(pin)?;
+            writeln!(context, "{}", high as u8).unwrap();
+            Ok(())
+        }
+        // watch
+        _ => context
+            .board
+            .gpio_watch(pin, args.value("time"), &mut context.uart),
+    }
+}
+
+// Callback function for pwm command
+fn pwm_cmd<'a>(_menu: &Menu<Console>, item: &Item<Console>, args: &[&str], context: &mut Console) {
+    let args = match args::parse_or_report(item, args, PWM_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
+    match set_pwm(&args, &mut context.board) {
+        Ok(info) => writeln!(
+            context,
+            "{} Hz, {}-bit resolution, duty {}/{}",
+            info.frequency, info.resolution, info.duty, info.max_duty
+        )
+        .unwrap(),
+        Err(e) => writeln!(context, "{}", e).unwrap(),
+    }
+}
+
+fn set_pwm(args: &Parsed, board: &mut Board) -> Result<PwmInfo, String> {
+    let duty = args.value::<u8>("duty");
+    board.pwm_set(args.value("pin"), args.value("freq"), duty as f32)
+}
+
+// Callback function for adc command
+fn adc_cmd<'a>(_menu: &Menu<Console>, item: &Item<Console>, args: &[&str], context: &mut Console) {
+    let args = match args::parse_or_report(item, args, ADC_ARGS, context) {
+        Some(args) => args,
+        None => return,
+    };
+    match read_adc(&args, &mut context.board) {
+        Ok((pin, summary)) => writeln!(context, "GPIO{}: {}", pin, summary).unwrap(),
+        Err(e) => writeln!(context, "{}", e).unwrap(),
+    }
+}
+
+fn read_adc(args: &Parsed, board: &mut Board) -> Result<(u8, pins::Summary), String> {
+    let pin = args.value("pin");
+    let attenuation = pins::parse_attenuation(args.value("atten"))?;
+    Ok((
+        pin,
+        board.adc_read(pin, attenuation, args.value("samples"))?,
+    ))
 }
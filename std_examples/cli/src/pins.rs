@@ -0,0 +1,282 @@
+//! Pin ownership and argument validation for the `gpio`, `pwm` and `adc`
+//! commands. Nothing in here touches the hardware.
+
+use std::fmt;
+
+/// GPIO0 to GPIO21 on the ESP32-C3
+pub const NUM_PINS: u8 = 22;
+/// ADC1 channels are on GPIO0 to GPIO4, ADC2 cannot be used with Wi-Fi
+pub const ADC_PINS: core::ops::RangeInclusive<u8> = 0..=4;
+/// The LEDC clock divider runs out below 5 Hz at the widest resolution
+pub const MIN_FREQUENCY: u32 = 5;
+pub const MAX_FREQUENCY: u32 = 1_000_000;
+pub const MAX_SAMPLES: u32 = 1000;
+/// Widest duty resolution of the LEDC timers
+const MAX_RESOLUTION: u32 = 14;
+/// LEDC source clock (APB)
+const LEDC_CLOCK: u32 = 80_000_000;
+
+/// Function a pin is currently claimed for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner {
+    /// Taken at startup by a driver the commands may not touch
+    System(&'static str),
+    Gpio,
+    Pwm,
+    Adc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PinError {
+    Invalid(u8),
+    Reserved(u8, &'static str),
+}
+
+impl fmt::Display for PinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PinError::Invalid(pin) => write!(f, "GPIO{} does not exist", pin),
+            PinError::Reserved(pin, what) => write!(f, "GPIO{} is used by {}", pin, what),
+        }
+    }
+}
+
+/// Tracks which function owns each pin. Pins reserved for the system are
+/// refused, the command functions may take pins over from each other.
+pub struct PinRegistry {
+    owners: [Option<Owner>; NUM_PINS as usize],
+}
+
+impl PinRegistry {
+    pub const fn new() -> Self {
+        PinRegistry {
+            owners: [None; NUM_PINS as usize],
+        }
+    }
+
+    /// Mark `pin` as used by a driver created outside the commands
+    pub fn reserve(&mut self, pin: u8, what: &'static str) {
+        if let Some(owner) = self.owners.get_mut(pin as usize) {
+            *owner = Some(Owner::System(what));
+        }
+    }
+
+    /// Claim `pin` for `owner`, returning the function that has to let go of it
+    pub fn claim(&mut self, pin: u8, owner: Owner) -> Result<Option<Owner>, PinError> {
+        let slot = self
+            .owners
+            .get_mut(pin as usize)
+            .ok_or(PinError::Invalid(pin))?;
+        match *slot {
+            Some(Owner::System(what)) => Err(PinError::Reserved(pin, what)),
+            Some(previous) if previous == owner => Ok(None),
+            previous => {
+                *slot = Some(owner);
+                Ok(previous)
+            }
+        }
+    }
+}
+
+impl Default for PinRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Input,
+    /// Push-pull output that can also be read back
+    Output,
+    OpenDrain,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pull {
+    None,
+    Up,
+    Down,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attenuation {
+    Db0,
+    Db2_5,
+    Db6,
+    Db11,
+}
+
+pub fn parse_mode(text: &str) -> Result<Mode, String> {
+    match text {
+        "in" | "input" => Ok(Mode::Input),
+        "out" | "output" => Ok(Mode::Output),
+        "od" | "opendrain" => Ok(Mode::OpenDrain),
+        _ => Err(format!("Invalid mode: {} (expected in, out or od)", text)),
+    }
+}
+
+pub fn parse_pull(text: &str) -> Result<Pull, String> {
+    match text {
+        "none" | "floating" => Ok(Pull::None),
+        "up" => Ok(Pull::Up),
+        "down" => Ok(Pull::Down),
+        _ => Err(format!(
+            "Invalid pull: {} (expected up, down or none)",
+            text
+        )),
+    }
+}
+
+pub fn parse_level(text: &str) -> Result<bool, String> {
+    match text {
+        "1" | "high" | "on" => Ok(true),
+        "0" | "low" | "off" => Ok(false),
+        _ => Err(format!("Invalid level: {} (expected 1 or 0)", text)),
+    }
+}
+
+/// Parse an attenuation in dB
+pub fn parse_attenuation(text: &str) -> Result<Attenuation, String> {
+    match text.strip_suffix("dB").unwrap_or(text) {
+        "0" => Ok(Attenuation::Db0),
+        "2.5" => Ok(Attenuation::Db2_5),
+        "6" => Ok(Attenuation::Db6),
+        "11" => Ok(Attenuation::Db11),
+        _ => Err(format!(
+            "Invalid attenuation: {} (expected 0, 2.5, 6 or 11)",
+            text
+        )),
+    }
+}
+
+pub fn check_adc_pin(pin: u8) -> Result<(), String> {
+    if ADC_PINS.contains(&pin) {
+        Ok(())
+    } else {
+        Err(format!("GPIO{} has no ADC1 channel (GPIO0-GPIO4)", pin))
+    }
+}
+
+/// Widest duty resolution in bits the LEDC can run at `frequency`
+pub fn resolution_bits(frequency: u32) -> u32 {
+    let ticks = LEDC_CLOCK / frequency.max(1);
+    (31 - ticks.max(2).leading_zeros()).min(MAX_RESOLUTION)
+}
+
+/// Duty register value for `percent` of `max_duty`
+pub fn duty_value(max_duty: u32, percent: f32) -> u32 {
+    (max_duty as f32 * percent / 100.0).round() as u32
+}
+
+/// Minimum, maximum and mean of ADC samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub min: u16,
+    pub max: u16,
+    pub mean: f32,
+    pub count: usize,
+}
+
+impl Summary {
+    pub fn new(samples: &[u16]) -> Option<Self> {
+        Some(Summary {
+            min: *samples.iter().min()?,
+            max: *samples.iter().max()?,
+            mean: samples.iter().map(|s| *s as f32).sum::<f32>() / samples.len() as f32,
+            count: samples.len(),
+        })
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count == 1 {
+            write!(f, "{} mV", self.min)
+        } else {
+            write!(
+                f,
+                "{:.0} mV (min {} mV, max {} mV, {} samples)",
+                self.mean, self.min, self.max, self.count
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_pins_are_refused() {
+        let mut registry = PinRegistry::new();
+        registry.reserve(18, "USB-Serial-JTAG D-");
+        assert_eq!(
+            registry.claim(18, Owner::Gpio),
+            Err(PinError::Reserved(18, "USB-Serial-JTAG D-"))
+        );
+        assert_eq!(
+            registry.claim(18, Owner::Gpio).unwrap_err().to_string(),
+            "GPIO18 is used by USB-Serial-JTAG D-"
+        );
+        // Reserving a pin that does not exist is ignored
+        registry.reserve(NUM_PINS, "nothing");
+        assert_eq!(
+            registry.claim(NUM_PINS, Owner::Gpio),
+            Err(PinError::Invalid(NUM_PINS))
+        );
+    }
+
+    #[test]
+    fn pins_pass_between_command_functions() {
+        let mut registry = PinRegistry::new();
+        assert_eq!(registry.claim(5, Owner::Gpio), Ok(None));
+        assert_eq!(registry.claim(5, Owner::Gpio), Ok(None));
+        assert_eq!(registry.claim(5, Owner::Pwm), Ok(Some(Owner::Gpio)));
+        assert_eq!(registry.claim(5, Owner::Adc), Ok(Some(Owner::Pwm)));
+        // Other pins are not affected
+        assert_eq!(registry.claim(6, Owner::Pwm), Ok(None));
+    }
+
+    #[test]
+    fn words_of_the_commands() {
+        assert_eq!(parse_mode("od"), Ok(Mode::OpenDrain));
+        assert_eq!(
+            parse_mode("1"),
+            Err("Invalid mode: 1 (expected in, out or od)".to_string())
+        );
+        assert_eq!(parse_pull("floating"), Ok(Pull::None));
+        assert_eq!(parse_level("high"), Ok(true));
+        assert_eq!(
+            parse_level("out"),
+            Err("Invalid level: out (expected 1 or 0)".to_string())
+        );
+        assert_eq!(parse_attenuation("2.5"), Ok(Attenuation::Db2_5));
+        assert_eq!(parse_attenuation("11dB"), Ok(Attenuation::Db11));
+        assert_eq!(check_adc_pin(4), Ok(()));
+        assert_eq!(
+            check_adc_pin(5),
+            Err("GPIO5 has no ADC1 channel (GPIO0-GPIO4)".to_string())
+        );
+    }
+
+    #[test]
+    fn ledc_resolution_shrinks_with_frequency() {
+        assert_eq!(resolution_bits(MIN_FREQUENCY), 14);
+        assert_eq!(resolution_bits(5_000), 13);
+        assert_eq!(resolution_bits(1_000_000), 6);
+        assert_eq!(resolution_bits(40_000_000), 1);
+        assert_eq!(duty_value(8191, 50.0), 4096);
+        assert_eq!(duty_value(8191, 100.0), 8191);
+    }
+
+    #[test]
+    fn sample_summaries() {
+        assert_eq!(Summary::new(&[]), None);
+        assert_eq!(Summary::new(&[1200]).unwrap().to_string(), "1200 mV");
+        assert_eq!(
+            Summary::new(&[1000, 1100, 1300]).unwrap().to_string(),
+            "1133 mV (min 1000 mV, max 1300 mV, 3 samples)"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! `log` backend writing every record to the console and, once attached, to
+//! an RFC 5424 syslog collector over UDP.
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fmt::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Unix time of 2020-01-01, earlier clocks are considered unset (no SNTP yet)
+const MIN_VALID_TIME: u64 = 1_577_836_800;
+
+/// Syslog facility `user`
+pub const FACILITY_USER: u8 = 1;
+
+/// Destination and header fields of forwarded syslog messages
+pub struct SyslogConfig {
+    pub collector: SocketAddr,
+    pub hostname: String,
+    pub app_name: &'static str,
+    pub facility: u8,
+}
+
+struct Syslog {
+    socket: UdpSocket,
+    config: SyslogConfig,
+}
+
+// Syslog severity of a `log` level
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Format an RFC 5424 message, `timestamp` is `(unix seconds, milliseconds)`
+fn format_syslog(
+    config: &SyslogConfig,
+    level: Level,
+    target: &str,
+    message: &str,
+    timestamp: Option<(u64, u32)>,
+) -> String {
+    let mut out = String::new();
+    let pri = config.facility as u32 * 8 + severity(level) as u32;
+    write!(out, "<{}>1 ", pri).unwrap();
+    match timestamp {
+        Some((secs, millis)) => {
+            let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+            let time = secs % 86_400;
+            write!(
+                out,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z ",
+                year,
+                month,
+                day,
+                time / 3600,
+                time / 60 % 60,
+                time % 60,
+                millis
+            )
+            .unwrap();
+        }
+        None => out.push_str("- "),
+    }
+    write!(
+        out,
+        "{} {} - - - {}: {}",
+        header_field(&config.hostname, 255),
+        header_field(config.app_name, 48),
+        target,
+        message
+    )
+    .unwrap();
+    out
+}
+
+// Header fields are printable US-ASCII without spaces, "-" when empty
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if field.is_empty() {
+        "-".into()
+    } else {
+        field
+    }
+}
+
+// Convert days since 1970-01-01 to a (year, month, day) date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub struct Logger {
+    level: LevelFilter,
+    syslog: Mutex<Option<Syslog>>,
+}
+
+impl Logger {
+    pub fn new(level: LevelFilter) -> Self {
+        Logger {
+            level,
+            syslog: Mutex::new(None),
+        }
+    }
+
+    /// Start forwarding records to a syslog collector, needs a running network stack
+    pub fn attach_syslog(&self, config: SyslogConfig) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        *self.syslog.lock().unwrap() = Some(Syslog { socket, config });
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = record.args().to_string();
+        println!("{:<5} [{}] {}", record.level(), record.target(), message);
+
+        if let Some(syslog) = self.syslog.lock().unwrap().as_ref() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .filter(|t| t.as_secs() >= MIN_VALID_TIME)
+                .map(|t| (t.as_secs(), t.subsec_millis()));
+            let datagram = format_syslog(
+                &syslog.config,
+                record.level(),
+                record.target(),
+                &message,
+                timestamp,
+            );
+            // Logging must never fail the caller, lost datagrams are acceptable
+            let _ = syslog
+                .socket
+                .send_to(datagram.as_bytes(), syslog.config.collector);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install `logger` as the global `log` backend
+pub fn init(logger: Logger) -> Result<&'static Logger, SetLoggerError> {
+    let logger: &'static Logger = Box::leak(Box::new(logger));
+    log::set_logger(logger)?;
+    log::set_max_level(logger.level);
+    Ok(logger)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(collector: SocketAddr) -> SyslogConfig {
+        SyslogConfig {
+            collector,
+            hostname: "esp32c3".into(),
+            app_name: "http",
+            facility: FACILITY_USER,
+        }
+    }
+
+    #[test]
+    fn syslog_messages_follow_rfc_5424() {
+        // 2024-02-29T13:05:09.042Z
+        let message = format_syslog(
+            &config("127.0.0.1:514".parse().unwrap()),
+            Level::Warn,
+            "http",
+            "Sending failed",
+            Some((1_709_211_909, 42)),
+        );
+        assert_eq!(
+            message,
+            "<12>1 2024-02-29T13:05:09.042Z esp32c3 http - - - http: Sending failed"
+        );
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn records_reach_the_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let logger = Logger::new(LevelFilter::Info);
+        logger
+            .attach_syslog(config(collector.local_addr().unwrap()))
+            .unwrap();
+
+        let record = |level, args| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target("http")
+                    .args(args)
+                    .build(),
+            )
+        };
+        record(Level::Debug, format_args!("filtered out"));
+        record(Level::Info, format_args!("time set"));
+
+        let mut buf = [0_u8; 512];
+        let (len, _) = collector.recv_from(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(datagram.starts_with("<14>1 "), "{}", datagram);
+        assert!(datagram.ends_with(" http: time set"), "{}", datagram);
+        collector.set_nonblocking(true).unwrap();
+        assert!(collector.recv_from(&mut buf).is_err());
+    }
+}
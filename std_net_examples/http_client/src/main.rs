@@ -9,6 +9,8 @@ For notifications on similar examples and more, subscribe to newsletter here:
 https://www.theembeddedrustacean.com/subscribe
 */
 
+mod logger;
+
 use anyhow;
 use embedded_svc::http::client::Client;
 use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
@@ -17,10 +19,19 @@ use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::http::client::{Configuration as HttpConfig, EspHttpConnection};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, LevelFilter};
+use logger::{Logger, SyslogConfig};
+
+// Collector receiving RFC 5424 syslog messages over UDP
+const SYSLOG_COLLECTOR: &str = "192.168.1.100:514";
+const HOSTNAME: &str = "esp32c3";
 
 fn main() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
 
+    // Install Logger
+    let logger = logger::init(Logger::new(LevelFilter::Info))?;
+
     // Configure Wifi
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
@@ -48,14 +59,22 @@ fn main() -> anyhow::Result<()> {
     // Wait until the network interface is up
     wifi.wait_netif_up()?;
 
+    // Forward logs to the syslog collector now that the network is up
+    logger.attach_syslog(SyslogConfig {
+        collector: SYSLOG_COLLECTOR.parse()?,
+        hostname: HOSTNAME.into(),
+        app_name: env!("CARGO_PKG_NAME"),
+        facility: logger::FACILITY_USER,
+    })?;
+
     // Print Out Wifi Connection Configuration
     while !wifi.is_connected().unwrap() {
         // Get and print connection configuration
         let config = wifi.get_configuration().unwrap();
-        println!("Waiting for station {:?}", config);
+        info!("Waiting for station {:?}", config);
     }
 
-    println!("Wifi Connected, Intiatlizing HTTP");
+    info!("Wifi Connected, Intiatlizing HTTP");
 
     // HTTP Configuration
     // Create HTTPS Connection Handle
@@ -76,29 +95,29 @@ fn main() -> anyhow::Result<()> {
     let request = httpclient.get(url)?;
 
     // Log URL and type of request
-    println!("-> GET {}", url);
+    info!("-> GET {}", url);
 
     // Submit Request and Store Response
     let response = request.submit()?;
 
     // HTTP Response Processing
     let status = response.status();
-    println!("<- {}", status);
+    info!("<- {}", status);
 
     match response.header("Content-Length") {
         Some(data) => {
-            println!("Content-Length: {}", data);
+            info!("Content-Length: {}", data);
         }
         None => {
-            println!("No Content-Length Header");
+            info!("No Content-Length Header");
         }
     }
     match response.header("Date") {
         Some(data) => {
-            println!("Date: {}", data);
+            info!("Date: {}", data);
         }
         None => {
-            println!("No Date Header");
+            info!("No Date Header");
         }
     }
 
@@ -0,0 +1,461 @@
+//! CoAP (RFC 7252) message codec together with the Observe (RFC 7641) and
+//! block-wise transfer (RFC 7959) options.
+//!
+//! Nothing in here touches the network, so messages can be built and parsed
+//! on any host.
+
+/// CoAP protocol version carried in every header
+pub const VERSION: u8 = 1;
+/// Marker separating options from the payload
+const PAYLOAD_MARKER: u8 = 0xff;
+
+/// Option numbers used by the server
+pub mod option {
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const URI_QUERY: u16 = 15;
+    pub const BLOCK2: u16 = 23;
+    pub const BLOCK1: u16 = 27;
+    pub const SIZE2: u16 = 28;
+    pub const SIZE1: u16 = 60;
+}
+
+/// Content-Format identifiers
+pub mod format {
+    pub const JSON: u16 = 50;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Confirmable,
+    NonConfirmable,
+    Acknowledgement,
+    Reset,
+}
+
+impl MessageType {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            MessageType::Confirmable => 0,
+            MessageType::NonConfirmable => 1,
+            MessageType::Acknowledgement => 2,
+            MessageType::Reset => 3,
+        }
+    }
+}
+
+/// Request method or response code in `class.detail` form
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Code(pub u8);
+
+impl Code {
+    pub const EMPTY: Code = Code::new(0, 0);
+    pub const GET: Code = Code::new(0, 1);
+    pub const PUT: Code = Code::new(0, 3);
+    pub const CHANGED: Code = Code::new(2, 4);
+    pub const CONTENT: Code = Code::new(2, 5);
+    pub const CONTINUE: Code = Code::new(2, 31);
+    pub const BAD_REQUEST: Code = Code::new(4, 0);
+    pub const BAD_OPTION: Code = Code::new(4, 2);
+    pub const NOT_FOUND: Code = Code::new(4, 4);
+    pub const METHOD_NOT_ALLOWED: Code = Code::new(4, 5);
+    pub const REQUEST_ENTITY_INCOMPLETE: Code = Code::new(4, 8);
+    pub const REQUEST_ENTITY_TOO_LARGE: Code = Code::new(4, 13);
+    pub const INTERNAL_SERVER_ERROR: Code = Code::new(5, 0);
+
+    pub const fn new(class: u8, detail: u8) -> Self {
+        Code((class << 5) | (detail & 0x1f))
+    }
+
+    pub fn class(self) -> u8 {
+        self.0 >> 5
+    }
+
+    pub fn detail(self) -> u8 {
+        self.0 & 0x1f
+    }
+
+    pub fn is_request(self) -> bool {
+        self.class() == 0 && self != Code::EMPTY
+    }
+}
+
+impl core::fmt::Display for Code {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{}.{:02}", self.class(), self.detail())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Datagram shorter than the header or the announced token/options
+    Truncated,
+    /// Version other than 1
+    Version,
+    /// Token length above 8
+    TokenLength,
+    /// Reserved option delta/length nibble (15) outside the payload marker
+    InvalidOption,
+    /// Payload marker followed by an empty payload
+    EmptyPayload,
+}
+
+/// Block1/Block2 option value
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub num: u32,
+    pub more: bool,
+    /// Size exponent, the block size is `2^(szx + 4)` bytes
+    pub szx: u8,
+}
+
+impl Block {
+    pub fn size(&self) -> usize {
+        1 << (self.szx as usize + 4)
+    }
+
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        let raw = decode_uint(value)?;
+        let szx = (raw & 0x07) as u8;
+        if szx == 7 {
+            return None;
+        }
+        Some(Block {
+            num: raw >> 4,
+            more: raw & 0x08 != 0,
+            szx,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        encode_uint((self.num << 4) | ((self.more as u32) << 3) | self.szx as u32)
+    }
+}
+
+/// Decode an unsigned integer option value (big endian, at most 4 bytes)
+pub fn decode_uint(value: &[u8]) -> Option<u32> {
+    if value.len() > 4 {
+        return None;
+    }
+    Some(value.iter().fold(0, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// Encode an unsigned integer option value with leading zero bytes removed
+pub fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub mtype: MessageType,
+    pub code: Code,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    /// Options as `(number, value)`, kept sorted by number
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(mtype: MessageType, code: Code, message_id: u16) -> Self {
+        Message {
+            mtype,
+            code,
+            message_id,
+            token: Vec::new(),
+            options: Vec::new(),
+            payload: Vec::new(),
+        }
+    }
+
+    /// Reset message rejecting `message_id`
+    pub fn reset(message_id: u16) -> Self {
+        Message::new(MessageType::Reset, Code::EMPTY, message_id)
+    }
+
+    /// Piggybacked response to `request`: an ACK for confirmable requests,
+    /// a non-confirmable message with `message_id` otherwise
+    pub fn response(request: &Message, code: Code, message_id: u16) -> Self {
+        let (mtype, message_id) = match request.mtype {
+            MessageType::Confirmable => (MessageType::Acknowledgement, request.message_id),
+            _ => (MessageType::NonConfirmable, message_id),
+        };
+        let mut response = Message::new(mtype, code, message_id);
+        response.token = request.token.clone();
+        response
+    }
+
+    /// First value of option `number`
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// All values of a repeatable option
+    pub fn options(&self, number: u16) -> impl Iterator<Item = &[u8]> {
+        self.options
+            .iter()
+            .filter(move |(n, _)| *n == number)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn uint_option(&self, number: u16) -> Option<u32> {
+        self.option(number).and_then(decode_uint)
+    }
+
+    /// Append an option, keeping options ordered by number
+    pub fn add_option(&mut self, number: u16, value: Vec<u8>) {
+        let index = self
+            .options
+            .iter()
+            .position(|(n, _)| *n > number)
+            .unwrap_or(self.options.len());
+        self.options.insert(index, (number, value));
+    }
+
+    /// Uri-Path segments joined with `/`, without a leading slash
+    pub fn uri_path(&self) -> String {
+        self.options(option::URI_PATH)
+            .map(|segment| String::from_utf8_lossy(segment))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    pub fn parse(datagram: &[u8]) -> Result<Self, Error> {
+        if datagram.len() < 4 {
+            return Err(Error::Truncated);
+        }
+        if datagram[0] >> 6 != VERSION {
+            return Err(Error::Version);
+        }
+        let token_length = (datagram[0] & 0x0f) as usize;
+        if token_length > 8 {
+            return Err(Error::TokenLength);
+        }
+        let mut message = Message::new(
+            MessageType::from_bits(datagram[0] >> 4),
+            Code(datagram[1]),
+            u16::from_be_bytes([datagram[2], datagram[3]]),
+        );
+        let mut rest = &datagram[4..];
+        if rest.len() < token_length {
+            return Err(Error::Truncated);
+        }
+        message.token = rest[..token_length].to_vec();
+        rest = &rest[token_length..];
+
+        let mut number = 0_u16;
+        while let Some((&first, tail)) = rest.split_first() {
+            if first == PAYLOAD_MARKER {
+                if tail.is_empty() {
+                    return Err(Error::EmptyPayload);
+                }
+                message.payload = tail.to_vec();
+                break;
+            }
+            rest = tail;
+            let delta = read_extended(first >> 4, &mut rest)?;
+            let length = read_extended(first & 0x0f, &mut rest)? as usize;
+            if rest.len() < length {
+                return Err(Error::Truncated);
+            }
+            number = number.checked_add(delta).ok_or(Error::InvalidOption)?;
+            message.options.push((number, rest[..length].to_vec()));
+            rest = &rest[length..];
+        }
+
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.payload.len());
+        out.push((VERSION << 6) | (self.mtype.bits() << 4) | self.token.len() as u8);
+        out.push(self.code.0);
+        out.extend_from_slice(&self.message_id.to_be_bytes());
+        out.extend_from_slice(&self.token);
+
+        let mut previous = 0;
+        for (number, value) in &self.options {
+            let (delta, delta_ext) = split_extended(number - previous);
+            let (length, length_ext) = split_extended(value.len() as u16);
+            out.push((delta << 4) | length);
+            out.extend_from_slice(&delta_ext);
+            out.extend_from_slice(&length_ext);
+            out.extend_from_slice(value);
+            previous = *number;
+        }
+
+        if !self.payload.is_empty() {
+            out.push(PAYLOAD_MARKER);
+            out.extend_from_slice(&self.payload);
+        }
+        out
+    }
+}
+
+// Resolve a delta/length nibble, consuming its extended bytes from `rest`
+fn read_extended(nibble: u8, rest: &mut &[u8]) -> Result<u16, Error> {
+    match nibble {
+        13 => {
+            let (&b, tail) = rest.split_first().ok_or(Error::Truncated)?;
+            *rest = tail;
+            Ok(b as u16 + 13)
+        }
+        14 => {
+            if rest.len() < 2 {
+                return Err(Error::Truncated);
+            }
+            let value = u16::from_be_bytes([rest[0], rest[1]]);
+            *rest = &rest[2..];
+            value.checked_add(269).ok_or(Error::InvalidOption)
+        }
+        15 => Err(Error::InvalidOption),
+        n => Ok(n as u16),
+    }
+}
+
+// Split a delta/length into its nibble and extended bytes
+fn split_extended(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_get_request() {
+        // CON GET, message ID 0x7d34, token 0x42, Uri-Path "temperature"
+        let datagram = b"\x41\x01\x7d\x34\x42\xbbtemperature";
+        let message = Message::parse(datagram).unwrap();
+        assert_eq!(message.mtype, MessageType::Confirmable);
+        assert_eq!(message.code, Code::GET);
+        assert_eq!(message.message_id, 0x7d34);
+        assert_eq!(message.token, [0x42]);
+        assert_eq!(message.uri_path(), "temperature");
+        assert!(message.payload.is_empty());
+        assert_eq!(message.encode(), datagram);
+    }
+
+    #[test]
+    fn round_trips_extended_options_and_payload() {
+        let mut message = Message::new(MessageType::NonConfirmable, Code::CONTENT, 7);
+        message.token = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        message.add_option(option::SIZE1, encode_uint(1024));
+        message.add_option(option::URI_PATH, b"a".to_vec());
+        message.add_option(option::URI_PATH, b"b".to_vec());
+        message.add_option(option::OBSERVE, Vec::new());
+        // Delta above 268 and a length above 12 both need extended bytes
+        message.add_option(2000, vec![0xAA; 300]);
+        message.payload = b"{}".to_vec();
+
+        let datagram = message.encode();
+        let parsed = Message::parse(&datagram).unwrap();
+        assert_eq!(parsed, message);
+        let numbers: Vec<u16> = parsed.options.iter().map(|(n, _)| *n).collect();
+        assert_eq!(numbers, [6, 11, 11, 60, 2000]);
+        assert_eq!(parsed.uri_path(), "a/b");
+        assert_eq!(parsed.uint_option(option::SIZE1), Some(1024));
+    }
+
+    #[test]
+    fn rejects_malformed_datagrams() {
+        assert_eq!(Message::parse(b"\x40\x01\x00"), Err(Error::Truncated));
+        assert_eq!(Message::parse(b"\x80\x01\x00\x01"), Err(Error::Version));
+        assert_eq!(Message::parse(b"\x49\x01\x00\x01"), Err(Error::TokenLength));
+        // Token announced but missing
+        assert_eq!(
+            Message::parse(b"\x42\x01\x00\x01\x01"),
+            Err(Error::Truncated)
+        );
+        // Option longer than what is left
+        assert_eq!(
+            Message::parse(b"\x40\x01\x00\x01\xb3ab"),
+            Err(Error::Truncated)
+        );
+        // Reserved nibble 15 as a delta
+        assert_eq!(
+            Message::parse(b"\x40\x01\x00\x01\xf1a"),
+            Err(Error::InvalidOption)
+        );
+        assert_eq!(
+            Message::parse(b"\x40\x01\x00\x01\xff"),
+            Err(Error::EmptyPayload)
+        );
+    }
+
+    #[test]
+    fn response_matches_the_request_type() {
+        let mut request = Message::new(MessageType::Confirmable, Code::GET, 10);
+        request.token = vec![9];
+        let ack = Message::response(&request, Code::CONTENT, 99);
+        assert_eq!(
+            (ack.mtype, ack.message_id, ack.token.as_slice()),
+            (MessageType::Acknowledgement, 10, &[9][..])
+        );
+        request.mtype = MessageType::NonConfirmable;
+        let non = Message::response(&request, Code::CONTENT, 99);
+        assert_eq!(
+            (non.mtype, non.message_id),
+            (MessageType::NonConfirmable, 99)
+        );
+    }
+
+    #[test]
+    fn codes_print_as_class_and_detail() {
+        assert_eq!(Code::CONTENT.to_string(), "2.05");
+        assert_eq!(Code::REQUEST_ENTITY_TOO_LARGE.to_string(), "4.13");
+        assert!(Code::GET.is_request());
+        assert!(!Code::EMPTY.is_request());
+        assert!(!Code::CONTENT.is_request());
+    }
+
+    #[test]
+    fn block_options() {
+        // Block 2 of 64 bytes with more to come
+        let block = Block::decode(&[0x2A]).unwrap();
+        assert_eq!(
+            block,
+            Block {
+                num: 2,
+                more: true,
+                szx: 2
+            }
+        );
+        assert_eq!((block.size(), block.offset()), (64, 128));
+        assert_eq!(block.encode(), [0x2A]);
+        let large = Block {
+            num: 4096,
+            more: false,
+            szx: 6,
+        };
+        assert_eq!(Block::decode(&large.encode()), Some(large));
+        // Size exponent 7 is reserved
+        assert_eq!(Block::decode(&[0x07]), None);
+        assert_eq!(encode_uint(0), Vec::<u8>::new());
+        assert_eq!(decode_uint(&[]), Some(0));
+        assert_eq!(decode_uint(&[1, 2, 3, 4, 5]), None);
+    }
+}
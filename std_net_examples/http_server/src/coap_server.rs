@@ -0,0 +1,868 @@
+//! CoAP server with a resource table, Observe registrations, block-wise
+//! transfers and retransmission of confirmable notifications.
+//!
+//! [`Server`] is a state machine fed with datagrams and a millisecond clock
+//! and returns the datagrams to send, so it runs unchanged on a host.
+//! [`serve`] drives it from a `UdpSocket`.
+
+use crate::coap::{encode_uint, option, Block, Code, Message, MessageType};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// Default CoAP port
+pub const PORT: u16 = 5683;
+
+/// Initial retransmission timeout of confirmable messages
+const ACK_TIMEOUT_MS: u64 = 2000;
+/// Number of retransmissions before a confirmable message is given up
+const MAX_RETRANSMIT: u8 = 4;
+/// Maximum number of simultaneous Observe registrations
+const MAX_OBSERVERS: usize = 8;
+/// Maximum size of a request body reassembled from Block1 transfers
+const MAX_BODY: usize = 1024;
+/// Preferred block size exponent for responses (512 bytes)
+const DEFAULT_SZX: u8 = 5;
+/// Number of answered confirmable requests remembered for deduplication
+const RECENT_EXCHANGES: usize = 16;
+/// Period at which observed resources are checked for changes
+const NOTIFY_INTERVAL_MS: u64 = 1000;
+/// Every n-th notification is sent confirmable to check the observer is alive
+const CONFIRMABLE_EVERY: u32 = 5;
+
+// Options understood by the server, all other critical options are rejected
+const KNOWN_OPTIONS: &[u16] = &[
+    3, // Uri-Host
+    option::OBSERVE,
+    7, // Uri-Port
+    option::URI_PATH,
+    option::CONTENT_FORMAT,
+    option::URI_QUERY,
+    17, // Accept
+    option::BLOCK2,
+    option::BLOCK1,
+    option::SIZE2,
+    option::SIZE1,
+];
+
+/// Datagrams to send, paired with their destination
+pub type Outgoing = Vec<(SocketAddr, Vec<u8>)>;
+
+/// Result of a resource handler
+pub struct Response {
+    pub code: Code,
+    pub format: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+impl Response {
+    pub fn content(format: u16, payload: impl Into<Vec<u8>>) -> Self {
+        Response {
+            code: Code::CONTENT,
+            format: Some(format),
+            payload: payload.into(),
+        }
+    }
+
+    pub fn changed() -> Self {
+        Response::error(Code::CHANGED)
+    }
+
+    pub fn error(code: Code) -> Self {
+        Response {
+            code,
+            format: None,
+            payload: Vec::new(),
+        }
+    }
+}
+
+/// Resource handler called with the request method and (reassembled) payload
+pub type Handler = Box<dyn FnMut(Code, &[u8]) -> Response + Send>;
+
+struct Resource {
+    path: &'static str,
+    observable: bool,
+    handler: Handler,
+    // Last representation sent to observers
+    last: Option<Vec<u8>>,
+}
+
+struct Observer {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    path: &'static str,
+    notifications: u32,
+}
+
+struct Pending {
+    addr: SocketAddr,
+    message_id: u16,
+    token: Vec<u8>,
+    datagram: Vec<u8>,
+    retransmissions: u8,
+    timeout: u64,
+    deadline: u64,
+}
+
+struct Exchange {
+    addr: SocketAddr,
+    message_id: u16,
+    response: Vec<u8>,
+}
+
+struct Upload {
+    addr: SocketAddr,
+    path: &'static str,
+    body: Vec<u8>,
+    next_num: u32,
+}
+
+pub struct Server {
+    resources: Vec<Resource>,
+    observers: Vec<Observer>,
+    pending: Vec<Pending>,
+    recent: VecDeque<Exchange>,
+    uploads: Vec<Upload>,
+    next_message_id: u16,
+    observe_seq: u32,
+    next_scan: u64,
+}
+
+impl Server {
+    /// Create a server, `message_id_seed` should be random to avoid clashes across reboots
+    pub fn new(message_id_seed: u16) -> Self {
+        Server {
+            resources: Vec::new(),
+            observers: Vec::new(),
+            pending: Vec::new(),
+            recent: VecDeque::new(),
+            uploads: Vec::new(),
+            next_message_id: message_id_seed,
+            observe_seq: 0,
+            next_scan: 0,
+        }
+    }
+
+    /// Register a resource at `path` (without leading slash)
+    pub fn add_resource(&mut self, path: &'static str, observable: bool, handler: Handler) {
+        self.resources.push(Resource {
+            path,
+            observable,
+            handler,
+            last: None,
+        });
+    }
+
+    /// Process a received datagram
+    pub fn handle(&mut self, from: SocketAddr, datagram: &[u8]) -> Outgoing {
+        let message = match Message::parse(datagram) {
+            Ok(message) => message,
+            Err(_) => {
+                // Reject malformed confirmable messages, silently drop the rest
+                if datagram.len() >= 4 && (datagram[0] >> 4) & 0x03 == 0 {
+                    let message_id = u16::from_be_bytes([datagram[2], datagram[3]]);
+                    return vec![(from, Message::reset(message_id).encode())];
+                }
+                return Vec::new();
+            }
+        };
+
+        match message.mtype {
+            MessageType::Acknowledgement => {
+                self.pending
+                    .retain(|p| !(p.addr == from && p.message_id == message.message_id));
+                Vec::new()
+            }
+            MessageType::Reset => {
+                // A reset notification cancels the observation it belongs to
+                if let Some(i) = self
+                    .pending
+                    .iter()
+                    .position(|p| p.addr == from && p.message_id == message.message_id)
+                {
+                    let pending = self.pending.remove(i);
+                    self.remove_observer(from, &pending.token);
+                }
+                Vec::new()
+            }
+            MessageType::Confirmable | MessageType::NonConfirmable => {
+                let confirmable = message.mtype == MessageType::Confirmable;
+                if !message.code.is_request() {
+                    // Empty CON is a CoAP ping, answered with a reset
+                    return if confirmable {
+                        vec![(from, Message::reset(message.message_id).encode())]
+                    } else {
+                        Vec::new()
+                    };
+                }
+
+                // Answer duplicates with the response already sent
+                if let Some(exchange) = self
+                    .recent
+                    .iter()
+                    .find(|e| e.addr == from && e.message_id == message.message_id)
+                {
+                    return vec![(from, exchange.response.clone())];
+                }
+
+                let response = self.request(from, &message).encode();
+                if confirmable {
+                    if self.recent.len() == RECENT_EXCHANGES {
+                        self.recent.pop_front();
+                    }
+                    self.recent.push_back(Exchange {
+                        addr: from,
+                        message_id: message.message_id,
+                        response: response.clone(),
+                    });
+                }
+                vec![(from, response)]
+            }
+        }
+    }
+
+    /// Retransmit unacknowledged messages and notify observers of changed resources
+    pub fn poll(&mut self, now: u64) -> Outgoing {
+        let mut outgoing = Vec::new();
+
+        // Retransmissions with exponential back-off
+        let mut expired = Vec::new();
+        for pending in self.pending.iter_mut().filter(|p| now >= p.deadline) {
+            if pending.retransmissions == MAX_RETRANSMIT {
+                expired.push((pending.addr, pending.token.clone()));
+                continue;
+            }
+            pending.retransmissions += 1;
+            pending.timeout *= 2;
+            pending.deadline = now + pending.timeout;
+            outgoing.push((pending.addr, pending.datagram.clone()));
+        }
+        self.pending
+            .retain(|p| !(now >= p.deadline && p.retransmissions == MAX_RETRANSMIT));
+        for (addr, token) in expired {
+            self.remove_observer(addr, &token);
+        }
+
+        // Check observed resources for changes
+        if now < self.next_scan {
+            return outgoing;
+        }
+        self.next_scan = now + NOTIFY_INTERVAL_MS;
+
+        for r in 0..self.resources.len() {
+            let path = self.resources[r].path;
+            if !self.observers.iter().any(|o| o.path == path) {
+                continue;
+            }
+            let resource = &mut self.resources[r];
+            let response = (resource.handler)(Code::GET, &[]);
+            if resource.last.as_ref() == Some(&response.payload) {
+                continue;
+            }
+            resource.last = Some(response.payload.clone());
+            self.observe_seq = (self.observe_seq + 1) & 0x00ff_ffff;
+
+            for o in 0..self.observers.len() {
+                if self.observers[o].path != path {
+                    continue;
+                }
+                self.observers[o].notifications += 1;
+                let observer = &self.observers[o];
+                let mtype = if observer.notifications % CONFIRMABLE_EVERY == 0 {
+                    MessageType::Confirmable
+                } else {
+                    MessageType::NonConfirmable
+                };
+                let (addr, token) = (observer.addr, observer.token.clone());
+
+                let message_id = self.message_id();
+                let mut notification = Message::new(mtype, response.code, message_id);
+                notification.token = token.clone();
+                notification.add_option(option::OBSERVE, encode_uint(self.observe_seq));
+                add_payload(
+                    &mut notification,
+                    &response,
+                    Block {
+                        num: 0,
+                        more: false,
+                        szx: DEFAULT_SZX,
+                    },
+                );
+                let datagram = notification.encode();
+
+                // A newer notification replaces one still waiting for its ACK
+                self.pending
+                    .retain(|p| !(p.addr == addr && p.token == token));
+                if mtype == MessageType::Confirmable {
+                    let timeout = ack_timeout(message_id);
+                    self.pending.push(Pending {
+                        addr,
+                        message_id,
+                        token,
+                        datagram: datagram.clone(),
+                        retransmissions: 0,
+                        timeout,
+                        deadline: now + timeout,
+                    });
+                }
+                outgoing.push((addr, datagram));
+            }
+        }
+
+        outgoing
+    }
+
+    fn request(&mut self, from: SocketAddr, request: &Message) -> Message {
+        let message_id = self.message_id();
+
+        let unknown_critical = request
+            .options
+            .iter()
+            .any(|(n, _)| n % 2 == 1 && !KNOWN_OPTIONS.contains(n));
+        if unknown_critical {
+            return Message::response(request, Code::BAD_OPTION, message_id);
+        }
+
+        let path = request.uri_path();
+        log::debug!("{} /{} from {}", request.code, path, from);
+        let index = match self.resources.iter().position(|r| r.path == path) {
+            Some(index) => index,
+            None => return Message::response(request, Code::NOT_FOUND, message_id),
+        };
+        let resource_path = self.resources[index].path;
+
+        // Reassemble Block1 uploads before handing them to the resource
+        let mut block1 = None;
+        let mut body = request.payload.clone();
+        if let Some(value) = request.option(option::BLOCK1) {
+            let block = match Block::decode(value) {
+                Some(block) => block,
+                None => return Message::response(request, Code::BAD_OPTION, message_id),
+            };
+            let upload = self
+                .uploads
+                .iter()
+                .position(|u| u.addr == from && u.path == resource_path);
+            let upload = match (block.num, upload) {
+                (0, existing) => {
+                    if let Some(i) = existing {
+                        self.uploads.remove(i);
+                    }
+                    self.uploads.push(Upload {
+                        addr: from,
+                        path: resource_path,
+                        body: Vec::new(),
+                        next_num: 0,
+                    });
+                    self.uploads.len() - 1
+                }
+                (num, Some(i)) if self.uploads[i].next_num == num => i,
+                _ => {
+                    return Message::response(request, Code::REQUEST_ENTITY_INCOMPLETE, message_id)
+                }
+            };
+
+            self.uploads[upload]
+                .body
+                .extend_from_slice(&request.payload);
+            self.uploads[upload].next_num += 1;
+            if self.uploads[upload].body.len() > MAX_BODY {
+                self.uploads.remove(upload);
+                let mut response =
+                    Message::response(request, Code::REQUEST_ENTITY_TOO_LARGE, message_id);
+                response.add_option(option::SIZE1, encode_uint(MAX_BODY as u32));
+                return response;
+            }
+            if block.more {
+                let mut response = Message::response(request, Code::CONTINUE, message_id);
+                response.add_option(option::BLOCK1, block.encode());
+                return response;
+            }
+            body = self.uploads.remove(upload).body;
+            block1 = Some(block);
+        }
+
+        let resource = &mut self.resources[index];
+        let result = (resource.handler)(request.code, &body);
+        let mut response = Message::response(request, result.code, message_id);
+        if let Some(block) = block1 {
+            response.add_option(option::BLOCK1, block.encode());
+        }
+
+        // Observe registration and deregistration
+        if request.code == Code::GET && resource.observable && result.code == Code::CONTENT {
+            match request.uint_option(option::OBSERVE) {
+                Some(0) => {
+                    resource.last = Some(result.payload.clone());
+                    self.observers
+                        .retain(|o| !(o.addr == from && o.token == request.token));
+                    if self.observers.len() < MAX_OBSERVERS {
+                        self.observers.push(Observer {
+                            addr: from,
+                            token: request.token.clone(),
+                            path: resource_path,
+                            notifications: 0,
+                        });
+                        response.add_option(option::OBSERVE, encode_uint(self.observe_seq));
+                    }
+                }
+                Some(1) => self.remove_observer(from, &request.token),
+                _ => (),
+            }
+        }
+
+        // Block2 transfer of large representations
+        let block2 = match request.option(option::BLOCK2).map(Block::decode) {
+            Some(Some(block)) => Block {
+                num: block.num,
+                more: false,
+                szx: block.szx.min(DEFAULT_SZX),
+            },
+            Some(None) => return Message::response(request, Code::BAD_OPTION, message_id),
+            None => Block {
+                num: 0,
+                more: false,
+                szx: DEFAULT_SZX,
+            },
+        };
+        if block2.num > 0 && block2.offset() >= result.payload.len() {
+            return Message::response(request, Code::BAD_REQUEST, message_id);
+        }
+        add_payload(&mut response, &result, block2);
+        response
+    }
+
+    fn remove_observer(&mut self, addr: SocketAddr, token: &[u8]) {
+        self.observers
+            .retain(|o| !(o.addr == addr && o.token == token));
+    }
+
+    fn message_id(&mut self) -> u16 {
+        let message_id = self.next_message_id;
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        message_id
+    }
+}
+
+// Attach the content format and the payload, or the requested block of it
+fn add_payload(message: &mut Message, response: &Response, block: Block) {
+    if let Some(format) = response.format {
+        message.add_option(option::CONTENT_FORMAT, encode_uint(format as u32));
+    }
+    let payload = &response.payload;
+    if payload.len() <= block.size() && block.num == 0 {
+        message.payload = payload.clone();
+        return;
+    }
+    let start = block.offset().min(payload.len());
+    let end = (start + block.size()).min(payload.len());
+    message.add_option(
+        option::BLOCK2,
+        Block {
+            more: end < payload.len(),
+            ..block
+        }
+        .encode(),
+    );
+    if block.num == 0 {
+        message.add_option(option::SIZE2, encode_uint(payload.len() as u32));
+    }
+    message.payload = payload[start..end].to_vec();
+}
+
+// ACK_TIMEOUT scaled by a factor between 1 and 1.5 (ACK_RANDOM_FACTOR),
+// derived from the message ID so the state machine stays deterministic
+fn ack_timeout(message_id: u16) -> u64 {
+    ACK_TIMEOUT_MS + (message_id as u64 * 7919) % (ACK_TIMEOUT_MS / 2)
+}
+
+/// Serve CoAP requests received on `socket` forever
+pub fn serve(socket: &UdpSocket, server: &mut Server) -> std::io::Result<()> {
+    // Wake up regularly to retransmit and send notifications
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let start = Instant::now();
+    let mut buf = [0_u8; 1280];
+
+    loop {
+        let mut outgoing = match socket.recv_from(&mut buf) {
+            Ok((len, from)) => server.handle(from, &buf[..len]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        outgoing.extend(server.poll(start.elapsed().as_millis() as u64));
+
+        // A datagram that cannot go out now is lost like any other, CON
+        // notifications are retransmitted and clients retry their requests
+        for (to, datagram) in outgoing {
+            if let Err(e) = socket.send_to(&datagram, to) {
+                log::warn!("Sending to {} failed: {}", to, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coap::{decode_uint, format};
+    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT: &str = "192.0.2.1:40000";
+
+    struct Fixture {
+        server: Server,
+        sensor: Arc<Mutex<String>>,
+        uploaded: Arc<Mutex<Vec<u8>>>,
+        calls: Arc<Mutex<u32>>,
+    }
+
+    fn fixture() -> Fixture {
+        let mut server = Server::new(0x1000);
+        let sensor = Arc::new(Mutex::new(String::from("20")));
+        let uploaded = Arc::new(Mutex::new(Vec::new()));
+        let calls = Arc::new(Mutex::new(0));
+
+        let (value, count) = (sensor.clone(), calls.clone());
+        server.add_resource(
+            "sensor",
+            true,
+            Box::new(move |code, _| {
+                *count.lock().unwrap() += 1;
+                match code {
+                    Code::GET => Response::content(format::JSON, value.lock().unwrap().clone()),
+                    _ => Response::error(Code::METHOD_NOT_ALLOWED),
+                }
+            }),
+        );
+        server.add_resource(
+            "big",
+            false,
+            Box::new(|_, _| Response::content(format::JSON, big())),
+        );
+        let body = uploaded.clone();
+        server.add_resource(
+            "upload",
+            false,
+            Box::new(move |_, payload| {
+                *body.lock().unwrap() = payload.to_vec();
+                Response::changed()
+            }),
+        );
+        Fixture {
+            server,
+            sensor,
+            uploaded,
+            calls,
+        }
+    }
+
+    fn big() -> Vec<u8> {
+        (0..1200).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn client() -> SocketAddr {
+        CLIENT.parse().unwrap()
+    }
+
+    fn request(mtype: MessageType, code: Code, message_id: u16, path: &str) -> Message {
+        let mut message = Message::new(mtype, code, message_id);
+        message.token = vec![0xAB, message_id as u8];
+        for segment in path.split('/') {
+            message.add_option(option::URI_PATH, segment.as_bytes().to_vec());
+        }
+        message
+    }
+
+    fn send(server: &mut Server, message: &Message) -> Vec<Message> {
+        parse(server.handle(client(), &message.encode()))
+    }
+
+    fn parse(outgoing: Outgoing) -> Vec<Message> {
+        outgoing
+            .into_iter()
+            .map(|(to, datagram)| {
+                assert_eq!(to, client());
+                Message::parse(&datagram).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn get_is_answered_with_a_piggybacked_ack() {
+        let mut f = fixture();
+        let request = request(MessageType::Confirmable, Code::GET, 1, "sensor");
+        let [response] = &send(&mut f.server, &request)[..] else {
+            panic!("expected one response");
+        };
+        assert_eq!(response.mtype, MessageType::Acknowledgement);
+        assert_eq!(response.message_id, 1);
+        assert_eq!(response.token, request.token);
+        assert_eq!(response.code, Code::CONTENT);
+        assert_eq!(
+            response.uint_option(option::CONTENT_FORMAT),
+            Some(format::JSON as u32)
+        );
+        assert_eq!(response.payload, b"20");
+    }
+
+    #[test]
+    fn duplicates_get_the_same_response_without_running_again() {
+        let mut f = fixture();
+        let request = request(MessageType::Confirmable, Code::GET, 2, "sensor");
+        let first = f.server.handle(client(), &request.encode());
+        let again = f.server.handle(client(), &request.encode());
+        assert_eq!(first, again);
+        assert_eq!(*f.calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn errors_and_pings() {
+        let mut f = fixture();
+        let missing = request(MessageType::Confirmable, Code::GET, 3, "nothing");
+        assert_eq!(send(&mut f.server, &missing)[0].code, Code::NOT_FOUND);
+
+        // Unknown critical (odd) options are refused, elective ones ignored
+        let mut critical = request(MessageType::Confirmable, Code::GET, 4, "sensor");
+        critical.add_option(2049, vec![1]);
+        assert_eq!(send(&mut f.server, &critical)[0].code, Code::BAD_OPTION);
+        let mut elective = request(MessageType::Confirmable, Code::GET, 5, "sensor");
+        elective.add_option(2048, vec![1]);
+        assert_eq!(send(&mut f.server, &elective)[0].code, Code::CONTENT);
+
+        // An empty CON is a ping, answered with a reset
+        let ping = Message::new(MessageType::Confirmable, Code::EMPTY, 6);
+        let pong = &send(&mut f.server, &ping)[0];
+        assert_eq!((pong.mtype, pong.message_id), (MessageType::Reset, 6));
+
+        // Malformed CON is reset, malformed NON dropped
+        let reset = parse(f.server.handle(client(), b"\x40\x01\x00\x07\xff"));
+        assert_eq!(reset[0].mtype, MessageType::Reset);
+        assert!(f
+            .server
+            .handle(client(), b"\x50\x01\x00\x08\xff")
+            .is_empty());
+    }
+
+    #[test]
+    fn observers_are_notified_of_changes() {
+        let mut f = fixture();
+        let mut register = request(MessageType::Confirmable, Code::GET, 10, "sensor");
+        register.add_option(option::OBSERVE, encode_uint(0));
+        let response = &send(&mut f.server, &register)[0];
+        assert_eq!(response.code, Code::CONTENT);
+        assert!(response.option(option::OBSERVE).is_some());
+
+        // Nothing changed, nothing to send
+        assert!(f.server.poll(0).is_empty());
+
+        *f.sensor.lock().unwrap() = String::from("21");
+        let notifications = parse(f.server.poll(1000));
+        let [notification] = &notifications[..] else {
+            panic!("expected one notification");
+        };
+        assert_eq!(notification.mtype, MessageType::NonConfirmable);
+        assert_eq!(notification.token, register.token);
+        assert_eq!(notification.payload, b"21");
+        assert_eq!(notification.uint_option(option::OBSERVE), Some(1));
+
+        // Deregistering stops the notifications
+        let mut deregister = request(MessageType::Confirmable, Code::GET, 11, "sensor");
+        deregister.token = register.token.clone();
+        deregister.add_option(option::OBSERVE, encode_uint(1));
+        send(&mut f.server, &deregister);
+        *f.sensor.lock().unwrap() = String::from("22");
+        assert!(f.server.poll(2000).is_empty());
+    }
+
+    // Register an observer and change the value until the notification that
+    // is sent confirmable, returning it with the time it went out
+    fn confirmable_notification(f: &mut Fixture) -> (Message, u64) {
+        let mut register = request(MessageType::Confirmable, Code::GET, 20, "sensor");
+        register.add_option(option::OBSERVE, encode_uint(0));
+        send(&mut f.server, &register);
+        for n in 1..=CONFIRMABLE_EVERY {
+            let now = n as u64 * NOTIFY_INTERVAL_MS;
+            *f.sensor.lock().unwrap() = n.to_string();
+            let notification = parse(f.server.poll(now)).remove(0);
+            if notification.mtype == MessageType::Confirmable {
+                return (notification, now);
+            }
+        }
+        panic!("no confirmable notification");
+    }
+
+    #[test]
+    fn confirmable_notifications_are_retransmitted_until_acked() {
+        let mut f = fixture();
+        let (notification, sent) = confirmable_notification(&mut f);
+        let timeout = ack_timeout(notification.message_id);
+        assert!((ACK_TIMEOUT_MS..ACK_TIMEOUT_MS * 3 / 2).contains(&timeout));
+
+        assert!(f.server.poll(sent + timeout - 1).is_empty());
+        let resent = parse(f.server.poll(sent + timeout));
+        assert_eq!(resent, std::slice::from_ref(&notification));
+
+        let ack = Message::new(
+            MessageType::Acknowledgement,
+            Code::EMPTY,
+            notification.message_id,
+        );
+        assert!(send(&mut f.server, &ack).is_empty());
+        assert!(f.server.poll(sent + 10 * timeout).is_empty());
+    }
+
+    #[test]
+    fn unacknowledged_observers_are_dropped() {
+        let mut f = fixture();
+        let (notification, mut now) = confirmable_notification(&mut f);
+        let mut timeout = ack_timeout(notification.message_id);
+        for _ in 0..MAX_RETRANSMIT {
+            now += timeout;
+            assert_eq!(
+                parse(f.server.poll(now)),
+                std::slice::from_ref(&notification)
+            );
+            timeout *= 2;
+        }
+        now += timeout;
+        assert!(f.server.poll(now).is_empty());
+        // The observer is gone, changes are not sent anymore
+        *f.sensor.lock().unwrap() = String::from("gone");
+        assert!(f.server.poll(now + NOTIFY_INTERVAL_MS).is_empty());
+    }
+
+    #[test]
+    fn reset_cancels_the_observation() {
+        let mut f = fixture();
+        let (notification, now) = confirmable_notification(&mut f);
+        let reset = Message::reset(notification.message_id);
+        assert!(send(&mut f.server, &reset).is_empty());
+        *f.sensor.lock().unwrap() = String::from("after reset");
+        assert!(f.server.poll(now + NOTIFY_INTERVAL_MS).is_empty());
+    }
+
+    #[test]
+    fn large_representations_go_out_in_blocks() {
+        let mut f = fixture();
+        let body = big();
+        let mut received = Vec::new();
+        for num in 0.. {
+            let mut request = request(MessageType::Confirmable, Code::GET, 30 + num as u16, "big");
+            let block = Block {
+                num,
+                more: false,
+                szx: 4,
+            };
+            request.add_option(option::BLOCK2, block.encode());
+            let response = send(&mut f.server, &request).remove(0);
+            assert_eq!(response.code, Code::CONTENT);
+            let block = Block::decode(response.option(option::BLOCK2).unwrap()).unwrap();
+            assert_eq!((block.num, block.szx), (num, 4));
+            if num == 0 {
+                assert_eq!(response.uint_option(option::SIZE2), Some(1200));
+            }
+            received.extend_from_slice(&response.payload);
+            if !block.more {
+                break;
+            }
+        }
+        assert_eq!(received, body);
+
+        // Without Block2 the server picks its own block size
+        let first = send(
+            &mut f.server,
+            &request(MessageType::Confirmable, Code::GET, 40, "big"),
+        );
+        let block = Block::decode(first[0].option(option::BLOCK2).unwrap()).unwrap();
+        assert_eq!((block.num, block.more, block.size()), (0, true, 512));
+
+        // Past the end of the representation
+        let mut past = request(MessageType::Confirmable, Code::GET, 41, "big");
+        let block = Block {
+            num: 5,
+            more: false,
+            szx: 4,
+        };
+        past.add_option(option::BLOCK2, block.encode());
+        assert_eq!(send(&mut f.server, &past)[0].code, Code::BAD_REQUEST);
+    }
+
+    // PUT one block of an upload, every call with a message ID of its own
+    fn put_block(server: &mut Server, num: u32, more: bool, payload: &[u8]) -> Message {
+        static MESSAGE_ID: AtomicU16 = AtomicU16::new(100);
+        let mut request = request(
+            MessageType::Confirmable,
+            Code::PUT,
+            MESSAGE_ID.fetch_add(1, Ordering::Relaxed),
+            "upload",
+        );
+        let block = Block { num, more, szx: 4 };
+        request.add_option(option::BLOCK1, block.encode());
+        request.payload = payload.to_vec();
+        send(server, &request).remove(0)
+    }
+
+    #[test]
+    fn uploads_are_reassembled_from_blocks() {
+        let mut f = fixture();
+        let body: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let blocks: Vec<&[u8]> = body.chunks(16).collect();
+        for (num, block) in blocks.iter().enumerate() {
+            let more = num + 1 < blocks.len();
+            let response = put_block(&mut f.server, num as u32, more, block);
+            let expected = if more { Code::CONTINUE } else { Code::CHANGED };
+            assert_eq!(response.code, expected);
+            let echoed = Block::decode(response.option(option::BLOCK1).unwrap()).unwrap();
+            assert_eq!((echoed.num, echoed.more), (num as u32, more));
+        }
+        assert_eq!(*f.uploaded.lock().unwrap(), body);
+    }
+
+    #[test]
+    fn broken_uploads_are_refused() {
+        let mut f = fixture();
+        put_block(&mut f.server, 0, true, &[0; 16]);
+        let skipped = put_block(&mut f.server, 2, true, &[0; 16]);
+        assert_eq!(skipped.code, Code::REQUEST_ENTITY_INCOMPLETE);
+
+        let mut response = put_block(&mut f.server, 0, true, &[0; 16]);
+        for num in 1.. {
+            if response.code != Code::CONTINUE {
+                break;
+            }
+            response = put_block(&mut f.server, num, true, &[0; 16]);
+        }
+        assert_eq!(response.code, Code::REQUEST_ENTITY_TOO_LARGE);
+        assert_eq!(
+            response.option(option::SIZE1).and_then(decode_uint),
+            Some(MAX_BODY as u32)
+        );
+        assert!(f.uploaded.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn serves_over_a_loopback_socket() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || serve(&socket, &mut fixture().server));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let request = request(MessageType::Confirmable, Code::GET, 60, "sensor");
+        client.send_to(&request.encode(), addr).unwrap();
+        let mut buf = [0_u8; 1280];
+        let (len, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(from, addr);
+        let response = Message::parse(&buf[..len]).unwrap();
+        assert_eq!(
+            (response.mtype, response.code, response.message_id),
+            (MessageType::Acknowledgement, Code::CONTENT, 60)
+        );
+        assert_eq!(response.payload, b"20");
+    }
+}
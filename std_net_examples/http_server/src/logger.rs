@@ -0,0 +1,407 @@
+//! `log` backend writing every record to the console, to a RAM ring buffer
+//! and, once attached, to an RFC 5424 syslog collector over UDP.
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Unix time of 2020-01-01, earlier clocks are considered unset (no SNTP yet)
+const MIN_VALID_TIME: u64 = 1_577_836_800;
+
+/// Syslog facility `user`
+pub const FACILITY_USER: u8 = 1;
+
+/// Level filtering with per-module overrides
+pub struct Filters {
+    default: LevelFilter,
+    modules: &'static [(&'static str, LevelFilter)],
+}
+
+impl Filters {
+    /// `modules` maps module paths (e.g. `http::coap_server`) to their level,
+    /// the longest matching path wins
+    pub const fn new(
+        default: LevelFilter,
+        modules: &'static [(&'static str, LevelFilter)],
+    ) -> Self {
+        Filters { default, modules }
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == *module
+                    || (target.starts_with(module) && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    /// Most verbose level any module may log at
+    pub fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+/// Byte-bounded buffer of the most recent log lines
+pub struct LogRing {
+    buf: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl LogRing {
+    pub fn new(capacity: usize) -> Self {
+        LogRing {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Append a line, dropping the oldest whole lines to make room
+    pub fn push_line(&mut self, line: &str) {
+        let mut bytes = line.as_bytes();
+        // Lines longer than the buffer keep only their tail
+        if bytes.len() + 1 > self.capacity {
+            bytes = &bytes[bytes.len() + 1 - self.capacity..];
+            self.buf.clear();
+        }
+        while self.buf.len() + bytes.len() + 1 > self.capacity {
+            match self.buf.iter().position(|b| *b == b'\n') {
+                Some(end) => drop(self.buf.drain(..=end)),
+                None => self.buf.clear(),
+            }
+        }
+        self.buf.extend(bytes);
+        self.buf.push_back(b'\n');
+    }
+
+    pub fn contents(&self) -> String {
+        let (a, b) = self.buf.as_slices();
+        let mut out = String::from_utf8_lossy(a).into_owned();
+        out.push_str(&String::from_utf8_lossy(b));
+        out
+    }
+}
+
+/// Destination and header fields of forwarded syslog messages
+pub struct SyslogConfig {
+    pub collector: SocketAddr,
+    pub hostname: String,
+    pub app_name: &'static str,
+    pub facility: u8,
+}
+
+struct Syslog {
+    socket: UdpSocket,
+    config: SyslogConfig,
+}
+
+/// Syslog severity of a `log` level
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Format an RFC 5424 message, `timestamp` is `(unix seconds, milliseconds)`
+pub fn format_syslog(
+    config: &SyslogConfig,
+    level: Level,
+    target: &str,
+    message: &str,
+    timestamp: Option<(u64, u32)>,
+) -> String {
+    let mut out = String::new();
+    let pri = config.facility as u32 * 8 + severity(level) as u32;
+    write!(out, "<{}>1 ", pri).unwrap();
+    match timestamp {
+        Some((secs, millis)) => {
+            let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+            let time = secs % 86_400;
+            write!(
+                out,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z ",
+                year,
+                month,
+                day,
+                time / 3600,
+                time / 60 % 60,
+                time % 60,
+                millis
+            )
+            .unwrap();
+        }
+        None => out.push_str("- "),
+    }
+    write!(
+        out,
+        "{} {} - - - {}: {}",
+        header_field(&config.hostname, 255),
+        header_field(config.app_name, 48),
+        target,
+        message
+    )
+    .unwrap();
+    out
+}
+
+// Header fields are printable US-ASCII without spaces, "-" when empty
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if field.is_empty() {
+        "-".into()
+    } else {
+        field
+    }
+}
+
+// Convert days since 1970-01-01 to a (year, month, day) date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub struct Logger {
+    filters: Filters,
+    ring: Mutex<LogRing>,
+    syslog: Mutex<Option<Syslog>>,
+}
+
+impl Logger {
+    pub fn new(filters: Filters, ring_capacity: usize) -> Self {
+        Logger {
+            filters,
+            ring: Mutex::new(LogRing::new(ring_capacity)),
+            syslog: Mutex::new(None),
+        }
+    }
+
+    /// Start forwarding records to a syslog collector, needs a running network stack
+    pub fn attach_syslog(&self, config: SyslogConfig) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        *self.syslog.lock().unwrap() = Some(Syslog { socket, config });
+        Ok(())
+    }
+
+    /// Contents of the ring buffer, oldest line first
+    pub fn recent(&self) -> String {
+        self.ring.lock().unwrap().contents()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filters.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let message = record.args().to_string();
+        let line = format!("{:<5} [{}] {}", record.level(), record.target(), message);
+        println!("{}", line);
+        self.ring.lock().unwrap().push_line(&line);
+
+        if let Some(syslog) = self.syslog.lock().unwrap().as_ref() {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .filter(|t| t.as_secs() >= MIN_VALID_TIME)
+                .map(|t| (t.as_secs(), t.subsec_millis()));
+            let datagram = format_syslog(
+                &syslog.config,
+                record.level(),
+                record.target(),
+                &message,
+                timestamp,
+            );
+            // Logging must never fail the caller, lost datagrams are acceptable
+            let _ = syslog
+                .socket
+                .send_to(datagram.as_bytes(), syslog.config.collector);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install `logger` as the global `log` backend
+pub fn init(logger: Logger) -> Result<&'static Logger, SetLoggerError> {
+    let logger: &'static Logger = Box::leak(Box::new(logger));
+    log::set_logger(logger)?;
+    log::set_max_level(logger.filters.max());
+    Ok(logger)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(collector: SocketAddr) -> SyslogConfig {
+        SyslogConfig {
+            collector,
+            hostname: "esp32c3".into(),
+            app_name: "http",
+            facility: FACILITY_USER,
+        }
+    }
+
+    fn local() -> SocketAddr {
+        "127.0.0.1:514".parse().unwrap()
+    }
+
+    #[test]
+    fn syslog_messages_follow_rfc_5424() {
+        // 2024-02-29T13:05:09.042Z
+        let message = format_syslog(
+            &config(local()),
+            Level::Warn,
+            "http::coap_server",
+            "Sending failed",
+            Some((1_709_211_909, 42)),
+        );
+        assert_eq!(
+            message,
+            "<12>1 2024-02-29T13:05:09.042Z esp32c3 http - - - http::coap_server: Sending failed"
+        );
+    }
+
+    #[test]
+    fn syslog_priority_combines_facility_and_severity() {
+        let mut config = config(local());
+        config.facility = 16;
+        let pri = |level| format_syslog(&config, level, "t", "m", None);
+        assert!(pri(Level::Error).starts_with("<131>1 - "));
+        assert!(pri(Level::Info).starts_with("<134>1 - "));
+        assert!(pri(Level::Trace).starts_with("<135>1 - "));
+    }
+
+    #[test]
+    fn syslog_header_fields_are_sanitized() {
+        let mut config = config(local());
+        config.hostname = "living room\n".into();
+        config.app_name = "";
+        let message = format_syslog(&config, Level::Info, "t", "line\nbreak", None);
+        assert_eq!(message, "<14>1 - livingroom - - - - t: line\nbreak");
+        config.hostname = "h".repeat(300);
+        let message = format_syslog(&config, Level::Info, "t", "m", None);
+        assert!(message.contains(&format!(" {} ", "h".repeat(255))));
+        assert!(!message.contains(&"h".repeat(256)));
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn longest_module_filter_wins() {
+        static MODULES: &[(&str, LevelFilter)] = &[
+            ("http", LevelFilter::Warn),
+            ("http::coap_server", LevelFilter::Debug),
+            ("esp_idf_svc", LevelFilter::Off),
+        ];
+        let filters = Filters::new(LevelFilter::Info, MODULES);
+        assert_eq!(filters.level_for("http"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("http::logger"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("http::coap_server"), LevelFilter::Debug);
+        assert_eq!(
+            filters.level_for("http::coap_server::tests"),
+            LevelFilter::Debug
+        );
+        // A prefix only matches whole path segments
+        assert_eq!(filters.level_for("http_server"), LevelFilter::Info);
+        assert_eq!(filters.level_for("esp_idf_svc::wifi"), LevelFilter::Off);
+        assert_eq!(filters.max(), LevelFilter::Debug);
+    }
+
+    #[test]
+    fn ring_drops_whole_lines_when_it_wraps() {
+        let mut ring = LogRing::new(16);
+        ring.push_line("one");
+        ring.push_line("two");
+        ring.push_line("three");
+        assert_eq!(ring.contents(), "one\ntwo\nthree\n");
+        ring.push_line("four");
+        assert_eq!(ring.contents(), "two\nthree\nfour\n");
+        ring.push_line("0123456789");
+        assert_eq!(ring.contents(), "four\n0123456789\n");
+    }
+
+    #[test]
+    fn ring_keeps_the_tail_of_long_lines() {
+        let mut ring = LogRing::new(8);
+        ring.push_line("a");
+        ring.push_line("0123456789");
+        assert_eq!(ring.contents(), "3456789\n");
+    }
+
+    #[test]
+    fn records_reach_the_ring_and_the_collector() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        static MODULES: &[(&str, LevelFilter)] = &[("quiet", LevelFilter::Error)];
+        let logger = Logger::new(Filters::new(LevelFilter::Info, MODULES), 1024);
+        logger
+            .attach_syslog(config(collector.local_addr().unwrap()))
+            .unwrap();
+
+        let record = |level, target: &'static str, args| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(args)
+                    .build(),
+            )
+        };
+        record(Level::Debug, "app", format_args!("filtered out"));
+        record(Level::Warn, "quiet", format_args!("filtered out"));
+        record(Level::Warn, "app", format_args!("disk {}% full", 93));
+
+        assert_eq!(logger.recent(), "WARN  [app] disk 93% full\n");
+        let mut buf = [0_u8; 512];
+        let (len, _) = collector.recv_from(&mut buf).unwrap();
+        let datagram = std::str::from_utf8(&buf[..len]).unwrap();
+        assert!(datagram.starts_with("<12>1 "), "{}", datagram);
+        assert!(
+            datagram.ends_with(" esp32c3 http - - - app: disk 93% full"),
+            "{}",
+            datagram
+        );
+        // Filtered records were not sent either
+        collector.set_nonblocking(true).unwrap();
+        assert!(collector.recv_from(&mut buf).is_err());
+    }
+}
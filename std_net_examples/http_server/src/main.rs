@@ -9,19 +9,83 @@ For notifications on similar examples and more, subscribe to newsletter here:
 https://www.theembeddedrustacean.com/subscribe
 */
 
+mod coap;
+mod coap_server;
+mod logger;
+mod metrics;
+mod resources;
+
 use anyhow;
+use coap::{format, Code};
+use coap_server::Response;
 use embedded_svc::http::Method;
+use embedded_svc::io::Read;
 use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
+use esp_idf_hal::adc::attenuation::adc_atten_t_ADC_ATTEN_DB_11;
+use esp_idf_hal::adc::config::Config as AdcConfig;
+use esp_idf_hal::adc::{AdcChannelDriver, AdcDriver};
+use esp_idf_hal::gpio::{Gpio1, Gpio4, Level, Output, PinDriver};
 use esp_idf_hal::peripherals::Peripherals;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::http::server::{Configuration as HttpServerConfig, EspHttpServer};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::ping::{Configuration as PingConfiguration, EspPing};
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, warn, LevelFilter};
+use logger::{Filters, Logger, SyslogConfig};
+use metrics::{Metric, REGISTRY};
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
 use std::{thread::sleep, time::Duration};
 
+// LED driven through the /led resource
+type Led = PinDriver<'static, Gpio1, Output>;
+
+// Metrics exposed on /metrics
+static UPTIME: Metric = Metric::gauge("uptime_seconds", "Time since boot in seconds");
+static FREE_HEAP: Metric = Metric::gauge("heap_free_bytes", "Free heap in bytes");
+static WIFI_RECONNECTS: Metric =
+    Metric::counter("wifi_reconnects_total", "Number of Wi-Fi reconnections");
+static PING_RTT: Metric = Metric::histogram(
+    "ping_rtt_seconds",
+    "Round trip time of pings to the gateway",
+    &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0],
+);
+static PING_LOST: Metric = Metric::counter("ping_lost_total", "Pings without a reply");
+static HTTP_REQUESTS: Metric = Metric::counter("http_requests_total", "Served HTTP requests");
+static TEMPERATURE: Metric = Metric::gauge(
+    "sensor_temperature_celsius",
+    "Thermistor temperature reading",
+);
+static LOOP_ERRORS: Metric = Metric::counter(
+    "loop_errors_total",
+    "Driver calls of the main loop that failed",
+);
+
+// Seconds between two pings to the gateway
+const PING_PERIOD: u32 = 10;
+
+// Logging configuration
+// Collector receiving RFC 5424 syslog messages over UDP
+const SYSLOG_COLLECTOR: &str = "192.168.1.100:514";
+const HOSTNAME: &str = "esp32c3";
+// Size of the log ring buffer served on /api/logs
+const LOG_RING_SIZE: usize = 8 * 1024;
+// Per-module levels, modules not listed log at Info
+static LOG_FILTERS: &[(&str, LevelFilter)] = &[
+    ("http::coap_server", LevelFilter::Debug),
+    ("esp_idf_svc", LevelFilter::Warn),
+];
+
 fn main() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
 
+    // Install Logger
+    let logger = logger::init(Logger::new(
+        Filters::new(LevelFilter::Info, LOG_FILTERS),
+        LOG_RING_SIZE,
+    ))?;
+
     // Configure Wifi
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
@@ -49,7 +113,22 @@ fn main() -> anyhow::Result<()> {
     // Wait until the network interface is up
     wifi.wait_netif_up()?;
 
-    println!("Wifi Connected, Starting HTTP Server");
+    // Forward logs to the syslog collector now that the network is up
+    logger.attach_syslog(SyslogConfig {
+        collector: SYSLOG_COLLECTOR.parse()?,
+        hostname: HOSTNAME.into(),
+        app_name: env!("CARGO_PKG_NAME"),
+        facility: logger::FACILITY_USER,
+    })?;
+
+    info!("Wifi Connected, Starting HTTP and CoAP Servers");
+
+    // Configure LED and Thermistor ADC Backing the Resources
+    let led: Arc<Mutex<Led>> = Arc::new(Mutex::new(PinDriver::output(peripherals.pins.gpio1)?));
+    let temperature = Arc::new(Mutex::new(None::<f64>));
+    let mut adc = AdcDriver::new(peripherals.adc1, &AdcConfig::new())?;
+    let mut adc_pin: AdcChannelDriver<'_, { adc_atten_t_ADC_ATTEN_DB_11 }, Gpio4> =
+        AdcChannelDriver::new(peripherals.pins.gpio4)?;
 
     // HTTP Configuration
     // Create HTTP Server Connection Handle
@@ -57,6 +136,8 @@ fn main() -> anyhow::Result<()> {
 
     // Define Server Request Handler Behaviour on Get for Root URL
     httpserver.fn_handler("/", Method::Get, |request| {
+        // Count request
+        count_request("/");
         // Retrieve html String
         let html = index_html();
         // Respond with OK status
@@ -66,12 +147,258 @@ fn main() -> anyhow::Result<()> {
         Ok(())
     })?;
 
-    // Loop to Avoid Program Termination
+    // Define Server Request Handler Behaviour on Get for Metrics URL
+    httpserver.fn_handler("/metrics", Method::Get, |request| {
+        // Encode all series in the Prometheus text format
+        count_request("/metrics");
+        let mut body = String::new();
+        REGISTRY.lock().unwrap().encode(&mut body)?;
+        // Respond with OK status and the exposition format content type
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "text/plain; version=0.0.4")])?;
+        response.write(body.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Define Server Request Handler Behaviour on Get for Logs URL
+    httpserver.fn_handler("/api/logs", Method::Get, move |request| {
+        count_request("/api/logs");
+        // Return the most recent log lines
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "text/plain; charset=utf-8")])?;
+        response.write(logger.recent().as_bytes())?;
+        Ok(())
+    })?;
+
+    // Define Server Request Handler Behaviour on Get for Status URL
+    httpserver.fn_handler("/status", Method::Get, |request| {
+        count_request("/status");
+        let json = resources::status_json(uptime_secs(), free_heap());
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "application/json")])?;
+        response.write(json.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Define Server Request Handler Behaviour on Get for Sensor URL
+    let http_temperature = temperature.clone();
+    httpserver.fn_handler("/sensor", Method::Get, move |request| {
+        count_request("/sensor");
+        let json = resources::sensor_json(*http_temperature.lock().unwrap());
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "application/json")])?;
+        response.write(json.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Define Server Request Handler Behaviour on Get for LED URL
+    let http_led = led.clone();
+    httpserver.fn_handler("/led", Method::Get, move |request| {
+        count_request("/led");
+        let json = resources::led_json(http_led.lock().unwrap().is_set_high());
+        let mut response =
+            request.into_response(200, None, &[("Content-Type", "application/json")])?;
+        response.write(json.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Define Server Request Handler Behaviour on Put for LED URL
+    let http_led = led.clone();
+    httpserver.fn_handler("/led", Method::Put, move |mut request| {
+        count_request("/led");
+        // Read requested state from the request body
+        let mut body = [0_u8; 16];
+        let len = request.read(&mut body)?;
+        match resources::parse_led(&body[..len]) {
+            Some(on) => {
+                http_led.lock().unwrap().set_level(Level::from(on))?;
+                let mut response =
+                    request.into_response(200, None, &[("Content-Type", "application/json")])?;
+                response.write(resources::led_json(on).as_bytes())?;
+            }
+            None => {
+                request.into_status_response(400)?;
+            }
+        }
+        Ok(())
+    })?;
+
+    // Start CoAP Server Exposing the Same Resources on its Own Thread
+    let coap_led = led.clone();
+    let coap_temperature = temperature.clone();
+    std::thread::Builder::new()
+        .stack_size(8192)
+        .spawn(move || -> std::io::Result<()> {
+            let socket = UdpSocket::bind(("0.0.0.0", coap_server::PORT))?;
+            let seed = unsafe { esp_idf_sys::esp_random() } as u16;
+            let mut server = coap_server::Server::new(seed);
+
+            server.add_resource(
+                "status",
+                false,
+                Box::new(|code, _| match code {
+                    Code::GET => Response::content(
+                        format::JSON,
+                        resources::status_json(uptime_secs(), free_heap()),
+                    ),
+                    _ => Response::error(Code::METHOD_NOT_ALLOWED),
+                }),
+            );
+
+            server.add_resource(
+                "sensor",
+                true,
+                Box::new(move |code, _| match code {
+                    Code::GET => Response::content(
+                        format::JSON,
+                        resources::sensor_json(*coap_temperature.lock().unwrap()),
+                    ),
+                    _ => Response::error(Code::METHOD_NOT_ALLOWED),
+                }),
+            );
+
+            server.add_resource(
+                "led",
+                true,
+                Box::new(move |code, payload| {
+                    let mut led = coap_led.lock().unwrap();
+                    match code {
+                        Code::GET => {
+                            Response::content(format::JSON, resources::led_json(led.is_set_high()))
+                        }
+                        Code::PUT => match resources::parse_led(payload) {
+                            Some(on) => match led.set_level(Level::from(on)) {
+                                Ok(_) => Response::changed(),
+                                Err(_) => Response::error(Code::INTERNAL_SERVER_ERROR),
+                            },
+                            None => Response::error(Code::BAD_REQUEST),
+                        },
+                        _ => Response::error(Code::METHOD_NOT_ALLOWED),
+                    }
+                }),
+            );
+
+            let result = coap_server::serve(&socket, &mut server);
+            if let Err(e) = &result {
+                log::error!("CoAP server stopped: {}", e);
+            }
+            result
+        })?;
+
+    // Create EspPing instance to measure gateway round trip times
+    let mut ping = EspPing::new(0_u32);
+    let ping_config = PingConfiguration {
+        count: 1,
+        ..Default::default()
+    };
+
+    // Loop to Avoid Program Termination and Refresh Metrics
+    let mut ticks = 0_u32;
     loop {
         sleep(Duration::from_millis(1000));
+        ticks = ticks.wrapping_add(1);
+
+        // Update system gauges
+        {
+            let mut registry = REGISTRY.lock().unwrap();
+            record(&UPTIME, registry.set(&UPTIME, &[], uptime_secs() as f64));
+            record(
+                &FREE_HEAP,
+                registry.set(&FREE_HEAP, &[], free_heap() as f64),
+            );
+        }
+
+        // Sample thermistor, a failed read keeps the last temperature
+        match adc.read(&mut adc_pin) {
+            Ok(sample) => {
+                let celsius = resources::temperature(sample);
+                if celsius.is_none() {
+                    warn!("Thermistor sample {} mV out of range", sample);
+                }
+                *temperature.lock().unwrap() = celsius;
+                // NaN tells Prometheus there is no current reading
+                let celsius = celsius.unwrap_or(f64::NAN);
+                record(
+                    &TEMPERATURE,
+                    REGISTRY.lock().unwrap().set(&TEMPERATURE, &[], celsius),
+                );
+            }
+            Err(e) => loop_error("adc", e),
+        }
+
+        // Reconnect if the connection dropped
+        match wifi.is_connected() {
+            Ok(true) => (),
+            Ok(false) => {
+                warn!("Wifi Disconnected, Reconnecting");
+                if wifi.connect().is_ok() && wifi.wait_netif_up().is_ok() {
+                    let result = REGISTRY.lock().unwrap().inc(&WIFI_RECONNECTS, &[]);
+                    record(&WIFI_RECONNECTS, result);
+                }
+                continue;
+            }
+            Err(e) => {
+                loop_error("wifi", e);
+                continue;
+            }
+        }
+
+        // Periodically ping the gateway
+        if ticks % PING_PERIOD == 0 {
+            let gateway = match wifi.wifi().sta_netif().get_ip_info() {
+                Ok(info) => info.subnet.gateway,
+                Err(e) => {
+                    loop_error("netif", e);
+                    continue;
+                }
+            };
+            let summary = ping.ping(gateway, &ping_config);
+            let mut registry = REGISTRY.lock().unwrap();
+            match summary {
+                Ok(summary) if summary.received > 0 => record(
+                    &PING_RTT,
+                    registry.observe(&PING_RTT, &[], summary.time.as_secs_f64()),
+                ),
+                _ => record(&PING_LOST, registry.inc(&PING_LOST, &[])),
+            }
+        }
+    }
+}
+
+fn count_request(path: &'static str) {
+    let result = REGISTRY
+        .lock()
+        .unwrap()
+        .inc(&HTTP_REQUESTS, &[("path", path)]);
+    record(&HTTP_REQUESTS, result);
+}
+
+// Log a failed driver call of the main loop, which carries on regardless
+fn loop_error(source: &'static str, error: impl std::fmt::Display) {
+    warn!("Reading {} failed: {}", source, error);
+    let result = REGISTRY
+        .lock()
+        .unwrap()
+        .inc(&LOOP_ERRORS, &[("source", source)]);
+    record(&LOOP_ERRORS, result);
+}
+
+// Log a metric that could not be recorded, e.g. with the registry full,
+// the caller carries on without it
+fn record(metric: &Metric, result: Result<(), metrics::Error>) {
+    if let Err(e) = result {
+        warn!("Recording {} failed: {:?}", metric.name, e);
     }
 }
 
+fn uptime_secs() -> u64 {
+    (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u64
+}
+
+fn free_heap() -> u32 {
+    unsafe { esp_idf_sys::esp_get_free_heap_size() }
+}
+
 fn index_html() -> String {
     format!(
         r#"
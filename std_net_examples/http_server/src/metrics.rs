@@ -0,0 +1,464 @@
+//! Device-side metrics registry with a Prometheus text exposition encoder.
+//!
+//! The registry only depends on `core`. Every series lives in a fixed-size
+//! table, so memory use is bounded at compile time, and the encoder writes
+//! into anything implementing `core::fmt::Write`.
+
+use core::fmt::{self, Write};
+
+/// Maximum number of series a registry can hold
+pub const MAX_SERIES: usize = 32;
+/// Maximum number of labels attached to a single series
+pub const MAX_LABELS: usize = 3;
+/// Maximum number of histogram buckets (the implicit `+Inf` bucket excluded)
+pub const MAX_BUCKETS: usize = 10;
+
+/// Label name/value pair
+pub type Label = (&'static str, &'static str);
+
+/// Type of a metric family
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    /// Histogram with the given ascending upper bounds
+    Histogram(&'static [f64]),
+}
+
+/// Static description of a metric family
+#[derive(Debug)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+impl Metric {
+    pub const fn counter(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Counter,
+        }
+    }
+
+    pub const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Gauge,
+        }
+    }
+
+    pub const fn histogram(name: &'static str, help: &'static str, bounds: &'static [f64]) -> Self {
+        Metric {
+            name,
+            help,
+            kind: Kind::Histogram(bounds),
+        }
+    }
+}
+
+/// Errors returned when recording into a registry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// No free slot left for a new series
+    Full,
+    /// More than `MAX_LABELS` labels were supplied
+    TooManyLabels,
+    /// Histogram declares more than `MAX_BUCKETS` bounds
+    TooManyBuckets,
+    /// Operation does not match the metric kind (e.g. `set` on a counter)
+    KindMismatch,
+}
+
+#[derive(Clone, Copy)]
+enum Value {
+    Counter(u64),
+    Gauge(f64),
+    Histogram {
+        buckets: [u64; MAX_BUCKETS],
+        sum: f64,
+        count: u64,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct Series {
+    metric: &'static Metric,
+    labels: [Label; MAX_LABELS],
+    label_count: usize,
+    value: Value,
+}
+
+impl Series {
+    fn labels(&self) -> &[Label] {
+        &self.labels[..self.label_count]
+    }
+}
+
+/// Fixed-capacity collection of counters, gauges and histograms
+pub struct Registry<const N: usize> {
+    series: [Option<Series>; N],
+}
+
+impl<const N: usize> Registry<N> {
+    pub const fn new() -> Self {
+        Registry { series: [None; N] }
+    }
+
+    /// Increment a counter by one
+    pub fn inc(&mut self, metric: &'static Metric, labels: &[Label]) -> Result<(), Error> {
+        self.add(metric, labels, 1)
+    }
+
+    /// Increment a counter by `n`
+    pub fn add(&mut self, metric: &'static Metric, labels: &[Label], n: u64) -> Result<(), Error> {
+        match &mut self.series_mut(metric, labels)?.value {
+            Value::Counter(c) => {
+                *c = c.wrapping_add(n);
+                Ok(())
+            }
+            _ => Err(Error::KindMismatch),
+        }
+    }
+
+    /// Set a gauge to `v`
+    pub fn set(&mut self, metric: &'static Metric, labels: &[Label], v: f64) -> Result<(), Error> {
+        match &mut self.series_mut(metric, labels)?.value {
+            Value::Gauge(g) => {
+                *g = v;
+                Ok(())
+            }
+            _ => Err(Error::KindMismatch),
+        }
+    }
+
+    /// Record a single observation into a histogram
+    pub fn observe(
+        &mut self,
+        metric: &'static Metric,
+        labels: &[Label],
+        v: f64,
+    ) -> Result<(), Error> {
+        let bounds = match metric.kind {
+            Kind::Histogram(bounds) => bounds,
+            _ => return Err(Error::KindMismatch),
+        };
+        match &mut self.series_mut(metric, labels)?.value {
+            Value::Histogram {
+                buckets,
+                sum,
+                count,
+            } => {
+                // Buckets are stored non-cumulative and summed up on encoding
+                if let Some(i) = bounds.iter().position(|b| v <= *b) {
+                    buckets[i] += 1;
+                }
+                *sum += v;
+                *count += 1;
+                Ok(())
+            }
+            _ => Err(Error::KindMismatch),
+        }
+    }
+
+    // Find the series for `metric` and `labels`, creating it if it doesn't exist yet
+    fn series_mut(
+        &mut self,
+        metric: &'static Metric,
+        labels: &[Label],
+    ) -> Result<&mut Series, Error> {
+        if labels.len() > MAX_LABELS {
+            return Err(Error::TooManyLabels);
+        }
+
+        let existing = self.series.iter().position(|s| match s {
+            Some(s) => core::ptr::eq(s.metric, metric) && s.labels() == labels,
+            None => false,
+        });
+
+        let index = match existing {
+            Some(i) => i,
+            None => {
+                let free = self
+                    .series
+                    .iter()
+                    .position(|s| s.is_none())
+                    .ok_or(Error::Full)?;
+                let value = match metric.kind {
+                    Kind::Counter => Value::Counter(0),
+                    Kind::Gauge => Value::Gauge(0.0),
+                    Kind::Histogram(bounds) => {
+                        if bounds.len() > MAX_BUCKETS {
+                            return Err(Error::TooManyBuckets);
+                        }
+                        Value::Histogram {
+                            buckets: [0; MAX_BUCKETS],
+                            sum: 0.0,
+                            count: 0,
+                        }
+                    }
+                };
+                let mut series = Series {
+                    metric,
+                    labels: [("", ""); MAX_LABELS],
+                    label_count: labels.len(),
+                    value,
+                };
+                series.labels[..labels.len()].copy_from_slice(labels);
+                self.series[free] = Some(series);
+                free
+            }
+        };
+
+        Ok(self.series[index].as_mut().unwrap())
+    }
+
+    /// Write all series in the Prometheus text exposition format (version 0.0.4)
+    pub fn encode<W: Write>(&self, w: &mut W) -> fmt::Result {
+        for (i, first) in self.series.iter().enumerate() {
+            let first = match first {
+                Some(s) => s,
+                None => continue,
+            };
+
+            // Families are emitted once, at the position of their first series
+            let seen = self.series[..i]
+                .iter()
+                .flatten()
+                .any(|s| core::ptr::eq(s.metric, first.metric));
+            if seen {
+                continue;
+            }
+
+            let metric = first.metric;
+            write!(w, "# HELP {} ", metric.name)?;
+            write_escaped(w, metric.help, false)?;
+            let kind = match metric.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+                Kind::Histogram(_) => "histogram",
+            };
+            write!(w, "\n# TYPE {} {}\n", metric.name, kind)?;
+
+            for series in self.series[i..]
+                .iter()
+                .flatten()
+                .filter(|s| core::ptr::eq(s.metric, metric))
+            {
+                encode_series(w, series)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for Registry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn encode_series<W: Write>(w: &mut W, series: &Series) -> fmt::Result {
+    let name = series.metric.name;
+    match series.value {
+        Value::Counter(c) => {
+            write_labels(w, name, "", series.labels(), None)?;
+            writeln!(w, " {}", c)
+        }
+        Value::Gauge(g) => {
+            write_labels(w, name, "", series.labels(), None)?;
+            w.write_char(' ')?;
+            write_f64(w, g)?;
+            w.write_char('\n')
+        }
+        Value::Histogram {
+            buckets,
+            sum,
+            count,
+        } => {
+            let bounds = match series.metric.kind {
+                Kind::Histogram(bounds) => bounds,
+                _ => &[],
+            };
+            let mut cumulative = 0;
+            for (bound, n) in bounds.iter().zip(buckets.iter()) {
+                cumulative += n;
+                write_labels(w, name, "_bucket", series.labels(), Some(*bound))?;
+                writeln!(w, " {}", cumulative)?;
+            }
+            write_labels(w, name, "_bucket", series.labels(), Some(f64::INFINITY))?;
+            writeln!(w, " {}", count)?;
+            write_labels(w, name, "_sum", series.labels(), None)?;
+            w.write_char(' ')?;
+            write_f64(w, sum)?;
+            w.write_char('\n')?;
+            write_labels(w, name, "_count", series.labels(), None)?;
+            writeln!(w, " {}", count)
+        }
+    }
+}
+
+// Write `name{a="b",le="0.5"}`, omitting the braces when there are no labels
+fn write_labels<W: Write>(
+    w: &mut W,
+    name: &str,
+    suffix: &str,
+    labels: &[Label],
+    le: Option<f64>,
+) -> fmt::Result {
+    write!(w, "{}{}", name, suffix)?;
+    if labels.is_empty() && le.is_none() {
+        return Ok(());
+    }
+    w.write_char('{')?;
+    for (i, (key, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            w.write_char(',')?;
+        }
+        write!(w, "{}=\"", key)?;
+        write_escaped(w, value, true)?;
+        w.write_char('"')?;
+    }
+    if let Some(le) = le {
+        if !labels.is_empty() {
+            w.write_char(',')?;
+        }
+        w.write_str("le=\"")?;
+        write_f64(w, le)?;
+        w.write_char('"')?;
+    }
+    w.write_char('}')
+}
+
+// Label values escape `\`, `"` and newlines; help text only `\` and newlines
+fn write_escaped<W: Write>(w: &mut W, s: &str, quotes: bool) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '\\' => w.write_str("\\\\")?,
+            '\n' => w.write_str("\\n")?,
+            '"' if quotes => w.write_str("\\\"")?,
+            c => w.write_char(c)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_f64<W: Write>(w: &mut W, v: f64) -> fmt::Result {
+    if v.is_nan() {
+        w.write_str("NaN")
+    } else if v == f64::INFINITY {
+        w.write_str("+Inf")
+    } else if v == f64::NEG_INFINITY {
+        w.write_str("-Inf")
+    } else {
+        write!(w, "{}", v)
+    }
+}
+
+/// Registry shared by every module of the application
+#[cfg(feature = "std")]
+pub static REGISTRY: std::sync::Mutex<Registry<MAX_SERIES>> =
+    std::sync::Mutex::new(Registry::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static REQUESTS: Metric = Metric::counter("requests_total", "Served requests");
+    static HEAP: Metric = Metric::gauge("heap_bytes", "Free heap\nin \\bytes\"");
+    static RTT: Metric = Metric::histogram("rtt_seconds", "Round trip time", &[0.1, 0.5]);
+    static TOO_FINE: Metric = Metric::histogram(
+        "too_fine",
+        "More bounds than buckets",
+        &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0],
+    );
+
+    fn encode<const N: usize>(registry: &Registry<N>) -> String {
+        let mut text = String::new();
+        registry.encode(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn counters_group_series_under_one_family() {
+        let mut registry = Registry::<4>::new();
+        registry.inc(&REQUESTS, &[("path", "/")]).unwrap();
+        registry.set(&HEAP, &[], 1024.0).unwrap();
+        registry.add(&REQUESTS, &[("path", "/metrics")], 2).unwrap();
+        registry.inc(&REQUESTS, &[("path", "/")]).unwrap();
+        assert_eq!(
+            encode(&registry),
+            "# HELP requests_total Served requests\n\
+             # TYPE requests_total counter\n\
+             requests_total{path=\"/\"} 2\n\
+             requests_total{path=\"/metrics\"} 2\n\
+             # HELP heap_bytes Free heap\\nin \\\\bytes\"\n\
+             # TYPE heap_bytes gauge\n\
+             heap_bytes 1024\n"
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut registry = Registry::<1>::new();
+        registry.inc(&REQUESTS, &[("path", "a\"b\\c\nd")]).unwrap();
+        assert!(encode(&registry).contains("requests_total{path=\"a\\\"b\\\\c\\nd\"} 1\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut registry = Registry::<1>::new();
+        for v in [0.05, 0.1, 0.3, 2.0] {
+            registry.observe(&RTT, &[("host", "gw")], v).unwrap();
+        }
+        assert_eq!(
+            encode(&registry),
+            "# HELP rtt_seconds Round trip time\n\
+             # TYPE rtt_seconds histogram\n\
+             rtt_seconds_bucket{host=\"gw\",le=\"0.1\"} 2\n\
+             rtt_seconds_bucket{host=\"gw\",le=\"0.5\"} 3\n\
+             rtt_seconds_bucket{host=\"gw\",le=\"+Inf\"} 4\n\
+             rtt_seconds_sum{host=\"gw\"} 2.45\n\
+             rtt_seconds_count{host=\"gw\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn gauges_encode_special_values() {
+        let mut registry = Registry::<1>::new();
+        registry.set(&HEAP, &[], f64::NAN).unwrap();
+        assert!(encode(&registry).ends_with("heap_bytes NaN\n"));
+        registry.set(&HEAP, &[], f64::NEG_INFINITY).unwrap();
+        assert!(encode(&registry).ends_with("heap_bytes -Inf\n"));
+    }
+
+    #[test]
+    fn series_are_capped() {
+        let mut registry = Registry::<2>::new();
+        registry.inc(&REQUESTS, &[("path", "/a")]).unwrap();
+        registry.inc(&REQUESTS, &[("path", "/b")]).unwrap();
+        assert_eq!(registry.inc(&REQUESTS, &[("path", "/c")]), Err(Error::Full));
+        // Existing series keep counting
+        registry.inc(&REQUESTS, &[("path", "/a")]).unwrap();
+        assert!(encode(&registry).contains("requests_total{path=\"/a\"} 2\n"));
+        assert!(!encode(&registry).contains("/c"));
+    }
+
+    #[test]
+    fn misuse_is_rejected() {
+        let mut registry = Registry::<4>::new();
+        assert_eq!(registry.set(&REQUESTS, &[], 1.0), Err(Error::KindMismatch));
+        assert_eq!(registry.inc(&HEAP, &[]), Err(Error::KindMismatch));
+        assert_eq!(registry.observe(&HEAP, &[], 1.0), Err(Error::KindMismatch));
+        assert_eq!(
+            registry.inc(&REQUESTS, &[("a", ""), ("b", ""), ("c", ""), ("d", "")]),
+            Err(Error::TooManyLabels)
+        );
+        assert_eq!(
+            registry.observe(&TOO_FINE, &[], 1.0),
+            Err(Error::TooManyBuckets)
+        );
+    }
+}
@@ -0,0 +1,78 @@
+//! Representations of the resources shared by the HTTP and CoAP servers.
+
+/// B value of the thermistor
+const B: f64 = 3950.0;
+/// Full range voltage of the ADC in mV
+const VMAX: f64 = 2500.0;
+
+/// Convert a thermistor ADC sample (mV) to degrees Celsius, `None` when the
+/// sample is at either end of the range, i.e. the thermistor is shorted or
+/// disconnected
+pub fn temperature(sample: u16) -> Option<f64> {
+    let sample = sample as f64;
+    if sample <= 0.0 || sample >= VMAX {
+        return None;
+    }
+    Some(1. / ((1. / (VMAX / sample - 1.)).ln() / B + 1.0 / 298.15) - 273.15)
+}
+
+pub fn status_json(uptime_secs: u64, free_heap: u32) -> String {
+    format!(r#"{{"uptime":{},"free_heap":{}}}"#, uptime_secs, free_heap)
+}
+
+/// Last temperature, or `null` with an error while there is no valid reading
+pub fn sensor_json(temperature: Option<f64>) -> String {
+    match temperature {
+        Some(celsius) => format!(r#"{{"temperature":{:.2}}}"#, celsius),
+        None => r#"{"temperature":null,"error":"no thermistor reading"}"#.to_string(),
+    }
+}
+
+pub fn led_json(on: bool) -> String {
+    format!(r#"{{"led":"{}"}}"#, if on { "on" } else { "off" })
+}
+
+/// Parse a LED state written by a client (`on`/`off`, `1`/`0` or `true`/`false`)
+pub fn parse_led(body: &[u8]) -> Option<bool> {
+    let body = core::str::from_utf8(body).ok()?.trim();
+    match body.to_ascii_lowercase().as_str() {
+        "on" | "1" | "true" => Some(true),
+        "off" | "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperature_of_a_sample() {
+        // Half the range is the thermistor at its 25 °C resistance
+        let celsius = temperature(1250).unwrap();
+        assert!((celsius - 25.0).abs() < 1e-9, "{}", celsius);
+        // A lower voltage across the fixed resistor is a warmer thermistor
+        assert!(temperature(1000).unwrap() > temperature(1500).unwrap());
+    }
+
+    #[test]
+    fn samples_at_the_ends_of_the_range_are_invalid() {
+        assert_eq!(temperature(0), None);
+        assert_eq!(temperature(2500), None);
+        assert_eq!(temperature(u16::MAX), None);
+        assert_eq!(
+            sensor_json(None),
+            r#"{"temperature":null,"error":"no thermistor reading"}"#
+        );
+        assert_eq!(sensor_json(Some(21.456)), r#"{"temperature":21.46}"#);
+    }
+
+    #[test]
+    fn led_representations() {
+        assert_eq!(led_json(true), r#"{"led":"on"}"#);
+        assert_eq!(parse_led(b" ON\n"), Some(true));
+        assert_eq!(parse_led(b"0"), Some(false));
+        assert_eq!(parse_led(b"maybe"), None);
+        assert_eq!(parse_led(&[0xFF]), None);
+    }
+}
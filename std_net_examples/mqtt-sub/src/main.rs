@@ -9,6 +9,8 @@ For notifications on similar examples and more, subscribe to newsletter here:
 https://www.theembeddedrustacean.com/subscribe
 */
 
+mod logger;
+
 use anyhow;
 use embedded_svc::mqtt::client::Event;
 use embedded_svc::mqtt::client::QoS;
@@ -18,13 +20,22 @@ use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, LevelFilter};
+use logger::{Logger, SyslogConfig};
 use std::{thread::sleep, time::Duration};
 
+// Collector receiving RFC 5424 syslog messages over UDP
+const SYSLOG_COLLECTOR: &str = "192.168.1.100:514";
+const HOSTNAME: &str = "esp32c3";
+
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_sys::link_patches();
 
+    // Install Logger
+    let logger = logger::init(Logger::new(LevelFilter::Info))?;
+
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
@@ -51,14 +62,22 @@ fn main() -> anyhow::Result<()> {
     // Wait until the network interface is up
     wifi.wait_netif_up()?;
 
+    // Forward logs to the syslog collector now that the network is up
+    logger.attach_syslog(SyslogConfig {
+        collector: SYSLOG_COLLECTOR.parse()?,
+        hostname: HOSTNAME.into(),
+        app_name: env!("CARGO_PKG_NAME"),
+        facility: logger::FACILITY_USER,
+    })?;
+
     // Print Out Wifi Connection Configuration
     while !wifi.is_connected().unwrap() {
         // Get and print connection configuration
         let config = wifi.get_configuration().unwrap();
-        println!("Waiting for station {:?}", config);
+        info!("Waiting for station {:?}", config);
     }
 
-    println!("Wifi Connected");
+    info!("Wifi Connected");
 
     // Set up handle for MQTT Config
     let mqtt_config = MqttClientConfiguration::default();
@@ -69,14 +88,14 @@ fn main() -> anyhow::Result<()> {
         &mqtt_config,
         move |message_event| {
             match message_event.as_ref().unwrap() {
-                Event::Connected(_) => println!("Connected"),
-                Event::Subscribed(id) => println!("Subscribed to {} id", id),
+                Event::Connected(_) => info!("Connected"),
+                Event::Subscribed(id) => info!("Subscribed to {} id", id),
                 Event::Received(msg) => {
                     if msg.data() != [] {
-                        println!("Recieved {}", std::str::from_utf8(msg.data()).unwrap())
+                        info!("Recieved {}", std::str::from_utf8(msg.data()).unwrap())
                     }
                 }
-                _ => println!("{:?}", message_event.as_ref().unwrap()),
+                _ => info!("{:?}", message_event.as_ref().unwrap()),
             };
         },
     )?;
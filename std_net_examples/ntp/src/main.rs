@@ -1,3 +1,5 @@
+mod logger;
+
 use anyhow;
 use chrono::{DateTime, Utc};
 use embedded_svc::wifi::{AuthMethod, ClientConfiguration, Configuration};
@@ -7,13 +9,22 @@ use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sntp::{EspSntp, SyncStatus};
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use log::{info, LevelFilter};
+use logger::{Logger, SyslogConfig};
 use std::time::SystemTime;
 
+// Collector receiving RFC 5424 syslog messages over UDP
+const SYSLOG_COLLECTOR: &str = "192.168.1.100:514";
+const HOSTNAME: &str = "esp32c3";
+
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_sys::link_patches();
 
+    // Install Logger
+    let logger = logger::init(Logger::new(LevelFilter::Info))?;
+
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
@@ -40,22 +51,30 @@ fn main() -> anyhow::Result<()> {
     // Wait until the network interface is up
     wifi.wait_netif_up()?;
 
+    // Forward logs to the syslog collector now that the network is up
+    logger.attach_syslog(SyslogConfig {
+        collector: SYSLOG_COLLECTOR.parse()?,
+        hostname: HOSTNAME.into(),
+        app_name: env!("CARGO_PKG_NAME"),
+        facility: logger::FACILITY_USER,
+    })?;
+
     // Print Out Wifi Connection Configuration
     while !wifi.is_connected().unwrap() {
         // Get and print connection configuration
         let config = wifi.get_configuration().unwrap();
-        println!("Waiting for station {:?}", config);
+        info!("Waiting for station {:?}", config);
     }
 
-    println!("Wifi Connected");
+    info!("Wifi Connected");
 
     // Create Handle and Configure SNTP
     let ntp = EspSntp::new_default().unwrap();
 
     // Synchronize NTP
-    println!("Synchronizing with NTP Server");
+    info!("Synchronizing with NTP Server");
     while ntp.get_sync_status() != SyncStatus::Completed {}
-    println!("Time Sync Completed");
+    info!("Time Sync Completed");
 
     loop {
         // Obtain System Time
@@ -65,7 +84,7 @@ fn main() -> anyhow::Result<()> {
         // Format Time String
         let formatted = format!("{}", dt_now_utc.format("%d/%m/%Y %H:%M:%S"));
         // Print Time
-        println!("{}", formatted);
+        info!("{}", formatted);
         // Delay
         FreeRtos::delay_ms(1000);
     }
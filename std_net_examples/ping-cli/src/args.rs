@@ -0,0 +1,539 @@
+//! Typed arguments for menu callbacks.
+//!
+//! Each command declares its parameters as a table of [`Arg`]s naming the
+//! `menu::Parameter`s of its item. [`parse`] checks every argument against
+//! the table, so callbacks only see valid values and bad input is reported
+//! instead of panicking the device.
+
+use menu::{argument_finder, Item, ItemType, Parameter};
+use std::fmt::{self, Write};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::time::Duration;
+
+/// What an argument accepts
+#[derive(Clone, Copy, Debug)]
+pub enum Kind {
+    /// Integer in an inclusive range, decimal or `0x` hexadecimal
+    Int(i64, i64),
+    /// Duration in an inclusive range, with a unit of `us`, `ms`, `s`, `m`
+    /// or `h`. Bare numbers are seconds.
+    Duration(Duration, Duration),
+    /// IP address or a hostname, resolved when parsed
+    Host,
+    /// One of a fixed set of words
+    Choice(&'static [&'static str]),
+    /// `--name` without a value
+    Flag,
+    /// Any text, checked by the command itself
+    Text,
+}
+
+/// Declaration of one argument
+#[derive(Clone, Copy, Debug)]
+pub struct Arg {
+    /// `parameter_name` of the matching `menu::Parameter`
+    pub name: &'static str,
+    pub kind: Kind,
+    /// Used when the argument is not given
+    pub default: Option<&'static str>,
+}
+
+impl Arg {
+    pub const fn new(name: &'static str, kind: Kind) -> Self {
+        Arg {
+            name,
+            kind,
+            default: None,
+        }
+    }
+
+    pub const fn default(self, value: &'static str) -> Self {
+        Arg {
+            default: Some(value),
+            ..self
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    Int(i64),
+    Duration(Duration),
+    /// Hostname as given and the address it resolved to
+    Host(&'a str, IpAddr),
+    Flag(bool),
+    Text(&'a str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArgError {
+    /// A mandatory argument is absent
+    Missing(&'static str),
+    /// The value does not match the declared kind
+    Invalid {
+        name: &'static str,
+        value: String,
+        expected: String,
+    },
+    /// The table names a parameter the menu item does not have
+    Undeclared(&'static str),
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgError::Missing(name) => write!(f, "Missing argument <{}>", name),
+            ArgError::Invalid {
+                name,
+                value,
+                expected,
+            } => write!(f, "Invalid {} '{}': expected {}", name, value, expected),
+            ArgError::Undeclared(name) => write!(f, "Unknown argument {}", name),
+        }
+    }
+}
+
+/// Arguments of one invocation, validated against their declarations
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parsed<'a> {
+    values: Vec<(&'static str, Value<'a>)>,
+}
+
+impl<'a> Parsed<'a> {
+    /// Value of `name`, `None` when it was neither given nor has a default
+    pub fn get<T: FromValue<'a>>(&self, name: &str) -> Option<T> {
+        self.values
+            .iter()
+            .find(|(n, _)| *n == name)
+            .and_then(|(_, value)| T::from_value(value))
+    }
+
+    /// Value of an argument that is mandatory or has a default
+    pub fn value<T: FromValue<'a>>(&self, name: &str) -> T {
+        self.get(name)
+            .unwrap_or_else(|| panic!("argument {} has no value of this type", name))
+    }
+}
+
+/// Conversion out of a parsed [`Value`]
+pub trait FromValue<'a>: Sized {
+    fn from_value(value: &Value<'a>) -> Option<Self>;
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(impl<'a> FromValue<'a> for $t {
+            fn from_value(value: &Value<'a>) -> Option<Self> {
+                match value {
+                    Value::Int(n) => <$t>::try_from(*n).ok(),
+                    _ => None,
+                }
+            }
+        })*
+    };
+}
+
+from_int!(i64, u64, u32, u16, u8, usize);
+
+impl<'a> FromValue<'a> for Duration {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Duration(d) => Some(*d),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for IpAddr {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Host(_, ip) => Some(*ip),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for bool {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Flag(set) => Some(*set),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromValue<'a> for &'a str {
+    fn from_value(value: &Value<'a>) -> Option<Self> {
+        match value {
+            Value::Text(text) | Value::Host(text, _) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+/// Check `args` against `specs`
+pub fn parse<'a, T>(
+    item: &'a Item<'a, T>,
+    args: &'a [&'a str],
+    specs: &[Arg],
+) -> Result<Parsed<'a>, ArgError> {
+    let mut values = Vec::with_capacity(specs.len());
+    for spec in specs {
+        let given =
+            argument_finder(item, args, spec.name).map_err(|_| ArgError::Undeclared(spec.name))?;
+        let value = match (given, spec.kind) {
+            (given, Kind::Flag) => Value::Flag(given.is_some()),
+            (Some(text), kind) => parse_value(spec.name, text, kind)?,
+            (None, kind) => match spec.default {
+                Some(text) => parse_value(spec.name, text, kind)?,
+                None if is_mandatory(item, spec.name) => return Err(ArgError::Missing(spec.name)),
+                None => continue,
+            },
+        };
+        values.push((spec.name, value));
+    }
+    Ok(Parsed { values })
+}
+
+/// One line summary such as `Usage: ping <hostname/IP> [--count=<cnt>]`
+pub fn usage<T>(item: &Item<T>) -> String {
+    let mut out = format!("Usage: {}", item.command);
+    if let ItemType::Callback { parameters, .. } = &item.item_type {
+        for parameter in parameters.iter() {
+            match parameter {
+                Parameter::Mandatory { parameter_name, .. } => {
+                    write!(out, " <{}>", parameter_name).unwrap()
+                }
+                Parameter::Optional { parameter_name, .. } => {
+                    write!(out, " [{}]", parameter_name).unwrap()
+                }
+                Parameter::Named { parameter_name, .. } => {
+                    write!(out, " [--{}]", parameter_name).unwrap()
+                }
+                Parameter::NamedValue {
+                    parameter_name,
+                    argument_name,
+                    ..
+                } => write!(out, " [--{}=<{}>]", parameter_name, argument_name).unwrap(),
+            }
+        }
+    }
+    out
+}
+
+fn is_mandatory<T>(item: &Item<T>, name: &str) -> bool {
+    match &item.item_type {
+        ItemType::Callback { parameters, .. } => parameters.iter().any(|parameter| {
+            matches!(parameter, Parameter::Mandatory { parameter_name, .. } if *parameter_name == name)
+        }),
+        _ => false,
+    }
+}
+
+/// Parse `text` as `kind`, `name` is only used in the error
+pub fn parse_value<'a>(
+    name: &'static str,
+    text: &'a str,
+    kind: Kind,
+) -> Result<Value<'a>, ArgError> {
+    let invalid = |expected: String| ArgError::Invalid {
+        name,
+        value: text.into(),
+        expected,
+    };
+    match kind {
+        Kind::Int(min, max) => match parse_int(text) {
+            Some(n) if (min..=max).contains(&n) => Ok(Value::Int(n)),
+            _ => Err(invalid(format!("an integer from {} to {}", min, max))),
+        },
+        Kind::Duration(min, max) => match parse_duration(text) {
+            Some(d) if (min..=max).contains(&d) => Ok(Value::Duration(d)),
+            _ => Err(invalid(format!(
+                "a duration from {:?} to {:?} (e.g. 500ms, 2s)",
+                min, max
+            ))),
+        },
+        Kind::Host => resolve(text)
+            .map(|ip| Value::Host(text, ip))
+            .ok_or_else(|| invalid("an IP address or a known hostname".into())),
+        Kind::Choice(choices) => match choices.iter().find(|choice| **choice == text) {
+            Some(choice) => Ok(Value::Text(choice)),
+            None => Err(invalid(format!("one of {}", choices.join(", ")))),
+        },
+        Kind::Flag => Ok(Value::Flag(true)),
+        Kind::Text => Ok(Value::Text(text)),
+    }
+}
+
+// A single leading minus is the only sign taken, `from_str_radix` alone
+// would also let `--5`, `+5` and `0x-5` through
+fn parse_int(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let (radix, digits) = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => (16, hex),
+        None => (10, digits),
+    };
+    if !digits.starts_with(|c: char| c.is_digit(radix)) {
+        return None;
+    }
+    let n = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -n } else { n })
+}
+
+/// Parse `500ms`, `1.5s`, `2m` and the like, bare numbers are seconds
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+    let scale = match unit {
+        "us" => 1e-6,
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(number * scale).ok()
+}
+
+// Literal addresses are used as they are, hostnames go through DNS and
+// IPv4 results are preferred
+fn resolve(host: &str) -> Option<IpAddr> {
+    if let Ok(ip) = host.parse() {
+        return Some(ip);
+    }
+    let addrs: Vec<_> = (host, 0).to_socket_addrs().ok()?.collect();
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .or_else(|| addrs.first())
+        .map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn noop(_menu: &menu::Menu<()>, _item: &Item<()>, _args: &[&str], _context: &mut ()) {}
+
+    const ITEM: Item<()> = Item {
+        item_type: ItemType::Callback {
+            function: noop,
+            parameters: &[
+                Parameter::Mandatory {
+                    parameter_name: "hostname/IP",
+                    help: None,
+                },
+                Parameter::NamedValue {
+                    parameter_name: "count",
+                    argument_name: "cnt",
+                    help: None,
+                },
+                Parameter::NamedValue {
+                    parameter_name: "interval",
+                    argument_name: "duration",
+                    help: None,
+                },
+                Parameter::NamedValue {
+                    parameter_name: "format",
+                    argument_name: "text|json",
+                    help: None,
+                },
+                Parameter::Named {
+                    parameter_name: "json",
+                    help: None,
+                },
+            ],
+        },
+        command: "ping",
+        help: None,
+    };
+
+    const SPECS: &[Arg] = &[
+        Arg::new("hostname/IP", Kind::Host),
+        Arg::new("count", Kind::Int(1, 1000)).default("4"),
+        Arg::new(
+            "interval",
+            Kind::Duration(Duration::from_millis(100), Duration::from_secs(60)),
+        ),
+        Arg::new("format", Kind::Choice(&["text", "json"])).default("text"),
+        Arg::new("json", Kind::Flag),
+    ];
+
+    fn invalid(name: &'static str, value: &str, expected: &str) -> ArgError {
+        ArgError::Invalid {
+            name,
+            value: value.into(),
+            expected: expected.into(),
+        }
+    }
+
+    #[test]
+    fn integers_in_decimal_or_hex() {
+        let kind = Kind::Int(-10, 0x77);
+        assert_eq!(parse_value("n", "0x3c", kind), Ok(Value::Int(0x3c)));
+        assert_eq!(parse_value("n", "0X3C", kind), Ok(Value::Int(0x3c)));
+        assert_eq!(parse_value("n", "60", kind), Ok(Value::Int(60)));
+        assert_eq!(parse_value("n", "-0xa", kind), Ok(Value::Int(-10)));
+        assert_eq!(parse_value("n", "-7", kind), Ok(Value::Int(-7)));
+        let expected = "an integer from -10 to 119";
+        for text in [
+            "0x78", "-11", "3c", "", "-", "0x", "--5", "+5", "-+5", "0x-5", "0x+5", "-0x-5", " 5",
+            "5 ",
+        ] {
+            assert_eq!(
+                parse_value("n", text, kind),
+                Err(invalid("n", text, expected)),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn durations_with_units() {
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_duration("250us"), Some(Duration::from_micros(250)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("2min"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("1d"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration("-1s"), None);
+        let kind = Kind::Duration(Duration::from_millis(100), Duration::from_secs(60));
+        assert_eq!(
+            parse_value("time", "50ms", kind),
+            Err(invalid(
+                "time",
+                "50ms",
+                "a duration from 100ms to 60s (e.g. 500ms, 2s)"
+            ))
+        );
+    }
+
+    #[test]
+    fn hosts_are_addresses_or_resolved_names() {
+        let host = |text| parse_value("host", text, Kind::Host);
+        assert_eq!(
+            host("192.168.1.1"),
+            Ok(Value::Host(
+                "192.168.1.1",
+                Ipv4Addr::new(192, 168, 1, 1).into()
+            ))
+        );
+        assert_eq!(
+            host("::1"),
+            Ok(Value::Host("::1", Ipv6Addr::LOCALHOST.into()))
+        );
+        // IPv4 is preferred where a name has both
+        assert_eq!(
+            host("localhost"),
+            Ok(Value::Host("localhost", Ipv4Addr::LOCALHOST.into()))
+        );
+        let expected = "an IP address or a known hostname";
+        for text in ["", "192.168.1.256", "no such host"] {
+            assert_eq!(
+                host(text),
+                Err(invalid("host", text, expected)),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn choices_flags_and_text() {
+        let kind = Kind::Choice(&["text", "json"]);
+        assert_eq!(parse_value("format", "json", kind), Ok(Value::Text("json")));
+        assert_eq!(
+            parse_value("format", "JSON", kind),
+            Err(invalid("format", "JSON", "one of text, json"))
+        );
+        assert_eq!(parse_value("json", "", Kind::Flag), Ok(Value::Flag(true)));
+        assert_eq!(
+            parse_value("name", "any thing", Kind::Text),
+            Ok(Value::Text("any thing"))
+        );
+    }
+
+    #[test]
+    fn parse_against_a_table() {
+        let args = [
+            "127.0.0.1",
+            "--count=0x10",
+            "--interval=2s",
+            "--format=json",
+            "--json",
+        ];
+        let parsed = parse(&ITEM, &args, SPECS).unwrap();
+        assert_eq!(
+            parsed.value::<IpAddr>("hostname/IP"),
+            IpAddr::from(Ipv4Addr::LOCALHOST)
+        );
+        assert_eq!(parsed.value::<&str>("hostname/IP"), "127.0.0.1");
+        assert_eq!(parsed.value::<u32>("count"), 16);
+        assert_eq!(parsed.value::<Duration>("interval"), Duration::from_secs(2));
+        assert_eq!(parsed.value::<&str>("format"), "json");
+        assert!(parsed.value::<bool>("json"));
+        // Values convert only to types they fit
+        assert_eq!(parsed.get::<&str>("count"), None);
+        assert_eq!(parsed.get::<u8>("interval"), None);
+    }
+
+    #[test]
+    fn defaults_and_absent_optionals() {
+        let args = ["127.0.0.1"];
+        let parsed = parse(&ITEM, &args, SPECS).unwrap();
+        assert_eq!(parsed.value::<u32>("count"), 4);
+        assert_eq!(parsed.get::<Duration>("interval"), None);
+        assert_eq!(parsed.value::<&str>("format"), "text");
+        assert!(!parsed.value::<bool>("json"));
+    }
+
+    #[test]
+    fn table_errors() {
+        let args: [&str; 0] = [];
+        assert_eq!(
+            parse(&ITEM, &args, SPECS),
+            Err(ArgError::Missing("hostname/IP"))
+        );
+        let args = ["127.0.0.1", "--count=--5"];
+        assert_eq!(
+            parse(&ITEM, &args, SPECS),
+            Err(invalid("count", "--5", "an integer from 1 to 1000"))
+        );
+        let args = ["127.0.0.1"];
+        assert_eq!(
+            parse(&ITEM, &args, &[Arg::new("size", Kind::Int(1, 10))]),
+            Err(ArgError::Undeclared("size"))
+        );
+        assert_eq!(
+            ArgError::Missing("ssid").to_string(),
+            "Missing argument <ssid>"
+        );
+        assert_eq!(
+            invalid("count", "0", "an integer from 1 to 1000").to_string(),
+            "Invalid count '0': expected an integer from 1 to 1000"
+        );
+    }
+
+    #[test]
+    fn usage_lists_every_parameter() {
+        assert_eq!(
+            usage(&ITEM),
+            "Usage: ping <hostname/IP> [--count=<cnt>] [--interval=<duration>] \
+             [--format=<text|json>] [--json]"
+        );
+    }
+}
@@ -0,0 +1,42 @@
+//! Back-ends the commands run against: the ESP-IDF drivers on the chip,
+//! simulations when the CLI runs on a PC. They are installed once at startup
+//! and shared by every CLI session.
+
+use crate::ping::Pinger;
+use crate::sysinfo::System;
+use crate::wifi::Wifi;
+use std::sync::Mutex;
+
+static PINGER: Mutex<Option<Box<dyn Pinger>>> = Mutex::new(None);
+static WIFI: Mutex<Option<Box<dyn Wifi>>> = Mutex::new(None);
+static SYSTEM: Mutex<Option<Box<dyn System>>> = Mutex::new(None);
+
+/// Make the back-ends available to the CLI sessions
+pub fn install(pinger: Box<dyn Pinger>, wifi: Box<dyn Wifi>, system: Box<dyn System>) {
+    *PINGER.lock().unwrap() = Some(pinger);
+    *WIFI.lock().unwrap() = Some(wifi);
+    *SYSTEM.lock().unwrap() = Some(system);
+}
+
+/// Run `f` on the pinger, `None` if none was installed
+pub fn pinger<R>(f: impl FnOnce(&mut dyn Pinger) -> R) -> Option<R> {
+    PINGER
+        .lock()
+        .ok()?
+        .as_mut()
+        .map(|pinger| f(pinger.as_mut()))
+}
+
+/// Run `f` on the Wi-Fi station, `None` if none was installed
+pub fn wifi<R>(f: impl FnOnce(&mut dyn Wifi) -> R) -> Option<R> {
+    WIFI.lock().ok()?.as_mut().map(|wifi| f(wifi.as_mut()))
+}
+
+/// Run `f` on the system, `None` if none was installed
+pub fn system<R>(f: impl FnOnce(&mut dyn System) -> R) -> Option<R> {
+    SYSTEM
+        .lock()
+        .ok()?
+        .as_mut()
+        .map(|system| f(system.as_mut()))
+}
@@ -0,0 +1,197 @@
+//! Nordic UART Service exposing the CLI menu over Bluetooth LE, for phone
+//! apps such as nRF Connect or Serial Bluetooth Terminal.
+//!
+//! One central at a time gets a session. It starts once the central
+//! subscribes to TX over a bonded link, encrypted after pairing with the
+//! passkey on the console, and ends when the central disconnects. The menu
+//! can join networks and reboot, so an unpaired phone gets nowhere.
+
+use crate::console::{Context, Transport};
+use crate::nus::{self, Input};
+use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
+use esp32_nimble::{uuid128, BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEError};
+use esp32_nimble::{BLEConnDesc, BleUuid, NimbleProperties};
+use menu::{Menu, Runner};
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+const SERVICE_UUID: BleUuid = uuid128!("6E400001-B5A3-F393-E0A9-E50E24DCCA9E");
+// Written by the central
+const RX_UUID: BleUuid = uuid128!("6E400002-B5A3-F393-E0A9-E50E24DCCA9E");
+// Notified to the central
+const TX_UUID: BleUuid = uuid128!("6E400003-B5A3-F393-E0A9-E50E24DCCA9E");
+
+/// Input held until the CLI reads it, larger writes are refused
+const RX_CAPACITY: usize = 512;
+/// ATT error refusing writes over a link that is not bonded
+const ATT_INSUFFICIENT_AUTHENTICATION: u8 = 0x05;
+
+struct Link {
+    conn_handle: Option<u16>,
+    mtu: u16,
+    subscribed: bool,
+    // Encrypted, authenticated and bonded
+    secure: bool,
+    input: Input,
+}
+
+static LINK: Mutex<Link> = Mutex::new(Link {
+    conn_handle: None,
+    mtu: nus::DEFAULT_MTU,
+    subscribed: false,
+    secure: false,
+    input: Input::new(RX_CAPACITY),
+});
+// Signalled whenever LINK changes
+static CHANGED: Condvar = Condvar::new();
+
+fn link() -> MutexGuard<'static, Link> {
+    LINK.lock().unwrap()
+}
+
+/// NUS connection used as a CLI transport
+pub struct BleTransport {
+    conn_handle: u16,
+    tx: Arc<NimbleMutex<BLECharacteristic>>,
+}
+
+impl Transport for BleTransport {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut link = link();
+        loop {
+            if link.conn_handle != Some(self.conn_handle) {
+                return Err(ErrorKind::ConnectionAborted.into());
+            }
+            if let Some(byte) = link.input.pop() {
+                return Ok(byte);
+            }
+            link = CHANGED.wait(link).unwrap();
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for fragment in nus::fragments(bytes, link().mtu) {
+            let link = link();
+            if link.conn_handle != Some(self.conn_handle) || !link.subscribed {
+                return Err(ErrorKind::ConnectionAborted.into());
+            }
+            drop(link);
+            self.tx.lock().set_value(fragment).notify();
+            std::thread::sleep(nus::NOTIFY_PACING);
+        }
+        Ok(())
+    }
+}
+
+/// Advertise as `name` and run `menu` for every central that subscribes.
+/// Next to the flags and the 128-bit service UUID the advertisement has room
+/// for a name of 8 characters.
+pub fn serve(name: &str, menu: fn() -> Menu<'static, Context>) -> Result<(), BLEError> {
+    let ble_device = BLEDevice::take();
+    let server = ble_device.get_server();
+
+    server.on_connect(|_server, desc| {
+        let mut link = link();
+        link.conn_handle = Some(desc.conn_handle());
+        link.mtu = desc.mtu();
+        link.subscribed = false;
+        link.secure = secure(desc);
+        link.input.clear();
+        CHANGED.notify_all();
+    });
+    server.on_disconnect(|_desc, _reason| {
+        let mut link = link();
+        link.conn_handle = None;
+        link.subscribed = false;
+        link.secure = false;
+        CHANGED.notify_all();
+    });
+
+    let service = server.create_service(SERVICE_UUID);
+    let rx = service.lock().create_characteristic(
+        RX_UUID,
+        NimbleProperties::WRITE
+            | NimbleProperties::WRITE_NO_RSP
+            | NimbleProperties::WRITE_ENC
+            | NimbleProperties::WRITE_AUTHEN,
+    );
+    rx.lock().on_write(|args| {
+        let mut link = link();
+        // The MTU exchange and pairing happen after connecting, pick up
+        // their results here
+        link.mtu = args.desc().mtu();
+        link.secure = secure(args.desc());
+        if !link.secure {
+            args.reject_with_error_code(ATT_INSUFFICIENT_AUTHENTICATION);
+            return;
+        }
+        match link.input.push(args.recv_data()) {
+            Ok(()) => CHANGED.notify_all(),
+            Err(code) => args.reject_with_error_code(code),
+        }
+    });
+    let tx = service
+        .lock()
+        .create_characteristic(TX_UUID, NimbleProperties::NOTIFY);
+    tx.lock()
+        .on_subscribe(|_characteristic, desc, subscription| {
+            let mut link = link();
+            link.mtu = desc.mtu();
+            link.subscribed = !subscription.is_empty();
+            link.secure = secure(desc);
+            CHANGED.notify_all();
+        });
+
+    let advertiser = ble_device.get_advertising();
+    advertiser.lock().set_data(
+        BLEAdvertisementData::new()
+            .name(name)
+            .add_service_uuid(SERVICE_UUID),
+    )?;
+    advertiser.lock().start()?;
+
+    loop {
+        let conn_handle = wait_for_subscriber();
+        session(conn_handle, tx.clone(), menu);
+        // Hang up on 'exit', NimBLE resumes advertising once disconnected
+        if link().conn_handle == Some(conn_handle) {
+            server.disconnect(conn_handle)?;
+        }
+    }
+}
+
+// Whether `desc` is a link a session may run over
+fn secure(desc: &BLEConnDesc) -> bool {
+    desc.encrypted() && desc.authenticated() && desc.bonded()
+}
+
+// Block until a connected central subscribes to TX over a secure link
+fn wait_for_subscriber() -> u16 {
+    let mut link = link();
+    loop {
+        match link.conn_handle {
+            Some(conn_handle) if link.subscribed && link.secure => return conn_handle,
+            _ => link = CHANGED.wait(link).unwrap(),
+        }
+    }
+}
+
+fn session(
+    conn_handle: u16,
+    tx: Arc<NimbleMutex<BLECharacteristic>>,
+    menu: fn() -> Menu<'static, Context>,
+) {
+    let transport = BleTransport { conn_handle, tx };
+
+    // Create a buffer to store CLI input
+    let mut clibuf = [0u8; 64];
+    // Instantiate CLI runner with root menu, buffer, and BLE connection
+    let mut r = Runner::new(menu(), &mut clibuf, Context::new(Box::new(transport)));
+
+    while let Ok(byte) = r.context.read_byte() {
+        r.input_byte(byte);
+        if r.context.is_closed() {
+            break;
+        }
+    }
+}
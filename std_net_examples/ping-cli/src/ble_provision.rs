@@ -0,0 +1,192 @@
+//! Wi-Fi provisioning over Bluetooth LE, for units set up from a phone
+//! rather than a console or captive portal. The phone writes the SSID and
+//! password and asks to connect, the Wi-Fi back-end joins the network and
+//! saves it to NVS just like `wifi connect`.
+//!
+//! Every write needs an encrypted link, NimBLE pairs on the first one as
+//! `main` configured the device.
+
+use crate::backend;
+use crate::nus;
+use crate::provision::{self, Command, Rejection, State};
+use crate::wifi::Network;
+use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
+use esp32_nimble::{uuid128, BLECharacteristic, BLEDevice, BleUuid, NimbleProperties};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SERVICE_UUID: BleUuid = uuid128!("c0f1b000-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+// Commands written by the phone
+const CONTROL_UUID: BleUuid = uuid128!("c0f1b001-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+// Fragments of the scan results, notified
+const NETWORKS_UUID: BleUuid = uuid128!("c0f1b002-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+const SSID_UUID: BleUuid = uuid128!("c0f1b003-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+// Write only, never read back
+const PASSWORD_UUID: BleUuid = uuid128!("c0f1b004-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+// Connection state, read and notified
+const STATE_UUID: BleUuid = uuid128!("c0f1b005-7b3e-4d2a-9f1e-5a8b6c2d4e10");
+
+/// How often the station is checked for a dropped or new connection
+const STATE_POLL: Duration = Duration::from_secs(2);
+
+// Written by the phone, taken by the worker
+#[derive(Default)]
+struct Pending {
+    ssid: String,
+    password: String,
+    mtu: u16,
+}
+
+/// Runs the commands of the service, which take too long for the NimBLE
+/// task that receives them
+pub struct Provisioner {
+    commands: Receiver<Command>,
+    pending: Arc<Mutex<Pending>>,
+    networks: Arc<NimbleMutex<BLECharacteristic>>,
+    state: Arc<NimbleMutex<BLECharacteristic>>,
+}
+
+/// Add the provisioning service to the BLE server. Has to be called before
+/// the server starts advertising.
+pub fn create() -> Provisioner {
+    let ble_device = BLEDevice::take();
+    let service = ble_device.get_server().create_service(SERVICE_UUID);
+    let pending = Arc::new(Mutex::new(Pending {
+        mtu: nus::DEFAULT_MTU,
+        ..Default::default()
+    }));
+    let (sender, commands) = mpsc::channel();
+
+    let control = service.lock().create_characteristic(
+        CONTROL_UUID,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_ENC,
+    );
+    let control_pending = pending.clone();
+    control.lock().on_write(move |args| {
+        let mut pending = control_pending.lock().unwrap();
+        pending.mtu = args.desc().mtu();
+        match Command::parse(args.recv_data()) {
+            Some(Command::Connect) if pending.ssid.is_empty() => {
+                args.reject_with_error_code(Rejection::Value.att_error())
+            }
+            // Only fails once the worker is gone
+            Some(command) => {
+                let _ = sender.send(command);
+            }
+            None => args.reject_with_error_code(Rejection::Value.att_error()),
+        }
+    });
+
+    let networks = service
+        .lock()
+        .create_characteristic(NETWORKS_UUID, NimbleProperties::NOTIFY);
+
+    let ssid = service.lock().create_characteristic(
+        SSID_UUID,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_ENC,
+    );
+    let ssid_pending = pending.clone();
+    ssid.lock()
+        .on_write(move |args| match provision::ssid(args.recv_data()) {
+            Ok(ssid) => ssid_pending.lock().unwrap().ssid = ssid.to_string(),
+            Err(rejection) => args.reject_with_error_code(rejection.att_error()),
+        });
+
+    let password = service.lock().create_characteristic(
+        PASSWORD_UUID,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_ENC,
+    );
+    let password_pending = pending.clone();
+    password
+        .lock()
+        .on_write(move |args| match provision::password(args.recv_data()) {
+            Ok(password) => password_pending.lock().unwrap().password = password.to_string(),
+            Err(rejection) => args.reject_with_error_code(rejection.att_error()),
+        });
+
+    let state = service.lock().create_characteristic(
+        STATE_UUID,
+        NimbleProperties::READ | NimbleProperties::READ_ENC | NimbleProperties::NOTIFY,
+    );
+    state.lock().set_value(&State::Disconnected.encode());
+
+    Provisioner {
+        commands,
+        pending,
+        networks,
+        state,
+    }
+}
+
+impl Provisioner {
+    /// Run commands as they arrive and keep the state characteristic up to
+    /// date, for as long as the service exists
+    pub fn run(self) {
+        let mut state = State::Disconnected;
+        let mut scanned: Vec<Network> = Vec::new();
+        loop {
+            match self.commands.recv_timeout(STATE_POLL) {
+                Ok(Command::Scan) => match backend::wifi(|wifi| wifi.scan()) {
+                    Some(Ok(networks)) => {
+                        let mtu = self.pending.lock().unwrap().mtu;
+                        let message = provision::encode_networks(&networks);
+                        for fragment in provision::fragments(&message, mtu) {
+                            self.networks.lock().set_value(&fragment).notify();
+                            std::thread::sleep(nus::NOTIFY_PACING);
+                        }
+                        scanned = networks;
+                    }
+                    Some(Err(e)) => println!("Provisioning scan failed: {}", e),
+                    None => println!("Provisioning scan failed: Wifi is not available"),
+                },
+                Ok(Command::Connect) => {
+                    // The password is not kept once handed over
+                    let (ssid, password) = {
+                        let mut pending = self.pending.lock().unwrap();
+                        (pending.ssid.clone(), std::mem::take(&mut pending.password))
+                    };
+                    self.publish(&mut state, State::Connecting);
+                    let auth = provision::auth(&ssid, &password, &scanned);
+                    match backend::wifi(|wifi| wifi.connect(&ssid, &password, auth)) {
+                        Some(Ok(())) => println!("Provisioned Wifi {}", ssid),
+                        Some(Err(e)) => {
+                            println!("Provisioning connect failed: {}", e);
+                            self.publish(&mut state, State::Failed);
+                        }
+                        None => self.publish(&mut state, State::Failed),
+                    }
+                }
+                Ok(Command::Forget) => {
+                    if let Some(Err(e)) = backend::wifi(|wifi| wifi.forget()) {
+                        println!("Provisioning forget failed: {}", e);
+                    }
+                    // Not a failure any more, the network is gone on purpose
+                    self.publish(&mut state, State::Disconnected);
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            // A failed connect stays reported until the station joins a network
+            let status = backend::wifi(|wifi| wifi.status()).flatten();
+            let current = match status {
+                Some(status) => State::Connected {
+                    ssid: status.ssid,
+                    ip: status.ip,
+                },
+                None if state == State::Failed => State::Failed,
+                None => State::Disconnected,
+            };
+            self.publish(&mut state, current);
+        }
+    }
+
+    // Set and notify the state when it changed
+    fn publish(&self, state: &mut State, new: State) {
+        if *state != new {
+            self.state.lock().set_value(&new.encode()).notify();
+            *state = new;
+        }
+    }
+}
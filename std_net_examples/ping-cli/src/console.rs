@@ -0,0 +1,121 @@
+//! CLI context shared by every transport the menu can run on (UART, telnet,
+//! and stdin/stdout on a PC).
+
+use crate::output::Format;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::{delay::BLOCK, uart::UartDriver};
+use std::fmt;
+use std::io;
+
+/// Byte stream a CLI session runs over
+pub trait Transport {
+    /// Block until the next byte of user input is available
+    fn read_byte(&mut self) -> io::Result<u8>;
+    /// Write all of `bytes` to the peer
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()>;
+}
+
+/// Context handed to the menu callbacks
+pub struct Context {
+    transport: Box<dyn Transport>,
+    closed: bool,
+    format: Format,
+}
+
+impl Context {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Context {
+            transport,
+            closed: false,
+            format: Format::Text,
+        }
+    }
+
+    pub fn read_byte(&mut self) -> io::Result<u8> {
+        let result = self.transport.read_byte();
+        if result.is_err() {
+            self.closed = true;
+        }
+        result
+    }
+
+    /// Output format of the session, set by the format command
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    /// True once the transport failed, the session should then be dropped
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl fmt::Write for Context {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // The menu runner unwraps every write, so a dropped connection is
+        // recorded instead of reported to avoid panicking the session
+        if !self.closed && self.transport.write_all(s.as_bytes()).is_err() {
+            self.closed = true;
+        }
+        Ok(())
+    }
+}
+
+/// Serial console transport
+#[cfg(target_os = "espidf")]
+pub struct UartTransport(pub UartDriver<'static>);
+
+#[cfg(target_os = "espidf")]
+impl Transport for UartTransport {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0_u8; 1];
+        loop {
+            let len = self
+                .0
+                .read(&mut buf, BLOCK)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            if len == 1 {
+                return Ok(buf[0]);
+            }
+        }
+    }
+
+    fn write_all(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            let len = self
+                .0
+                .write(bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            bytes = &bytes[len..];
+        }
+        Ok(())
+    }
+}
+
+/// Terminal of the PC the CLI runs on
+#[cfg(not(target_os = "espidf"))]
+pub struct StdioTransport;
+
+#[cfg(not(target_os = "espidf"))]
+impl Transport for StdioTransport {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        use std::io::Read;
+
+        let mut buf = [0_u8; 1];
+        io::stdin().lock().read_exact(&mut buf)?;
+        // The menu runner ends lines on carriage return like a serial terminal
+        Ok(if buf[0] == b'\n' { b'\r' } else { buf[0] })
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        use std::io::Write;
+
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(bytes)?;
+        stdout.flush()
+    }
+}
@@ -0,0 +1,1050 @@
+//! Throughput test compatible with iperf2 (`iperf -s`, `iperf -c <host>`,
+//! `-u` for UDP) running as either client or server.
+//!
+//! Only `std::net` is used, so the whole module also runs on a host. Report
+//! formatting and the UDP statistics are kept apart from the socket code.
+
+use crate::output::{Object, Output, Status};
+use std::fmt::{self, Write};
+use std::io::{self, ErrorKind, Read, Write as _};
+use std::net::{Shutdown, SocketAddr, TcpListener, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Port iperf2 uses by default
+pub const DEFAULT_PORT: u16 = 5001;
+/// iperf2 numbers its first connection 3, kept so reports look the same
+const ID: u32 = 3;
+
+/// UDP datagram header: id, seconds, microseconds
+const UDP_HEADER_LEN: usize = 12;
+/// Header plus the client header iperf2 servers inspect for extra flags
+const MIN_UDP_LEN: usize = UDP_HEADER_LEN + 24;
+/// Flag marking a server report as valid
+const HEADER_VERSION1: u32 = 0x8000_0000;
+/// Length of a server report including the datagram header
+const SERVER_REPORT_LEN: usize = UDP_HEADER_LEN + 40;
+
+/// FIN datagrams sent before giving up on the server report
+const FIN_RETRIES: u32 = 10;
+const FIN_TIMEOUT: Duration = Duration::from_millis(250);
+/// How long a server waits for a client before returning to the prompt
+const SERVER_WAIT: Duration = Duration::from_secs(60);
+/// A peer silent for this long is considered gone
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time a UDP server keeps answering repeated FINs
+const LINGER: Duration = Duration::from_secs(1);
+
+const SEPARATOR: &str = "------------------------------------------------------------";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Client(SocketAddr),
+    Server,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub mode: Mode,
+    pub protocol: Protocol,
+    pub port: u16,
+    /// Transmit time of a client
+    pub duration: Duration,
+    /// Read/write buffer size, the datagram size for UDP
+    pub len: usize,
+    /// Period of the intermediate reports, `None` prints the total only
+    pub interval: Option<Duration>,
+    /// Target rate of a UDP client in bits per second
+    pub bandwidth: u64,
+}
+
+impl Config {
+    /// Configuration with the iperf2 defaults, scaled down to fit the device
+    /// for the TCP buffer
+    pub fn new(mode: Mode, protocol: Protocol) -> Self {
+        Config {
+            mode,
+            protocol,
+            port: DEFAULT_PORT,
+            duration: Duration::from_secs(10),
+            len: match protocol {
+                Protocol::Tcp => 4096,
+                Protocol::Udp => 1470,
+            },
+            interval: None,
+            bandwidth: 1_000_000,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self.protocol {
+            Protocol::Tcp if self.len == 0 => Err("Buffer length must not be zero"),
+            Protocol::Udp if self.len < MIN_UDP_LEN => Err("UDP datagrams need at least 36 bytes"),
+            Protocol::Udp if self.len > 65_507 => Err("UDP datagrams are limited to 65507 bytes"),
+            Protocol::Udp if self.bandwidth == 0 => Err("Bandwidth must not be zero"),
+            _ if self.duration.is_zero() => Err("Duration must not be zero"),
+            _ if self.interval.is_some_and(|i| i.is_zero()) => Err("Interval must not be zero"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Parse a rate in bits per second with an optional `K`, `M` or `G` suffix
+/// (e.g. `10M`), as accepted by `iperf -b`
+pub fn parse_bandwidth(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, scale) = match text.chars().last()? {
+        'k' | 'K' => (&text[..text.len() - 1], 1e3),
+        'm' | 'M' => (&text[..text.len() - 1], 1e6),
+        'g' | 'G' => (&text[..text.len() - 1], 1e9),
+        _ => (text, 1.0),
+    };
+    let value = number.parse::<f64>().ok()? * scale;
+    (value.is_finite() && value >= 1.0).then_some(value as u64)
+}
+
+// iperf2 style value with adaptive units: two significant decimals for
+// small values, fewer as the value grows
+fn adaptive(f: &mut dyn Write, mut value: f64, base: f64, units: [&str; 4]) -> fmt::Result {
+    let mut unit = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    if value < 9.995 {
+        write!(f, "{:4.2} {}", value, units[unit])
+    } else if value < 99.95 {
+        write!(f, "{:4.1} {}", value, units[unit])
+    } else {
+        write!(f, "{:4.0} {}", value, units[unit])
+    }
+}
+
+/// Amount of data, e.g. `1.25 MBytes`
+pub fn format_bytes(bytes: u64) -> String {
+    let mut out = String::new();
+    adaptive(
+        &mut out,
+        bytes as f64,
+        1024.0,
+        ["Bytes", "KBytes", "MBytes", "GBytes"],
+    )
+    .unwrap();
+    out
+}
+
+/// Rate, e.g. `10.5 Mbits/sec`
+pub fn format_bandwidth(bits_per_sec: f64) -> String {
+    let mut out = String::new();
+    adaptive(
+        &mut out,
+        bits_per_sec,
+        1000.0,
+        ["bits/sec", "Kbits/sec", "Mbits/sec", "Gbits/sec"],
+    )
+    .unwrap();
+    out
+}
+
+/// Datagram accounting of a UDP interval or of the whole test
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UdpSummary {
+    /// Jitter in seconds
+    pub jitter: f64,
+    pub lost: u32,
+    pub total: u32,
+    pub out_of_order: u32,
+}
+
+impl UdpSummary {
+    /// Counters accumulated since `start`, the jitter is the current estimate
+    pub fn since(&self, start: &UdpSummary) -> UdpSummary {
+        UdpSummary {
+            jitter: self.jitter,
+            lost: self.lost.saturating_sub(start.lost),
+            total: self.total.saturating_sub(start.total),
+            out_of_order: self.out_of_order.saturating_sub(start.out_of_order),
+        }
+    }
+}
+
+/// One line of a report
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Interval {
+    /// Seconds since the start of the test
+    pub start: f64,
+    pub end: f64,
+    pub bytes: u64,
+    /// Present on the receiving side of UDP tests
+    pub udp: Option<UdpSummary>,
+}
+
+impl Interval {
+    pub fn bits_per_sec(&self) -> f64 {
+        let secs = self.end - self.start;
+        if secs > 0.0 {
+            self.bytes as f64 * 8.0 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Column headings matching the lines written by [`write_interval`]
+pub fn write_heading(f: &mut dyn Write, udp_receiver: bool) -> fmt::Result {
+    if udp_receiver {
+        writeln!(
+            f,
+            "[ ID] Interval       Transfer     Bandwidth        Jitter   Lost/Total Datagrams"
+        )
+    } else {
+        writeln!(f, "[ ID] Interval       Transfer     Bandwidth")
+    }
+}
+
+pub fn write_interval(f: &mut dyn Write, interval: &Interval) -> fmt::Result {
+    write!(
+        f,
+        "[{:3}] {:4.1}-{:4.1} sec  {}  {}",
+        ID,
+        interval.start,
+        interval.end,
+        format_bytes(interval.bytes),
+        format_bandwidth(interval.bits_per_sec())
+    )?;
+    if let Some(udp) = &interval.udp {
+        let percent = if udp.total > 0 {
+            udp.lost as f64 * 100.0 / udp.total as f64
+        } else {
+            0.0
+        };
+        write!(
+            f,
+            "  {:6.3} ms {:4}/{:5} ({:.2}%)",
+            udp.jitter * 1000.0,
+            udp.lost,
+            udp.total,
+            percent
+        )?;
+        if udp.out_of_order > 0 {
+            write!(
+                f,
+                "\n[{:3}] {:4.1}-{:4.1} sec  {} datagrams received out-of-order",
+                ID, interval.start, interval.end, udp.out_of_order
+            )?;
+        }
+    }
+    writeln!(f)
+}
+
+/// JSON form of an interval line, `kind` tells intervals and totals apart
+pub fn interval_object(kind: &'static str, interval: &Interval) -> Object {
+    let object = Object::new()
+        .field("type", kind)
+        .field("start", interval.start)
+        .field("end", interval.end)
+        .field("bytes", interval.bytes)
+        .field("bits_per_sec", interval.bits_per_sec());
+    match &interval.udp {
+        Some(udp) => object
+            .field("jitter_ms", udp.jitter * 1000.0)
+            .field("lost", udp.lost)
+            .field("total", udp.total)
+            .field("out_of_order", udp.out_of_order),
+        None => object,
+    }
+}
+
+fn connected_object(local: SocketAddr, peer: SocketAddr) -> Object {
+    Object::new()
+        .field("type", "connected")
+        .field("local", local.to_string())
+        .field("peer", peer.to_string())
+}
+
+pub fn write_connected(f: &mut dyn Write, local: SocketAddr, peer: SocketAddr) -> fmt::Result {
+    writeln!(
+        f,
+        "[{:3}] local {} port {} connected with {} port {}",
+        ID,
+        local.ip(),
+        local.port(),
+        peer.ip(),
+        peer.port()
+    )
+}
+
+/// Banner printed before a test starts
+pub fn write_banner(f: &mut dyn Write, config: &Config) -> fmt::Result {
+    let protocol = match config.protocol {
+        Protocol::Tcp => "TCP",
+        Protocol::Udp => "UDP",
+    };
+    writeln!(f, "{}", SEPARATOR)?;
+    match config.mode {
+        Mode::Server => writeln!(f, "Server listening on {} port {}", protocol, config.port)?,
+        Mode::Client(peer) => {
+            writeln!(
+                f,
+                "Client connecting to {}, {} port {}",
+                peer.ip(),
+                protocol,
+                peer.port()
+            )?;
+            if config.protocol == Protocol::Udp {
+                writeln!(f, "Sending {} byte datagrams", config.len)?;
+            }
+        }
+    }
+    if config.protocol == Protocol::Udp && config.mode == Mode::Server {
+        writeln!(f, "Receiving {} byte datagrams", config.len)?;
+    }
+    writeln!(f, "{}", SEPARATOR)
+}
+
+/// Receiver side UDP statistics: loss from gaps in the datagram ids and
+/// jitter as the smoothed transit time variation of RFC 1889. Ids start at 1
+/// as with iperf 2.1, the id 0 iperf 2.0 clients start with is not counted.
+#[derive(Clone, Debug)]
+pub struct UdpStats {
+    pub bytes: u64,
+    max_id: i64,
+    gaps: u32,
+    out_of_order: u32,
+    // Seconds
+    jitter: f64,
+    last_transit: Option<f64>,
+}
+
+impl UdpStats {
+    pub fn new() -> Self {
+        UdpStats {
+            bytes: 0,
+            max_id: 0,
+            gaps: 0,
+            out_of_order: 0,
+            jitter: 0.0,
+            last_transit: None,
+        }
+    }
+
+    /// Account for datagram `id` of `len` bytes sent at `sent` and received
+    /// at `arrival`, both in seconds on the sender's and receiver's clocks
+    pub fn record(&mut self, id: u32, len: usize, sent: f64, arrival: f64) {
+        self.bytes += len as u64;
+
+        // Clock offsets cancel out, only the transit variation is used
+        let transit = arrival - sent;
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+        }
+        self.last_transit = Some(transit);
+
+        let id = id as i64;
+        if id == 0 {
+            return;
+        }
+        if id > self.max_id + 1 {
+            self.gaps += (id - self.max_id - 1) as u32;
+        } else if id <= self.max_id {
+            // A late datagram fills a gap counted earlier
+            self.out_of_order += 1;
+        }
+        self.max_id = self.max_id.max(id);
+    }
+
+    pub fn summary(&self) -> UdpSummary {
+        UdpSummary {
+            jitter: self.jitter,
+            lost: self.gaps.saturating_sub(self.out_of_order),
+            total: self.max_id as u32,
+            out_of_order: self.out_of_order,
+        }
+    }
+}
+
+impl Default for UdpStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Header starting every UDP datagram. Ids count up from 1, the last datagram
+/// (FIN) carries the next id negated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DatagramHeader {
+    pub id: i32,
+    pub sec: u32,
+    pub usec: u32,
+}
+
+impl DatagramHeader {
+    pub fn new(id: i32, time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        DatagramHeader {
+            id,
+            sec: since_epoch.as_secs() as u32,
+            usec: since_epoch.subsec_micros(),
+        }
+    }
+
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() < UDP_HEADER_LEN {
+            return None;
+        }
+        Some(DatagramHeader {
+            id: read_u32(datagram, 0) as i32,
+            sec: read_u32(datagram, 4),
+            usec: read_u32(datagram, 8),
+        })
+    }
+
+    pub fn encode(&self, datagram: &mut [u8]) {
+        datagram[0..4].copy_from_slice(&self.id.to_be_bytes());
+        datagram[4..8].copy_from_slice(&self.sec.to_be_bytes());
+        datagram[8..12].copy_from_slice(&self.usec.to_be_bytes());
+    }
+
+    /// Send time in seconds
+    pub fn time(&self) -> f64 {
+        self.sec as f64 + self.usec as f64 / 1e6
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Final statistics a UDP server returns in answer to the FIN datagram
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ServerReport {
+    pub bytes: u64,
+    /// Seconds between the first and the last datagram
+    pub duration: f64,
+    pub udp: UdpSummary,
+}
+
+impl ServerReport {
+    pub fn encode(&self, header: &DatagramHeader) -> [u8; SERVER_REPORT_LEN] {
+        let mut out = [0; SERVER_REPORT_LEN];
+        header.encode(&mut out);
+        let fields = [
+            HEADER_VERSION1,
+            (self.bytes >> 32) as u32,
+            self.bytes as u32,
+            self.duration as u32,
+            (self.duration.fract() * 1e6) as u32,
+            self.udp.lost,
+            self.udp.out_of_order,
+            self.udp.total,
+            self.udp.jitter as u32,
+            (self.udp.jitter.fract() * 1e6) as u32,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            let offset = UDP_HEADER_LEN + i * 4;
+            out[offset..offset + 4].copy_from_slice(&field.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn decode(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() < SERVER_REPORT_LEN {
+            return None;
+        }
+        let field = |i: usize| read_u32(datagram, UDP_HEADER_LEN + i * 4);
+        if field(0) & HEADER_VERSION1 == 0 {
+            return None;
+        }
+        Some(ServerReport {
+            bytes: (field(1) as u64) << 32 | field(2) as u64,
+            duration: field(3) as f64 + field(4) as f64 / 1e6,
+            udp: UdpSummary {
+                jitter: field(8) as f64 + field(9) as f64 / 1e6,
+                lost: field(5),
+                total: field(7),
+                out_of_order: field(6),
+            },
+        })
+    }
+
+    pub fn interval(&self) -> Interval {
+        Interval {
+            start: 0.0,
+            end: self.duration,
+            bytes: self.bytes,
+            udp: Some(self.udp),
+        }
+    }
+}
+
+// Emits the intermediate reports as their boundaries are crossed
+struct Reporter {
+    interval: Option<f64>,
+    next: f64,
+    start: f64,
+    bytes: u64,
+    udp: UdpSummary,
+}
+
+impl Reporter {
+    fn new(interval: Option<Duration>) -> Self {
+        let interval = interval.map(|i| i.as_secs_f64());
+        Reporter {
+            interval,
+            next: interval.unwrap_or(f64::INFINITY),
+            start: 0.0,
+            bytes: 0,
+            udp: UdpSummary::default(),
+        }
+    }
+
+    fn update<W: Write>(
+        &mut self,
+        out: &mut Output<W>,
+        elapsed: f64,
+        bytes: u64,
+        udp: Option<UdpSummary>,
+    ) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        while elapsed >= self.next {
+            let line = Interval {
+                start: self.start,
+                end: self.next,
+                bytes: bytes - self.bytes,
+                udp: udp.map(|udp| udp.since(&self.udp)),
+            };
+            out.result(interval_object("interval", &line), |f| {
+                write_interval(f, &line)
+            });
+            self.start = self.next;
+            self.next += interval;
+            self.bytes = bytes;
+            self.udp = udp.unwrap_or_default();
+        }
+    }
+}
+
+/// Run the test described by `config`, printing the reports to `out`
+pub fn run<W: Write>(config: &Config, out: &mut Output<W>) -> io::Result<()> {
+    out.text(|f| write_banner(f, config));
+    match (config.mode, config.protocol) {
+        (Mode::Client(peer), Protocol::Tcp) => tcp_client(config, peer, out),
+        (Mode::Client(peer), Protocol::Udp) => udp_client(config, peer, out),
+        (Mode::Server, Protocol::Tcp) => tcp_server(config, out),
+        (Mode::Server, Protocol::Udp) => udp_server(config, out),
+    }
+}
+
+// Payload with the digit pattern iperf2 sends, the leading client header is
+// left zeroed so servers do not look for extra test flags
+fn payload(len: usize, header_len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| {
+            if i < header_len {
+                0
+            } else {
+                b'0' + (i % 10) as u8
+            }
+        })
+        .collect()
+}
+
+fn tcp_client<W: Write>(config: &Config, peer: SocketAddr, out: &mut Output<W>) -> io::Result<()> {
+    let mut stream = std::net::TcpStream::connect_timeout(&peer, PEER_TIMEOUT)?;
+    stream.set_write_timeout(Some(PEER_TIMEOUT))?;
+    let local = stream.local_addr()?;
+    out.result(connected_object(local, peer), |f| {
+        write_connected(f, local, peer)
+    });
+    out.text(|f| write_heading(f, false));
+
+    let buf = payload(config.len, 24);
+    let mut reporter = Reporter::new(config.interval);
+    let mut bytes = 0;
+    let start = Instant::now();
+    while start.elapsed() < config.duration {
+        stream.write_all(&buf)?;
+        bytes += buf.len() as u64;
+        reporter.update(out, start.elapsed().as_secs_f64(), bytes, None);
+    }
+    stream.shutdown(Shutdown::Write)?;
+
+    let total = Interval {
+        start: 0.0,
+        end: start.elapsed().as_secs_f64(),
+        bytes,
+        udp: None,
+    };
+    out.result(interval_object("summary", &total), |f| {
+        write_interval(f, &total)
+    });
+    Ok(())
+}
+
+fn tcp_server<W: Write>(config: &Config, out: &mut Output<W>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", config.port))?;
+    // Poll so the prompt comes back when nobody connects
+    listener.set_nonblocking(true)?;
+    let waiting = Instant::now();
+    let (mut stream, peer) = loop {
+        match listener.accept() {
+            Ok(connection) => break connection,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if waiting.elapsed() >= SERVER_WAIT {
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+    let local = stream.local_addr()?;
+    out.result(connected_object(local, peer), |f| {
+        write_connected(f, local, peer)
+    });
+    out.text(|f| write_heading(f, false));
+
+    let mut buf = vec![0; config.len];
+    let mut reporter = Reporter::new(config.interval);
+    let mut bytes = 0;
+    let start = Instant::now();
+    let mut end = start;
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                bytes += len as u64;
+                end = Instant::now();
+                reporter.update(out, start.elapsed().as_secs_f64(), bytes, None);
+            }
+            // A vanished client still gets the data received so far reported
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let total = Interval {
+        start: 0.0,
+        end: (end - start).as_secs_f64(),
+        bytes,
+        udp: None,
+    };
+    out.result(interval_object("summary", &total), |f| {
+        write_interval(f, &total)
+    });
+    Ok(())
+}
+
+fn udp_client<W: Write>(config: &Config, peer: SocketAddr, out: &mut Output<W>) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(peer)?;
+    let local = socket.local_addr()?;
+    out.result(connected_object(local, peer), |f| {
+        write_connected(f, local, peer)
+    });
+    out.text(|f| write_heading(f, false));
+
+    let mut buf = payload(config.len, MIN_UDP_LEN);
+    let gap = Duration::from_secs_f64(config.len as f64 * 8.0 / config.bandwidth as f64);
+    let mut reporter = Reporter::new(config.interval);
+    let mut bytes = 0;
+    let mut id = 1_i32;
+    let mut datagrams = 0_u64;
+    let start = Instant::now();
+    let mut next_send = start;
+    while start.elapsed() < config.duration {
+        let now = Instant::now();
+        // The tick granularity may be coarse, late datagrams are sent in a burst
+        if now < next_send {
+            std::thread::sleep(next_send - now);
+        }
+        DatagramHeader::new(id, SystemTime::now()).encode(&mut buf);
+        match socket.send(&buf) {
+            Ok(len) => bytes += len as u64,
+            // Full buffers drop the datagram like a congested link would
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+        datagrams += 1;
+        // Negative ids are left to the FIN
+        id = id.checked_add(1).unwrap_or(1);
+        next_send += gap;
+        reporter.update(out, start.elapsed().as_secs_f64(), bytes, None);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let total = Interval {
+        start: 0.0,
+        end: elapsed,
+        bytes,
+        udp: None,
+    };
+    out.result(interval_object("summary", &total), |f| {
+        write_interval(f, &total)
+    });
+    out.result(
+        Object::new()
+            .field("type", "sent")
+            .field("datagrams", datagrams),
+        |f| writeln!(f, "[{:3}] Sent {} datagrams", ID, datagrams),
+    );
+
+    // Repeat the FIN until the server answers with its statistics
+    socket.set_read_timeout(Some(FIN_TIMEOUT))?;
+    let mut reply = [0; SERVER_REPORT_LEN + 64];
+    for _ in 0..FIN_RETRIES {
+        DatagramHeader::new(-id, SystemTime::now()).encode(&mut buf);
+        socket.send(&buf)?;
+        match socket.recv(&mut reply) {
+            Ok(len) => {
+                if let Some(report) = ServerReport::decode(&reply[..len]) {
+                    let interval = report.interval();
+                    out.result(interval_object("server_report", &interval), |f| {
+                        writeln!(f, "[{:3}] Server Report:", ID)?;
+                        write_interval(f, &interval)
+                    });
+                    return Ok(());
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    out.error(
+        Status::Failed,
+        format_args!(
+            "[{:3}] WARNING: did not receive ack of last datagram after {} tries.",
+            ID, FIN_RETRIES
+        ),
+    );
+    Ok(())
+}
+
+fn udp_server<W: Write>(config: &Config, out: &mut Output<W>) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", config.port))?;
+    let mut buf = vec![0; config.len.max(SERVER_REPORT_LEN)];
+
+    // The first datagram selects the client
+    socket.set_read_timeout(Some(SERVER_WAIT))?;
+    let (len, peer) = socket.recv_from(&mut buf)?;
+    let start = Instant::now();
+    socket.set_read_timeout(Some(PEER_TIMEOUT))?;
+    let local = socket.local_addr()?;
+    out.result(connected_object(local, peer), |f| {
+        write_connected(f, local, peer)
+    });
+    out.text(|f| write_heading(f, true));
+
+    let mut stats = UdpStats::new();
+    let mut reporter = Reporter::new(config.interval);
+    let mut end = start;
+    let mut datagram = Some(len);
+    let fin = loop {
+        let len = match datagram.take() {
+            Some(len) => len,
+            None => match socket.recv_from(&mut buf) {
+                Ok((len, from)) if from == peer => len,
+                Ok(_) => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break None;
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        let Some(header) = DatagramHeader::decode(&buf[..len]) else {
+            continue;
+        };
+        end = Instant::now();
+        let arrival = DatagramHeader::new(0, SystemTime::now()).time();
+        stats.record(header.id.unsigned_abs(), len, header.time(), arrival);
+        if header.id < 0 {
+            break Some(header);
+        }
+        reporter.update(
+            out,
+            start.elapsed().as_secs_f64(),
+            stats.bytes,
+            Some(stats.summary()),
+        );
+    };
+
+    let report = ServerReport {
+        bytes: stats.bytes,
+        duration: (end - start).as_secs_f64(),
+        udp: stats.summary(),
+    };
+    let interval = report.interval();
+    out.result(interval_object("summary", &interval), |f| {
+        write_interval(f, &interval)
+    });
+
+    // Answer the FIN and any retransmission of it while the client waits
+    if let Some(header) = fin {
+        socket.send_to(&report.encode(&header), peer)?;
+        socket.set_read_timeout(Some(LINGER))?;
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            match DatagramHeader::decode(&buf[..len]) {
+                Some(header) if from == peer && header.id < 0 => {
+                    socket.send_to(&report.encode(&header), peer)?;
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Format;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn at(sec: u64, usec: u32) -> SystemTime {
+        UNIX_EPOCH + Duration::new(sec, usec * 1000)
+    }
+
+    #[test]
+    fn datagram_header_round_trip() {
+        let header = DatagramHeader::new(-5, at(1_700_000_000, 250_000));
+        let mut datagram = [0; MIN_UDP_LEN];
+        header.encode(&mut datagram);
+        assert_eq!(
+            datagram[..UDP_HEADER_LEN],
+            [0xFF, 0xFF, 0xFF, 0xFB, 0x65, 0x53, 0xF1, 0x00, 0x00, 0x03, 0xD0, 0x90]
+        );
+        assert_eq!(DatagramHeader::decode(&datagram), Some(header));
+        assert_eq!(header.time(), 1_700_000_000.25);
+        assert_eq!(
+            DatagramHeader::decode(&datagram[..UDP_HEADER_LEN - 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn server_report_round_trip() {
+        let report = ServerReport {
+            bytes: 5 << 32 | 1234,
+            duration: 10.5,
+            udp: UdpSummary {
+                jitter: 0.001_234,
+                lost: 7,
+                total: 9000,
+                out_of_order: 2,
+            },
+        };
+        let header = DatagramHeader::new(-9001, at(1, 0));
+        let datagram = report.encode(&header);
+        assert_eq!(DatagramHeader::decode(&datagram), Some(header));
+        assert_eq!(
+            datagram[UDP_HEADER_LEN..UDP_HEADER_LEN + 24],
+            [
+                0x80, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0x04, 0xD2, 0, 0, 0, 10, 0, 0x07, 0xA1, 0x20, 0,
+                0, 0, 7
+            ]
+        );
+        let decoded = ServerReport::decode(&datagram).unwrap();
+        assert_eq!(decoded.bytes, report.bytes);
+        assert_eq!(decoded.udp.lost, 7);
+        assert_eq!(decoded.udp.total, 9000);
+        assert_eq!(decoded.udp.out_of_order, 2);
+        assert!((decoded.duration - 10.5).abs() < 1e-6);
+        assert!((decoded.udp.jitter - 0.001_234).abs() < 1e-6);
+    }
+
+    #[test]
+    fn server_report_needs_its_flag_and_length() {
+        let report = ServerReport {
+            bytes: 1,
+            duration: 1.0,
+            udp: UdpSummary::default(),
+        };
+        let mut datagram = report.encode(&DatagramHeader::new(-1, at(1, 0)));
+        assert_eq!(
+            ServerReport::decode(&datagram[..SERVER_REPORT_LEN - 1]),
+            None
+        );
+        datagram[UDP_HEADER_LEN] = 0;
+        assert_eq!(ServerReport::decode(&datagram), None);
+    }
+
+    #[test]
+    fn datagrams_in_order_lose_nothing() {
+        let mut stats = UdpStats::new();
+        for id in 1..=10 {
+            stats.record(id, 100, id as f64, id as f64 + 0.01);
+        }
+        let summary = stats.summary();
+        assert_eq!(
+            (summary.lost, summary.total, summary.out_of_order),
+            (0, 10, 0)
+        );
+        assert_eq!(stats.bytes, 1000);
+        assert!(summary.jitter.abs() < 1e-9);
+    }
+
+    #[test]
+    fn gaps_count_as_lost_until_late_datagrams_fill_them() {
+        let mut stats = UdpStats::new();
+        for id in [1, 2, 5, 6] {
+            stats.record(id, 100, 0.0, 0.0);
+        }
+        let summary = stats.summary();
+        assert_eq!(
+            (summary.lost, summary.total, summary.out_of_order),
+            (2, 6, 0)
+        );
+        stats.record(3, 100, 0.0, 0.0);
+        let summary = stats.summary();
+        assert_eq!(
+            (summary.lost, summary.total, summary.out_of_order),
+            (1, 6, 1)
+        );
+    }
+
+    #[test]
+    fn id_zero_of_iperf_2_0_clients_is_not_counted() {
+        let mut stats = UdpStats::new();
+        for id in 0..=3 {
+            stats.record(id, 100, 0.0, 0.0);
+        }
+        let summary = stats.summary();
+        assert_eq!(
+            (summary.lost, summary.total, summary.out_of_order),
+            (0, 3, 0)
+        );
+        assert_eq!(stats.bytes, 400);
+    }
+
+    #[test]
+    fn jitter_smooths_transit_variation() {
+        let mut stats = UdpStats::new();
+        // Transit times alternate between 10 and 12 ms
+        for id in 1..=3 {
+            let transit = if id % 2 == 0 { 0.012 } else { 0.010 };
+            stats.record(id, 100, 0.0, transit);
+        }
+        let first = 0.002 / 16.0;
+        let second = first + (0.002 - first) / 16.0;
+        assert!((stats.summary().jitter - second).abs() < 1e-12);
+    }
+
+    #[test]
+    fn summaries_subtract_counters_but_keep_jitter() {
+        let now = UdpSummary {
+            jitter: 0.5,
+            lost: 5,
+            total: 100,
+            out_of_order: 1,
+        };
+        let start = UdpSummary {
+            jitter: 0.1,
+            lost: 2,
+            total: 40,
+            out_of_order: 3,
+        };
+        let since = now.since(&start);
+        assert_eq!(
+            (since.jitter, since.lost, since.total, since.out_of_order),
+            (0.5, 3, 60, 0)
+        );
+    }
+
+    #[test]
+    fn bandwidths_and_amounts() {
+        assert_eq!(parse_bandwidth("10M"), Some(10_000_000));
+        assert_eq!(parse_bandwidth("1.5k"), Some(1500));
+        assert_eq!(parse_bandwidth("2G"), Some(2_000_000_000));
+        assert_eq!(parse_bandwidth("0.5"), None);
+        assert_eq!(parse_bandwidth("M"), None);
+        assert_eq!(format_bytes(512), " 512 Bytes");
+        assert_eq!(format_bytes(1_310_720), "1.25 MBytes");
+        assert_eq!(format_bandwidth(10_500_000.0), "10.5 Mbits/sec");
+    }
+
+    #[test]
+    fn udp_lines_report_loss() {
+        let interval = Interval {
+            start: 0.0,
+            end: 1.0,
+            bytes: 128_000,
+            udp: Some(UdpSummary {
+                jitter: 0.000_5,
+                lost: 2,
+                total: 100,
+                out_of_order: 1,
+            }),
+        };
+        let mut line = String::new();
+        write_interval(&mut line, &interval).unwrap();
+        assert_eq!(
+            line,
+            "[  3]  0.0- 1.0 sec   125 KBytes  1.02 Mbits/sec   0.500 ms    2/  100 (2.00%)\n\
+             [  3]  0.0- 1.0 sec  1 datagrams received out-of-order\n"
+        );
+    }
+
+    #[test]
+    fn udp_client_and_server_over_loopback() {
+        // Find a free port for the server
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = std::thread::spawn(move || {
+            let mut config = Config::new(Mode::Server, Protocol::Udp);
+            config.port = port;
+            let mut text = String::new();
+            udp_server(&config, &mut Output::new(&mut text, Format::Json, "iperf")).unwrap();
+            text
+        });
+
+        let peer = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+        let mut config = Config::new(Mode::Client(peer), Protocol::Udp);
+        config.duration = Duration::from_millis(200);
+        config.len = 100;
+        config.bandwidth = 100_000;
+        let mut text = String::new();
+        // Give the server time to bind
+        std::thread::sleep(Duration::from_millis(100));
+        udp_client(
+            &config,
+            peer,
+            &mut Output::new(&mut text, Format::Text, "iperf"),
+        )
+        .unwrap();
+
+        let sent: u32 = text
+            .lines()
+            .find_map(|line| line.strip_prefix("[  3] Sent "))
+            .and_then(|rest| rest.strip_suffix(" datagrams"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(sent > 0);
+        assert!(text.contains("Server Report:"), "{}", text);
+        // The FIN carries id sent + 1 and is counted by the server
+        let server = server.join().unwrap();
+        assert!(
+            server.contains(&format!("\"lost\":0,\"total\":{}", sent + 1)),
+            "{}",
+            server
+        );
+    }
+}
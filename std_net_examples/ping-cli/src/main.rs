@@ -10,31 +10,130 @@ For notifications on similar examples and more, subscribe to newsletter here:
 https://www.theembeddedrustacean.com/subscribe
 */
 
-use esp_idf_hal::delay::BLOCK;
-use esp_idf_hal::gpio;
-use esp_idf_hal::prelude::*;
-use esp_idf_hal::uart::*;
-use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::ping::{Configuration as PingConfiguration, EspPing, Summary};
-use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+mod args;
+mod backend;
+#[cfg(target_os = "espidf")]
+mod ble;
+#[cfg(target_os = "espidf")]
+mod ble_provision;
+mod console;
+mod iperf;
+// Only the BLE transport uses the framing, the PC build tests it
+#[cfg(any(target_os = "espidf", test))]
+mod nus;
+mod output;
+// Pairing of both BLE services, tested on the PC like the framing
+#[cfg(any(target_os = "espidf", test))]
+mod passkey;
+mod ping;
+// Only the provisioning service uses the framing, the PC build tests it
+#[cfg(any(target_os = "espidf", test))]
+mod provision;
+#[cfg(not(target_os = "espidf"))]
+mod sim;
+#[cfg(target_os = "espidf")]
+mod station;
+mod sysinfo;
+#[cfg(target_os = "espidf")]
+mod system;
+mod telnet;
+mod wifi;
+
+use args::{Arg, Kind, Parsed};
+use console::Context;
+use iperf::{Config as IperfConfig, Mode as IperfMode, Protocol};
 use menu::*;
+use output::{Format, Object, Output, Status};
 use std::fmt::Write;
-use std::net::ToSocketAddrs;
-use std::str::FromStr;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
+use wifi::{Auth, Echo};
+
+// Telnet access to the CLI, set a password to require a login
+#[cfg(target_os = "espidf")]
+const TELNET_PORT: u16 = 23;
+#[cfg(target_os = "espidf")]
+const TELNET_PASSWORD: Option<&str> = None;
+#[cfg(target_os = "espidf")]
+const TELNET_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Name advertised for the Nordic UART Service, at most 8 characters
+#[cfg(target_os = "espidf")]
+const BLE_NAME: &str = "ESP-CLI";
+
+// Arguments of the commands, checked before their callbacks run
+const HW_ARGS: &[Arg] = &[Arg::new("name", Kind::Text)];
+const PING_ARGS: &[Arg] = &[
+    Arg::new("hostname/IP", Kind::Host),
+    Arg::new("count", Kind::Int(1, 1000)).default("4"),
+    Arg::new(
+        "interval",
+        Kind::Duration(Duration::ZERO, Duration::from_secs(60)),
+    ),
+    Arg::new(
+        "timeout",
+        Kind::Duration(Duration::from_millis(1), Duration::from_secs(60)),
+    ),
+    Arg::new("size", Kind::Int(0, 65_507)),
+];
+const IPERF_ARGS: &[Arg] = &[
+    Arg::new("server", Kind::Flag),
+    Arg::new("client", Kind::Host),
+    Arg::new("udp", Kind::Flag),
+    Arg::new(
+        "time",
+        Kind::Duration(Duration::from_millis(100), Duration::from_secs(3600)),
+    ),
+    Arg::new("len", Kind::Int(1, 65_535)),
+    Arg::new(
+        "interval",
+        Kind::Duration(Duration::from_millis(100), Duration::from_secs(3600)),
+    ),
+    Arg::new("port", Kind::Int(1, 65_535)),
+    Arg::new("bandwidth", Kind::Text),
+];
+const WIFI_ARGS: &[Arg] = &[
+    Arg::new(
+        "action",
+        Kind::Choice(&["scan", "connect", "status", "forget"]),
+    ),
+    Arg::new("ssid", Kind::Text),
+    Arg::new("auth", Kind::Choice(wifi::AUTH_NAMES)),
+];
+const SYS_ARGS: &[Arg] = &[
+    Arg::new(
+        "action",
+        Kind::Choice(&["heap", "tasks", "uptime", "reset-reason", "chip", "reboot"]),
+    ),
+    Arg::new(
+        "delay",
+        Kind::Duration(Duration::ZERO, Duration::from_secs(3600)),
+    )
+    .default("0s"),
+];
+const FORMAT_ARGS: &[Arg] = &[Arg::new("format", Kind::Choice(&["text", "json"]))];
+
+// Accepted by every command to print its result as JSON
+const JSON: Parameter = Parameter::Named {
+    parameter_name: "json",
+    help: Some("Print the result as JSON"),
+};
 
 // CLI Root Menu Struct Initialization
-const ROOT_MENU: Menu<UartDriver> = Menu {
+const ROOT_MENU: Menu<Context> = Menu {
     label: "root",
     items: &[
         &Item {
             item_type: ItemType::Callback {
                 function: hello_name,
-                parameters: &[Parameter::Mandatory {
-                    parameter_name: "name",
-                    help: Some("Enter your name"),
-                }],
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "name",
+                        help: Some("Enter your name"),
+                    },
+                    JSON,
+                ],
             },
             command: "hw",
             help: Some("This is the help for the hello, name hw command!"),
@@ -65,7 +164,8 @@ const ROOT_MENU: Menu<UartDriver> = Menu {
                     parameter_name: "size",
                     argument_name: "sz",
                     help: Some("Set the size of the packet"),
-                },],
+                },
+                JSON,],
             },
             command: "ping",
             help: Some("
@@ -76,16 +176,173 @@ const ROOT_MENU: Menu<UartDriver> = Menu {
             
             Options:
               --count=<number>     Number of ICMP Echo Request packets to send (default is 4).
-              --interval=<seconds> Set the interval between successive ping packets in seconds.
-              --timeout=<seconds>  Specify a timeout value for each ping attempt.
+              --interval=<time>    Set the interval between successive ping packets (e.g. 500ms, 2s).
+              --timeout=<time>     Specify a timeout value for each ping attempt (e.g. 1s).
               --size=<bytes>       Set the size of the ICMP packets.
+              --json               Print one JSON object per reply and for the statistics.
               --help               Display this help message and exit.
             
             Examples:
               ping 192.168.1.1          # Ping the IP address 192.168.1.1
               ping example.com          # Ping the hostname 'example.com'
-              ping -count=10 google.com     # Send 10 ping requests to google.com
-              ping -interval=0.5 -size=100 example.com  # Ping with interval of 0.5 seconds and packet size of 100 bytes to 'example.com'
+              ping --count=10 google.com     # Send 10 ping requests to google.com
+              ping --interval=500ms --size=100 example.com  # Ping with interval of 0.5 seconds and packet size of 100 bytes to 'example.com'
+            "),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: iperf_app,
+                parameters: &[
+                    Parameter::Named {
+                        parameter_name: "server",
+                        help: Some("Run in server mode"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "client",
+                        argument_name: "host",
+                        help: Some("Run in client mode, connecting to host"),
+                    },
+                    Parameter::Named {
+                        parameter_name: "udp",
+                        help: Some("Use UDP rather than TCP"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "time",
+                        argument_name: "time",
+                        help: Some("Time to transmit for"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "len",
+                        argument_name: "bytes",
+                        help: Some("Length of buffer to read or write"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "interval",
+                        argument_name: "time",
+                        help: Some("Time between periodic bandwidth reports"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "port",
+                        argument_name: "port",
+                        help: Some("Server port to listen on/connect to"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "bandwidth",
+                        argument_name: "bits/sec",
+                        help: Some("UDP bandwidth to send at"),
+                    },
+                    JSON,
+                ],
+            },
+            command: "iperf",
+            help: Some("
+            Measure network throughput against iperf2 running on another machine.
+
+            Usage: iperf --server [options]
+                   iperf --client=<host> [options]
+
+            Options:
+              --udp                 Use UDP rather than TCP.
+              --time=<time>         Time to transmit for, e.g. 30s or 2m (default is 10s).
+              --len=<bytes>         Buffer length (default is 4096, 1470 for UDP).
+              --interval=<time>     Time between periodic bandwidth reports, e.g. 1s.
+              --port=<port>         Server port (default is 5001).
+              --bandwidth=<rate>    UDP bandwidth in bits/sec, K/M/G suffixes allowed (default is 1M).
+              --json                Print one JSON object per report.
+
+            Examples:
+              iperf --server --interval=1            # Pair with 'iperf -c <device> -i 1' on the PC
+              iperf --client=192.168.1.2 --time=30   # Pair with 'iperf -s' on the PC
+              iperf --client=192.168.1.2 --udp --bandwidth=5M   # Pair with 'iperf -s -u' on the PC
+            "),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: wifi_app,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "action",
+                        help: Some("scan, connect, status or forget"),
+                    },
+                    Parameter::Optional {
+                        parameter_name: "ssid",
+                        help: Some("Network to connect to"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "auth",
+                        argument_name: "method",
+                        help: Some("Authentication method of the network"),
+                    },
+                    JSON,
+                ],
+            },
+            command: "wifi",
+            help: Some("
+            Manage the Wi-Fi connection. The joined network is saved and joined again after a reset.
+
+            Usage: wifi scan
+                   wifi connect [ssid] [--auth=<method>]
+                   wifi status
+                   wifi forget
+
+            Options:
+              --auth=<method>  open, wep, wpa, wpa2, wpa-wpa2, wpa2-enterprise, wpa3, wpa2-wpa3 or wapi
+                               (default is wpa2, or open when the password is left empty).
+
+            Examples:
+              wifi connect               # Asks for the SSID, use this for SSIDs containing spaces
+              wifi connect Wokwi-GUEST   # Press enter at the password prompt for an open network
+            "),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: sys_app,
+                parameters: &[
+                    Parameter::Mandatory {
+                        parameter_name: "action",
+                        help: Some("heap, tasks, uptime, reset-reason, chip or reboot"),
+                    },
+                    Parameter::NamedValue {
+                        parameter_name: "delay",
+                        argument_name: "time",
+                        help: Some("Time to wait before rebooting"),
+                    },
+                    JSON,
+                ],
+            },
+            command: "sys",
+            help: Some("
+            Show the state of the system.
+
+            Usage: sys heap           # Free, minimum ever free and largest allocatable heap
+                   sys tasks          # Tasks with state, priority, stack high-water mark and CPU use
+                   sys uptime         # Time since boot
+                   sys reset-reason   # Why the chip last reset
+                   sys chip           # Chip model, revision, MAC, flash size and IDF version
+                   sys reboot [--delay=<time>]
+            "),
+        },
+        &Item {
+            item_type: ItemType::Callback {
+                function: format_app,
+                parameters: &[
+                    Parameter::Optional {
+                        parameter_name: "format",
+                        help: Some("text or json"),
+                    },
+                    JSON,
+                ],
+            },
+            command: "format",
+            help: Some("
+            Choose how every command of this session prints its results.
+
+            Usage: format          # Show the current format
+                   format text     # Human readable output (default)
+                   format json     # One JSON object per line with the command and a status code
+
+            A single command can print JSON with --json, e.g. 'ping --json example.com'.
+            Status codes: 0 ok, 1 failed, 2 invalid argument, 3 unavailable.
             "),
         },
     ],
@@ -93,35 +350,80 @@ const ROOT_MENU: Menu<UartDriver> = Menu {
     exit: None,
 };
 
+#[cfg(target_os = "espidf")]
 fn main() -> anyhow::Result<()> {
+    use console::UartTransport;
+    use esp32_nimble::enums::{AuthReq, SecurityIOCap};
+    use esp_idf_hal::gpio;
+    use esp_idf_hal::prelude::*;
+    use esp_idf_hal::uart::*;
+    use esp_idf_svc::eventloop::EspSystemEventLoop;
+    use esp_idf_svc::nvs::EspDefaultNvsPartition;
+    use esp_idf_svc::ping::EspPing;
+    use station::Station;
+    use system::EspSystem;
+    use telnet::TelnetConfig;
+
     // Take Peripherals
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
 
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sysloop.clone(), Some(nvs))?,
-        sysloop,
-    )?;
+    // Bring up the radio, the CLI works offline until a network is joined
+    backend::install(
+        Box::new(EspPing::new(0_u32)),
+        Box::new(Station::new(peripherals.modem, sysloop, nvs)?),
+        Box::new(EspSystem),
+    );
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: "Wokwi-GUEST".try_into().unwrap(),
-        bssid: None,
-        auth_method: AuthMethod::None,
-        password: "".try_into().unwrap(),
-        channel: None,
-    }))?;
+    // Join the network saved by 'wifi connect' without holding up the CLI
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(join_saved_network)?;
 
-    // Start Wifi
-    wifi.start()?;
+    // Start Telnet Server Running the Same Menu on its Own Thread
+    std::thread::Builder::new().stack_size(4096).spawn(|| {
+        let config = TelnetConfig {
+            port: TELNET_PORT,
+            idle_timeout: TELNET_IDLE_TIMEOUT,
+            password: TELNET_PASSWORD,
+            ..Default::default()
+        };
+        if let Err(e) = telnet::serve(config, || ROOT_MENU) {
+            println!("Telnet server stopped: {}", e);
+        }
+    })?;
 
-    // Connect Wifi
-    wifi.connect()?;
+    // Phones pair with the passkey printed here, which keeps an attacker in
+    // the middle from pairing instead. Both BLE services need it.
+    let ble_device = esp32_nimble::BLEDevice::take();
+    ble_device
+        .security()
+        .set_auth(AuthReq::Bond | AuthReq::Mitm | AuthReq::Sc)
+        .set_io_cap(SecurityIOCap::DisplayOnly);
+    new_passkey();
+    // Each pairing attempt uses up the passkey
+    ble_device
+        .get_server()
+        .on_authentication_complete(|_desc, result| {
+            if let Err(e) = result {
+                println!("BLE pairing failed: {:?}", e);
+            }
+            new_passkey();
+        });
 
-    // Wait until the network interface is up
-    wifi.wait_netif_up()?;
+    // Provision Wi-Fi from a phone, a service next to the Nordic UART one
+    let provisioner = ble_provision::create();
+    std::thread::Builder::new()
+        .stack_size(4096)
+        .spawn(move || provisioner.run())?;
 
-    println!("Wifi Connected");
+    // Offer the Same Menu over the Nordic UART Service
+    std::thread::Builder::new().stack_size(8192).spawn(|| {
+        if let Err(e) = ble::serve(BLE_NAME, || ROOT_MENU) {
+            println!("BLE UART service stopped: {:?}", e);
+        }
+    })?;
 
     // Configure UART
     // Create handle for UART config struct
@@ -144,134 +446,226 @@ fn main() -> anyhow::Result<()> {
     // Create a buffer to store CLI input
     let mut clibuf = [0u8; 64];
     // Instantiate CLI runner with root menu, buffer, and uart
-    let mut r = Runner::new(ROOT_MENU, &mut clibuf, uart);
+    let mut r = Runner::new(
+        ROOT_MENU,
+        &mut clibuf,
+        Context::new(Box::new(UartTransport(uart))),
+    );
 
     loop {
-        // Create single element buffer for UART characters
-        let mut buf = [0_u8; 1];
         // Read single byte from UART
-        r.context.read(&mut buf, BLOCK).unwrap();
+        let byte = r.context.read_byte().unwrap();
         // Pass read byte to CLI runner for processing
-        r.input_byte(buf[0]);
+        r.input_byte(byte);
     }
 }
 
-// Callback function for hw command
-fn hello_name<'a>(
-    _menu: &Menu<UartDriver>,
-    item: &Item<UartDriver>,
+// Run the CLI on a PC over stdin/stdout, e.g. with 'cargo sim'
+#[cfg(not(target_os = "espidf"))]
+fn main() {
+    use console::StdioTransport;
+    use sim::{Options, SimPinger, SimSystem, SimWifi};
+    use std::net::Ipv4Addr;
+    use telnet::TelnetConfig;
+
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    backend::install(
+        Box::new(SimPinger::new(&options)),
+        Box::<SimWifi>::default(),
+        Box::<SimSystem>::default(),
+    );
+
+    join_saved_network();
+
+    // Serve the same menu over telnet, e.g. to try the negotiation with a client
+    if let Some(port) = options.telnet {
+        let config = TelnetConfig {
+            port,
+            bind: Ipv4Addr::LOCALHOST,
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            if let Err(e) = telnet::serve(config, || ROOT_MENU) {
+                eprintln!("Telnet server stopped: {}", e);
+            }
+        });
+    }
+
+    let mut clibuf = [0u8; 64];
+    let mut r = Runner::new(
+        ROOT_MENU,
+        &mut clibuf,
+        Context::new(Box::new(StdioTransport)),
+    );
+
+    // The session ends with the input, e.g. at the end of a piped script
+    while let Ok(byte) = r.context.read_byte() {
+        r.input_byte(byte);
+        if r.context.is_closed() {
+            break;
+        }
+    }
+}
+
+// Join the network saved by 'wifi connect', if any
+fn join_saved_network() {
+    match backend::wifi(|wifi| wifi.reconnect()) {
+        Some(Ok(Some(ssid))) => println!("Wifi Connected to {}", ssid),
+        Some(Ok(None)) => println!("No saved network, use 'wifi connect <ssid>'"),
+        Some(Err(e)) => println!("Wifi connection failed: {}", e),
+        None => (),
+    }
+}
+
+// Draw the passkey for the next BLE pairing from the hardware RNG and print
+// it for the user to type on the phone
+#[cfg(target_os = "espidf")]
+fn new_passkey() {
+    let passkey = passkey::passkey(|| unsafe { esp_idf_svc::sys::esp_random() });
+    esp32_nimble::BLEDevice::take()
+        .security()
+        .set_passkey(passkey);
+    println!("Passkey for BLE pairing: {:06}", passkey);
+}
+
+// Output of a command in the format of the session, or in JSON for this
+// command only when its line has --json
+fn output<'a>(
+    item: &'a Item<'a, Context>,
     args: &[&str],
-    context: &mut UartDriver,
-) {
+    context: &'a mut Context,
+) -> Output<'a, Context> {
+    let format = if args.contains(&"--json") {
+        Format::Json
+    } else {
+        context.format()
+    };
+    Output::new(context, format, item.command)
+}
+
+// Check the arguments against `specs`, reporting what is wrong with them
+fn parse_args<'a>(
+    item: &'a Item<'a, Context>,
+    args: &'a [&'a str],
+    specs: &[Arg],
+    out: &mut Output<'_, Context>,
+) -> Option<Parsed<'a>> {
+    match args::parse(item, args, specs) {
+        Ok(parsed) => Some(parsed),
+        Err(e) => {
+            out.error(Status::InvalidArgument, &e);
+            out.text(|f| writeln!(f, "{}", args::usage(item)));
+            None
+        }
+    }
+}
+
+// Callback function for hw command
+fn hello_name(_menu: &Menu<Context>, item: &Item<Context>, args: &[&str], context: &mut Context) {
+    let mut out = output(item, args, context);
+    let args = match parse_args(item, args, HW_ARGS, &mut out) {
+        Some(args) => args,
+        None => return,
+    };
     // Print to console passed "name" argument
-    writeln!(
-        context,
-        "Hello, {}!",
-        argument_finder(item, args, "name").unwrap().unwrap()
-    )
-    .unwrap();
+    let name: &str = args.value("name");
+    let greeting = format!("Hello, {}!", name);
+    out.result(
+        Object::new()
+            .field("name", name)
+            .field("greeting", greeting.as_str()),
+        |f| writeln!(f, "{}", greeting),
+    );
 }
 
 // Callback function for ping command
-fn ping_app<'a>(
-    _menu: &Menu<UartDriver>,
-    item: &Item<UartDriver>,
-    args: &[&str],
-    context: &mut UartDriver,
-) {
+fn ping_app(_menu: &Menu<Context>, item: &Item<Context>, args: &[&str], context: &mut Context) {
     // Retreieve CLI Input
-    let ip_str = argument_finder(item, args, "hostname/IP").unwrap().unwrap();
-
-    // Resolve IP Address
-    let addresses = (ip_str, 0)
-        .to_socket_addrs()
-        .expect("Unable to resolve domain")
-        .next()
-        .unwrap();
+    let mut out = output(item, args, context);
+    let args = match parse_args(item, args, PING_ARGS, &mut out) {
+        Some(args) => args,
+        None => return,
+    };
+    let ip_str: &str = args.value("hostname/IP");
 
     // Convert to IP v4 type address
-    let addr = match addresses {
-        std::net::SocketAddr::V4(a) => *a.ip(),
-        std::net::SocketAddr::V6(_) => {
-            writeln!(context, "Address not compatible, try again").unwrap();
+    let addr = match args.value("hostname/IP") {
+        IpAddr::V4(a) => a,
+        IpAddr::V6(_) => {
+            out.error(Status::InvalidArgument, "Address not compatible, try again");
             return;
         }
     };
 
-    // Create EspPing instance
-    let mut ping = EspPing::new(0_u32);
-
     // Setup Default Ping Config
-    let mut ping_config = PingConfiguration::default();
+    let mut ping_config = ping::Config::default();
 
     // Obtain CLI Options and Modify Default Configuration Accordingly
-    ping_config.count = 1;
-    let mut ping_attempts = 4;
-
-    match argument_finder(item, args, "count") {
-        Ok(arg) => match arg {
-            Some(cnt) => ping_attempts = FromStr::from_str(cnt).unwrap(),
-            None => (),
-        },
-        Err(_) => (),
-    }
-
-    match argument_finder(item, args, "interval") {
-        Ok(arg) => match arg {
-            Some(inter) => {
-                ping_config.interval = Duration::from_secs(FromStr::from_str(inter).unwrap())
-            }
-            None => (),
-        },
-        Err(_) => (),
+    let ping_attempts: u32 = args.value("count");
+    if let Some(interval) = args.get("interval") {
+        ping_config.interval = interval;
     }
-
-    match argument_finder(item, args, "timeout") {
-        Ok(arg) => match arg {
-            Some(to) => ping_config.timeout = Duration::from_secs(FromStr::from_str(to).unwrap()),
-            None => (),
-        },
-        Err(_) => (),
+    if let Some(timeout) = args.get("timeout") {
+        ping_config.timeout = timeout;
     }
-
-    match argument_finder(item, args, "size") {
-        Ok(arg) => match arg {
-            Some(sz) => ping_config.data_size = FromStr::from_str(sz).unwrap(),
-            None => (),
-        },
-        Err(_) => (),
+    if let Some(size) = args.get("size") {
+        ping_config.data_size = size;
     }
 
-    println!("{:?}", ping_config);
-
     // Update CLI
     // Pinging {IP} with {x} bytes of data
-    writeln!(
-        context,
-        "Pinging {} [{:?}] with {} bytes of data\n",
-        ip_str, addr, ping_config.data_size
-    )
-    .unwrap();
+    out.text(|f| {
+        writeln!(
+            f,
+            "Pinging {} [{:?}] with {} bytes of data\n",
+            ip_str, addr, ping_config.data_size
+        )
+    });
 
-    let mut summary = Summary::default();
     let mut times: Vec<u128> = Vec::new();
     let mut rx_count = 0;
 
     // Ping 4 times and print results in following format:
     // Reply from {IP}: bytes={summary.recieved} time={summary.time} TTL={summary.timeout}
-    for _n in 1..=ping_attempts {
-        summary = ping.ping(addr, &ping_config).unwrap();
+    for n in 1..=ping_attempts {
+        let reply = match backend::pinger(|pinger| pinger.ping(addr, &ping_config)) {
+            Some(Ok(reply)) => reply,
+            Some(Err(e)) => {
+                out.error(Status::Failed, format_args!("Ping failed: {}", e));
+                return;
+            }
+            None => {
+                out.error(Status::Unavailable, "Ping is not available");
+                return;
+            }
+        };
 
-        writeln!(
-            context,
-            "Reply from {:?}: bytes = {}, time = {:?}, TTL = {:?}",
-            addr, ping_config.data_size, summary.time, ping_config.timeout
-        )
-        .unwrap();
+        out.result(
+            Object::new()
+                .field("type", "reply")
+                .field("seq", n)
+                .field("address", addr.to_string())
+                .field("bytes", ping_config.data_size)
+                .field("time_ms", reply.time.as_secs_f64() * 1000.0)
+                .field("received", reply.received),
+            |f| {
+                writeln!(
+                    f,
+                    "Reply from {:?}: bytes = {}, time = {:?}, TTL = {:?}",
+                    addr, ping_config.data_size, reply.time, ping_config.timeout
+                )
+            },
+        );
 
         // Update values for statistics
-        times.push(summary.time.as_millis());
-        if summary.transmitted == summary.received {
+        times.push(reply.time.as_millis());
+        if reply.received {
             rx_count += 1;
         }
     }
@@ -281,23 +675,546 @@ fn ping_app<'a>(
     //      Packets: Sent = {sent}, Recieved  = {rec}, Lost = {loss} <{per}% Loss>
     // Approximate round trip times in milliseconds:
     //      Minimum = {min}ms, Maximum = {max}ms, Average = {avg}ms
-    writeln!(context, "\nPing Statistics for {:?}", addr).unwrap();
-    writeln!(
-        context,
-        "     Packets: Sent = {}, Recieved  = {}, Lost = {} <{}% loss>",
-        ping_attempts,
-        rx_count,
-        ping_attempts - rx_count,
-        ((ping_attempts - rx_count) / ping_attempts) * 100
-    )
-    .unwrap();
-    writeln!(context, "Approximate round trip times in milliseconds:").unwrap();
-    writeln!(
-        context,
-        "     Minimum = {} ms, Maximum = {} ms, Average = {} ms",
-        times.iter().min().unwrap(),
-        times.iter().max().unwrap(),
-        times.iter().sum::<u128>() / times.len() as u128,
-    )
-    .unwrap();
+    let min = *times.iter().min().unwrap();
+    let max = *times.iter().max().unwrap();
+    let avg = times.iter().sum::<u128>() / times.len() as u128;
+    let loss = (ping_attempts - rx_count) * 100 / ping_attempts;
+    out.result(
+        Object::new()
+            .field("type", "statistics")
+            .field("address", addr.to_string())
+            .field("sent", ping_attempts)
+            .field("received", rx_count)
+            .field("lost", ping_attempts - rx_count)
+            .field("loss_percent", loss)
+            .field("min_ms", min as u64)
+            .field("max_ms", max as u64)
+            .field("avg_ms", avg as u64),
+        |f| {
+            writeln!(f, "\nPing Statistics for {:?}", addr)?;
+            writeln!(
+                f,
+                "     Packets: Sent = {}, Recieved  = {}, Lost = {} <{}% loss>",
+                ping_attempts,
+                rx_count,
+                ping_attempts - rx_count,
+                loss
+            )?;
+            writeln!(f, "Approximate round trip times in milliseconds:")?;
+            writeln!(
+                f,
+                "     Minimum = {} ms, Maximum = {} ms, Average = {} ms",
+                min, max, avg,
+            )
+        },
+    );
+}
+
+// Callback function for iperf command
+fn iperf_app(_menu: &Menu<Context>, item: &Item<Context>, args: &[&str], context: &mut Context) {
+    let mut out = output(item, args, context);
+    let args = match parse_args(item, args, IPERF_ARGS, &mut out) {
+        Some(args) => args,
+        None => return,
+    };
+    let protocol = if args.value("udp") {
+        Protocol::Udp
+    } else {
+        Protocol::Tcp
+    };
+
+    // Exactly one of server or client mode has to be chosen
+    let server: bool = args.value("server");
+    let mode = match args.get::<IpAddr>("client") {
+        Some(_) if server => {
+            out.error(
+                Status::InvalidArgument,
+                "Choose either --server or --client",
+            );
+            return;
+        }
+        Some(ip @ IpAddr::V4(_)) => IperfMode::Client(SocketAddr::new(ip, iperf::DEFAULT_PORT)),
+        Some(IpAddr::V6(_)) => {
+            out.error(Status::InvalidArgument, "Address not compatible, try again");
+            return;
+        }
+        None if server => IperfMode::Server,
+        None => {
+            out.error(
+                Status::InvalidArgument,
+                "Choose either --server or --client=<host>",
+            );
+            return;
+        }
+    };
+
+    let mut config = IperfConfig::new(mode, protocol);
+    if let Some(time) = args.get("time") {
+        config.duration = time;
+    }
+    if let Some(len) = args.get("len") {
+        config.len = len;
+    }
+    config.interval = args.get("interval");
+    if let Some(port) = args.get("port") {
+        config.port = port;
+    }
+    if let Some(rate) = args.get("bandwidth") {
+        match iperf::parse_bandwidth(rate) {
+            Some(bandwidth) => config.bandwidth = bandwidth,
+            None => {
+                out.error(
+                    Status::InvalidArgument,
+                    format_args!("Invalid bandwidth: {}", rate),
+                );
+                return;
+            }
+        }
+    }
+
+    // The client connects to the chosen port rather than the default one
+    if let IperfMode::Client(addr) = &mut config.mode {
+        addr.set_port(config.port);
+    }
+
+    if let Err(e) = config.validate() {
+        out.error(Status::InvalidArgument, e);
+        return;
+    }
+
+    if let Err(e) = iperf::run(&config, &mut out) {
+        out.error(Status::Failed, format_args!("iperf failed: {}", e));
+    }
+}
+
+// Callback function for wifi command
+fn wifi_app(_menu: &Menu<Context>, item: &Item<Context>, args: &[&str], context: &mut Context) {
+    let mut out = output(item, args, context);
+    let args = match parse_args(item, args, WIFI_ARGS, &mut out) {
+        Some(args) => args,
+        None => return,
+    };
+    let action: &str = args.value("action");
+    if action == "connect" {
+        // A failed read means the session is gone, there is nobody to tell
+        let _ = wifi_connect(&args, &mut out);
+        return;
+    }
+
+    let result = backend::wifi(|wifi| match action {
+        "scan" => wifi.scan().map(|networks| {
+            (wifi::scan_object(&networks), {
+                let mut text = String::new();
+                wifi::write_scan(&networks, &mut text).unwrap();
+                text
+            })
+        }),
+        "status" => {
+            let status = wifi.status();
+            let mut text = String::new();
+            wifi::write_status(status.as_ref(), &mut text).unwrap();
+            Ok((wifi::status_object(status.as_ref()), text))
+        }
+        _ => wifi
+            .forget()
+            .map(|_| (Object::new(), "Saved network forgotten\n".to_string())),
+    });
+    match result {
+        Some(Ok((object, text))) => out.result(object, |f| f.write_str(&text)),
+        Some(Err(e)) => out.error(
+            Status::Failed,
+            format_args!("Wifi {} failed: {}", action, e),
+        ),
+        None => out.error(Status::Unavailable, "Wifi is not available"),
+    }
+}
+
+// Ask for whatever the command line left out, then join the network
+fn wifi_connect(args: &Parsed, out: &mut Output<Context>) -> io::Result<()> {
+    let ssid = match args.get::<&str>("ssid") {
+        Some(ssid) => ssid.to_string(),
+        None => match wifi::prompt(
+            out.inner(),
+            Context::read_byte,
+            "SSID: ",
+            Echo::Visible,
+            wifi::MAX_SSID_LEN,
+        )? {
+            Some(ssid) => ssid,
+            None => return Ok(()),
+        },
+    };
+    if let Err(e) = wifi::check_ssid(&ssid) {
+        out.error(Status::InvalidArgument, e);
+        return Ok(());
+    }
+
+    let auth = args.get("auth").and_then(Auth::parse);
+    let password = if auth == Some(Auth::Open) {
+        String::new()
+    } else {
+        match wifi::prompt(
+            out.inner(),
+            Context::read_byte,
+            "Password: ",
+            Echo::Hidden,
+            wifi::MAX_PASSWORD_LEN,
+        )? {
+            Some(password) => password,
+            None => return Ok(()),
+        }
+    };
+    let auth = auth.unwrap_or_else(|| Auth::guess(&password));
+    if let Err(e) = wifi::check_password(&password, auth) {
+        out.error(Status::InvalidArgument, e);
+        return Ok(());
+    }
+
+    out.text(|f| writeln!(f, "Connecting to {} ({})...", ssid, auth.name()));
+    match backend::wifi(|wifi| wifi.connect(&ssid, &password, auth).map(|_| wifi.status())) {
+        Some(Ok(status)) => out.result(wifi::status_object(status.as_ref()), |f| {
+            wifi::write_status(status.as_ref(), f)
+        }),
+        Some(Err(e)) => out.error(Status::Failed, format_args!("Wifi connect failed: {}", e)),
+        None => out.error(Status::Unavailable, "Wifi is not available"),
+    }
+    Ok(())
+}
+
+// Callback function for sys command
+fn sys_app(_menu: &Menu<Context>, item: &Item<Context>, args: &[&str], context: &mut Context) {
+    let mut out = output(item, args, context);
+    let args = match parse_args(item, args, SYS_ARGS, &mut out) {
+        Some(args) => args,
+        None => return,
+    };
+    let action: &str = args.value("action");
+    // Reboots wait outside of the closure so other sessions can still use
+    // the system back-end during the delay
+    let found = backend::system(|system| match action {
+        "heap" => {
+            let heap = system.heap();
+            out.result(sysinfo::heap_object(&heap), |f| {
+                sysinfo::write_heap(&heap, f)
+            });
+            None
+        }
+        "tasks" => {
+            let (tasks, total_runtime) = system.tasks();
+            out.result(sysinfo::tasks_object(&tasks, total_runtime), |f| {
+                sysinfo::write_tasks(&tasks, total_runtime, f)
+            });
+            None
+        }
+        "uptime" => {
+            let uptime = system.uptime();
+            out.result(sysinfo::uptime_object(uptime), |f| {
+                writeln!(f, "{}", sysinfo::format_uptime(uptime))
+            });
+            None
+        }
+        "reset-reason" => {
+            let reason = system.reset_reason();
+            out.result(
+                Object::new()
+                    .field("reason", reason.name())
+                    .field("description", reason.to_string()),
+                |f| writeln!(f, "{}", reason),
+            );
+            None
+        }
+        "chip" => {
+            match system.chip() {
+                Ok(chip) => out.result(sysinfo::chip_object(&chip), |f| {
+                    sysinfo::write_chip(&chip, f)
+                }),
+                Err(e) => out.error(
+                    Status::Failed,
+                    format_args!("Reading chip info failed: {}", e),
+                ),
+            }
+            None
+        }
+        _ => {
+            let delay: Duration = args.value("delay");
+            out.result(
+                Object::new().field("delay_ms", delay.as_millis() as u64),
+                |f| match delay.as_millis() {
+                    0 => writeln!(f, "Rebooting now"),
+                    ms => writeln!(f, "Rebooting in {} ms", ms),
+                },
+            );
+            Some(delay)
+        }
+    });
+    match found {
+        Some(Some(delay)) => {
+            std::thread::sleep(delay);
+            backend::system(|system| system.reboot());
+        }
+        Some(None) => (),
+        None => out.error(Status::Unavailable, "System information is not available"),
+    }
+}
+
+// Callback function for format command
+fn format_app(_menu: &Menu<Context>, item: &Item<Context>, args: &[&str], context: &mut Context) {
+    let format = {
+        let mut out = output(item, args, context);
+        match parse_args(item, args, FORMAT_ARGS, &mut out) {
+            Some(args) => args.get::<&str>("format"),
+            None => return,
+        }
+    };
+    match format {
+        Some("json") => context.set_format(Format::Json),
+        Some(_) => context.set_format(Format::Text),
+        None => (),
+    }
+    let mut out = output(item, args, context);
+    let format = out.format();
+    out.result(Object::new().field("format", format.name()), |f| {
+        writeln!(f, "Output format: {}", format.name())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::Transport;
+    use sim::{Options, SimPinger, SimSystem, SimWifi};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex, Once};
+
+    /// Types the input and records what the CLI writes, the input ending
+    /// closes the session
+    struct Script {
+        input: VecDeque<u8>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Script {
+        fn new(input: &str) -> (Self, Arc<Mutex<Vec<u8>>>) {
+            let output = Arc::default();
+            let script = Script {
+                input: input.bytes().collect(),
+                output: Arc::clone(&output),
+            };
+            (script, output)
+        }
+    }
+
+    impl Transport for Script {
+        fn read_byte(&mut self) -> io::Result<u8> {
+            self.input
+                .pop_front()
+                .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+        }
+
+        fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.output.lock().unwrap().extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn text(output: &Mutex<Vec<u8>>) -> String {
+        String::from_utf8(output.lock().unwrap().clone()).unwrap()
+    }
+
+    // The back-ends are process wide, every test gets the same simulation
+    fn install_sim() {
+        static INSTALL: Once = Once::new();
+        INSTALL.call_once(|| {
+            backend::install(
+                Box::new(SimPinger::new(&Options::default())),
+                Box::<SimWifi>::default(),
+                Box::<SimSystem>::default(),
+            )
+        });
+    }
+
+    /// Run one command callback the way the menu runner would
+    fn run(command: &str, args: &[&str]) -> String {
+        install_sim();
+        let item = ROOT_MENU
+            .items
+            .iter()
+            .find(|item| item.command == command)
+            .unwrap();
+        let (script, output) = Script::new("");
+        let mut context = Context::new(Box::new(script));
+        if let ItemType::Callback { function, .. } = item.item_type {
+            function(&ROOT_MENU, item, args, &mut context);
+        }
+        text(&output)
+    }
+
+    /// Type `input` into a whole CLI session like the PC build's main loop,
+    /// returning what it printed without the prompts and the echo of the
+    /// typed lines
+    fn session(input: &str) -> Vec<String> {
+        install_sim();
+        let (script, output) = Script::new(input);
+        let mut buffer = [0u8; 64];
+        let mut runner = Runner::new(ROOT_MENU, &mut buffer, Context::new(Box::new(script)));
+        while let Ok(byte) = runner.context.read_byte() {
+            runner.input_byte(byte);
+        }
+        text(&output)
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with("> "))
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn hello_text_and_json() {
+        assert_eq!(run("hw", &["Ana"]), "Hello, Ana!\n");
+        assert_eq!(
+            run("hw", &["Ana", "--json"]),
+            "{\"command\":\"hw\",\"status\":0,\"name\":\"Ana\",\"greeting\":\"Hello, Ana!\"}\n"
+        );
+    }
+
+    #[test]
+    fn ping_text() {
+        assert_eq!(
+            run("ping", &["192.0.2.1", "--count=2", "--interval=0s"]),
+            "Pinging 192.0.2.1 [192.0.2.1] with 56 bytes of data\n\
+             \n\
+             Reply from 192.0.2.1: bytes = 56, time = 20ms, TTL = 1s\n\
+             Reply from 192.0.2.1: bytes = 56, time = 20ms, TTL = 1s\n\
+             \n\
+             Ping Statistics for 192.0.2.1\n     \
+             Packets: Sent = 2, Recieved  = 2, Lost = 0 <0% loss>\n\
+             Approximate round trip times in milliseconds:\n     \
+             Minimum = 20 ms, Maximum = 20 ms, Average = 20 ms\n"
+        );
+    }
+
+    #[test]
+    fn ping_json_is_only_json_lines() {
+        assert_eq!(
+            run(
+                "ping",
+                &[
+                    "192.0.2.1",
+                    "--count=2",
+                    "--interval=0s",
+                    "--size=100",
+                    "--json"
+                ]
+            ),
+            concat!(
+                r#"{"command":"ping","status":0,"type":"reply","seq":1,"address":"192.0.2.1","bytes":100,"time_ms":20,"received":true}"#,
+                "\n",
+                r#"{"command":"ping","status":0,"type":"reply","seq":2,"address":"192.0.2.1","bytes":100,"time_ms":20,"received":true}"#,
+                "\n",
+                r#"{"command":"ping","status":0,"type":"statistics","address":"192.0.2.1","sent":2,"received":2,"lost":0,"loss_percent":0,"min_ms":20,"max_ms":20,"avg_ms":20}"#,
+                "\n",
+            )
+        );
+    }
+
+    #[test]
+    fn ping_errors() {
+        assert_eq!(
+            run("ping", &["::1", "--json"]),
+            "{\"command\":\"ping\",\"status\":2,\"error\":\"Address not compatible, try again\"}\n"
+        );
+        // The usage line is only for people
+        assert_eq!(
+            run("ping", &["192.0.2.1", "--count=0", "--json"])
+                .lines()
+                .count(),
+            1
+        );
+        assert!(run("ping", &["192.0.2.1", "--count=0"]).ends_with(
+            "Usage: ping <hostname/IP> [--count=<cnt>] [--interval=<int>] [--timeout=<to>] [--size=<sz>] [--json]\n"
+        ));
+    }
+
+    // The Wi-Fi simulation is shared, so this is the only test using it
+    #[test]
+    fn wifi_session() {
+        let transcript = session(
+            "wifi scan\r\
+             wifi connect sim-home\rwrong password\r\
+             wifi connect sim-home\rshort\r\
+             wifi connect sim-home\rpassword\r\
+             wifi status --json\r\
+             wifi forget\r\
+             wifi status\r",
+        );
+        assert_eq!(
+            transcript,
+            [
+                "SSID         RSSI  CH  AUTH",
+                "Wokwi-GUEST   -42   6  open",
+                "sim-home      -58   1  wpa2",
+                "sim-office    -71  11  wpa2-wpa3",
+                "<hidden>      -85  11  wpa2",
+                "Password: ",
+                "Connecting to sim-home (wpa2)...",
+                "Wifi connect failed: wrong password",
+                "Password: ",
+                "wpa2 passwords are 8 to 63 characters, or 64 hex digits",
+                "Password: ",
+                "Connecting to sim-home (wpa2)...",
+                "SSID:     sim-home",
+                "BSSID:    02:00:00:00:00:02",
+                "Channel:  1",
+                "RSSI:     -58 dBm",
+                "IP:       192.168.71.10/24",
+                "Gateway:  192.168.71.1",
+                "DNS:      192.168.71.1",
+                r#"{"command":"wifi","status":0,"connected":true,"ssid":"sim-home","bssid":"02:00:00:00:00:02","channel":1,"rssi":-58,"ip":"192.168.71.10","prefix":24,"gateway":"192.168.71.1","dns":"192.168.71.1"}"#,
+                "Saved network forgotten",
+                "Not connected",
+            ]
+        );
+    }
+
+    #[test]
+    fn json_session() {
+        let transcript = session(
+            "format\r\
+             format json\r\
+             ping 192.0.2.7 --count=1 --interval=0s\r\
+             ping 192.0.2.7 --size=70000\r\
+             sys reset-reason\r\
+             sys heap\r\
+             format text\r\
+             sys heap\r",
+        );
+        assert_eq!(
+            transcript,
+            [
+                "Output format: text",
+                r#"{"command":"format","status":0,"format":"json"}"#,
+                r#"{"command":"ping","status":0,"type":"reply","seq":1,"address":"192.0.2.7","bytes":56,"time_ms":20,"received":true}"#,
+                r#"{"command":"ping","status":0,"type":"statistics","address":"192.0.2.7","sent":1,"received":1,"lost":0,"loss_percent":0,"min_ms":20,"max_ms":20,"avg_ms":20}"#,
+                r#"{"command":"ping","status":2,"error":"Invalid size '70000': expected an integer from 0 to 65507"}"#,
+                r#"{"command":"sys","status":0,"reason":"poweron","description":"power-on"}"#,
+                r#"{"command":"sys","status":0,"free":200000,"minimum":180000,"largest_block":110592}"#,
+                "Output format: text",
+                "Free:            200000 bytes",
+                "Minimum free:    180000 bytes",
+                "Largest block:   110592 bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_commands_and_arguments() {
+        assert_eq!(
+            session("bogus\rsys nap\rhw\r"),
+            [
+                "Command \"bogus\" not found. Try 'help'.",
+                "Invalid action 'nap': expected one of heap, tasks, uptime, reset-reason, chip, reboot",
+                "Usage: sys <action> [--delay=<time>] [--json]",
+                "Error: Insufficient arguments given",
+            ]
+        );
+    }
 }
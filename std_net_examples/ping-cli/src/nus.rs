@@ -0,0 +1,194 @@
+//! Framing of CLI sessions carried over the Nordic UART Service.
+//!
+//! Output goes out as TX notifications no longer than the ATT MTU allows,
+//! input arrives as RX writes of any length. Terminal apps decode every
+//! notification on its own, so characters are never split across two.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// MTU every connection starts with, before an exchange raises it
+pub const DEFAULT_MTU: u16 = 23;
+/// Opcode and handle in front of every notification
+const ATT_HEADER_LEN: usize = 3;
+/// ATT error answering writes that do not fit the input buffer
+pub const ATT_INSUFFICIENT_RESOURCES: u8 = 0x11;
+/// Time the controller gets to send one notification before the next is
+/// queued, so long output does not run NimBLE out of buffers
+#[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+pub const NOTIFY_PACING: Duration = Duration::from_millis(10);
+
+/// Largest notification payload on a connection with `mtu`
+pub fn payload_len(mtu: u16) -> usize {
+    mtu.max(DEFAULT_MTU) as usize - ATT_HEADER_LEN
+}
+
+/// Split `bytes` into notification payloads for `mtu`. A payload only ends
+/// inside a UTF-8 character if the character alone is longer than a payload.
+pub fn fragments(bytes: &[u8], mtu: u16) -> Vec<&[u8]> {
+    let max = payload_len(mtu);
+    let mut fragments = Vec::with_capacity(bytes.len() / max + 1);
+    let mut rest = bytes;
+    while rest.len() > max {
+        // Back off over continuation bytes to the start of the cut character
+        let mut end = max;
+        while end > 0 && rest[end] & 0xc0 == 0x80 {
+            end -= 1;
+        }
+        if end == 0 {
+            end = max;
+        }
+        let (fragment, tail) = rest.split_at(end);
+        fragments.push(fragment);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        fragments.push(rest);
+    }
+    fragments
+}
+
+/// Input written to RX, read by the CLI a byte at a time. Line endings are
+/// turned into the carriage return the menu runner expects, whether the app
+/// sends CR, LF or CR LF, even when the pair is split across two writes.
+pub struct Input {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+    last: u8,
+}
+
+impl Input {
+    pub const fn new(capacity: usize) -> Self {
+        Input {
+            bytes: VecDeque::new(),
+            capacity,
+            last: 0,
+        }
+    }
+
+    /// Queue a write, all of it or nothing when it does not fit. The error
+    /// is the ATT code that tells the writer to back off.
+    pub fn push(&mut self, data: &[u8]) -> Result<(), u8> {
+        if self.bytes.len() + data.len() > self.capacity {
+            return Err(ATT_INSUFFICIENT_RESOURCES);
+        }
+        for &byte in data {
+            match byte {
+                b'\n' if self.last == b'\r' => (),
+                b'\n' => self.bytes.push_back(b'\r'),
+                byte => self.bytes.push_back(byte),
+            }
+            self.last = byte;
+        }
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        self.bytes.pop_front()
+    }
+
+    /// Drop what the previous connection left behind
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.last = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(input: &mut Input) -> Vec<u8> {
+        std::iter::from_fn(|| input.pop()).collect()
+    }
+
+    #[test]
+    fn payload_follows_the_mtu() {
+        assert_eq!(payload_len(DEFAULT_MTU), 20);
+        assert_eq!(payload_len(247), 244);
+        // Below the ATT minimum is treated as the minimum
+        assert_eq!(payload_len(0), 20);
+    }
+
+    #[test]
+    fn ascii_is_cut_at_the_payload_length() {
+        let text = [b'x'; 45];
+        let lens: Vec<_> = fragments(&text, 23).iter().map(|f| f.len()).collect();
+        assert_eq!(lens, [20, 20, 5]);
+        assert_eq!(fragments(&text[..20], 23).len(), 1);
+        assert!(fragments(b"", 23).is_empty());
+    }
+
+    #[test]
+    fn characters_are_not_split() {
+        // 19 ASCII bytes, then 'é' (2 bytes) straddling the 20 byte payload
+        let text = format!("{}é{}", "a".repeat(19), "b".repeat(5));
+        let fragments = fragments(text.as_bytes(), 23);
+        assert_eq!(fragments[0], "a".repeat(19).as_bytes());
+        assert_eq!(fragments[1], "ébbbbb".as_bytes());
+
+        // Every fragment of a long mixed text is valid UTF-8 on its own
+        let text = "température 25 °C → ok ✓ 🙂".repeat(8);
+        let fragments = super::fragments(text.as_bytes(), 23);
+        assert!(fragments.iter().all(|f| f.len() <= 20));
+        for fragment in &fragments {
+            assert!(std::str::from_utf8(fragment).is_ok());
+        }
+        assert_eq!(fragments.concat(), text.as_bytes());
+    }
+
+    #[test]
+    fn four_byte_character_at_the_boundary() {
+        let text = format!("{}🙂", "a".repeat(18));
+        let fragments = fragments(text.as_bytes(), 23);
+        assert_eq!(fragments, [&text.as_bytes()[..18], "🙂".as_bytes()]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_cut_where_it_must() {
+        // Continuation bytes with no start can only be cut at the limit
+        let bytes = [0x80; 30];
+        let lens: Vec<_> = fragments(&bytes, 23).iter().map(|f| f.len()).collect();
+        assert_eq!(lens, [20, 10]);
+    }
+
+    #[test]
+    fn line_endings_become_cr() {
+        let mut rx = Input::new(64);
+        rx.push(b"a\rb\nc\r\nd").unwrap();
+        assert_eq!(input(&mut rx), b"a\rb\rc\rd");
+        rx.push(b"\n\n").unwrap();
+        assert_eq!(input(&mut rx), b"\r\r");
+    }
+
+    #[test]
+    fn cr_lf_split_across_writes() {
+        let mut rx = Input::new(64);
+        rx.push(b"help\r").unwrap();
+        rx.push(b"\nping").unwrap();
+        assert_eq!(input(&mut rx), b"help\rping");
+    }
+
+    #[test]
+    fn overflow_is_refused_whole() {
+        let mut rx = Input::new(8);
+        rx.push(b"12345").unwrap();
+        assert_eq!(rx.push(b"6789"), Err(0x11));
+        assert_eq!(rx.push(b"678"), Ok(()));
+        assert_eq!(rx.push(b"9"), Err(ATT_INSUFFICIENT_RESOURCES));
+        assert_eq!(input(&mut rx), b"12345678");
+        // Reading makes room again
+        assert_eq!(rx.push(b"9"), Ok(()));
+    }
+
+    #[test]
+    fn clear_forgets_the_previous_connection() {
+        let mut rx = Input::new(8);
+        rx.push(b"abc\r").unwrap();
+        rx.clear();
+        assert_eq!(rx.pop(), None);
+        // A leading LF is a line ending of its own again
+        rx.push(b"\n").unwrap();
+        assert_eq!(input(&mut rx), b"\r");
+    }
+}
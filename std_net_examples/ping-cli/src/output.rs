@@ -0,0 +1,296 @@
+//! Command output in the human readable form or as JSON, one object per
+//! line, for scripts driving the CLI.
+//!
+//! Every JSON object starts with the `command` that produced it and a
+//! numeric `status`, followed by the fields of the result or an `error`.
+
+use std::fmt::{self, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// Value of the `status` field, these numbers must not change
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    /// The command ran but the operation failed
+    Failed = 1,
+    /// The command line was not understood
+    InvalidArgument = 2,
+    /// A resource the command needs is missing, e.g. no network
+    Unavailable = 3,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Object),
+}
+
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Self {
+                Value::Int(n as i64)
+            }
+        })*
+    };
+}
+
+from_int!(i8, i16, i32, i64, u8, u16, u32, usize, u64);
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Float(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl From<Object> for Value {
+    fn from(object: Object) -> Self {
+        Value::Object(object)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(n) => write!(f, "{}", n),
+            // JSON has no NaN or infinity
+            Value::Float(n) if !n.is_finite() => f.write_str("null"),
+            Value::Float(n) => write!(f, "{}", n),
+            Value::String(s) => write_string(f, s),
+            Value::Array(values) => {
+                f.write_char('[')?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                f.write_char(']')
+            }
+            Value::Object(object) => write!(f, "{}", object),
+        }
+    }
+}
+
+/// JSON object keeping its fields in the order they were added
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Object(Vec<(&'static str, Value)>);
+
+impl Object {
+    pub fn new() -> Self {
+        Object(Vec::new())
+    }
+
+    pub fn field(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.0.push((name, value.into()));
+        self
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('{')?;
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            write_string(f, name)?;
+            write!(f, ":{}", value)?;
+        }
+        f.write_char('}')
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+/// Writes the results of one command in the chosen format
+pub struct Output<'a, W: Write> {
+    out: &'a mut W,
+    format: Format,
+    command: &'a str,
+}
+
+impl<'a, W: Write> Output<'a, W> {
+    pub fn new(out: &'a mut W, format: Format, command: &'a str) -> Self {
+        Output {
+            out,
+            format,
+            command,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The writer underneath, for commands that also read from it
+    pub fn inner(&mut self) -> &mut W {
+        self.out
+    }
+
+    /// Output that only makes sense to people, such as banners and headings,
+    /// left out of JSON
+    pub fn text(&mut self, text: impl FnOnce(&mut W) -> fmt::Result) {
+        if self.format == Format::Text {
+            text(self.out).unwrap();
+        }
+    }
+
+    /// A successful result, written by `text` or as `object`
+    pub fn result(&mut self, object: Object, text: impl FnOnce(&mut W) -> fmt::Result) {
+        match self.format {
+            Format::Text => text(self.out).unwrap(),
+            Format::Json => self.json(Status::Ok, object),
+        }
+    }
+
+    pub fn error(&mut self, status: Status, message: impl fmt::Display) {
+        match self.format {
+            Format::Text => writeln!(self.out, "{}", message).unwrap(),
+            Format::Json => self.json(status, Object::new().field("error", message.to_string())),
+        }
+    }
+
+    fn json(&mut self, status: Status, object: Object) {
+        let mut fields = vec![
+            ("command", Value::from(self.command)),
+            ("status", Value::from(status as i32)),
+        ];
+        fields.extend(object.0);
+        writeln!(self.out, "{}", Object(fields)).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(format: Format, f: impl FnOnce(&mut Output<String>)) -> String {
+        let mut out = String::new();
+        f(&mut Output::new(&mut out, format, "demo"));
+        out
+    }
+
+    fn result(out: &mut Output<String>) {
+        out.text(|f| writeln!(f, "Heading"));
+        out.result(Object::new().field("answer", 42).field("ok", true), |f| {
+            writeln!(f, "The answer is 42")
+        });
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(write(Format::Text, result), "Heading\nThe answer is 42\n");
+        assert_eq!(
+            write(Format::Text, |out| out.error(Status::Failed, "It broke")),
+            "It broke\n"
+        );
+    }
+
+    #[test]
+    fn json_lines_start_with_command_and_status() {
+        assert_eq!(
+            write(Format::Json, result),
+            "{\"command\":\"demo\",\"status\":0,\"answer\":42,\"ok\":true}\n"
+        );
+        assert_eq!(
+            write(Format::Json, |out| {
+                out.error(Status::InvalidArgument, "Bad");
+                out.error(Status::Unavailable, "Gone");
+            }),
+            "{\"command\":\"demo\",\"status\":2,\"error\":\"Bad\"}\n\
+             {\"command\":\"demo\",\"status\":3,\"error\":\"Gone\"}\n"
+        );
+    }
+
+    #[test]
+    fn values() {
+        let object = Object::new()
+            .field("null", None::<u32>)
+            .field("some", Some(-1_i32))
+            .field("float", 2.5)
+            .field("nan", f64::NAN)
+            .field("whole", 20.0)
+            .field("list", vec!["a", "b"])
+            .field("empty", Vec::<u8>::new())
+            .field("nested", Object::new().field("n", u64::from(u32::MAX)));
+        assert_eq!(
+            object.to_string(),
+            r#"{"null":null,"some":-1,"float":2.5,"nan":null,"whole":20,"list":["a","b"],"empty":[],"nested":{"n":4294967295}}"#
+        );
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let object = Object::new().field("s", "quote \" back \\ \n\r\t \u{1} é");
+        assert_eq!(
+            object.to_string(),
+            r#"{"s":"quote \" back \\ \n\r\t \u0001 é"}"#
+        );
+    }
+}
@@ -0,0 +1,45 @@
+//! Passkeys shown on the console for Bluetooth LE pairing
+
+/// Passkeys have six decimal digits
+const RANGE: u32 = 1_000_000;
+
+/// Random passkey from `random`, a source of uniform `u32`s such as the
+/// hardware RNG. Draws from the incomplete range at the top of `u32` are
+/// repeated so every passkey is equally likely.
+pub fn passkey(mut random: impl FnMut() -> u32) -> u32 {
+    let limit = u32::MAX - u32::MAX % RANGE;
+    loop {
+        let value = random();
+        if value < limit {
+            return value % RANGE;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Passkey drawn from `values`, with the number of values used
+    fn draw(values: &[u32]) -> (u32, usize) {
+        let mut used = 0;
+        let passkey = passkey(|| {
+            used += 1;
+            values[used - 1]
+        });
+        (passkey, used)
+    }
+
+    #[test]
+    fn six_digits() {
+        assert_eq!(draw(&[0]), (0, 1));
+        assert_eq!(draw(&[1_234_567]), (234_567, 1));
+        assert_eq!(draw(&[4_293_999_999]), (999_999, 1));
+    }
+
+    #[test]
+    fn the_incomplete_range_is_drawn_again() {
+        assert_eq!(draw(&[4_294_000_000, 42]), (42, 2));
+        assert_eq!(draw(&[u32::MAX, u32::MAX, 7]), (7, 3));
+    }
+}
@@ -0,0 +1,58 @@
+//! Echo requests behind the `ping` command. The command sends one request
+//! per call so it can print every reply as it arrives.
+
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// Settings of one echo request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Least time from one request to the next
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub data_size: u32,
+}
+
+impl Default for Config {
+    // Same as the ESP-IDF ping defaults
+    fn default() -> Self {
+        Config {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+            data_size: 56,
+        }
+    }
+}
+
+/// Outcome of one echo request
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reply {
+    /// False when the request timed out
+    pub received: bool,
+    pub time: Duration,
+}
+
+/// Sends echo requests, over the chip's network interface or simulated
+pub trait Pinger: Send {
+    fn ping(&mut self, addr: Ipv4Addr, config: &Config) -> io::Result<Reply>;
+}
+
+#[cfg(target_os = "espidf")]
+impl Pinger for esp_idf_svc::ping::EspPing {
+    fn ping(&mut self, addr: Ipv4Addr, config: &Config) -> io::Result<Reply> {
+        let config = esp_idf_svc::ping::Configuration {
+            count: 1,
+            interval: config.interval,
+            timeout: config.timeout,
+            data_size: config.data_size,
+            ..Default::default()
+        };
+        let summary = esp_idf_svc::ping::EspPing::ping(self, addr, &config)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        Ok(Reply {
+            received: summary.transmitted == summary.received,
+            time: summary.time,
+        })
+    }
+}
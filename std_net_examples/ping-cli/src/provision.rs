@@ -0,0 +1,310 @@
+//! Framing of the Wi-Fi provisioning service: the commands a phone writes,
+//! the scan results notified back and the connection state.
+//!
+//! Scan results go out as one message cut into notifications. Each starts
+//! with its index in the message, the high bit set on the last one, so the
+//! phone knows when a scan is complete whatever the MTU.
+
+use crate::nus;
+use crate::wifi::{Auth, Network, MAX_PASSWORD_LEN, MAX_SSID_LEN};
+use std::cmp::Reverse;
+use std::net::Ipv4Addr;
+
+/// Networks sent back from a scan, the strongest ones
+const MAX_NETWORKS: usize = 20;
+/// Fragment header bit marking the last fragment of a message
+const LAST: u8 = 0x80;
+/// Authentication byte of a network that did not report one
+const AUTH_UNKNOWN: u8 = 0xFF;
+
+/// Written to the control characteristic
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Scan and notify the networks found
+    Scan,
+    /// Join the network written to the SSID and password characteristics
+    Connect,
+    /// Leave the network and drop the saved one
+    Forget,
+}
+
+impl Command {
+    pub fn parse(value: &[u8]) -> Option<Command> {
+        match value {
+            [0x01] => Some(Command::Scan),
+            [0x02] => Some(Command::Connect),
+            [0x03] => Some(Command::Forget),
+            _ => None,
+        }
+    }
+}
+
+/// Why a write was refused
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// Too short or too long
+    Length,
+    /// Not UTF-8, or a command that does not exist or cannot run yet
+    Value,
+}
+
+impl Rejection {
+    /// ATT error code answering the write
+    pub fn att_error(self) -> u8 {
+        match self {
+            // Invalid Attribute Value Length
+            Rejection::Length => 0x0D,
+            // Value Not Allowed
+            Rejection::Value => 0x13,
+        }
+    }
+}
+
+/// SSID written by the phone, 1 to 32 bytes of UTF-8
+pub fn ssid(value: &[u8]) -> Result<&str, Rejection> {
+    if value.is_empty() || value.len() > MAX_SSID_LEN {
+        return Err(Rejection::Length);
+    }
+    std::str::from_utf8(value).map_err(|_| Rejection::Value)
+}
+
+/// Password written by the phone, empty for an open network
+pub fn password(value: &[u8]) -> Result<&str, Rejection> {
+    if value.len() > MAX_PASSWORD_LEN {
+        return Err(Rejection::Length);
+    }
+    std::str::from_utf8(value).map_err(|_| Rejection::Value)
+}
+
+/// Connection state of the status characteristic
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Disconnected,
+    Connecting,
+    Connected {
+        ssid: String,
+        ip: Ipv4Addr,
+    },
+    /// The last connect did not bring the interface up
+    Failed,
+}
+
+impl State {
+    /// State byte, followed by the IP address and SSID once connected
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            State::Disconnected => vec![0x00],
+            State::Connecting => vec![0x01],
+            State::Connected { ssid, ip } => {
+                let mut value = vec![0x02];
+                value.extend_from_slice(&ip.octets());
+                value.extend_from_slice(ssid.as_bytes());
+                value
+            }
+            State::Failed => vec![0x03],
+        }
+    }
+}
+
+/// Scan results as one message, the strongest `MAX_NETWORKS` networks each
+/// as RSSI, channel, authentication, SSID length and SSID
+pub fn encode_networks(networks: &[Network]) -> Vec<u8> {
+    let mut networks: Vec<_> = networks.iter().collect();
+    networks.sort_by_key(|network| Reverse(network.rssi));
+
+    let mut message = Vec::new();
+    for network in networks.into_iter().take(MAX_NETWORKS) {
+        let ssid = &network.ssid.as_bytes()[..network.ssid.len().min(MAX_SSID_LEN)];
+        message.push(network.rssi as u8);
+        message.push(network.channel);
+        message.push(network.auth.map_or(AUTH_UNKNOWN, |auth| auth as u8));
+        message.push(ssid.len() as u8);
+        message.extend_from_slice(ssid);
+    }
+    message
+}
+
+/// `message` cut into notification payloads for `mtu`, each behind its
+/// fragment header. The index wraps after 127 fragments. An empty message
+/// still makes one fragment, telling the phone nothing was found.
+pub fn fragments(message: &[u8], mtu: u16) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        vec![&[]]
+    } else {
+        message.chunks(nus::payload_len(mtu) - 1).collect()
+    };
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut header = index as u8 & !LAST;
+            if index == last {
+                header |= LAST;
+            }
+            let mut fragment = vec![header];
+            fragment.extend_from_slice(chunk);
+            fragment
+        })
+        .collect()
+}
+
+/// Authentication to join `ssid` with, as seen by the last scan or else
+/// guessed from the password like `wifi connect` does
+pub fn auth(ssid: &str, password: &str, scanned: &[Network]) -> Auth {
+    scanned
+        .iter()
+        .find(|network| network.ssid == ssid)
+        .and_then(|network| network.auth)
+        .unwrap_or_else(|| Auth::guess(password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str, rssi: i8, auth: Option<Auth>) -> Network {
+        Network {
+            ssid: ssid.to_string(),
+            rssi,
+            channel: 6,
+            auth,
+        }
+    }
+
+    #[test]
+    fn commands_are_single_bytes() {
+        assert_eq!(Command::parse(&[0x01]), Some(Command::Scan));
+        assert_eq!(Command::parse(&[0x02]), Some(Command::Connect));
+        assert_eq!(Command::parse(&[0x03]), Some(Command::Forget));
+        assert_eq!(Command::parse(&[]), None);
+        assert_eq!(Command::parse(&[0x00]), None);
+        assert_eq!(Command::parse(&[0x04]), None);
+        assert_eq!(Command::parse(&[0x01, 0x01]), None);
+    }
+
+    #[test]
+    fn credentials_are_checked() {
+        assert_eq!(ssid(b"home"), Ok("home"));
+        assert_eq!(ssid(&[b'x'; MAX_SSID_LEN]).map(str::len), Ok(MAX_SSID_LEN));
+        assert_eq!(ssid(b""), Err(Rejection::Length));
+        assert_eq!(ssid(&[b'x'; MAX_SSID_LEN + 1]), Err(Rejection::Length));
+        assert_eq!(ssid(&[0xFF]), Err(Rejection::Value));
+
+        assert_eq!(password(b""), Ok(""));
+        assert_eq!(
+            password(&[b'x'; MAX_PASSWORD_LEN]).map(str::len),
+            Ok(MAX_PASSWORD_LEN)
+        );
+        assert_eq!(
+            password(&[b'x'; MAX_PASSWORD_LEN + 1]),
+            Err(Rejection::Length)
+        );
+        assert_eq!(password(&[0xC3]), Err(Rejection::Value));
+
+        assert_eq!(Rejection::Length.att_error(), 0x0D);
+        assert_eq!(Rejection::Value.att_error(), 0x13);
+    }
+
+    #[test]
+    fn state_encoding() {
+        assert_eq!(State::Disconnected.encode(), [0x00]);
+        assert_eq!(State::Connecting.encode(), [0x01]);
+        assert_eq!(State::Failed.encode(), [0x03]);
+        let connected = State::Connected {
+            ssid: "home".to_string(),
+            ip: Ipv4Addr::new(192, 168, 1, 20),
+        };
+        assert_eq!(connected.encode(), b"\x02\xC0\xA8\x01\x14home");
+    }
+
+    #[test]
+    fn networks_strongest_first() {
+        let networks = [
+            network("weak", -80, Some(Auth::Open)),
+            network("strong", -40, Some(Auth::Wpa2)),
+            network("hidden", -60, None),
+        ];
+        assert_eq!(
+            encode_networks(&networks),
+            [
+                b"\xD8\x06\x03\x06strong".as_slice(),
+                b"\xC4\x06\xFF\x06hidden",
+                b"\xB0\x06\x00\x04weak",
+            ]
+            .concat()
+        );
+        assert_eq!(encode_networks(&[]), []);
+    }
+
+    #[test]
+    fn networks_are_limited() {
+        let networks: Vec<_> = (0..30)
+            .map(|rssi| network("n", -rssi, Some(Auth::Wpa2)))
+            .collect();
+        let message = encode_networks(&networks);
+        assert_eq!(message.len(), MAX_NETWORKS * 5);
+        // The strongest 20 are kept, down to -19 dBm
+        assert_eq!(message[0], 0);
+        assert_eq!(message[message.len() - 5] as i8, -19);
+    }
+
+    #[test]
+    fn fragment_headers() {
+        let message: Vec<u8> = (0..45).collect();
+        let fragments = fragments(&message, nus::DEFAULT_MTU);
+        // 19 bytes of the message fit behind each header
+        assert_eq!(
+            fragments
+                .iter()
+                .map(|f| (f[0], f.len()))
+                .collect::<Vec<_>>(),
+            [(0x00, 20), (0x01, 20), (0x82, 8)]
+        );
+        assert_eq!(
+            fragments
+                .iter()
+                .flat_map(|f| &f[1..])
+                .copied()
+                .collect::<Vec<_>>(),
+            message
+        );
+    }
+
+    #[test]
+    fn single_and_empty_messages_are_last() {
+        assert_eq!(fragments(&[], nus::DEFAULT_MTU), [vec![LAST]]);
+        assert_eq!(fragments(&[1, 2], 247), [vec![LAST, 1, 2]]);
+        // Exactly one full fragment
+        let full = [7; 19];
+        let fragments = fragments(&full, nus::DEFAULT_MTU);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0][0], LAST);
+    }
+
+    #[test]
+    fn fragment_index_wraps_below_the_last_bit() {
+        let message = vec![0; 19 * 130];
+        let headers: Vec<u8> = fragments(&message, nus::DEFAULT_MTU)
+            .iter()
+            .map(|f| f[0])
+            .collect();
+        assert_eq!(headers.len(), 130);
+        assert_eq!(headers[127], 0x7F);
+        assert_eq!(headers[128], 0x00);
+        assert_eq!(headers[129], LAST | 0x01);
+        assert!(headers[..129].iter().all(|header| header & LAST == 0));
+    }
+
+    #[test]
+    fn auth_from_the_scan_or_guessed() {
+        let scanned = [
+            network("office", -50, Some(Auth::Wpa2Wpa3)),
+            network("cafe", -70, None),
+        ];
+        assert_eq!(auth("office", "secret", &scanned), Auth::Wpa2Wpa3);
+        assert_eq!(auth("cafe", "", &scanned), Auth::Open);
+        assert_eq!(auth("home", "secret", &scanned), Auth::Wpa2);
+        assert_eq!(auth("home", "", &[]), Auth::Open);
+    }
+}
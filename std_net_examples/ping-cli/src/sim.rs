@@ -0,0 +1,285 @@
+//! Simulated back-ends for running the CLI on a PC, where there is no radio
+//! and no FreeRTOS. Ping latency and loss are configurable from the command
+//! line so sessions can be scripted against known conditions.
+
+use crate::args::parse_duration;
+use crate::ping::{Config, Pinger, Reply};
+use crate::sysinfo::{Chip, Heap, ResetReason, System, Task, TaskState};
+use crate::wifi::{Auth, Network, Status, Wifi};
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+pub const USAGE: &str = "\
+Usage: ping-cli [--ping-latency=<time>] [--ping-jitter=<time>] [--ping-loss=<percent>] [--seed=<n>] [--telnet=<port>]
+
+Runs the CLI over stdin/stdout with simulated ping, Wi-Fi and system back-ends.
+
+Options:
+  --ping-latency=<time>    Round trip time of every reply (default is 20ms).
+  --ping-jitter=<time>     Random extra round trip time up to this much (default is 0s).
+  --ping-loss=<percent>    Share of requests that time out (default is 0).
+  --seed=<n>               Seed of the loss and jitter sequence (default is 1).
+  --telnet=<port>          Also serve the CLI over telnet on this port of localhost.";
+
+/// Settings of the simulation, taken from the command line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Percentage of echo requests that get no reply
+    pub loss: u8,
+    pub seed: u64,
+    /// Port of the telnet server, none by default
+    pub telnet: Option<u16>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            latency: Duration::from_millis(20),
+            jitter: Duration::ZERO,
+            loss: 0,
+            seed: 1,
+            telnet: None,
+        }
+    }
+}
+
+impl Options {
+    /// Parse `--name=value` options, the error names the offending one
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options::default();
+        for arg in args {
+            let (name, value) = arg.split_once('=').unwrap_or((&arg, ""));
+            let invalid = || format!("Invalid option '{}'\n\n{}", arg, USAGE);
+            match name {
+                "--ping-latency" => options.latency = parse_duration(value).ok_or_else(invalid)?,
+                "--ping-jitter" => options.jitter = parse_duration(value).ok_or_else(invalid)?,
+                "--ping-loss" => {
+                    options.loss = value
+                        .parse()
+                        .ok()
+                        .filter(|loss| *loss <= 100)
+                        .ok_or_else(invalid)?
+                }
+                "--seed" => options.seed = value.parse().map_err(|_| invalid())?,
+                "--telnet" => options.telnet = Some(value.parse().map_err(|_| invalid())?),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Small xorshift generator, so a seed always gives the same session
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // Zero is the one state xorshift never leaves
+        Rng(seed.max(1))
+    }
+
+    /// Uniform in `0..n`
+    fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % n
+    }
+}
+
+/// Answers echo requests after the configured latency, dropping some of them
+pub struct SimPinger {
+    latency: Duration,
+    jitter: Duration,
+    loss: u8,
+    rng: Rng,
+}
+
+impl SimPinger {
+    pub fn new(options: &Options) -> Self {
+        SimPinger {
+            latency: options.latency,
+            jitter: options.jitter,
+            loss: options.loss,
+            rng: Rng::new(options.seed),
+        }
+    }
+}
+
+impl Pinger for SimPinger {
+    fn ping(&mut self, _addr: Ipv4Addr, config: &Config) -> io::Result<Reply> {
+        let jitter = self.rng.below(self.jitter.as_micros() as u64 + 1);
+        let time = self.latency + Duration::from_micros(jitter);
+        let lost = self.rng.below(100) < self.loss as u64 || time > config.timeout;
+        let reply = Reply {
+            received: !lost,
+            time: if lost { config.timeout } else { time },
+        };
+        // Like the chip, the next request goes out an interval later at the earliest
+        std::thread::sleep(reply.time.max(config.interval));
+        Ok(reply)
+    }
+}
+
+/// Access point the simulated station can see
+struct SimNetwork {
+    network: Network,
+    password: &'static str,
+    bssid: [u8; 6],
+}
+
+/// Station that can join a few made-up networks. The saved network lives
+/// until the program exits.
+pub struct SimWifi {
+    networks: Vec<SimNetwork>,
+    connected: Option<usize>,
+    saved: Option<(String, String, Auth)>,
+}
+
+impl Default for SimWifi {
+    fn default() -> Self {
+        let network = |ssid: &str, rssi, channel, auth, password, last| SimNetwork {
+            network: Network {
+                ssid: ssid.into(),
+                rssi,
+                channel,
+                auth: Some(auth),
+            },
+            password,
+            bssid: [0x02, 0x00, 0x00, 0x00, 0x00, last],
+        };
+        SimWifi {
+            networks: vec![
+                network("Wokwi-GUEST", -42, 6, Auth::Open, "", 1),
+                network("sim-home", -58, 1, Auth::Wpa2, "password", 2),
+                network("sim-office", -71, 11, Auth::Wpa2Wpa3, "correct horse", 3),
+                network("", -85, 11, Auth::Wpa2, "hidden", 4),
+            ],
+            connected: None,
+            saved: None,
+        }
+    }
+}
+
+impl Wifi for SimWifi {
+    fn scan(&mut self) -> io::Result<Vec<Network>> {
+        Ok(self
+            .networks
+            .iter()
+            .map(|sim| sim.network.clone())
+            .collect())
+    }
+
+    fn connect(&mut self, ssid: &str, password: &str, auth: Auth) -> io::Result<()> {
+        self.connected = None;
+        let index = self
+            .networks
+            .iter()
+            .position(|sim| sim.network.ssid == ssid)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "network not found"))?;
+        if self.networks[index].password != password {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                "wrong password",
+            ));
+        }
+        self.connected = Some(index);
+        self.saved = Some((ssid.into(), password.into(), auth));
+        Ok(())
+    }
+
+    fn reconnect(&mut self) -> io::Result<Option<String>> {
+        match self.saved.clone() {
+            Some((ssid, password, auth)) => {
+                self.connect(&ssid, &password, auth)?;
+                Ok(Some(ssid))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn forget(&mut self) -> io::Result<()> {
+        self.connected = None;
+        self.saved = None;
+        Ok(())
+    }
+
+    fn status(&self) -> Option<Status> {
+        let sim = &self.networks[self.connected?];
+        Some(Status {
+            ssid: sim.network.ssid.clone(),
+            bssid: sim.bssid,
+            channel: sim.network.channel,
+            rssi: sim.network.rssi,
+            ip: Ipv4Addr::new(192, 168, 71, 10),
+            prefix: 24,
+            gateway: Ipv4Addr::new(192, 168, 71, 1),
+            dns: Some(Ipv4Addr::new(192, 168, 71, 1)),
+        })
+    }
+}
+
+/// Process statistics standing in for the chip's
+pub struct SimSystem {
+    boot: Instant,
+}
+
+impl Default for SimSystem {
+    fn default() -> Self {
+        SimSystem {
+            boot: Instant::now(),
+        }
+    }
+}
+
+impl System for SimSystem {
+    fn heap(&self) -> Heap {
+        Heap {
+            free: 200_000,
+            minimum: 180_000,
+            largest_block: 110_592,
+        }
+    }
+
+    fn tasks(&self) -> (Vec<Task>, u32) {
+        let task = |name: &str, state, priority, stack_high_water| Task {
+            name: name.into(),
+            state,
+            priority,
+            stack_high_water,
+            runtime: 0,
+        };
+        let tasks = vec![
+            task("main", TaskState::Running, 1, 3_072),
+            task("IDLE", TaskState::Ready, 0, 1_024),
+        ];
+        (tasks, 0)
+    }
+
+    fn uptime(&self) -> Duration {
+        self.boot.elapsed()
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        ResetReason::PowerOn
+    }
+
+    fn chip(&self) -> io::Result<Chip> {
+        Ok(Chip {
+            model: "simulated",
+            revision: 0,
+            cores: std::thread::available_parallelism().map_or(1, |n| n.get().min(255) as u8),
+            features: vec!["WiFi"],
+            mac: [0x02, 0x00, 0x00, 0x00, 0x00, 0x00],
+            flash_size: 4 * 1024 * 1024,
+            idf_version: "none".into(),
+        })
+    }
+
+    fn reboot(&self) -> ! {
+        std::process::exit(0)
+    }
+}
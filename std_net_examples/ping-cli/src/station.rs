@@ -0,0 +1,188 @@
+//! Wi-Fi station of the chip. The network joined with `wifi connect` is
+//! saved to NVS and joined again after a reset.
+
+use crate::wifi::{Auth, Network, Status, Wifi};
+use esp_idf_hal::modem::Modem;
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{esp, esp_wifi_sta_get_ap_info, wifi_ap_record_t, EspError};
+use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use std::io;
+
+const NAMESPACE: &str = "wifi";
+const SSID_KEY: &str = "ssid";
+const PASSWORD_KEY: &str = "password";
+const AUTH_KEY: &str = "auth";
+
+pub struct Station {
+    wifi: BlockingWifi<EspWifi<'static>>,
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Station {
+    /// Start the radio in station mode without joining any network
+    pub fn new(
+        modem: Modem,
+        sysloop: EspSystemEventLoop,
+        partition: EspDefaultNvsPartition,
+    ) -> Result<Self, EspError> {
+        let mut wifi = BlockingWifi::wrap(
+            EspWifi::new(modem, sysloop.clone(), Some(partition.clone()))?,
+            sysloop,
+        )?;
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        wifi.start()?;
+        Ok(Station {
+            wifi,
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    fn scan(&mut self) -> Result<Vec<Network>, EspError> {
+        Ok(self
+            .wifi
+            .scan()?
+            .into_iter()
+            .map(|ap| Network {
+                ssid: ap.ssid.as_str().into(),
+                rssi: ap.signal_strength,
+                channel: ap.channel,
+                auth: ap.auth_method.map(from_auth_method),
+            })
+            .collect())
+    }
+
+    /// Join `ssid` and remember it once the interface is up
+    fn connect(&mut self, ssid: &str, password: &str, auth: Auth) -> Result<(), EspError> {
+        if self.wifi.is_connected()? {
+            self.wifi.disconnect()?;
+        }
+        self.wifi
+            .set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.try_into().unwrap(),
+                auth_method: to_auth_method(auth),
+                password: password.try_into().unwrap(),
+                ..Default::default()
+            }))?;
+        self.wifi.connect()?;
+        self.wifi.wait_netif_up()?;
+
+        self.nvs.set_str(SSID_KEY, ssid)?;
+        self.nvs.set_str(PASSWORD_KEY, password)?;
+        self.nvs.set_str(AUTH_KEY, auth.name())?;
+        Ok(())
+    }
+
+    /// Join the saved network, returning its SSID or `None` if there is none
+    fn reconnect(&mut self) -> Result<Option<String>, EspError> {
+        let mut ssid = [0_u8; 33];
+        let mut password = [0_u8; 65];
+        let mut auth = [0_u8; 16];
+        let ssid = match self.nvs.get_str(SSID_KEY, &mut ssid)? {
+            Some(ssid) => ssid.to_string(),
+            None => return Ok(None),
+        };
+        let password = self
+            .nvs
+            .get_str(PASSWORD_KEY, &mut password)?
+            .unwrap_or_default()
+            .to_string();
+        let auth = self
+            .nvs
+            .get_str(AUTH_KEY, &mut auth)?
+            .and_then(Auth::parse)
+            .unwrap_or_else(|| Auth::guess(&password));
+        self.connect(&ssid, &password, auth)?;
+        Ok(Some(ssid))
+    }
+
+    /// Leave the current network and drop the saved one
+    fn forget(&mut self) -> Result<(), EspError> {
+        if self.wifi.is_connected()? {
+            self.wifi.disconnect()?;
+        }
+        self.wifi
+            .set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+        for key in [SSID_KEY, PASSWORD_KEY, AUTH_KEY] {
+            self.nvs.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Details of the connection, `None` while not connected
+    fn status(&self) -> Option<Status> {
+        if !self.wifi.is_connected().ok()? {
+            return None;
+        }
+        let ip_info = self.wifi.wifi().sta_netif().get_ip_info().ok()?;
+        // Zeroed C struct filled in by the driver
+        let mut record: wifi_ap_record_t = unsafe { core::mem::zeroed() };
+        esp!(unsafe { esp_wifi_sta_get_ap_info(&mut record) }).ok()?;
+        let ssid_len = record
+            .ssid
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(record.ssid.len());
+        Some(Status {
+            ssid: String::from_utf8_lossy(&record.ssid[..ssid_len]).into_owned(),
+            bssid: record.bssid,
+            channel: record.primary,
+            rssi: record.rssi,
+            ip: ip_info.ip,
+            prefix: ip_info.subnet.mask.0,
+            gateway: ip_info.subnet.gateway,
+            dns: ip_info.dns,
+        })
+    }
+}
+
+impl Wifi for Station {
+    fn scan(&mut self) -> io::Result<Vec<Network>> {
+        Station::scan(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn connect(&mut self, ssid: &str, password: &str, auth: Auth) -> io::Result<()> {
+        Station::connect(self, ssid, password, auth)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn reconnect(&mut self) -> io::Result<Option<String>> {
+        Station::reconnect(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn forget(&mut self) -> io::Result<()> {
+        Station::forget(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    fn status(&self) -> Option<Status> {
+        Station::status(self)
+    }
+}
+
+fn to_auth_method(auth: Auth) -> AuthMethod {
+    match auth {
+        Auth::Open => AuthMethod::None,
+        Auth::Wep => AuthMethod::WEP,
+        Auth::Wpa => AuthMethod::WPA,
+        Auth::Wpa2 => AuthMethod::WPA2Personal,
+        Auth::WpaWpa2 => AuthMethod::WPAWPA2Personal,
+        Auth::Wpa2Enterprise => AuthMethod::WPA2Enterprise,
+        Auth::Wpa3 => AuthMethod::WPA3Personal,
+        Auth::Wpa2Wpa3 => AuthMethod::WPA2WPA3Personal,
+        Auth::Wapi => AuthMethod::WAPIPersonal,
+    }
+}
+
+fn from_auth_method(method: AuthMethod) -> Auth {
+    match method {
+        AuthMethod::None => Auth::Open,
+        AuthMethod::WEP => Auth::Wep,
+        AuthMethod::WPA => Auth::Wpa,
+        AuthMethod::WPA2Personal => Auth::Wpa2,
+        AuthMethod::WPAWPA2Personal => Auth::WpaWpa2,
+        AuthMethod::WPA2Enterprise => Auth::Wpa2Enterprise,
+        AuthMethod::WPA3Personal => Auth::Wpa3,
+        AuthMethod::WPA2WPA3Personal => Auth::Wpa2Wpa3,
+        AuthMethod::WAPIPersonal => Auth::Wapi,
+    }
+}
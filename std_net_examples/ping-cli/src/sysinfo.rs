@@ -0,0 +1,497 @@
+//! Formatting of the `sys` command output. A [`System`] back-end reads the
+//! numbers, everything else here works on plain data.
+
+use crate::output::Object;
+use crate::wifi::format_mac;
+use std::fmt::{self, Write};
+use std::io;
+use std::time::Duration;
+
+/// Heap statistics in bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heap {
+    pub free: usize,
+    /// Lowest free heap since boot
+    pub minimum: usize,
+    /// Largest block that can currently be allocated
+    pub largest_block: usize,
+}
+
+// The simulation only reports some of the states
+#[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+    Suspended,
+    Deleted,
+    Invalid,
+}
+
+impl TaskState {
+    /// State from the `eTaskState` value FreeRTOS reports
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    pub fn from_code(code: u32) -> TaskState {
+        match code {
+            0 => TaskState::Running,
+            1 => TaskState::Ready,
+            2 => TaskState::Blocked,
+            3 => TaskState::Suspended,
+            4 => TaskState::Deleted,
+            _ => TaskState::Invalid,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Blocked => "blocked",
+            TaskState::Suspended => "suspended",
+            TaskState::Deleted => "deleted",
+            TaskState::Invalid => "invalid",
+        }
+    }
+}
+
+/// One FreeRTOS task
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+    pub name: String,
+    pub state: TaskState,
+    pub priority: u32,
+    /// Least free stack the task ever had, in bytes
+    pub stack_high_water: u32,
+    /// Run time counter ticks spent in the task
+    pub runtime: u32,
+}
+
+// The simulation always starts from power-on
+#[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    External,
+    Software,
+    Panic,
+    InterruptWatchdog,
+    TaskWatchdog,
+    OtherWatchdog,
+    DeepSleep,
+    Brownout,
+    Sdio,
+    /// Code not known to this firmware
+    Unknown(u32),
+}
+
+impl ResetReason {
+    /// Reason from the `esp_reset_reason_t` value ESP-IDF reports
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    pub fn from_code(code: u32) -> ResetReason {
+        match code {
+            1 => ResetReason::PowerOn,
+            2 => ResetReason::External,
+            3 => ResetReason::Software,
+            4 => ResetReason::Panic,
+            5 => ResetReason::InterruptWatchdog,
+            6 => ResetReason::TaskWatchdog,
+            7 => ResetReason::OtherWatchdog,
+            8 => ResetReason::DeepSleep,
+            9 => ResetReason::Brownout,
+            10 => ResetReason::Sdio,
+            code => ResetReason::Unknown(code),
+        }
+    }
+
+    /// Identifier used in JSON output, `unknown` for [`ResetReason::Unknown`]
+    pub fn name(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "poweron",
+            ResetReason::External => "external",
+            ResetReason::Software => "software",
+            ResetReason::Panic => "panic",
+            ResetReason::InterruptWatchdog => "int_wdt",
+            ResetReason::TaskWatchdog => "task_wdt",
+            ResetReason::OtherWatchdog => "wdt",
+            ResetReason::DeepSleep => "deepsleep",
+            ResetReason::Brownout => "brownout",
+            ResetReason::Sdio => "sdio",
+            ResetReason::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResetReason::PowerOn => write!(f, "power-on"),
+            ResetReason::External => write!(f, "external pin"),
+            ResetReason::Software => write!(f, "software restart"),
+            ResetReason::Panic => write!(f, "panic or exception"),
+            ResetReason::InterruptWatchdog => write!(f, "interrupt watchdog"),
+            ResetReason::TaskWatchdog => write!(f, "task watchdog"),
+            ResetReason::OtherWatchdog => write!(f, "other watchdog"),
+            ResetReason::DeepSleep => write!(f, "wake from deep sleep"),
+            ResetReason::Brownout => write!(f, "brownout"),
+            ResetReason::Sdio => write!(f, "SDIO"),
+            ResetReason::Unknown(code) => write!(f, "unknown ({})", code),
+        }
+    }
+}
+
+// Feature bits of esp_chip_info_t, defined with a macro bindgen cannot see through
+const CHIP_FEATURES: &[(u32, &str)] = &[
+    (1 << 0, "embedded flash"),
+    (1 << 1, "WiFi"),
+    (1 << 4, "BLE"),
+    (1 << 5, "Bluetooth Classic"),
+    (1 << 6, "IEEE 802.15.4"),
+    (1 << 7, "embedded PSRAM"),
+];
+
+/// Name of the `esp_chip_model_t` value ESP-IDF reports
+#[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+pub fn chip_model(code: u32) -> &'static str {
+    match code {
+        1 => "ESP32",
+        2 => "ESP32-S2",
+        9 => "ESP32-S3",
+        5 => "ESP32-C3",
+        12 => "ESP32-C2",
+        13 => "ESP32-C6",
+        16 => "ESP32-H2",
+        _ => "unknown",
+    }
+}
+
+/// Names of the `esp_chip_info_t` feature bits set in `bits`
+#[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+pub fn chip_features(bits: u32) -> Vec<&'static str> {
+    CHIP_FEATURES
+        .iter()
+        .filter(|(bit, _)| bits & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Identification of the chip and firmware
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chip {
+    pub model: &'static str,
+    /// Major version times 100 plus minor version
+    pub revision: u16,
+    pub cores: u8,
+    pub features: Vec<&'static str>,
+    pub mac: [u8; 6],
+    pub flash_size: u32,
+    pub idf_version: String,
+}
+
+/// Source of the `sys` command data, the chip or a simulation
+pub trait System: Send {
+    fn heap(&self) -> Heap;
+
+    /// Every task with the total run time counter, zero if run time stats
+    /// are not available
+    fn tasks(&self) -> (Vec<Task>, u32);
+
+    /// Time since boot
+    fn uptime(&self) -> Duration;
+
+    fn reset_reason(&self) -> ResetReason;
+
+    fn chip(&self) -> io::Result<Chip>;
+
+    fn reboot(&self) -> !;
+}
+
+pub fn write_heap(heap: &Heap, out: &mut impl Write) -> fmt::Result {
+    writeln!(out, "Free:          {:>8} bytes", heap.free)?;
+    writeln!(out, "Minimum free:  {:>8} bytes", heap.minimum)?;
+    writeln!(out, "Largest block: {:>8} bytes", heap.largest_block)
+}
+
+pub fn heap_object(heap: &Heap) -> Object {
+    Object::new()
+        .field("free", heap.free)
+        .field("minimum", heap.minimum)
+        .field("largest_block", heap.largest_block)
+}
+
+/// Print the tasks highest priority first. `total_runtime` is the run time
+/// counter of all tasks together, zero if run time stats are disabled.
+pub fn write_tasks(tasks: &[Task], total_runtime: u32, out: &mut impl Write) -> fmt::Result {
+    let mut tasks: Vec<_> = tasks.iter().collect();
+    tasks.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.name.cmp(&b.name)));
+
+    let width = tasks
+        .iter()
+        .map(|task| task.name.len())
+        .max()
+        .unwrap_or(0)
+        .max("NAME".len());
+    writeln!(out, "{:<width$}  STATE      PRIO  STACK    CPU", "NAME")?;
+    for task in tasks {
+        write!(
+            out,
+            "{:<width$}  {:<9}  {:>4}  {:>5}  ",
+            task.name,
+            task.state.name(),
+            task.priority,
+            task.stack_high_water
+        )?;
+        if total_runtime == 0 {
+            writeln!(out, "    -")?;
+        } else {
+            let percent = task.runtime as f64 * 100.0 / total_runtime as f64;
+            writeln!(out, "{:>4.1}%", percent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Tasks as JSON in the order of [`write_tasks`], `cpu_percent` is null
+/// without run time stats
+pub fn tasks_object(tasks: &[Task], total_runtime: u32) -> Object {
+    let mut tasks: Vec<_> = tasks.iter().collect();
+    tasks.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.name.cmp(&b.name)));
+    let tasks: Vec<_> = tasks
+        .into_iter()
+        .map(|task| {
+            let percent =
+                (total_runtime != 0).then(|| task.runtime as f64 * 100.0 / total_runtime as f64);
+            Object::new()
+                .field("name", task.name.as_str())
+                .field("state", task.state.name())
+                .field("priority", task.priority)
+                .field("stack_high_water", task.stack_high_water)
+                .field("cpu_percent", percent)
+        })
+        .collect();
+    Object::new().field("tasks", tasks)
+}
+
+/// Format as `3d 04:05:06`, days are left out while zero
+pub fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (days, hours, minutes, secs) = (secs / 86_400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {:02}:{:02}:{:02}", days, hours, minutes, secs)
+    } else {
+        format!("{:02}:{:02}:{:02}", hours, minutes, secs)
+    }
+}
+
+pub fn uptime_object(uptime: Duration) -> Object {
+    Object::new()
+        .field("uptime_ms", uptime.as_millis() as u64)
+        .field("uptime", format_uptime(uptime))
+}
+
+pub fn chip_object(chip: &Chip) -> Object {
+    Object::new()
+        .field("model", chip.model)
+        .field("revision", chip.revision)
+        .field("cores", chip.cores)
+        .field("features", chip.features.clone())
+        .field("mac", format_mac(&chip.mac))
+        .field("flash_size", chip.flash_size)
+        .field("idf_version", chip.idf_version.as_str())
+}
+
+pub fn write_chip(chip: &Chip, out: &mut impl Write) -> fmt::Result {
+    writeln!(
+        out,
+        "Chip:      {} rev v{}.{}",
+        chip.model,
+        chip.revision / 100,
+        chip.revision % 100
+    )?;
+    writeln!(out, "Cores:     {}", chip.cores)?;
+    writeln!(out, "Features:  {}", chip.features.join(", "))?;
+    writeln!(out, "MAC:       {}", format_mac(&chip.mac))?;
+    writeln!(out, "Flash:     {} MB", chip.flash_size / (1024 * 1024))?;
+    writeln!(out, "IDF:       {}", chip.idf_version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str, state: TaskState, priority: u32, runtime: u32) -> Task {
+        Task {
+            name: name.into(),
+            state,
+            priority,
+            stack_high_water: 1_500,
+            runtime,
+        }
+    }
+
+    fn chip() -> Chip {
+        Chip {
+            model: chip_model(5),
+            revision: 4,
+            cores: 1,
+            features: chip_features(0b1_0010),
+            mac: [0x84, 0xf7, 0x03, 0x12, 0x34, 0x56],
+            flash_size: 4 * 1024 * 1024,
+            idf_version: "v5.2.2".into(),
+        }
+    }
+
+    #[test]
+    fn task_states() {
+        let states: Vec<_> = (0..7).map(TaskState::from_code).collect();
+        assert_eq!(
+            states,
+            [
+                TaskState::Running,
+                TaskState::Ready,
+                TaskState::Blocked,
+                TaskState::Suspended,
+                TaskState::Deleted,
+                TaskState::Invalid,
+                TaskState::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn reset_reasons() {
+        assert_eq!(ResetReason::from_code(1), ResetReason::PowerOn);
+        assert_eq!(ResetReason::from_code(3), ResetReason::Software);
+        assert_eq!(ResetReason::from_code(4), ResetReason::Panic);
+        assert_eq!(ResetReason::from_code(6), ResetReason::TaskWatchdog);
+        assert_eq!(ResetReason::from_code(9), ResetReason::Brownout);
+        assert_eq!(ResetReason::from_code(10), ResetReason::Sdio);
+        assert_eq!(ResetReason::from_code(0), ResetReason::Unknown(0));
+        assert_eq!(ResetReason::from_code(14), ResetReason::Unknown(14));
+
+        assert_eq!(ResetReason::Panic.name(), "panic");
+        assert_eq!(ResetReason::from_code(14).name(), "unknown");
+        assert_eq!(ResetReason::Panic.to_string(), "panic or exception");
+        assert_eq!(ResetReason::from_code(14).to_string(), "unknown (14)");
+    }
+
+    #[test]
+    fn chip_decoding() {
+        assert_eq!(chip_model(1), "ESP32");
+        assert_eq!(chip_model(9), "ESP32-S3");
+        assert_eq!(chip_model(16), "ESP32-H2");
+        assert_eq!(chip_model(3), "unknown");
+        assert_eq!(chip_features(0), Vec::<&str>::new());
+        assert_eq!(chip_features(0b1_0010), ["WiFi", "BLE"]);
+        assert_eq!(
+            chip_features(0b1111_0011),
+            [
+                "embedded flash",
+                "WiFi",
+                "BLE",
+                "Bluetooth Classic",
+                "IEEE 802.15.4",
+                "embedded PSRAM"
+            ]
+        );
+        // Bits 2 and 3 are not features
+        assert_eq!(chip_features(0b1100), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn heap() {
+        let heap = Heap {
+            free: 201_344,
+            minimum: 187_120,
+            largest_block: 110_592,
+        };
+        let mut text = String::new();
+        write_heap(&heap, &mut text).unwrap();
+        assert_eq!(
+            text,
+            "Free:            201344 bytes\n\
+             Minimum free:    187120 bytes\n\
+             Largest block:   110592 bytes\n"
+        );
+        assert_eq!(
+            heap_object(&heap).to_string(),
+            r#"{"free":201344,"minimum":187120,"largest_block":110592}"#
+        );
+    }
+
+    #[test]
+    fn tasks_with_runtime_stats() {
+        let tasks = [
+            task("IDLE", TaskState::Ready, 0, 750),
+            task("main", TaskState::Running, 1, 200),
+            task("wifi", TaskState::Blocked, 23, 50),
+            task("esp_timer", TaskState::Suspended, 22, 0),
+        ];
+        let mut text = String::new();
+        write_tasks(&tasks, 1_000, &mut text).unwrap();
+        assert_eq!(
+            text,
+            "NAME       STATE      PRIO  STACK    CPU\n\
+             wifi       blocked      23   1500   5.0%\n\
+             esp_timer  suspended    22   1500   0.0%\n\
+             main       running       1   1500  20.0%\n\
+             IDLE       ready         0   1500  75.0%\n"
+        );
+        assert_eq!(
+            tasks_object(&tasks[1..3], 1_000).to_string(),
+            r#"{"tasks":[{"name":"wifi","state":"blocked","priority":23,"stack_high_water":1500,"cpu_percent":5},{"name":"main","state":"running","priority":1,"stack_high_water":1500,"cpu_percent":20}]}"#
+        );
+    }
+
+    #[test]
+    fn tasks_without_runtime_stats() {
+        // Equal priorities sort by name
+        let tasks = [
+            task("b", TaskState::Ready, 5, 0),
+            task("a", TaskState::Deleted, 5, 0),
+        ];
+        let mut text = String::new();
+        write_tasks(&tasks, 0, &mut text).unwrap();
+        assert_eq!(
+            text,
+            "NAME  STATE      PRIO  STACK    CPU\n\
+             a     deleted       5   1500      -\n\
+             b     ready         5   1500      -\n"
+        );
+        assert_eq!(
+            tasks_object(&tasks[..1], 0).to_string(),
+            r#"{"tasks":[{"name":"b","state":"ready","priority":5,"stack_high_water":1500,"cpu_percent":null}]}"#
+        );
+    }
+
+    #[test]
+    fn uptime() {
+        assert_eq!(format_uptime(Duration::ZERO), "00:00:00");
+        assert_eq!(format_uptime(Duration::from_millis(3_723_999)), "01:02:03");
+        assert_eq!(format_uptime(Duration::from_secs(86_399)), "23:59:59");
+        assert_eq!(format_uptime(Duration::from_secs(273_906)), "3d 04:05:06");
+        assert_eq!(
+            uptime_object(Duration::from_millis(61_500)).to_string(),
+            r#"{"uptime_ms":61500,"uptime":"00:01:01"}"#
+        );
+    }
+
+    #[test]
+    fn chip_output() {
+        let mut text = String::new();
+        write_chip(&chip(), &mut text).unwrap();
+        assert_eq!(
+            text,
+            "Chip:      ESP32-C3 rev v0.4\n\
+             Cores:     1\n\
+             Features:  WiFi, BLE\n\
+             MAC:       84:f7:03:12:34:56\n\
+             Flash:     4 MB\n\
+             IDF:       v5.2.2\n"
+        );
+        assert_eq!(
+            chip_object(&chip()).to_string(),
+            r#"{"model":"ESP32-C3","revision":4,"cores":1,"features":["WiFi","BLE"],"mac":"84:f7:03:12:34:56","flash_size":4194304,"idf_version":"v5.2.2"}"#
+        );
+    }
+}
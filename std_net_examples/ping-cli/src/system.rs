@@ -0,0 +1,96 @@
+//! ESP-IDF and FreeRTOS queries behind the `sys` command.
+
+use crate::sysinfo::{self, Chip, Heap, ResetReason, System, Task, TaskState};
+use esp_idf_svc::sys::*;
+use std::ffi::CStr;
+use std::io;
+use std::time::Duration;
+
+// sysinfo decodes the C enums by value so it can be tested on a PC, check
+// that the values it expects are the ones of these bindings
+const _: () = {
+    assert!(eTaskState_eRunning == 0 && eTaskState_eDeleted == 4);
+    assert!(esp_reset_reason_t_ESP_RST_POWERON == 1);
+    assert!(esp_reset_reason_t_ESP_RST_SDIO == 10);
+    assert!(esp_chip_model_t_CHIP_ESP32C3 == 5 && esp_chip_model_t_CHIP_ESP32H2 == 16);
+};
+
+/// The chip the firmware runs on
+pub struct EspSystem;
+
+impl System for EspSystem {
+    fn heap(&self) -> Heap {
+        unsafe {
+            Heap {
+                free: heap_caps_get_free_size(MALLOC_CAP_DEFAULT),
+                minimum: heap_caps_get_minimum_free_size(MALLOC_CAP_DEFAULT),
+                largest_block: heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT),
+            }
+        }
+    }
+
+    /// The total run time counter is zero unless
+    /// CONFIG_FREERTOS_GENERATE_RUN_TIME_STATS is enabled
+    fn tasks(&self) -> (Vec<Task>, u32) {
+        let mut total_runtime = 0;
+        // Leave room for tasks created between counting and listing
+        let capacity = unsafe { uxTaskGetNumberOfTasks() } + 4;
+        let mut statuses: Vec<TaskStatus_t> = Vec::with_capacity(capacity as usize);
+        unsafe {
+            let len = uxTaskGetSystemState(statuses.as_mut_ptr(), capacity, &mut total_runtime);
+            statuses.set_len(len as usize);
+        }
+
+        let tasks = statuses
+            .iter()
+            .map(|status| Task {
+                name: unsafe { CStr::from_ptr(status.pcTaskName) }
+                    .to_string_lossy()
+                    .into_owned(),
+                state: TaskState::from_code(status.eCurrentState as u32),
+                priority: status.uxCurrentPriority,
+                stack_high_water: status.usStackHighWaterMark,
+                runtime: status.ulRunTimeCounter,
+            })
+            .collect();
+        (tasks, total_runtime)
+    }
+
+    fn uptime(&self) -> Duration {
+        Duration::from_micros(unsafe { esp_timer_get_time() } as u64)
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        ResetReason::from_code(unsafe { esp_reset_reason() } as u32)
+    }
+
+    fn chip(&self) -> io::Result<Chip> {
+        let mut info = esp_chip_info_t::default();
+        let mut mac = [0_u8; 6];
+        let mut flash_size = 0;
+        unsafe {
+            esp_chip_info(&mut info);
+            esp!(esp_efuse_mac_get_default(mac.as_mut_ptr()))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            // A null chip is the flash the firmware runs from
+            esp!(esp_flash_get_size(core::ptr::null_mut(), &mut flash_size))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+
+        Ok(Chip {
+            model: sysinfo::chip_model(info.model as u32),
+            revision: info.revision,
+            cores: info.cores,
+            features: sysinfo::chip_features(info.features),
+            mac,
+            flash_size,
+            idf_version: unsafe { CStr::from_ptr(esp_get_idf_version()) }
+                .to_string_lossy()
+                .into_owned(),
+        })
+    }
+
+    fn reboot(&self) -> ! {
+        unsafe { esp_restart() }
+    }
+}
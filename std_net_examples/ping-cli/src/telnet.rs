@@ -0,0 +1,554 @@
+//! Telnet server exposing the CLI menu over TCP.
+//!
+//! Only the negotiation needed for a usable character-mode session is
+//! supported: the server offers ECHO and SUPPRESS-GO-AHEAD and refuses every
+//! other option.
+
+use crate::console::{Context, Transport};
+use menu::{Menu, Runner};
+use std::fmt::Write;
+use std::io::{self, ErrorKind, Read, Write as _};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+
+// Ctrl-D closes the session
+const EOT: u8 = 0x04;
+
+/// Stack size of each session thread
+const SESSION_STACK_SIZE: usize = 8192;
+/// Failed password attempts before the connection is dropped
+const MAX_LOGIN_ATTEMPTS: u8 = 3;
+
+pub struct TelnetConfig {
+    /// Address to listen on, all interfaces by default
+    pub bind: Ipv4Addr,
+    pub port: u16,
+    /// Sessions without input for this long are closed
+    pub idle_timeout: Duration,
+    /// Password asked for before the menu is shown, `None` disables login
+    pub password: Option<&'static str>,
+    pub max_sessions: usize,
+}
+
+impl Default for TelnetConfig {
+    fn default() -> Self {
+        TelnetConfig {
+            bind: Ipv4Addr::UNSPECIFIED,
+            port: 23,
+            idle_timeout: Duration::from_secs(300),
+            password: None,
+            max_sessions: 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Data,
+    // Previous byte was a CR, a following LF or NUL is dropped
+    Cr,
+    Iac,
+    Command(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Telnet protocol state machine separating user input from commands
+pub struct Parser {
+    state: State,
+    // Options enabled on our side (ECHO, SGA)
+    local_echo: bool,
+    local_sga: bool,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser {
+            state: State::Data,
+            local_echo: true,
+            local_sga: true,
+        }
+    }
+
+    /// Negotiation sent when the connection opens
+    pub fn greeting() -> [u8; 9] {
+        [IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SGA, IAC, DO, OPT_SGA]
+    }
+
+    /// Feed one received byte, returning it if it is user input. Replies to
+    /// option negotiation are appended to `replies`.
+    pub fn feed(&mut self, byte: u8, replies: &mut Vec<u8>) -> Option<u8> {
+        match self.state {
+            State::Data | State::Cr => {
+                let after_cr = self.state == State::Cr;
+                self.state = State::Data;
+                match byte {
+                    IAC => {
+                        self.state = State::Iac;
+                        None
+                    }
+                    b'\n' | 0 if after_cr => None,
+                    b'\r' => {
+                        self.state = State::Cr;
+                        Some(b'\r')
+                    }
+                    // Clients in line mode may only send LF
+                    b'\n' => Some(b'\r'),
+                    byte => Some(byte),
+                }
+            }
+            State::Iac => {
+                self.state = State::Data;
+                match byte {
+                    IAC => Some(IAC),
+                    WILL | WONT | DO | DONT => {
+                        self.state = State::Command(byte);
+                        None
+                    }
+                    SB => {
+                        self.state = State::Subnegotiation;
+                        None
+                    }
+                    // NOP, GA, AYT and friends carry no data
+                    _ => None,
+                }
+            }
+            State::Command(verb) => {
+                self.state = State::Data;
+                self.negotiate(verb, byte, replies);
+                None
+            }
+            State::Subnegotiation => {
+                if byte == IAC {
+                    self.state = State::SubnegotiationIac;
+                }
+                None
+            }
+            State::SubnegotiationIac => {
+                self.state = if byte == SE {
+                    State::Data
+                } else {
+                    State::Subnegotiation
+                };
+                None
+            }
+        }
+    }
+
+    // Answer only when an option changes state to avoid negotiation loops
+    fn negotiate(&mut self, verb: u8, option: u8, replies: &mut Vec<u8>) {
+        match (verb, option) {
+            (DO, OPT_ECHO) if !self.local_echo => {
+                self.local_echo = true;
+                replies.extend_from_slice(&[IAC, WILL, option]);
+            }
+            (DO, OPT_SGA) if !self.local_sga => {
+                self.local_sga = true;
+                replies.extend_from_slice(&[IAC, WILL, option]);
+            }
+            (DO, OPT_ECHO) | (DO, OPT_SGA) => (),
+            (DO, _) => replies.extend_from_slice(&[IAC, WONT, option]),
+            (DONT, OPT_ECHO) if self.local_echo => {
+                self.local_echo = false;
+                replies.extend_from_slice(&[IAC, WONT, option]);
+            }
+            (DONT, OPT_SGA) if self.local_sga => {
+                self.local_sga = false;
+                replies.extend_from_slice(&[IAC, WONT, option]);
+            }
+            // SGA was requested in the greeting, so WILL SGA is an acknowledgement
+            (WILL, OPT_SGA) => (),
+            (WILL, _) => replies.extend_from_slice(&[IAC, DONT, option]),
+            _ => (),
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Translate output to the network virtual terminal: bare LF becomes CR LF
+/// and 0xFF is escaped. `last` carries the previous byte across calls.
+pub fn encode_output(bytes: &[u8], last: &mut u8, out: &mut Vec<u8>) {
+    for &byte in bytes {
+        match byte {
+            b'\n' if *last != b'\r' => out.extend_from_slice(b"\r\n"),
+            IAC => out.extend_from_slice(&[IAC, IAC]),
+            byte => out.push(byte),
+        }
+        *last = byte;
+    }
+}
+
+/// Telnet connection used as a CLI transport
+pub struct TelnetTransport {
+    stream: TcpStream,
+    parser: Parser,
+    rx: [u8; 64],
+    rx_pos: usize,
+    rx_len: usize,
+    last_tx: u8,
+}
+
+impl TelnetTransport {
+    pub fn new(mut stream: TcpStream) -> io::Result<Self> {
+        stream.write_all(&Parser::greeting())?;
+        Ok(TelnetTransport {
+            stream,
+            parser: Parser::new(),
+            rx: [0; 64],
+            rx_pos: 0,
+            rx_len: 0,
+            last_tx: 0,
+        })
+    }
+}
+
+impl Transport for TelnetTransport {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut replies = Vec::new();
+        loop {
+            if self.rx_pos == self.rx_len {
+                self.rx_len = self.stream.read(&mut self.rx)?;
+                self.rx_pos = 0;
+                if self.rx_len == 0 {
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+            }
+            let byte = self.rx[self.rx_pos];
+            self.rx_pos += 1;
+            let data = self.parser.feed(byte, &mut replies);
+            if !replies.is_empty() {
+                self.stream.write_all(&replies)?;
+                replies.clear();
+            }
+            if let Some(data) = data {
+                return Ok(data);
+            }
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let mut out = Vec::with_capacity(bytes.len() + 8);
+        encode_output(bytes, &mut self.last_tx, &mut out);
+        self.stream.write_all(&out)
+    }
+}
+
+// Number of sessions currently running
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Accept telnet connections forever, running `menu` in one thread per session
+pub fn serve(config: TelnetConfig, menu: fn() -> Menu<'static, Context>) -> io::Result<()> {
+    let listener = TcpListener::bind((config.bind, config.port))?;
+    run(listener, config, menu)
+}
+
+fn run(
+    listener: TcpListener,
+    config: TelnetConfig,
+    menu: fn() -> Menu<'static, Context>,
+) -> io::Result<()> {
+    let config: &'static TelnetConfig = Box::leak(Box::new(config));
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+
+        if SESSIONS.fetch_add(1, Ordering::SeqCst) >= config.max_sessions {
+            SESSIONS.fetch_sub(1, Ordering::SeqCst);
+            let _ = stream.write_all(b"Too many sessions\r\n");
+            continue;
+        }
+
+        let spawned = std::thread::Builder::new()
+            .stack_size(SESSION_STACK_SIZE)
+            .spawn(move || {
+                let _ = session(stream, config, menu);
+                SESSIONS.fetch_sub(1, Ordering::SeqCst);
+            });
+        if spawned.is_err() {
+            SESSIONS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    Ok(())
+}
+
+fn session(
+    stream: TcpStream,
+    config: &TelnetConfig,
+    menu: fn() -> Menu<'static, Context>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(config.idle_timeout))?;
+    stream.set_nodelay(true)?;
+    let mut transport = TelnetTransport::new(stream)?;
+
+    if let Some(password) = config.password {
+        if !login(&mut transport, password)? {
+            return Ok(());
+        }
+    }
+
+    // Create a buffer to store CLI input
+    let mut clibuf = [0u8; 64];
+    // Instantiate CLI runner with root menu, buffer, and telnet connection
+    let mut r = Runner::new(menu(), &mut clibuf, Context::new(Box::new(transport)));
+
+    loop {
+        match r.context.read_byte() {
+            Ok(EOT) => break,
+            Ok(byte) => r.input_byte(byte),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                let _ = writeln!(r.context, "\nSession timed out");
+                break;
+            }
+            Err(_) => break,
+        }
+        if r.context.is_closed() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Prompt for the password without echoing it
+fn login(transport: &mut TelnetTransport, password: &str) -> io::Result<bool> {
+    for _ in 0..MAX_LOGIN_ATTEMPTS {
+        transport.write_all(b"Password: ")?;
+        let mut line = Vec::new();
+        loop {
+            match transport.read_byte()? {
+                b'\r' => break,
+                // Backspace and DEL
+                0x08 | 0x7f => {
+                    line.pop();
+                }
+                byte if line.len() < 64 => line.push(byte),
+                _ => (),
+            }
+        }
+        transport.write_all(b"\n")?;
+        if constant_time_eq(&line, password.as_bytes()) {
+            return Ok(true);
+        }
+        std::thread::sleep(Duration::from_secs(1));
+        transport.write_all(b"Login incorrect\n")?;
+    }
+    Ok(false)
+}
+
+// Compare without leaking the position of the first mismatch through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use menu::{Item, ItemType};
+    use std::net::SocketAddr;
+
+    fn feed(parser: &mut Parser, bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut replies = Vec::new();
+        let data = bytes
+            .iter()
+            .filter_map(|&byte| parser.feed(byte, &mut replies))
+            .collect();
+        (data, replies)
+    }
+
+    #[test]
+    fn acknowledged_options_get_no_reply() {
+        let mut parser = Parser::new();
+        let (data, replies) = feed(
+            &mut parser,
+            &[IAC, DO, OPT_ECHO, IAC, DO, OPT_SGA, IAC, WILL, OPT_SGA],
+        );
+        assert!(data.is_empty());
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn other_options_are_refused() {
+        let mut parser = Parser::new();
+        // DO TERMINAL-TYPE, WILL NAWS, DO LINEMODE
+        let (_, replies) = feed(&mut parser, &[IAC, DO, 24, IAC, WILL, 31, IAC, DO, 34]);
+        assert_eq!(replies, [IAC, WONT, 24, IAC, DONT, 31, IAC, WONT, 34]);
+    }
+
+    #[test]
+    fn options_are_answered_only_when_they_change() {
+        let mut parser = Parser::new();
+        let (_, replies) = feed(&mut parser, &[IAC, DONT, OPT_ECHO, IAC, DONT, OPT_ECHO]);
+        assert_eq!(replies, [IAC, WONT, OPT_ECHO]);
+        let (_, replies) = feed(&mut parser, &[IAC, DO, OPT_ECHO, IAC, DO, OPT_ECHO]);
+        assert_eq!(replies, [IAC, WILL, OPT_ECHO]);
+    }
+
+    #[test]
+    fn commands_and_subnegotiation_are_not_data() {
+        let mut parser = Parser::new();
+        // NAWS 80x24 with an escaped IAC inside, then NOP and AYT
+        let (data, replies) = feed(
+            &mut parser,
+            &[
+                b'a', IAC, SB, 31, 0, 80, IAC, IAC, 24, IAC, SE, b'b', IAC, 241, IAC, 246, b'c',
+            ],
+        );
+        assert_eq!(data, b"abc");
+        assert!(replies.is_empty());
+        let (data, _) = feed(&mut parser, &[IAC, IAC]);
+        assert_eq!(data, [IAC]);
+    }
+
+    #[test]
+    fn line_endings_become_cr() {
+        let mut parser = Parser::new();
+        let (data, _) = feed(&mut parser, b"a\r\nb\r\0c\nd\r");
+        assert_eq!(data, b"a\rb\rc\rd\r");
+        // The LF of a CR LF split across reads is still dropped
+        let (data, _) = feed(&mut parser, b"\ne");
+        assert_eq!(data, b"e");
+    }
+
+    #[test]
+    fn output_is_encoded_for_the_terminal() {
+        let mut last = 0;
+        let mut out = Vec::new();
+        encode_output(b"one\ntwo\r\n\xff", &mut last, &mut out);
+        encode_output(b"\r", &mut last, &mut out);
+        encode_output(b"\n", &mut last, &mut out);
+        assert_eq!(out, b"one\r\ntwo\r\n\xff\xff\r\n");
+    }
+
+    #[test]
+    fn passwords_compare_whole() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(!constant_time_eq(b"", b"secret"));
+    }
+
+    fn hello(_menu: &Menu<Context>, _item: &Item<Context>, _args: &[&str], context: &mut Context) {
+        let _ = writeln!(context, "Hello over telnet");
+    }
+
+    fn test_menu() -> Menu<'static, Context> {
+        Menu {
+            label: "root",
+            items: &[&Item {
+                item_type: ItemType::Callback {
+                    function: hello,
+                    parameters: &[],
+                },
+                command: "hello",
+                help: None,
+            }],
+            entry: None,
+            exit: None,
+        }
+    }
+
+    // Serve `test_menu` on a loopback port, returning the address to dial
+    fn server(password: Option<&'static str>) -> SocketAddr {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = TelnetConfig {
+            bind: Ipv4Addr::LOCALHOST,
+            port: addr.port(),
+            idle_timeout: Duration::from_secs(10),
+            password,
+            max_sessions: 8,
+        };
+        std::thread::spawn(move || run(listener, config, test_menu));
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream
+    }
+
+    // Read from `stream` until `expected` was received, returning everything
+    fn read_until(stream: &mut TcpStream, expected: &[u8]) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buf = [0; 256];
+        while !received.windows(expected.len()).any(|w| w == expected) {
+            let len = stream.read(&mut buf).unwrap();
+            assert!(len > 0, "closed before {:?}, got {:?}", expected, received);
+            received.extend_from_slice(&buf[..len]);
+        }
+        received
+    }
+
+    #[test]
+    fn session_negotiates_and_runs_the_menu() {
+        let mut stream = connect(server(None));
+        let received = read_until(&mut stream, &Parser::greeting());
+        assert!(received.starts_with(&Parser::greeting()));
+
+        // Answer like a typical client, asking for an option we refuse
+        stream
+            .write_all(&[IAC, DO, OPT_ECHO, IAC, WILL, OPT_SGA, IAC, DO, 24])
+            .unwrap();
+        read_until(&mut stream, &[IAC, WONT, 24]);
+
+        stream.write_all(b"hello\r\n").unwrap();
+        read_until(&mut stream, b"Hello over telnet\r\n");
+
+        // Ctrl-D ends the session
+        stream.write_all(&[EOT]).unwrap();
+        let mut rest = Vec::new();
+        assert!(stream.read_to_end(&mut rest).is_ok());
+    }
+
+    #[test]
+    fn login_lets_the_right_password_in() {
+        let mut stream = connect(server(Some("s3cret")));
+        read_until(&mut stream, b"Password: ");
+        // Backspace fixes a typo, the password is not echoed
+        stream.write_all(b"s3cx\x7fret\r\n").unwrap();
+        stream.write_all(b"hello\r").unwrap();
+        let received = read_until(&mut stream, b"Hello over telnet");
+        assert!(!received.windows(6).any(|w| w == b"s3cret"));
+        assert!(!received.windows(15).any(|w| w == b"Login incorrect"));
+    }
+
+    #[test]
+    fn login_drops_the_connection_after_failed_attempts() {
+        let mut stream = connect(server(Some("s3cret")));
+        for _ in 0..=MAX_LOGIN_ATTEMPTS {
+            stream.write_all(b"guess\r\n").unwrap();
+        }
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        let count = |needle: &[u8]| {
+            received
+                .windows(needle.len())
+                .filter(|w| *w == needle)
+                .count()
+        };
+        assert_eq!(count(b"Password: "), MAX_LOGIN_ATTEMPTS as usize);
+        assert_eq!(count(b"Login incorrect\r\n"), MAX_LOGIN_ATTEMPTS as usize);
+    }
+}
@@ -0,0 +1,494 @@
+//! Output and input handling of the `wifi` command. Nothing in here touches
+//! the radio, a [`Wifi`] back-end feeds it plain data.
+
+use crate::output::Object;
+use std::cmp::Reverse;
+use std::fmt::{self, Write};
+use std::io;
+use std::net::Ipv4Addr;
+
+/// Longest SSID and WPA passphrase the driver accepts
+pub const MAX_SSID_LEN: usize = 32;
+pub const MAX_PASSWORD_LEN: usize = 64;
+
+/// Names accepted by `--auth`, in the order of [`Auth`]
+pub const AUTH_NAMES: &[&str] = &[
+    "open",
+    "wep",
+    "wpa",
+    "wpa2",
+    "wpa-wpa2",
+    "wpa2-enterprise",
+    "wpa3",
+    "wpa2-wpa3",
+    "wapi",
+];
+
+/// Authentication method of a network
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Auth {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    WpaWpa2,
+    Wpa2Enterprise,
+    Wpa3,
+    Wpa2Wpa3,
+    Wapi,
+}
+
+impl Auth {
+    const ALL: [Auth; 9] = [
+        Auth::Open,
+        Auth::Wep,
+        Auth::Wpa,
+        Auth::Wpa2,
+        Auth::WpaWpa2,
+        Auth::Wpa2Enterprise,
+        Auth::Wpa3,
+        Auth::Wpa2Wpa3,
+        Auth::Wapi,
+    ];
+
+    pub fn parse(name: &str) -> Option<Auth> {
+        AUTH_NAMES
+            .iter()
+            .position(|n| *n == name)
+            .map(|index| Auth::ALL[index])
+    }
+
+    pub fn name(self) -> &'static str {
+        AUTH_NAMES[self as usize]
+    }
+
+    /// Method used when `--auth` is not given
+    pub fn guess(password: &str) -> Auth {
+        if password.is_empty() {
+            Auth::Open
+        } else {
+            Auth::Wpa2
+        }
+    }
+}
+
+/// Check an SSID typed or written by a user
+pub fn check_ssid(ssid: &str) -> Result<(), String> {
+    if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+        return Err(format!("SSID must be 1 to {} bytes long", MAX_SSID_LEN));
+    }
+    Ok(())
+}
+
+/// Check that `password` is a key `auth` can use: nothing for open networks,
+/// a 5 or 13 character key (10 or 26 hex digits) for WEP and a passphrase of
+/// 8 to 63 characters or a 64 hex digit key otherwise. Enterprise networks
+/// are not checked.
+pub fn check_password(password: &str, auth: Auth) -> Result<(), String> {
+    let hex = password.bytes().all(|b| b.is_ascii_hexdigit());
+    let len = password.len();
+    let valid = match auth {
+        Auth::Open => len == 0,
+        Auth::Wep => matches!(len, 5 | 13) || (hex && matches!(len, 10 | 26)),
+        Auth::Wpa2Enterprise => true,
+        _ => (8..MAX_PASSWORD_LEN).contains(&len) || (hex && len == MAX_PASSWORD_LEN),
+    };
+    match (valid, auth) {
+        (true, _) => Ok(()),
+        (false, Auth::Open) => Err("Open networks take no password".to_string()),
+        (false, Auth::Wep) => {
+            Err("WEP keys are 5 or 13 characters, or 10 or 26 hex digits".to_string())
+        }
+        (false, _) => Err(format!(
+            "{} passwords are 8 to 63 characters, or 64 hex digits",
+            auth.name()
+        )),
+    }
+}
+
+/// One access point found by a scan
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    pub ssid: String,
+    pub rssi: i8,
+    pub channel: u8,
+    pub auth: Option<Auth>,
+}
+
+/// Connection details shown by `wifi status`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+    pub ip: Ipv4Addr,
+    /// Subnet prefix length
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+}
+
+/// Station interface behind the `wifi` command, the radio or a simulation
+pub trait Wifi: Send {
+    fn scan(&mut self) -> io::Result<Vec<Network>>;
+
+    /// Join `ssid` and remember it once the interface is up
+    fn connect(&mut self, ssid: &str, password: &str, auth: Auth) -> io::Result<()>;
+
+    /// Join the saved network, returning its SSID or `None` if there is none
+    fn reconnect(&mut self) -> io::Result<Option<String>>;
+
+    /// Leave the current network and drop the saved one
+    fn forget(&mut self) -> io::Result<()>;
+
+    /// Details of the connection, `None` while not connected
+    fn status(&self) -> Option<Status>;
+}
+
+/// Print the networks strongest first, one per line
+pub fn write_scan(networks: &[Network], out: &mut impl Write) -> fmt::Result {
+    if networks.is_empty() {
+        return writeln!(out, "No networks found");
+    }
+    let mut networks: Vec<_> = networks.iter().collect();
+    networks.sort_by_key(|network| Reverse(network.rssi));
+
+    let width = networks
+        .iter()
+        .map(|network| ssid_name(&network.ssid).chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("SSID".len());
+    writeln!(out, "{:<width$}  RSSI  CH  AUTH", "SSID")?;
+    for network in networks {
+        writeln!(
+            out,
+            "{:<width$}  {:>4}  {:>2}  {}",
+            ssid_name(&network.ssid),
+            network.rssi,
+            network.channel,
+            network.auth.map_or("?", Auth::name)
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the connection, `None` when the station is not connected
+pub fn write_status(status: Option<&Status>, out: &mut impl Write) -> fmt::Result {
+    let status = match status {
+        Some(status) => status,
+        None => return writeln!(out, "Not connected"),
+    };
+    writeln!(out, "SSID:     {}", ssid_name(&status.ssid))?;
+    writeln!(out, "BSSID:    {}", format_mac(&status.bssid))?;
+    writeln!(out, "Channel:  {}", status.channel)?;
+    writeln!(out, "RSSI:     {} dBm", status.rssi)?;
+    writeln!(out, "IP:       {}/{}", status.ip, status.prefix)?;
+    writeln!(out, "Gateway:  {}", status.gateway)?;
+    match status.dns {
+        Some(dns) => writeln!(out, "DNS:      {}", dns),
+        None => writeln!(out, "DNS:      none"),
+    }
+}
+
+/// Scan result as JSON, strongest network first
+pub fn scan_object(networks: &[Network]) -> Object {
+    let mut networks: Vec<_> = networks.iter().collect();
+    networks.sort_by_key(|network| Reverse(network.rssi));
+    let networks: Vec<_> = networks
+        .into_iter()
+        .map(|network| {
+            Object::new()
+                .field("ssid", network.ssid.as_str())
+                .field("rssi", network.rssi)
+                .field("channel", network.channel)
+                .field("auth", network.auth.map(Auth::name))
+        })
+        .collect();
+    Object::new().field("networks", networks)
+}
+
+/// Connection as JSON, every field but `connected` is null when not connected
+pub fn status_object(status: Option<&Status>) -> Object {
+    Object::new()
+        .field("connected", status.is_some())
+        .field("ssid", status.map(|s| s.ssid.as_str()))
+        .field("bssid", status.map(|s| format_mac(&s.bssid)))
+        .field("channel", status.map(|s| s.channel))
+        .field("rssi", status.map(|s| s.rssi))
+        .field("ip", status.map(|s| s.ip.to_string()))
+        .field("prefix", status.map(|s| s.prefix))
+        .field("gateway", status.map(|s| s.gateway.to_string()))
+        .field("dns", status.and_then(|s| s.dns).map(|dns| dns.to_string()))
+}
+
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn ssid_name(ssid: &str) -> &str {
+    if ssid.is_empty() {
+        "<hidden>"
+    } else {
+        ssid
+    }
+}
+
+/// Whether typed characters are shown
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Echo {
+    Visible,
+    Hidden,
+}
+
+/// Ask for a line of up to `max_len` characters. Backspace edits the line,
+/// Ctrl-C cancels it and returns `None`. `read` fetches the next byte the
+/// user typed from `term`.
+pub fn prompt<T: Write>(
+    term: &mut T,
+    mut read: impl FnMut(&mut T) -> io::Result<u8>,
+    label: &str,
+    echo: Echo,
+    max_len: usize,
+) -> io::Result<Option<String>> {
+    // Context swallows write errors, a dropped session shows up on the next read
+    let _ = term.write_str(label);
+    let mut line = String::new();
+    loop {
+        match read(term)? {
+            b'\r' | b'\n' => break,
+            // Ctrl-C
+            0x03 => {
+                let _ = term.write_str("^C\n");
+                return Ok(None);
+            }
+            // Backspace and DEL
+            0x08 | 0x7f => {
+                let erased = line.pop().is_some();
+                if erased && echo == Echo::Visible {
+                    let _ = term.write_str("\x08 \x08");
+                }
+            }
+            byte @ 0x20..=0x7e if line.len() < max_len => {
+                line.push(byte as char);
+                if echo == Echo::Visible {
+                    let _ = term.write_char(byte as char);
+                }
+            }
+            _ => (),
+        }
+    }
+    let _ = term.write_str("\n");
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str, rssi: i8, channel: u8, auth: Option<Auth>) -> Network {
+        Network {
+            ssid: ssid.into(),
+            rssi,
+            channel,
+            auth,
+        }
+    }
+
+    fn status() -> Status {
+        Status {
+            ssid: "home".into(),
+            bssid: [0x02, 0xab, 0x00, 0x10, 0x20, 0xff],
+            channel: 6,
+            rssi: -52,
+            ip: Ipv4Addr::new(192, 168, 1, 23),
+            prefix: 24,
+            gateway: Ipv4Addr::new(192, 168, 1, 1),
+            dns: None,
+        }
+    }
+
+    #[test]
+    fn auth_names_round_trip() {
+        for auth in Auth::ALL {
+            assert_eq!(Auth::parse(auth.name()), Some(auth));
+        }
+        assert_eq!(Auth::parse("wpa2-wpa3"), Some(Auth::Wpa2Wpa3));
+        assert_eq!(Auth::parse("WPA2"), None);
+        assert_eq!(Auth::parse(""), None);
+        assert_eq!(Auth::guess(""), Auth::Open);
+        assert_eq!(Auth::guess("password"), Auth::Wpa2);
+    }
+
+    #[test]
+    fn scan_table_strongest_first() {
+        let networks = [
+            network("office", -71, 11, Some(Auth::Wpa2Wpa3)),
+            network("", -85, 1, Some(Auth::Wpa2)),
+            network("guest", -42, 6, Some(Auth::Open)),
+            network("odd", -60, 13, None),
+        ];
+        let mut out = String::new();
+        write_scan(&networks, &mut out).unwrap();
+        assert_eq!(
+            out,
+            "SSID      RSSI  CH  AUTH\n\
+             guest      -42   6  open\n\
+             odd        -60  13  ?\n\
+             office     -71  11  wpa2-wpa3\n\
+             <hidden>   -85   1  wpa2\n"
+        );
+        assert_eq!(
+            scan_object(&networks[2..]).to_string(),
+            "{\"networks\":[\
+             {\"ssid\":\"guest\",\"rssi\":-42,\"channel\":6,\"auth\":\"open\"},\
+             {\"ssid\":\"odd\",\"rssi\":-60,\"channel\":13,\"auth\":null}]}"
+        );
+    }
+
+    #[test]
+    fn scan_table_widens_for_long_ssids() {
+        let networks = [network("ü-long-network", -50, 1, Some(Auth::Wpa2))];
+        let mut out = String::new();
+        write_scan(&networks, &mut out).unwrap();
+        assert_eq!(
+            out,
+            "SSID            RSSI  CH  AUTH\nü-long-network   -50   1  wpa2\n"
+        );
+        let mut out = String::new();
+        write_scan(&[], &mut out).unwrap();
+        assert_eq!(out, "No networks found\n");
+    }
+
+    #[test]
+    fn status_text_and_json() {
+        let mut out = String::new();
+        write_status(Some(&status()), &mut out).unwrap();
+        assert_eq!(
+            out,
+            "SSID:     home\n\
+             BSSID:    02:ab:00:10:20:ff\n\
+             Channel:  6\n\
+             RSSI:     -52 dBm\n\
+             IP:       192.168.1.23/24\n\
+             Gateway:  192.168.1.1\n\
+             DNS:      none\n"
+        );
+        let mut out = String::new();
+        write_status(None, &mut out).unwrap();
+        assert_eq!(out, "Not connected\n");
+        let mut with_dns = status();
+        with_dns.dns = Some(Ipv4Addr::new(1, 1, 1, 1));
+        assert_eq!(
+            status_object(Some(&with_dns)).to_string(),
+            "{\"connected\":true,\"ssid\":\"home\",\"bssid\":\"02:ab:00:10:20:ff\",\
+             \"channel\":6,\"rssi\":-52,\"ip\":\"192.168.1.23\",\"prefix\":24,\
+             \"gateway\":\"192.168.1.1\",\"dns\":\"1.1.1.1\"}"
+        );
+        assert_eq!(
+            status_object(None).to_string(),
+            "{\"connected\":false,\"ssid\":null,\"bssid\":null,\"channel\":null,\
+             \"rssi\":null,\"ip\":null,\"prefix\":null,\"gateway\":null,\"dns\":null}"
+        );
+    }
+
+    #[test]
+    fn ssid_lengths() {
+        assert_eq!(check_ssid("a"), Ok(()));
+        assert_eq!(check_ssid(&"s".repeat(32)), Ok(()));
+        let error = Err("SSID must be 1 to 32 bytes long".to_string());
+        assert_eq!(check_ssid(""), error);
+        assert_eq!(check_ssid(&"s".repeat(33)), error);
+        // Bytes are counted, not characters
+        assert_eq!(check_ssid(&"ü".repeat(17)), error);
+    }
+
+    #[test]
+    fn passwords_fit_the_authentication() {
+        assert_eq!(check_password("", Auth::Open), Ok(()));
+        assert_eq!(
+            check_password("secret", Auth::Open),
+            Err("Open networks take no password".to_string())
+        );
+        for key in [
+            "12345",
+            "1234567890123",
+            "0123456789",
+            "0123456789abcdef0123456789",
+        ] {
+            assert_eq!(check_password(key, Auth::Wep), Ok(()), "{}", key);
+        }
+        assert_eq!(
+            check_password("012345678g", Auth::Wep),
+            Err("WEP keys are 5 or 13 characters, or 10 or 26 hex digits".to_string())
+        );
+        assert_eq!(check_password("password", Auth::Wpa2), Ok(()));
+        assert_eq!(check_password(&"p".repeat(63), Auth::Wpa3), Ok(()));
+        assert_eq!(check_password(&"ab".repeat(32), Auth::WpaWpa2), Ok(()));
+        let error = Err("wpa2 passwords are 8 to 63 characters, or 64 hex digits".to_string());
+        assert_eq!(check_password("short", Auth::Wpa2), error);
+        assert_eq!(check_password(&"p".repeat(64), Auth::Wpa2), error);
+        assert_eq!(check_password("", Auth::Wpa2Enterprise), Ok(()));
+    }
+
+    // Run `prompt` over `input`, returning the line and the echo
+    fn prompt_with(input: &[u8], echo: Echo, max_len: usize) -> (Option<String>, String) {
+        let mut input = input.iter().copied();
+        let mut term = String::new();
+        let line = prompt(
+            &mut term,
+            |_| {
+                input
+                    .next()
+                    .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+            },
+            "SSID: ",
+            echo,
+            max_len,
+        )
+        .unwrap();
+        (line, term)
+    }
+
+    #[test]
+    fn prompt_edits_and_echoes() {
+        assert_eq!(
+            prompt_with(b"homx\x7fe\r", Echo::Visible, 32),
+            (Some("home".into()), "SSID: homx\x08 \x08e\n".into())
+        );
+        assert_eq!(
+            prompt_with(b"s3cret\n", Echo::Hidden, 64),
+            (Some("s3cret".into()), "SSID: \n".into())
+        );
+        // Backspace on an empty line and control characters are ignored
+        assert_eq!(
+            prompt_with(b"\x08a\x1bb\r", Echo::Visible, 32),
+            (Some("ab".into()), "SSID: ab\n".into())
+        );
+    }
+
+    #[test]
+    fn prompt_stops_at_max_len_and_cancels() {
+        assert_eq!(
+            prompt_with(b"abcdef\r", Echo::Hidden, 4).0,
+            Some("abcd".into())
+        );
+        assert_eq!(
+            prompt_with(b"ab\x03", Echo::Visible, 32),
+            (None, "SSID: ab^C\n".into())
+        );
+        let mut term = String::new();
+        let result = prompt(
+            &mut term,
+            |_| Err(io::ErrorKind::UnexpectedEof.into()),
+            "SSID: ",
+            Echo::Visible,
+            32,
+        );
+        assert!(result.is_err());
+    }
+}